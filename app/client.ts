@@ -11,6 +11,7 @@ import {
   TOKEN_PROGRAM_ID,
   createMint,
   createAssociatedTokenAccount,
+  getOrCreateAssociatedTokenAccount,
   mintTo,
   getAssociatedTokenAddress,
   getAccount,
@@ -31,6 +32,7 @@ class SwapClient {
   mintAuthority: Keypair;
 
   readonly TOKEN_DECIMALS = 6;
+  readonly FEE_TIER_BPS = 30;
 
   constructor() {
     // Connect to local testnet
@@ -113,6 +115,11 @@ class SwapClient {
       this.TOKEN_DECIMALS
     );
 
+    // Pools require mint_a to sort before mint_b
+    if (Buffer.compare(this.mintA.toBuffer(), this.mintB.toBuffer()) > 0) {
+      [this.mintA, this.mintB] = [this.mintB, this.mintA];
+    }
+
     console.log(`Token A Mint: ${this.mintA.toBase58()}`);
     console.log(`Token B Mint: ${this.mintB.toBase58()}\n`);
   }
@@ -201,9 +208,33 @@ class SwapClient {
   async initializePool() {
     console.log("Initializing swap pool...");
 
-    // Derive PDAs
+    // The factory is created once per program deployment
+    const [factory] = PublicKey.findProgramAddressSync(
+      [Buffer.from("factory")],
+      this.program.programId
+    );
+    if ((await this.connection.getAccountInfo(factory)) === null) {
+      await this.program.methods
+        .initializeFactory()
+        .accounts({
+          payer: this.payer.publicKey,
+          factory,
+          systemProgram: SystemProgram.programId,
+        } as any)
+        .signers([this.payer])
+        .rpc();
+    }
+
+    // Derive PDAs; the pool address includes its fee tier (u16 LE)
+    const feeTier = Buffer.alloc(2);
+    feeTier.writeUInt16LE(this.FEE_TIER_BPS);
     const [poolPda] = PublicKey.findProgramAddressSync(
-      [Buffer.from("pool"), this.mintA.toBuffer(), this.mintB.toBuffer()],
+      [
+        Buffer.from("pool"),
+        this.mintA.toBuffer(),
+        this.mintB.toBuffer(),
+        feeTier,
+      ],
       this.program.programId
     );
 
@@ -217,16 +248,44 @@ class SwapClient {
       this.program.programId
     );
 
+    const [lpMint] = PublicKey.findProgramAddressSync(
+      [Buffer.from("lp_mint"), poolPda.toBuffer()],
+      this.program.programId
+    );
+
+    const [lockedLp] = PublicKey.findProgramAddressSync(
+      [Buffer.from("locked_lp"), poolPda.toBuffer()],
+      this.program.programId
+    );
+
     try {
       const tx = await this.program.methods
-        .initializePool()
+        // The pair's canonical (unlabeled) 0.3% pool, no protocol or referral
+        // fee, constant-product curve
+        .initializePool(
+          this.FEE_TIER_BPS,
+          Array(8).fill(0),
+          null,
+          null,
+          null,
+          null,
+          null,
+          null,
+          null,
+          null,
+          null
+        )
         .accounts({
+          payer: this.payer.publicKey,
           authority: this.payer.publicKey,
+          factory,
           pool: poolPda,
           mintA: this.mintA,
           mintB: this.mintB,
           vaultA: vaultA,
           vaultB: vaultB,
+          lpMint: lpMint,
+          lockedLp: lockedLp,
           tokenProgram: TOKEN_PROGRAM_ID,
           systemProgram: SystemProgram.programId,
         } as any)
@@ -236,9 +295,10 @@ class SwapClient {
       console.log(`✅ Pool initialized! Transaction: ${tx}`);
       console.log(`Pool PDA: ${poolPda.toBase58()}`);
       console.log(`Vault A: ${vaultA.toBase58()}`);
-      console.log(`Vault B: ${vaultB.toBase58()}\n`);
+      console.log(`Vault B: ${vaultB.toBase58()}`);
+      console.log(`LP Mint: ${lpMint.toBase58()}\n`);
 
-      return { poolPda, vaultA, vaultB };
+      return { poolPda, vaultA, vaultB, lpMint };
     } catch (error) {
       console.error("❌ Error initializing pool:", error);
       throw error;
@@ -249,6 +309,7 @@ class SwapClient {
     poolPda: PublicKey,
     vaultA: PublicKey,
     vaultB: PublicKey,
+    lpMint: PublicKey,
     amountA: number,
     amountB: number
   ) {
@@ -264,6 +325,16 @@ class SwapClient {
       this.mintB,
       this.liquidityProvider.publicKey
     );
+    const [lockedLp] = PublicKey.findProgramAddressSync(
+      [Buffer.from("locked_lp"), poolPda.toBuffer()],
+      this.program.programId
+    );
+    const lpTokenAccount = await getOrCreateAssociatedTokenAccount(
+      this.connection,
+      this.payer,
+      lpMint,
+      this.liquidityProvider.publicKey
+    );
 
     // Show LP balances before adding liquidity
     await this.showUserBalances(
@@ -277,15 +348,23 @@ class SwapClient {
       const tx = await this.program.methods
         .addLiquidity(
           this.tokenToLamports(amountA),
-          this.tokenToLamports(amountB)
+          this.tokenToLamports(amountB),
+          new BN(0), // accept any LP amount
+          null, // no opening price check
+          0
         )
         .accounts({
           user: this.liquidityProvider.publicKey,
           pool: poolPda,
+          mintA: this.mintA,
+          mintB: this.mintB,
           userTokenA: lpTokenA,
           userTokenB: lpTokenB,
           vaultA: vaultA,
           vaultB: vaultB,
+          lpMint: lpMint,
+          lockedLp: lockedLp,
+          userLpToken: lpTokenAccount.address,
           tokenProgram: TOKEN_PROGRAM_ID,
         } as any)
         .signers([this.liquidityProvider])
@@ -293,6 +372,14 @@ class SwapClient {
 
       console.log(`✅ Liquidity added! Transaction: ${tx}`);
 
+      const lpBalance = await getAccount(
+        this.connection,
+        lpTokenAccount.address
+      );
+      console.log(
+        `  LP tokens held: ${this.lamportsToToken(lpBalance.amount)}`
+      );
+
       // Show LP balances after adding liquidity
       await this.showUserBalances(
         lpTokenA,
@@ -341,6 +428,7 @@ class SwapClient {
 
     // Calculate expected output
     const expectedOutput = await this.calculateSwapOutput(
+      poolPda,
       vaultA,
       vaultB,
       amountIn,
@@ -353,11 +441,15 @@ class SwapClient {
         .swap(
           this.tokenToLamports(amountIn),
           this.tokenToLamports(minAmountOut),
-          aToB
+          aToB,
+          new BN(Math.floor(Date.now() / 1000) + 60), // valid for 60 seconds
+          null // no price impact cap
         )
         .accounts({
           user: this.trader.publicKey,
           pool: poolPda,
+          mintA: this.mintA,
+          mintB: this.mintB,
           userTokenA: traderTokenA,
           userTokenB: traderTokenB,
           vaultA: vaultA,
@@ -384,24 +476,33 @@ class SwapClient {
 
   // Helper function to calculate expected swap output
   async calculateSwapOutput(
+    poolPda: PublicKey,
     vaultA: PublicKey,
     vaultB: PublicKey,
     amountIn: number,
     aToB: boolean
   ): Promise<number> {
     try {
+      const pool = await this.program.account.pool.fetch(poolPda);
       const accountA = await getAccount(this.connection, vaultA);
       const accountB = await getAccount(this.connection, vaultB);
 
       const vaultABalance = this.lamportsToToken(accountA.amount);
       const vaultBBalance = this.lamportsToToken(accountB.amount);
 
+      // The pool keeps fee_bps of the input before applying the curve
+      const amountInWithFee = (amountIn * (10000 - pool.feeBps)) / 10000;
+
       if (aToB) {
         // amount_out = (amount_in * vault_b) / (vault_a + amount_in)
-        return (amountIn * vaultBBalance) / (vaultABalance + amountIn);
+        return (
+          (amountInWithFee * vaultBBalance) / (vaultABalance + amountInWithFee)
+        );
       } else {
         // amount_out = (amount_in * vault_a) / (vault_b + amount_in)
-        return (amountIn * vaultABalance) / (vaultBBalance + amountIn);
+        return (
+          (amountInWithFee * vaultABalance) / (vaultBBalance + amountInWithFee)
+        );
       }
     } catch (error) {
       console.log("Could not calculate expected output:", error);
@@ -459,10 +560,10 @@ class SwapClient {
       await this.initialize();
 
       // Initialize pool
-      const { poolPda, vaultA, vaultB } = await this.initializePool();
+      const { poolPda, vaultA, vaultB, lpMint } = await this.initializePool();
 
       // Liquidity Provider adds liquidity
-      await this.addLiquidity(poolPda, vaultA, vaultB, 50, 50);
+      await this.addLiquidity(poolPda, vaultA, vaultB, lpMint, 50, 50);
 
       console.log("⏳ Waiting 2 seconds before first swap...\n");
       await new Promise((resolve) => setTimeout(resolve, 2000));
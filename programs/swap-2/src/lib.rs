@@ -1,282 +1,9171 @@
+// Anchor 0.31's generated IDL instructions still call the deprecated `AccountInfo::realloc`
+#![allow(deprecated)]
+
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
+use anchor_lang::solana_program::compute_units::sol_remaining_compute_units;
+use anchor_lang::solana_program::program_pack::Pack;
+use anchor_lang::solana_program::sysvar::instructions::{
+    load_current_index_checked, load_instruction_at_checked,
+};
+use anchor_lang::system_program::{self, Allocate, Assign, CreateAccount};
+use anchor_spl::metadata::{
+    self, mpl_token_metadata::types::DataV2, CreateMetadataAccountsV3, Metadata,
+};
+use anchor_spl::token::spl_token;
+use anchor_spl::token_interface::{
+    self, spl_token_2022, Burn, CloseAccount, InitializeAccount3, Mint, MintTo, SetAuthority,
+    SyncNative, TokenAccount, TokenInterface, TransferChecked,
+};
 
+#[cfg(not(feature = "simulate"))]
 declare_id!("CvnhLUPvpUo5gWfURBBR787G9xNVuoia4mZ67MpMhjmh");
 
+// Simulated swaps book trades without moving tokens, so a `simulate` build gets
+// an ID of its own that no one holds the keypair for. It can be loaded into a
+// test harness at that address but never deployed as the real program.
+#[cfg(feature = "simulate")]
+declare_id!("6PcGE5hRYmRLzvvh2UzYjGTpsUtxYTWa2Ats1eF5Dng2");
+
+// Simulated swaps leave the vaults untouched, which strict-checks would report
+// as broken accounting
+#[cfg(all(feature = "simulate", feature = "strict-checks"))]
+compile_error!("the `simulate` and `strict-checks` features cannot be combined");
+
+#[constant]
+pub const LP_MINT_DECIMALS: u8 = 6;
+
+#[constant]
+pub const DEFAULT_FEE_BPS: u16 = 30;
+
+// Fee tiers a pool can be created at. The tier is part of the pool's address,
+// so one pair can have a pool at each of them.
+#[constant]
+pub const FEE_TIERS: [u16; 3] = [5, 30, 100];
+
+#[constant]
+pub const FEE_DENOMINATOR: u64 = 10_000;
+
+// Highest swap fee a pool can be created with or moved to
+#[constant]
+pub const MAX_FEE_BPS: u16 = 1_000;
+
+// Delay between proposing a fee change and it taking effect
+#[constant]
+pub const FEE_TIMELOCK_SECONDS: i64 = 86_400;
+
+// Length of the TWAP window that dynamic fees measure volatility against
+#[constant]
+pub const VOLATILITY_WINDOW_SECONDS: i64 = 3_600;
+
+// Deviation of the spot price from the TWAP at which a dynamic fee reaches
+// the top of its band
+#[constant]
+pub const VOLATILITY_CAP_BPS: u64 = 1_000;
+
+// Number of oracle observations each pool keeps for consult
+pub const OBSERVATION_COUNT: usize = 16;
+
+// Remaining accounts each batch_swap leg takes
+pub const BATCH_LEG_ACCOUNTS: usize = 7;
+
+// Remaining accounts each batch_add_liquidity deposit takes
+pub const BATCH_DEPOSIT_ACCOUNTS: usize = 9;
+
+// LP tokens locked in the pool on the first deposit so the supply never returns to zero
+#[constant]
+pub const MINIMUM_LIQUIDITY: u64 = 1_000;
+
+// Token count and Newton iteration cap for the StableSwap invariant
+const STABLE_N_COINS: u128 = 2;
+const STABLE_MAX_ITERATIONS: usize = 255;
+
+// Upper bound on the StableSwap amplification coefficient
+#[constant]
+pub const MAX_AMP: u64 = 10_000;
+
+// Range of mint decimals a pool accepts. Outside it the decimals-normalized
+// price math overflows or loses most of its precision.
+#[constant]
+pub const MIN_MINT_DECIMALS: u8 = 1;
+#[constant]
+pub const MAX_MINT_DECIMALS: u8 = 12;
+
+// Layout version of new pools. Bump it whenever `Pool` gains fields (always
+// appended at the end) and give them their defaults in `Pool::migrate`.
+#[constant]
+pub const POOL_VERSION: u8 = 6;
+
+// How many mints a permissioned deployment can approve
+pub const MAX_WHITELISTED_MINTS: usize = 64;
+
+// Fixed-point scale for prices reported by get_spot_price
+#[constant]
+pub const PRICE_SCALE: u128 = 1_000_000_000_000;
+
+// Default tick spacing of concentrated pools: positions start and end on
+// multiples of it, i.e. price steps of about 0.6%
+#[constant]
+pub const TICK_SPACING: i32 = 60;
+// Tick range of concentrated pools, a price of token A in token B (raw units)
+// from about 2^-32 to 2^32
+#[constant]
+pub const MIN_TICK: i32 = -221_760;
+#[constant]
+pub const MAX_TICK: i32 = 221_760;
+
+// Initialized ticks (range ends in use by some position) a concentrated pool
+// can track
+pub const MAX_TICKS: usize = 32;
+
+// 1.0 in Q64.64, the fixed-point format of concentrated pools' sqrt prices and
+// fee growth
+const Q64: u128 = 1 << 64;
+
+// Liquidity whose virtual reserves stand in for a concentrated pool's reserves
+// wherever a price is read off the reserve ratio (oracle, spot price, impact)
+const PRICE_REFERENCE_LIQUIDITY: u128 = 1 << 32;
+
+// Compute units set aside for the parts of a swap's heaviest path, checked by
+// pools with the compute guard on: the token transfers, checks, and event
+// every swap makes; the Newton iterations for a stable pool's D and new
+// balance; each initialized tick a concentrated swap might cross; the TWAP
+// accumulators and observation write; and re-pricing a dynamic fee off the
+// TWAP. They are unmeasured allowances, picked to be generous rather than
+// benchmarked against this program.
+pub const SWAP_BASE_COMPUTE: u64 = 40_000;
+pub const STABLE_CURVE_COMPUTE: u64 = 30_000;
+pub const TICK_CROSSING_COMPUTE: u64 = 2_500;
+pub const ORACLE_COMPUTE: u64 = 5_000;
+pub const DYNAMIC_FEE_COMPUTE: u64 = 5_000;
+
 #[program]
 pub mod simple_swap {
     use super::*;
 
-    pub fn initialize_pool(ctx: Context<InitializePool>) -> Result<()> {
-        let pool = &mut ctx.accounts.pool;
-        pool.authority = ctx.accounts.authority.key();
-        pool.mint_a = ctx.accounts.mint_a.key();
-        pool.mint_b = ctx.accounts.mint_b.key();
-        pool.bump = ctx.bumps.pool;
+    /// Creates the program-wide factory that counts pools. Needed once, before
+    /// the first `initialize_pool`.
+    pub fn initialize_factory(ctx: Context<InitializeFactory>) -> Result<()> {
+        ctx.accounts.factory.bump = ctx.bumps.factory;
         Ok(())
     }
 
-    pub fn add_liquidity(ctx: Context<AddLiquidity>, amount_a: u64, amount_b: u64) -> Result<()> {
-        // Transfer tokens from user to pool vaults
-        let cpi_accounts_a = Transfer {
-            from: ctx.accounts.user_token_a.to_account_info(),
-            to: ctx.accounts.vault_a.to_account_info(),
-            authority: ctx.accounts.user.to_account_info(),
-        };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx_a = CpiContext::new(cpi_program.clone(), cpi_accounts_a);
-        token::transfer(cpi_ctx_a, amount_a)?;
+    /// Turns on the mint whitelist: from now on `initialize_pool` only accepts
+    /// pairs whose mints are both on it. Only the program's upgrade authority
+    /// can do this, and it becomes the whitelist's authority. Deployments that
+    /// never call this stay permissionless.
+    pub fn initialize_mint_whitelist(ctx: Context<InitializeMintWhitelist>) -> Result<()> {
+        let whitelist = &mut ctx.accounts.mint_whitelist;
+        whitelist.authority = ctx.accounts.authority.key();
+        whitelist.enabled = true;
+        whitelist.bump = ctx.bumps.mint_whitelist;
+        Ok(())
+    }
 
-        let cpi_accounts_b = Transfer {
-            from: ctx.accounts.user_token_b.to_account_info(),
-            to: ctx.accounts.vault_b.to_account_info(),
-            authority: ctx.accounts.user.to_account_info(),
-        };
-        let cpi_ctx_b = CpiContext::new(cpi_program, cpi_accounts_b);
-        token::transfer(cpi_ctx_b, amount_b)?;
+    /// Approves `mint` for new pools. Approving it twice is a no-op.
+    pub fn add_allowed_mint(ctx: Context<UpdateMintWhitelist>, mint: Pubkey) -> Result<()> {
+        ctx.accounts.mint_whitelist.add(mint)
+    }
 
+    /// Withdraws approval for `mint`. Existing pools that use it are unaffected.
+    pub fn remove_allowed_mint(ctx: Context<UpdateMintWhitelist>, mint: Pubkey) -> Result<()> {
+        ctx.accounts.mint_whitelist.remove(mint);
         Ok(())
     }
 
-    pub fn swap(
-        ctx: Context<Swap>,
-        amount_in: u64,
-        minimum_amount_out: u64,
-        a_to_b: bool, // true if swapping A for B, false if swapping B for A
+    /// Suspends (or resumes) the whitelist check without forgetting the list.
+    pub fn set_mint_whitelist_enabled(
+        ctx: Context<UpdateMintWhitelist>,
+        enabled: bool,
     ) -> Result<()> {
-        // Get current balances
-        let balance_a = ctx.accounts.vault_a.amount;
-        let balance_b = ctx.accounts.vault_b.amount;
+        ctx.accounts.mint_whitelist.enabled = enabled;
+        Ok(())
+    }
 
-        // Calculate output amount using constant product formula (x * y = k)
-        let amount_out = if a_to_b {
-            calculate_swap_output(amount_in, balance_a, balance_b)?
-        } else {
-            calculate_swap_output(amount_in, balance_b, balance_a)?
-        };
+    /// Creates the program-wide config whose fee defaults seed new pools. Only
+    /// the program's upgrade authority can do this, and it becomes the config's
+    /// admin. Until it exists, pools default to their fee tier.
+    pub fn initialize_global_config(
+        ctx: Context<InitializeGlobalConfig>,
+        default_fee_bps: u16,
+        default_protocol_fee_bps: u16,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.global_config;
+        config.admin = ctx.accounts.admin.key();
+        config.set_defaults(default_fee_bps, default_protocol_fee_bps)?;
+        config.protocol_fee_on = true;
+        config.bump = ctx.bumps.global_config;
+        Ok(())
+    }
 
-        require!(amount_out >= minimum_amount_out, SwapError::SlippageTooHigh);
+    /// Changes the fee defaults for pools created from now on. Existing pools
+    /// keep the fees they have.
+    pub fn update_global_config(
+        ctx: Context<UpdateGlobalConfig>,
+        default_fee_bps: u16,
+        default_protocol_fee_bps: u16,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .set_defaults(default_fee_bps, default_protocol_fee_bps)
+    }
 
-        // Perform the swap
-        if a_to_b {
-            // Transfer token A from user to vault
-            let cpi_accounts_in = Transfer {
-                from: ctx.accounts.user_token_a.to_account_info(),
-                to: ctx.accounts.vault_a.to_account_info(),
-                authority: ctx.accounts.user.to_account_info(),
-            };
-            let cpi_ctx_in = CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                cpi_accounts_in,
-            );
-            token::transfer(cpi_ctx_in, amount_in)?;
-
-            // Transfer token B from vault to user
-            let seeds = &[
-                b"pool",
-                ctx.accounts.pool.mint_a.as_ref(),
-                ctx.accounts.pool.mint_b.as_ref(),
-                &[ctx.accounts.pool.bump],
-            ];
-            let signer = &[&seeds[..]];
-
-            let cpi_accounts_out = Transfer {
-                from: ctx.accounts.vault_b.to_account_info(),
-                to: ctx.accounts.user_token_b.to_account_info(),
+    /// Turns protocol fee accrual on or off for every pool at once. While it is
+    /// off, swaps leave the protocol's cut of the fee in the reserves for LPs;
+    /// pools keep their `protocol_fee_bps` for when it comes back on. Only the
+    /// config's admin may call this.
+    pub fn set_protocol_fee_on(
+        ctx: Context<UpdateGlobalConfig>,
+        protocol_fee_on: bool,
+    ) -> Result<()> {
+        ctx.accounts.global_config.protocol_fee_on = protocol_fee_on;
+        Ok(())
+    }
+
+    /// Creates the pool for a token pair at one of the `FEE_TIERS`. `mint_a` must
+    /// sort strictly before `mint_b` (byte order of the pubkeys) so each pair has
+    /// a single canonical pool per tier. A nonzero `label` instead creates an
+    /// isolated pool for the pair and tier, one per label, e.g. per strategy;
+    /// all zeroes is the canonical pool. `fee_bps` and `protocol_fee_bps` (the
+    /// part of `fee_bps` set aside for the protocol) default to the global
+    /// config's defaults if it exists, otherwise to the tier and zero.
+    /// `curve_type` defaults to constant product; pick `Stable` for pegged pairs.
+    /// `fee_band` makes the fee dynamic: it then moves between the band's ends
+    /// with volatility, starting from `fee_bps` (the band's minimum by default).
+    /// `initial_tick` sets the opening price of a `Concentrated` pool (and must
+    /// be omitted for the other curves). `rebasing` marks a pool for tokens
+    /// whose balances change on their own, which `sync_rebase` then reconciles.
+    /// `oracle_enabled` (default true) set to false saves the compute of the
+    /// TWAP accumulators on every swap, at the cost of having no oracle.
+    #[allow(clippy::too_many_arguments)]
+    pub fn initialize_pool(
+        ctx: Context<InitializePool>,
+        fee_tier: u16,
+        label: [u8; 8],
+        fee_bps: Option<u16>,
+        protocol_fee_bps: Option<u16>,
+        referral_fee_bps: Option<u16>,
+        curve_type: Option<CurveType>,
+        fee_band: Option<FeeBand>,
+        initial_tick: Option<i32>,
+        tick_spacing: Option<u16>,
+        rebasing: Option<bool>,
+        oracle_enabled: Option<bool>,
+    ) -> Result<()> {
+        ctx.accounts.create(
+            &ctx.bumps,
+            fee_tier,
+            label,
+            fee_bps,
+            protocol_fee_bps,
+            referral_fee_bps,
+            curve_type,
+            fee_band,
+            initial_tick,
+            tick_spacing,
+            rebasing,
+            oracle_enabled,
+        )
+    }
+
+    /// Creates a pool for `fee_tier` and `label` with `initialize_pool`'s defaults and
+    /// makes its first deposit of `amount_a` and `amount_b` in the same
+    /// instruction, so no one can seed the empty pool at a price of their
+    /// choosing in between. The LP tokens go to `user_lp_token`, a new account
+    /// owned by the authority.
+    pub fn initialize_pool_with_liquidity(
+        ctx: Context<InitializePoolWithLiquidity>,
+        fee_tier: u16,
+        label: [u8; 8],
+        amount_a: u64,
+        amount_b: u64,
+    ) -> Result<()> {
+        require!(amount_a > 0 && amount_b > 0, SwapError::ZeroAmount);
+        ctx.accounts.init.create(
+            &ctx.bumps.init,
+            fee_tier,
+            label,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )?;
+
+        let init = &mut ctx.accounts.init;
+        let received_a = transfer_tokens_received(
+            &init.token_program,
+            &ctx.accounts.user_token_a,
+            &mut init.vault_a,
+            &init.mint_a,
+            init.authority.to_account_info(),
+            &[],
+            amount_a,
+        )?;
+        let received_b = transfer_tokens_received(
+            &init.token_program,
+            &ctx.accounts.user_token_b,
+            &mut init.vault_b,
+            &init.mint_b,
+            init.authority.to_account_info(),
+            &[],
+            amount_b,
+        )?;
+
+        let lp_amount = calculate_lp_to_mint(received_a, received_b, 0, 0, 0)?;
+        init.pool.check_liquidity_cap(0, lp_amount)?;
+
+        let seeds = &[
+            b"pool",
+            init.pool.mint_a.as_ref(),
+            init.pool.mint_b.as_ref(),
+            &init.pool.fee_tier.to_le_bytes(),
+            init.pool.label_seed(),
+            &[init.pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+        // Lock MINIMUM_LIQUIDITY just as the first add_liquidity would
+        for (to, amount) in [
+            (init.locked_lp.to_account_info(), MINIMUM_LIQUIDITY),
+            (ctx.accounts.user_lp_token.to_account_info(), lp_amount),
+        ] {
+            token_interface::mint_to(
+                CpiContext::new_with_signer(
+                    init.token_program.to_account_info(),
+                    MintTo {
+                        mint: init.lp_mint.to_account_info(),
+                        to,
+                        authority: init.pool.to_account_info(),
+                    },
+                    signer,
+                ),
+                amount,
+            )?;
+        }
+
+        let pool = &mut init.pool;
+        pool.add_reserves(received_a, received_b)?;
+        pool.record_k();
+
+        emit!(LiquidityAdded {
+            pool: pool.key(),
+            user: init.authority.key(),
+            amount_a: received_a,
+            amount_b: received_b,
+            lp_minted: lp_amount,
+            reserve_a: pool.reserve_a,
+            reserve_b: pool.reserve_b,
+        });
+
+        Ok(())
+    }
+
+    /// Deposits up to `max_amount_a` and `max_amount_b`. Once the pool has
+    /// reserves only the amounts matching the current ratio are pulled, so the
+    /// excess on the over-supplied side stays with the user. Fails if fewer than
+    /// `min_lp_out` LP tokens would be minted.
+    ///
+    /// The first deposit sets the pool's price. Passing `expected_price` (token
+    /// A in token B, decimals-normalized and scaled by `PRICE_SCALE`) makes that
+    /// deposit fail unless its implied price is within `price_tolerance_bps` of
+    /// it. Both are ignored once the pool has liquidity.
+    pub fn add_liquidity(
+        ctx: Context<AddLiquidity>,
+        max_amount_a: u64,
+        max_amount_b: u64,
+        min_lp_out: u64,
+        expected_price: Option<u128>,
+        price_tolerance_bps: u16,
+    ) -> Result<()> {
+        require!(!ctx.accounts.pool.is_paused, SwapError::PoolPaused);
+        require!(
+            !ctx.accounts.pool.flash_loan_active,
+            SwapError::FlashLoanActive
+        );
+        // A one-sided deposit can never mint LP tokens
+        require!(max_amount_a > 0 && max_amount_b > 0, SwapError::ZeroAmount);
+        // Concentrated pools take liquidity through positions instead
+        require!(
+            ctx.accounts.pool.curve_type != CurveType::Concentrated,
+            SwapError::UnsupportedCurve
+        );
+        lock_pool(&mut ctx.accounts.pool)?;
+
+        let (amount_a, amount_b) = calculate_deposit_amounts(
+            max_amount_a,
+            max_amount_b,
+            ctx.accounts.pool.reserve_a,
+            ctx.accounts.pool.reserve_b,
+        )?;
+
+        // Close out the oracle interval at the old reserves before they change
+        ctx.accounts
+            .pool
+            .update_oracle(Clock::get()?.unix_timestamp);
+
+        // Transfer tokens from user to pool vaults
+        let vault_a_before = ctx.accounts.vault_a.amount;
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.user_token_a,
+            &ctx.accounts.vault_a,
+            &ctx.accounts.mint_a,
+            ctx.accounts.user.to_account_info(),
+            &[],
+            amount_a,
+        )?;
+
+        let vault_b_before = ctx.accounts.vault_b.amount;
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.user_token_b,
+            &ctx.accounts.vault_b,
+            &ctx.accounts.mint_b,
+            ctx.accounts.user.to_account_info(),
+            &[],
+            amount_b,
+        )?;
+
+        // Credit what actually landed in the vaults, which is less than the
+        // requested amounts for tokens that charge a fee on transfer
+        ctx.accounts.vault_a.reload()?;
+        ctx.accounts.vault_b.reload()?;
+        let received_a = ctx
+            .accounts
+            .vault_a
+            .amount
+            .checked_sub(vault_a_before)
+            .ok_or(SwapError::MathOverflow)?;
+        let received_b = ctx
+            .accounts
+            .vault_b
+            .amount
+            .checked_sub(vault_b_before)
+            .ok_or(SwapError::MathOverflow)?;
+
+        // Guard the opening price against a mistyped ratio
+        if let Some(expected_price) = expected_price {
+            if ctx.accounts.lp_mint.supply == 0 {
+                let implied_price = calculate_spot_price(
+                    received_a,
+                    ctx.accounts.pool.decimals_a,
+                    received_b,
+                    ctx.accounts.pool.decimals_b,
+                )?;
+                require!(
+                    price_within_tolerance(implied_price, expected_price, price_tolerance_bps)?,
+                    SwapError::PriceOutOfRange
+                );
+            }
+        }
+
+        // Work out the LP share against the reserves before this deposit
+        let lp_amount = calculate_lp_to_mint(
+            received_a,
+            received_b,
+            ctx.accounts.pool.reserve_a,
+            ctx.accounts.pool.reserve_b,
+            ctx.accounts.lp_mint.supply,
+        )?;
+        require!(lp_amount >= min_lp_out, SwapError::SlippageTooHigh);
+        ctx.accounts
+            .pool
+            .check_liquidity_cap(ctx.accounts.lp_mint.supply, lp_amount)?;
+
+        // Mint LP tokens to the user, signed by the pool PDA
+        let seeds = &[
+            b"pool",
+            ctx.accounts.pool.mint_a.as_ref(),
+            ctx.accounts.pool.mint_b.as_ref(),
+            &ctx.accounts.pool.fee_tier.to_le_bytes(),
+            ctx.accounts.pool.label_seed(),
+            &[ctx.accounts.pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        // The first deposit also locks MINIMUM_LIQUIDITY in a pool-owned account
+        // that nothing ever withdraws from
+        if ctx.accounts.lp_mint.supply == 0 {
+            let cpi_accounts_lock = MintTo {
+                mint: ctx.accounts.lp_mint.to_account_info(),
+                to: ctx.accounts.locked_lp.to_account_info(),
                 authority: ctx.accounts.pool.to_account_info(),
             };
-            let cpi_ctx_out = CpiContext::new_with_signer(
+            let cpi_ctx_lock = CpiContext::new_with_signer(
                 ctx.accounts.token_program.to_account_info(),
-                cpi_accounts_out,
+                cpi_accounts_lock,
                 signer,
             );
-            token::transfer(cpi_ctx_out, amount_out)?;
+            token_interface::mint_to(cpi_ctx_lock, MINIMUM_LIQUIDITY)?;
+        }
+
+        let cpi_accounts_mint = MintTo {
+            mint: ctx.accounts.lp_mint.to_account_info(),
+            to: ctx.accounts.user_lp_token.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(),
+        };
+        let cpi_ctx_mint = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            cpi_accounts_mint,
+            signer,
+        );
+        token_interface::mint_to(cpi_ctx_mint, lp_amount)?;
+
+        // Update stored reserves
+        let pool = &mut ctx.accounts.pool;
+        pool.add_reserves(received_a, received_b)?;
+        pool.record_k();
+        pool.unlock();
+
+        emit!(LiquidityAdded {
+            pool: pool.key(),
+            user: ctx.accounts.user.key(),
+            amount_a: received_a,
+            amount_b: received_b,
+            lp_minted: lp_amount,
+            reserve_a: pool.reserve_a,
+            reserve_b: pool.reserve_b,
+        });
+
+        Ok(())
+    }
+
+    /// Deposits `amount_in` of a single token (A if `a_token`, otherwise B). Part
+    /// of it is swapped to the other token at the pool's curve and fee, sized by
+    /// `calculate_single_sided_swap` so that the rest and the swap output match
+    /// the post-swap reserve ratio, and both are then added as liquidity. Fails
+    /// if fewer than `min_lp_out` LP tokens would be minted. Only
+    /// constant-product pools that already hold liquidity support this.
+    pub fn add_liquidity_single(
+        ctx: Context<AddLiquidity>,
+        amount_in: u64,
+        a_token: bool,
+        min_lp_out: u64,
+    ) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        require!(!pool.is_paused, SwapError::PoolPaused);
+        require!(!pool.flash_loan_active, SwapError::FlashLoanActive);
+        require!(amount_in > 0, SwapError::ZeroAmount);
+        require!(
+            pool.curve_type == CurveType::ConstantProduct,
+            SwapError::UnsupportedCurve
+        );
+        require!(
+            pool.reserve_a > 0 && pool.reserve_b > 0,
+            SwapError::InsufficientLiquidity
+        );
+        lock_pool(&mut ctx.accounts.pool)?;
+
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.pool.update_oracle(now);
+        ctx.accounts.pool.refresh_fee(now);
+
+        let accounts = &mut *ctx.accounts;
+        let received = if a_token {
+            transfer_tokens_received(
+                &accounts.token_program,
+                &accounts.user_token_a,
+                &mut accounts.vault_a,
+                &accounts.mint_a,
+                accounts.user.to_account_info(),
+                &[],
+                amount_in,
+            )?
         } else {
-            // Transfer token B from user to vault
-            let cpi_accounts_in = Transfer {
-                from: ctx.accounts.user_token_b.to_account_info(),
-                to: ctx.accounts.vault_b.to_account_info(),
-                authority: ctx.accounts.user.to_account_info(),
-            };
-            let cpi_ctx_in = CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                cpi_accounts_in,
-            );
-            token::transfer(cpi_ctx_in, amount_in)?;
-
-            // Transfer token A from vault to user
-            let seeds = &[
-                b"pool",
-                ctx.accounts.pool.mint_a.as_ref(),
-                ctx.accounts.pool.mint_b.as_ref(),
-                &[ctx.accounts.pool.bump],
-            ];
-            let signer = &[&seeds[..]];
-
-            let cpi_accounts_out = Transfer {
-                from: ctx.accounts.vault_a.to_account_info(),
-                to: ctx.accounts.user_token_a.to_account_info(),
-                authority: ctx.accounts.pool.to_account_info(),
+            transfer_tokens_received(
+                &accounts.token_program,
+                &accounts.user_token_b,
+                &mut accounts.vault_b,
+                &accounts.mint_b,
+                accounts.user.to_account_info(),
+                &[],
+                amount_in,
+            )?
+        };
+
+        // Swap part of the deposit into the other token. The output never
+        // leaves the vault; it moves from the reserves into this deposit.
+        let protocol_fee_on = GlobalConfig::protocol_fee_on(&accounts.global_config)?;
+        let pool = &mut accounts.pool;
+        let reserve_in = if a_token {
+            pool.reserve_a
+        } else {
+            pool.reserve_b
+        };
+        let swap_in = calculate_single_sided_swap(received, reserve_in, pool.fee_bps)?;
+        let swap_out = pool.swap_output(swap_in, a_token)?;
+        require!(swap_out > 0, SwapError::ZeroOutput);
+        let fees = pool.fee_split(swap_in, protocol_fee_on, false)?;
+        pool.apply_swap(swap_in, swap_out, a_token, fees)?;
+
+        let (amount_a, amount_b) = if a_token {
+            (received - swap_in, swap_out)
+        } else {
+            (swap_out, received - swap_in)
+        };
+        let lp_amount = calculate_lp_to_mint(
+            amount_a,
+            amount_b,
+            pool.reserve_a,
+            pool.reserve_b,
+            accounts.lp_mint.supply,
+        )?;
+        require!(lp_amount >= min_lp_out, SwapError::SlippageTooHigh);
+        pool.check_liquidity_cap(accounts.lp_mint.supply, lp_amount)?;
+
+        let seeds = &[
+            b"pool",
+            accounts.pool.mint_a.as_ref(),
+            accounts.pool.mint_b.as_ref(),
+            &accounts.pool.fee_tier.to_le_bytes(),
+            accounts.pool.label_seed(),
+            &[accounts.pool.bump],
+        ];
+        token_interface::mint_to(
+            CpiContext::new_with_signer(
+                accounts.token_program.to_account_info(),
+                MintTo {
+                    mint: accounts.lp_mint.to_account_info(),
+                    to: accounts.user_lp_token.to_account_info(),
+                    authority: accounts.pool.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            lp_amount,
+        )?;
+
+        let pool = &mut accounts.pool;
+        pool.add_reserves(amount_a, amount_b)?;
+        pool.record_k();
+        pool.unlock();
+
+        // Report the tokens the user put in; the internal swap only moved
+        // value between the reserves
+        let (deposited_a, deposited_b) = if a_token {
+            (received, 0)
+        } else {
+            (0, received)
+        };
+        emit!(LiquidityAdded {
+            pool: pool.key(),
+            user: accounts.user.key(),
+            amount_a: deposited_a,
+            amount_b: deposited_b,
+            lp_minted: lp_amount,
+            reserve_a: pool.reserve_a,
+            reserve_b: pool.reserve_b,
+        });
+
+        Ok(())
+    }
+
+    /// Burns LP tokens for a share of both reserves. This stays available while
+    /// the pool is paused so LPs can always exit.
+    pub fn remove_liquidity(
+        ctx: Context<RemoveLiquidity>,
+        lp_amount: u64,
+        min_a: u64,
+        min_b: u64,
+        deadline: i64, // unix timestamp after which the withdrawal must not execute
+    ) -> Result<()> {
+        require!(
+            !ctx.accounts.pool.flash_loan_active,
+            SwapError::FlashLoanActive
+        );
+        require!(
+            ctx.accounts.pool.curve_type != CurveType::Concentrated,
+            SwapError::UnsupportedCurve
+        );
+        let now = Clock::get()?.unix_timestamp;
+        require!(now <= deadline, SwapError::DeadlineExceeded);
+        lock_pool(&mut ctx.accounts.pool)?;
+
+        ctx.accounts.pool.update_oracle(now);
+
+        // Work out the user's share of each reserve
+        let (amount_a, amount_b) = ctx
+            .accounts
+            .pool
+            .lp_value(lp_amount, ctx.accounts.lp_mint.supply)?;
+
+        require!(amount_a >= min_a, SwapError::SlippageTooHigh);
+        require!(amount_b >= min_b, SwapError::SlippageTooHigh);
+
+        // Burn the user's LP tokens
+        let cpi_accounts_burn = Burn {
+            mint: ctx.accounts.lp_mint.to_account_info(),
+            from: ctx.accounts.user_lp_token.to_account_info(),
+            authority: ctx.accounts.user.to_account_info(),
+        };
+        let cpi_ctx_burn = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            cpi_accounts_burn,
+        );
+        token_interface::burn(cpi_ctx_burn, lp_amount)?;
+
+        // Transfer tokens from pool vaults back to user
+        let seeds = &[
+            b"pool",
+            ctx.accounts.pool.mint_a.as_ref(),
+            ctx.accounts.pool.mint_b.as_ref(),
+            &ctx.accounts.pool.fee_tier.to_le_bytes(),
+            ctx.accounts.pool.label_seed(),
+            &[ctx.accounts.pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_a,
+            &ctx.accounts.user_token_a,
+            &ctx.accounts.mint_a,
+            ctx.accounts.pool.to_account_info(),
+            signer,
+            amount_a,
+        )?;
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_b,
+            &ctx.accounts.user_token_b,
+            &ctx.accounts.mint_b,
+            ctx.accounts.pool.to_account_info(),
+            signer,
+            amount_b,
+        )?;
+
+        // Update stored reserves
+        let pool = &mut ctx.accounts.pool;
+        pool.reserve_a = pool
+            .reserve_a
+            .checked_sub(amount_a)
+            .ok_or(SwapError::MathOverflow)?;
+        pool.reserve_b = pool
+            .reserve_b
+            .checked_sub(amount_b)
+            .ok_or(SwapError::MathOverflow)?;
+        pool.record_k();
+        pool.unlock();
+
+        emit!(LiquidityRemoved {
+            pool: pool.key(),
+            user: ctx.accounts.user.key(),
+            amount_a,
+            amount_b,
+            lp_burned: lp_amount,
+            reserve_a: pool.reserve_a,
+            reserve_b: pool.reserve_b,
+        });
+
+        Ok(())
+    }
+
+    /// Opens a position on a concentrated pool: `liquidity` over
+    /// [`tick_lower`, `tick_upper`), both multiples of the pool's
+    /// `tick_spacing`. The
+    /// tokens it needs at the current price (rounded up) are taken from the
+    /// owner, failing if either exceeds its maximum. The position trades, and
+    /// earns its share of the LP fee, only while the price is inside its range.
+    pub fn open_position(
+        ctx: Context<OpenPosition>,
+        tick_lower: i32,
+        tick_upper: i32,
+        liquidity: u128,
+        max_amount_a: u64,
+        max_amount_b: u64,
+    ) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        require!(!pool.is_paused, SwapError::PoolPaused);
+        require!(!pool.flash_loan_active, SwapError::FlashLoanActive);
+        require!(
+            pool.curve_type == CurveType::Concentrated,
+            SwapError::UnsupportedCurve
+        );
+        pool.check_tick_range(tick_lower, tick_upper)?;
+        require!(liquidity > 0, SwapError::ZeroAmount);
+        let liquidity_delta =
+            i128::try_from(liquidity).map_err(|_| error!(SwapError::MathOverflow))?;
+        lock_pool(&mut ctx.accounts.pool)?;
+
+        let pool = &mut ctx.accounts.pool;
+        pool.update_oracle(Clock::get()?.unix_timestamp);
+        let (amount_a, amount_b) =
+            pool.position_amounts(tick_lower, tick_upper, liquidity, RoundingMode::Up)?;
+        require!(
+            amount_a <= max_amount_a && amount_b <= max_amount_b,
+            SwapError::SlippageTooHigh
+        );
+        pool.update_position(tick_lower, tick_upper, liquidity_delta)?;
+        let (inside_a, inside_b) = pool.fee_growth_inside(tick_lower, tick_upper);
+
+        // The range needs exactly these amounts, so a transfer fee that shorts
+        // the vault fails the deposit like any other shortfall
+        for (amount, a_side) in [(amount_a, true), (amount_b, false)] {
+            let (from, vault, mint) = if a_side {
+                (
+                    &ctx.accounts.user_token_a,
+                    &mut ctx.accounts.vault_a,
+                    &ctx.accounts.mint_a,
+                )
+            } else {
+                (
+                    &ctx.accounts.user_token_b,
+                    &mut ctx.accounts.vault_b,
+                    &ctx.accounts.mint_b,
+                )
             };
-            let cpi_ctx_out = CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                cpi_accounts_out,
-                signer,
-            );
-            token::transfer(cpi_ctx_out, amount_out)?;
+            let received = transfer_tokens_received(
+                &ctx.accounts.token_program,
+                from,
+                vault,
+                mint,
+                ctx.accounts.owner.to_account_info(),
+                &[],
+                amount,
+            )?;
+            require!(received == amount, SwapError::SlippageTooHigh);
         }
 
+        let pool = &mut ctx.accounts.pool;
+        pool.add_reserves(amount_a, amount_b)?;
+        pool.record_k();
+        pool.unlock();
+
+        let position = &mut ctx.accounts.position;
+        position.pool = pool.key();
+        position.owner = ctx.accounts.owner.key();
+        position.tick_lower = tick_lower;
+        position.tick_upper = tick_upper;
+        position.liquidity = liquidity;
+        position.fee_growth_inside_a = inside_a;
+        position.fee_growth_inside_b = inside_b;
+        position.bump = ctx.bumps.position;
+
+        emit!(PositionOpened {
+            pool: pool.key(),
+            owner: position.owner,
+            position: position.key(),
+            tick_lower,
+            tick_upper,
+            liquidity,
+            amount_a,
+            amount_b,
+        });
+
         Ok(())
     }
-}
 
-// Helper function to calculate swap output using constant product formula
-fn calculate_swap_output(amount_in: u64, reserve_in: u64, reserve_out: u64) -> Result<u64> {
-    let numerator = amount_in
-        .checked_mul(reserve_out)
-        .ok_or(SwapError::MathOverflow)?;
+    /// Closes a position, paying the owner its tokens at the current price
+    /// (rounded down) plus the fees it earned while in range. Fails if either
+    /// token amount, fees excluded, is below its minimum. Like
+    /// `remove_liquidity`, this stays available while the pool is paused.
+    pub fn close_position(ctx: Context<ClosePosition>, min_a: u64, min_b: u64) -> Result<()> {
+        require!(
+            !ctx.accounts.pool.flash_loan_active,
+            SwapError::FlashLoanActive
+        );
+        lock_pool(&mut ctx.accounts.pool)?;
 
-    let denominator = reserve_in
-        .checked_add(amount_in)
-        .ok_or(SwapError::MathOverflow)?;
+        let position = &ctx.accounts.position;
+        let (tick_lower, tick_upper) = (position.tick_lower, position.tick_upper);
+        let liquidity = position.liquidity;
+        let liquidity_delta =
+            i128::try_from(liquidity).map_err(|_| error!(SwapError::MathOverflow))?;
 
-    let amount_out = numerator
-        .checked_div(denominator)
-        .ok_or(SwapError::MathOverflow)?;
+        let pool = &mut ctx.accounts.pool;
+        pool.update_oracle(Clock::get()?.unix_timestamp);
+        let (amount_a, amount_b) =
+            pool.position_amounts(tick_lower, tick_upper, liquidity, RoundingMode::Down)?;
+        require!(
+            amount_a >= min_a && amount_b >= min_b,
+            SwapError::SlippageTooHigh
+        );
+        let (fees_a, fees_b) = pool.position_fees(
+            tick_lower,
+            tick_upper,
+            liquidity,
+            position.fee_growth_inside_a,
+            position.fee_growth_inside_b,
+        )?;
+        pool.update_position(tick_lower, tick_upper, -liquidity_delta)?;
 
-    Ok(amount_out)
-}
+        pool.reserve_a = pool
+            .reserve_a
+            .checked_sub(amount_a)
+            .ok_or(SwapError::MathOverflow)?;
+        pool.reserve_b = pool
+            .reserve_b
+            .checked_sub(amount_b)
+            .ok_or(SwapError::MathOverflow)?;
+        pool.position_fees_a = pool
+            .position_fees_a
+            .checked_sub(fees_a)
+            .ok_or(SwapError::MathOverflow)?;
+        pool.position_fees_b = pool
+            .position_fees_b
+            .checked_sub(fees_b)
+            .ok_or(SwapError::MathOverflow)?;
 
-#[derive(Accounts)]
-pub struct InitializePool<'info> {
-    #[account(mut)]
-    pub authority: Signer<'info>,
+        let pool = &ctx.accounts.pool;
+        let seeds = &[
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
 
-    #[account(
-        init,
-        payer = authority,
-        space = 8 + Pool::INIT_SPACE,
-        seeds = [b"pool", mint_a.key().as_ref(), mint_b.key().as_ref()],
-        bump
-    )]
-    pub pool: Account<'info, Pool>,
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_a,
+            &ctx.accounts.user_token_a,
+            &ctx.accounts.mint_a,
+            ctx.accounts.pool.to_account_info(),
+            signer,
+            amount_a + fees_a,
+        )?;
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_b,
+            &ctx.accounts.user_token_b,
+            &ctx.accounts.mint_b,
+            ctx.accounts.pool.to_account_info(),
+            signer,
+            amount_b + fees_b,
+        )?;
 
-    pub mint_a: Account<'info, Mint>,
-    pub mint_b: Account<'info, Mint>,
+        let pool = &mut ctx.accounts.pool;
+        pool.record_k();
+        pool.unlock();
 
-    #[account(
-        init,
-        payer = authority,
-        token::mint = mint_a,
-        token::authority = pool,
-        seeds = [b"vault_a", pool.key().as_ref()],
-        bump
-    )]
-    pub vault_a: Account<'info, TokenAccount>,
+        emit!(PositionClosed {
+            pool: pool.key(),
+            owner: ctx.accounts.owner.key(),
+            position: ctx.accounts.position.key(),
+            liquidity,
+            amount_a,
+            amount_b,
+            fees_a,
+            fees_b,
+        });
 
-    #[account(
-        init,
-        payer = authority,
-        token::mint = mint_b,
-        token::authority = pool,
-        seeds = [b"vault_b", pool.key().as_ref()],
-        bump
-    )]
-    pub vault_b: Account<'info, TokenAccount>,
+        // The position account itself is closed to the owner by the `close`
+        // constraint once this returns
+        Ok(())
+    }
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-}
+    /// Swaps exactly `amount_in` of one token for at least `minimum_amount_out`
+    /// of the other. Up to three protections apply together, each with its own
+    /// error, and when several would fail the first in this order is reported:
+    /// `deadline` (`DeadlineExceeded`, checked before any tokens move), then
+    /// `minimum_amount_out` (`SlippageTooHigh`), then the optional
+    /// `max_price_impact_bps` (`PriceImpactTooHigh`).
+    pub fn swap(
+        ctx: Context<Swap>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+        a_to_b: bool,  // true if swapping A for B, false if swapping B for A
+        deadline: i64, // unix timestamp after which the swap must not execute
+        max_price_impact_bps: Option<u16>, // cap on execution vs. pre-trade spot price
+    ) -> Result<()> {
+        require!(!ctx.accounts.pool.is_paused, SwapError::PoolPaused);
+        require!(
+            !ctx.accounts.pool.flash_loan_active,
+            SwapError::FlashLoanActive
+        );
+        require!(amount_in > 0, SwapError::ZeroAmount);
+        let now = Clock::get()?.unix_timestamp;
+        require!(now <= deadline, SwapError::DeadlineExceeded);
+        ctx.accounts
+            .pool
+            .check_compute(sol_remaining_compute_units())?;
+        ctx.accounts.check_cooldown(now, ctx.bumps.last_swap)?;
+        lock_pool(&mut ctx.accounts.pool)?;
 
-#[derive(Accounts)]
-pub struct AddLiquidity<'info> {
-    pub user: Signer<'info>,
+        // Accumulate the TWAP oracle using the pre-swap reserves
+        ctx.accounts.pool.update_oracle(now);
+        ctx.accounts.pool.refresh_fee(now);
+        let rates = ctx.accounts.fee_rates();
+        ctx.accounts.execute(
+            amount_in,
+            minimum_amount_out,
+            a_to_b,
+            max_price_impact_bps,
+            rates,
+        )?;
 
-    #[account(
-        mut,
-        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
-        bump = pool.bump
-    )]
-    pub pool: Account<'info, Pool>,
+        Ok(())
+    }
 
-    #[account(mut)]
-    pub user_token_a: Account<'info, TokenAccount>,
+    /// Swaps without any fee, for the pool authority to rebalance the pool.
+    /// The curve prices the whole `amount_in`, and no protocol or referral cut
+    /// is taken. Emits `FeeFreeSwap` besides `SwapExecuted` so these swaps can
+    /// be told apart. The swap cooldown doesn't apply. Only the pool authority
+    /// may call this, as the `user`.
+    pub fn swap_no_fee(
+        ctx: Context<Swap>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+        a_to_b: bool,
+    ) -> Result<()> {
+        require_keys_eq!(
+            ctx.accounts.user.key(),
+            ctx.accounts.pool.authority,
+            SwapError::Unauthorized
+        );
+        require!(!ctx.accounts.pool.is_paused, SwapError::PoolPaused);
+        require!(
+            !ctx.accounts.pool.flash_loan_active,
+            SwapError::FlashLoanActive
+        );
+        require!(amount_in > 0, SwapError::ZeroAmount);
+        let now = Clock::get()?.unix_timestamp;
+        lock_pool(&mut ctx.accounts.pool)?;
 
-    #[account(mut)]
-    pub user_token_b: Account<'info, TokenAccount>,
+        ctx.accounts.pool.update_oracle(now);
+        ctx.accounts.pool.refresh_fee(now);
+        // No fee, so no cuts out of it either
+        let amount_out = ctx.accounts.execute(
+            amount_in,
+            minimum_amount_out,
+            a_to_b,
+            None,
+            FeeRates::default(),
+        )?;
+        let pool = &ctx.accounts.pool;
 
-    #[account(
-        mut,
-        seeds = [b"vault_a", pool.key().as_ref()],
-        bump,
-        token::mint = pool.mint_a,
-        token::authority = pool,
-    )]
-    pub vault_a: Account<'info, TokenAccount>,
+        emit!(FeeFreeSwap {
+            pool: pool.key(),
+            authority: ctx.accounts.user.key(),
+            amount_in,
+            amount_out,
+            a_to_b,
+        });
 
-    #[account(
-        mut,
-        seeds = [b"vault_b", pool.key().as_ref()],
-        bump,
-        token::mint = pool.mint_b,
-        token::authority = pool,
-    )]
-    pub vault_b: Account<'info, TokenAccount>,
+        Ok(())
+    }
 
-    pub token_program: Program<'info, Token>,
-}
+    /// Like `swap`, but when the whole `amount_in` would move the price by more
+    /// than `max_impact_bps`, fills the largest part of it that stays within the
+    /// limit instead of failing. Only the filled part is taken from the user, so
+    /// the rest stays in their account. `minimum_amount_out` applies to what is
+    /// actually filled.
+    pub fn swap_partial(
+        ctx: Context<Swap>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+        max_impact_bps: u16,
+        a_to_b: bool,
+    ) -> Result<()> {
+        require!(!ctx.accounts.pool.is_paused, SwapError::PoolPaused);
+        require!(
+            !ctx.accounts.pool.flash_loan_active,
+            SwapError::FlashLoanActive
+        );
+        require!(amount_in > 0, SwapError::ZeroAmount);
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts
+            .pool
+            .check_compute(sol_remaining_compute_units())?;
+        ctx.accounts.check_cooldown(now, ctx.bumps.last_swap)?;
+        lock_pool(&mut ctx.accounts.pool)?;
 
-#[derive(Accounts)]
-pub struct Swap<'info> {
-    pub user: Signer<'info>,
+        ctx.accounts.pool.update_oracle(now);
+        ctx.accounts.pool.refresh_fee(now);
+        let rates = ctx.accounts.fee_rates();
 
-    #[account(
-        mut,
-        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
-        bump = pool.bump
-    )]
-    pub pool: Account<'info, Pool>,
+        // Size the fill against the fee it will actually pay
+        let amount_filled = ctx.accounts.pool.max_input_within_impact_at(
+            amount_in,
+            max_impact_bps,
+            a_to_b,
+            rates,
+        )?;
+        require!(amount_filled > 0, SwapError::PriceImpactTooHigh);
 
-    #[account(mut)]
-    pub user_token_a: Account<'info, TokenAccount>,
+        let amount_out = ctx.accounts.execute(
+            amount_filled,
+            minimum_amount_out,
+            a_to_b,
+            Some(max_impact_bps),
+            rates,
+        )?;
 
-    #[account(mut)]
-    pub user_token_b: Account<'info, TokenAccount>,
+        emit!(PartialFill {
+            pool: ctx.accounts.pool.key(),
+            user: ctx.accounts.user.key(),
+            amount_filled,
+            amount_refunded: amount_in - amount_filled,
+            amount_out,
+            a_to_b,
+        });
 
-    #[account(
-        mut,
-        seeds = [b"vault_a", pool.key().as_ref()],
-        bump,
-        token::mint = pool.mint_a,
-        token::authority = pool,
-    )]
-    pub vault_a: Account<'info, TokenAccount>,
+        Ok(())
+    }
 
-    #[account(
-        mut,
-        seeds = [b"vault_b", pool.key().as_ref()],
-        bump,
-        token::mint = pool.mint_b,
-        token::authority = pool,
-    )]
-    pub vault_b: Account<'info, TokenAccount>,
+    /// Swaps for an exact `amount_out`, charging whatever input the curve and fee
+    /// require as long as it does not exceed `maximum_amount_in`.
+    pub fn swap_exact_out(
+        ctx: Context<Swap>,
+        amount_out: u64,
+        maximum_amount_in: u64,
+        a_to_b: bool,
+    ) -> Result<()> {
+        require!(!ctx.accounts.pool.is_paused, SwapError::PoolPaused);
+        require!(
+            !ctx.accounts.pool.flash_loan_active,
+            SwapError::FlashLoanActive
+        );
+        require!(amount_out > 0, SwapError::ZeroAmount);
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts
+            .pool
+            .check_compute(sol_remaining_compute_units())?;
+        ctx.accounts.check_cooldown(now, ctx.bumps.last_swap)?;
+        lock_pool(&mut ctx.accounts.pool)?;
 
-    pub token_program: Program<'info, Token>,
-}
+        ctx.accounts.pool.update_oracle(now);
+        ctx.accounts.pool.refresh_fee(now);
+        let rates = ctx.accounts.fee_rates();
+        #[cfg(feature = "strict-checks")]
+        let invariant_before = ctx.accounts.pool.invariant()?;
 
-#[account]
-#[derive(InitSpace)]
-pub struct Pool {
+        let amount_in = ctx.accounts.pool.swap_input_at(amount_out, a_to_b, rates)?;
+
+        require!(amount_in <= maximum_amount_in, SwapError::SlippageTooHigh);
+
+        // A fee-on-transfer input may land short; what arrived must still pay
+        // for the requested output
+        let amount_in = ctx.accounts.transfer_in(amount_in, a_to_b)?;
+        require!(
+            ctx.accounts.pool.swap_output_at(amount_in, a_to_b, rates)? >= amount_out,
+            SwapError::SlippageTooHigh
+        );
+
+        ctx.accounts.transfer_out(amount_out, a_to_b)?;
+
+        let protocol_fee_on = GlobalConfig::protocol_fee_on(&ctx.accounts.global_config)?;
+        let fees = ctx
+            .accounts
+            .pool
+            .fee_split_at(amount_in, rates, protocol_fee_on, false)?;
+        ctx.accounts
+            .pool
+            .apply_swap(amount_in, amount_out, a_to_b, fees)?;
+        #[cfg(feature = "strict-checks")]
+        ctx.accounts.assert_invariant(invariant_before)?;
+        let pool = &mut ctx.accounts.pool;
+        pool.record_k();
+        pool.unlock();
+
+        emit!(SwapExecuted {
+            pool: pool.key(),
+            user: ctx.accounts.user.key(),
+            mint_a: pool.mint_a,
+            mint_b: pool.mint_b,
+            amount_in,
+            amount_out,
+            a_to_b,
+            reserve_a: pool.reserve_a,
+            reserve_b: pool.reserve_b,
+        });
+
+        Ok(())
+    }
+
+    /// Swaps `mint_in` for `mint_out` through `pool_1` (in -> mid) and then
+    /// `pool_2` (mid -> out) in one instruction. Only the final output is held to
+    /// `minimum_amount_out`; the intermediate tokens pass through `user_token_mid`.
+    ///
+    /// With the `exact_hops` routing hint the hops forward the amounts sent
+    /// instead of reloading each receiving account to measure them. Only
+    /// routes under the SPL Token program, where every transfer arrives in
+    /// full, may take it; Token-2022 routes fail with `ExactHopsUnsupported`.
+    pub fn swap_two_hop(
+        ctx: Context<SwapTwoHop>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+        exact_hops: bool,
+    ) -> Result<()> {
+        require!(amount_in > 0, SwapError::ZeroAmount);
+        require!(
+            !exact_hops || ctx.accounts.exact_hops_supported(),
+            SwapError::ExactHopsUnsupported
+        );
+        require!(
+            ctx.accounts.user_token_in.amount >= amount_in,
+            SwapError::InsufficientUserBalance
+        );
+        let accounts = &mut *ctx.accounts;
+        for pool in [&accounts.pool_1, &accounts.pool_2] {
+            require!(!pool.is_paused, SwapError::PoolPaused);
+            require!(!pool.flash_loan_active, SwapError::FlashLoanActive);
+            require!(
+                pool.min_swap_interval == 0,
+                SwapError::CooldownRequiresDirectSwap
+            );
+        }
+        let first_a_to_b = accounts.first_leg_direction()?;
+        let second_a_to_b = accounts.second_leg_direction()?;
+
+        let now = Clock::get()?.unix_timestamp;
+        for pool in [&mut accounts.pool_1, &mut accounts.pool_2] {
+            lock_pool(pool)?;
+            pool.update_oracle(now);
+            pool.refresh_fee(now);
+        }
+
+        // The second leg runs on what the first leaves over
+        let remaining = sol_remaining_compute_units();
+        accounts.pool_1.check_compute(remaining)?;
+        accounts
+            .pool_2
+            .check_compute(remaining.saturating_sub(accounts.pool_1.estimated_swap_compute()))?;
+
+        let protocol_fee_on = GlobalConfig::protocol_fee_on(&accounts.global_config)?;
+        accounts.pool_1.check_input_after_fee(amount_in)?;
+
+        // First leg: in -> mid, paid out to the user's intermediate account
+        let first_in = transfer_tokens_hop(
+            &accounts.token_program,
+            &*accounts.user_token_in,
+            &mut accounts.pool_1_vault_in,
+            &accounts.mint_in,
+            accounts.user.to_account_info(),
+            &[],
+            amount_in,
+            exact_hops,
+        )?;
+        let first_out = accounts.pool_1.swap_output(first_in, first_a_to_b)?;
+        require!(first_out > 0, SwapError::ZeroOutput);
+
+        let seeds = &[
+            b"pool",
+            accounts.pool_1.mint_a.as_ref(),
+            accounts.pool_1.mint_b.as_ref(),
+            &accounts.pool_1.fee_tier.to_le_bytes(),
+            accounts.pool_1.label_seed(),
+            &[accounts.pool_1.bump],
+        ];
+        let mid_received = transfer_tokens_hop(
+            &accounts.token_program,
+            &*accounts.pool_1_vault_out,
+            &mut accounts.user_token_mid,
+            &accounts.mint_mid,
+            accounts.pool_1.to_account_info(),
+            &[&seeds[..]],
+            first_out,
+            exact_hops,
+        )?;
+        let fees = accounts
+            .pool_1
+            .fee_split(first_in, protocol_fee_on, false)?;
+        accounts
+            .pool_1
+            .apply_swap(first_in, first_out, first_a_to_b, fees)?;
+
+        // Second leg: forward exactly what reached the intermediate account
+        require!(mid_received > 0, SwapError::ZeroAmount);
+        let second_in = transfer_tokens_hop(
+            &accounts.token_program,
+            &*accounts.user_token_mid,
+            &mut accounts.pool_2_vault_in,
+            &accounts.mint_mid,
+            accounts.user.to_account_info(),
+            &[],
+            mid_received,
+            exact_hops,
+        )?;
+        let second_out = accounts.pool_2.swap_output(second_in, second_a_to_b)?;
+        require!(second_out > 0, SwapError::ZeroOutput);
+        require!(second_out >= minimum_amount_out, SwapError::SlippageTooHigh);
+
+        let seeds = &[
+            b"pool",
+            accounts.pool_2.mint_a.as_ref(),
+            accounts.pool_2.mint_b.as_ref(),
+            &accounts.pool_2.fee_tier.to_le_bytes(),
+            accounts.pool_2.label_seed(),
+            &[accounts.pool_2.bump],
+        ];
+        transfer_tokens(
+            &accounts.token_program,
+            &*accounts.pool_2_vault_out,
+            &*accounts.user_token_out,
+            &accounts.mint_out,
+            accounts.pool_2.to_account_info(),
+            &[&seeds[..]],
+            second_out,
+        )?;
+        let fees = accounts
+            .pool_2
+            .fee_split(second_in, protocol_fee_on, false)?;
+        accounts
+            .pool_2
+            .apply_swap(second_in, second_out, second_a_to_b, fees)?;
+        for pool in [&mut accounts.pool_1, &mut accounts.pool_2] {
+            pool.record_k();
+            pool.unlock();
+        }
+
+        for (pool, amount_in, amount_out, a_to_b) in [
+            (&accounts.pool_1, first_in, first_out, first_a_to_b),
+            (&accounts.pool_2, second_in, second_out, second_a_to_b),
+        ] {
+            emit!(SwapExecuted {
+                pool: pool.key(),
+                user: accounts.user.key(),
+                mint_a: pool.mint_a,
+                mint_b: pool.mint_b,
+                amount_in,
+                amount_out,
+                a_to_b,
+                reserve_a: pool.reserve_a,
+                reserve_b: pool.reserve_b,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Executes independent swaps across any number of pools in one
+    /// instruction, e.g. to rebalance. Leg `i` is `legs[i]` together with
+    /// remaining accounts `i * BATCH_LEG_ACCOUNTS..(i + 1) * BATCH_LEG_ACCOUNTS`,
+    /// in this order:
+    ///
+    /// 0. pool (writable)
+    /// 1. mint_in
+    /// 2. mint_out
+    /// 3. the pool's vault for mint_in (writable)
+    /// 4. the pool's vault for mint_out (writable)
+    /// 5. the user's token account for mint_in (writable)
+    /// 6. the user's token account for mint_out (writable)
+    ///
+    /// Legs run in order and each is held to its own `minimum_amount_out`; if
+    /// any leg fails the whole batch reverts. All legs share `token_program`.
+    pub fn batch_swap<'info>(
+        ctx: Context<'_, '_, 'info, 'info, BatchSwap<'info>>,
+        legs: Vec<BatchLeg>,
+        deadline: i64,
+    ) -> Result<()> {
+        require!(
+            !legs.is_empty() && ctx.remaining_accounts.len() == legs.len() * BATCH_LEG_ACCOUNTS,
+            SwapError::InvalidBatch
+        );
+        let now = Clock::get()?.unix_timestamp;
+        require!(now <= deadline, SwapError::DeadlineExceeded);
+        let protocol_fee_on = GlobalConfig::protocol_fee_on(&ctx.accounts.global_config)?;
+
+        for (leg, accounts) in legs
+            .iter()
+            .zip(ctx.remaining_accounts.chunks(BATCH_LEG_ACCOUNTS))
+        {
+            execute_batch_leg(
+                &ctx.accounts.user,
+                &ctx.accounts.token_program,
+                accounts,
+                leg,
+                now,
+                protocol_fee_on,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Deposits into any number of pools in one instruction, e.g. for an index
+    /// product. Deposit `i` is `deposits[i]` together with remaining accounts
+    /// `i * BATCH_DEPOSIT_ACCOUNTS..(i + 1) * BATCH_DEPOSIT_ACCOUNTS`, in this
+    /// order:
+    ///
+    /// 0. pool (writable)
+    /// 1. mint_a
+    /// 2. mint_b
+    /// 3. the pool's vault_a (writable)
+    /// 4. the pool's vault_b (writable)
+    /// 5. the pool's lp_mint (writable)
+    /// 6. the user's token account for mint_a (writable)
+    /// 7. the user's token account for mint_b (writable)
+    /// 8. the user's token account for lp_mint (writable)
+    ///
+    /// Each deposit works like `add_liquidity` and is held to its own
+    /// `min_lp_out`; if any fails the whole batch reverts. Only pools that
+    /// already hold liquidity take batch deposits, since a first deposit also
+    /// locks `MINIMUM_LIQUIDITY` and sets the price. All deposits share
+    /// `token_program`.
+    pub fn batch_add_liquidity<'info>(
+        ctx: Context<'_, '_, 'info, 'info, BatchAddLiquidity<'info>>,
+        deposits: Vec<BatchDeposit>,
+        deadline: i64,
+    ) -> Result<()> {
+        require!(
+            !deposits.is_empty()
+                && ctx.remaining_accounts.len() == deposits.len() * BATCH_DEPOSIT_ACCOUNTS,
+            SwapError::InvalidBatch
+        );
+        let now = Clock::get()?.unix_timestamp;
+        require!(now <= deadline, SwapError::DeadlineExceeded);
+
+        for (deposit, accounts) in deposits
+            .iter()
+            .zip(ctx.remaining_accounts.chunks(BATCH_DEPOSIT_ACCOUNTS))
+        {
+            execute_batch_deposit(
+                &ctx.accounts.user,
+                &ctx.accounts.token_program,
+                accounts,
+                deposit,
+                now,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Lends `amount` of token A (`a_token`) or token B from the pool's vault.
+    /// A `repay_flash_loan` for the same pool must follow later in the same
+    /// transaction, returning the principal plus a fee at the pool's swap rate.
+    pub fn flash_loan(ctx: Context<FlashLoan>, amount: u64, a_token: bool) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        require!(!pool.is_paused, SwapError::PoolPaused);
+        require!(!pool.flash_loan_active, SwapError::FlashLoanActive);
+        let reserve = if a_token {
+            pool.reserve_a
+        } else {
+            pool.reserve_b
+        };
+        require!(amount <= reserve, SwapError::InsufficientLiquidity);
+
+        require!(
+            repayment_scheduled(
+                &ctx.accounts.instructions,
+                &pool.key(),
+                instruction::RepayFlashLoan::DISCRIMINATOR,
+            )?,
+            SwapError::FlashLoanNotRepaid
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        pool.flash_loan_active = true;
+        pool.flash_loan_a_token = a_token;
+        pool.flash_loan_amount = amount;
+
+        ctx.accounts.transfer_out(amount, a_token)
+    }
+
+    /// Settles the outstanding flash loan. The vault must receive at least the
+    /// borrowed amount plus the fee, which is added to the reserves for LPs.
+    pub fn repay_flash_loan(ctx: Context<FlashLoan>) -> Result<()> {
+        require!(
+            ctx.accounts.pool.flash_loan_active && !ctx.accounts.pool.flash_swap,
+            SwapError::NoActiveFlashLoan
+        );
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.pool.update_oracle(now);
+        ctx.accounts.pool.refresh_fee(now);
+        let pool = &ctx.accounts.pool;
+        let (amount, a_token) = (pool.flash_loan_amount, pool.flash_loan_a_token);
+        let fee = calculate_flash_loan_fee(amount, pool.fee_bps)?;
+        let owed = amount.checked_add(fee).ok_or(SwapError::MathOverflow)?;
+
+        let received = ctx.accounts.transfer_in(owed, a_token)?;
+        require!(received >= owed, SwapError::FlashLoanNotRepaid);
+
+        // The reserves never dropped for the loan, so only the fee is new. On a
+        // concentrated pool the fee goes to the positions in range, if any.
+        let pool = &mut ctx.accounts.pool;
+        let mut surplus = received - amount;
+        if pool.curve_type == CurveType::Concentrated && pool.liquidity > 0 {
+            pool.credit_position_fees(fee, a_token)?;
+            surplus -= fee;
+        }
+        let reserve = if a_token {
+            &mut pool.reserve_a
+        } else {
+            &mut pool.reserve_b
+        };
+        *reserve = reserve
+            .checked_add(surplus)
+            .ok_or(SwapError::MathOverflow)?;
+
+        pool.record_k();
+        pool.flash_loan_active = false;
+        pool.flash_loan_amount = 0;
+
+        Ok(())
+    }
+
+    /// Uniswap-style flash swap: sends `amount_out` of the output token (B
+    /// when `a_to_b`) to the borrower before anything is paid for it. As with
+    /// `flash_loan`, the same transaction must contain a later
+    /// `repay_flash_swap` for the pool; the instructions in between play the
+    /// callback, e.g. selling the output elsewhere for more input than the
+    /// pool asks. Constant-product pools only.
+    pub fn flash_swap(ctx: Context<FlashLoan>, amount_out: u64, a_to_b: bool) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        require!(!pool.is_paused, SwapError::PoolPaused);
+        require!(!pool.flash_loan_active, SwapError::FlashLoanActive);
+        require!(
+            pool.curve_type == CurveType::ConstantProduct,
+            SwapError::UnsupportedCurve
+        );
+        require!(amount_out > 0, SwapError::ZeroAmount);
+        let reserve_out = if a_to_b {
+            pool.reserve_b
+        } else {
+            pool.reserve_a
+        };
+        require!(amount_out < reserve_out, SwapError::InsufficientLiquidity);
+        // Its input is whatever the borrower repays, so only the output is capped
+        pool.check_swap_limits(0, amount_out)?;
+        require!(
+            repayment_scheduled(
+                &ctx.accounts.instructions,
+                &pool.key(),
+                instruction::RepayFlashSwap::DISCRIMINATOR,
+            )?,
+            SwapError::FlashLoanNotRepaid
+        );
+
+        // Recorded as a loan of the output token, which keeps swaps, deposits,
+        // and withdrawals out until it is settled
+        let pool = &mut ctx.accounts.pool;
+        pool.flash_loan_active = true;
+        pool.flash_swap = true;
+        pool.flash_loan_a_token = !a_to_b;
+        pool.flash_loan_amount = amount_out;
+
+        ctx.accounts.transfer_out(amount_out, !a_to_b)
+    }
+
+    /// Settles the outstanding flash swap with `amount_a` of token A and
+    /// `amount_b` of token B from the borrower, in any mix: the input, the
+    /// output back, or both. With the borrowed amount gone and these added,
+    /// the reserves less the pool's fee on what was paid in must still hold
+    /// the constant product from before, otherwise this fails with
+    /// `FlashLoanNotRepaid`.
+    pub fn repay_flash_swap(ctx: Context<FlashLoan>, amount_a: u64, amount_b: u64) -> Result<()> {
+        require!(
+            ctx.accounts.pool.flash_loan_active && ctx.accounts.pool.flash_swap,
+            SwapError::NoActiveFlashLoan
+        );
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.pool.update_oracle(now);
+        ctx.accounts.pool.refresh_fee(now);
+
+        let received_a = if amount_a > 0 {
+            ctx.accounts.transfer_in(amount_a, true)?
+        } else {
+            0
+        };
+        let received_b = if amount_b > 0 {
+            ctx.accounts.transfer_in(amount_b, false)?
+        } else {
+            0
+        };
+
+        let pool = &mut ctx.accounts.pool;
+        let (amount_out, a_to_b) = (pool.flash_loan_amount, !pool.flash_loan_a_token);
+        pool.settle_flash_swap(received_a, received_b)?;
+        pool.record_k();
+        pool.flash_loan_active = false;
+        pool.flash_swap = false;
+        pool.flash_loan_amount = 0;
+
+        emit!(FlashSwapped {
+            pool: pool.key(),
+            borrower: ctx.accounts.borrower.key(),
+            amount_out,
+            a_to_b,
+            amount_a: received_a,
+            amount_b: received_b,
+        });
+
+        Ok(())
+    }
+
+    /// Halts or resumes swaps and deposits. Only the pool authority may call this.
+    pub fn set_paused(ctx: Context<UpdatePool>, paused: bool) -> Result<()> {
+        ctx.accounts.pool.is_paused = paused;
+        Ok(())
+    }
+
+    /// Sets how many seconds must pass between two swaps by the same user on
+    /// this pool (`swap`, `swap_partial`, and `swap_exact_out`); 0 turns the
+    /// cooldown off. Only the pool authority may call this.
+    pub fn set_min_swap_interval(ctx: Context<UpdatePool>, min_swap_interval: i64) -> Result<()> {
+        require!(min_swap_interval >= 0, SwapError::InvalidSwapInterval);
+        ctx.accounts.pool.min_swap_interval = min_swap_interval;
+        Ok(())
+    }
+
+    /// Turns the compute guard on or off. With it on, `swap`, `swap_partial`,
+    /// and `swap_exact_out` first estimate the compute of the pool's heaviest
+    /// swap path and fail with `ComputeBudgetTooLow` if less than that is
+    /// left, before any tokens move. Only the pool authority may call this.
+    pub fn set_compute_guard(ctx: Context<UpdatePool>, enabled: bool) -> Result<()> {
+        ctx.accounts.pool.compute_guard = enabled;
+        Ok(())
+    }
+
+    /// Caps the pool's LP supply at `liquidity_cap` tokens, e.g. to limit TVL
+    /// during a launch; deposits that would mint past it fail. 0 removes the
+    /// cap. Lowering it below the current supply only blocks new deposits.
+    /// Only the pool authority may call this.
+    pub fn set_liquidity_cap(ctx: Context<UpdatePool>, liquidity_cap: u64) -> Result<()> {
+        ctx.accounts.pool.liquidity_cap = liquidity_cap;
+        Ok(())
+    }
+
+    /// Sets a floor that swaps may not take either reserve below, so a thin
+    /// pool can't be drained to dust where its price is trivial to move. 0
+    /// (the default) removes the floor. Raising it above a current reserve
+    /// only blocks swaps out of that side. Only the pool authority may call
+    /// this.
+    pub fn set_min_reserve(ctx: Context<UpdatePool>, min_reserve: u64) -> Result<()> {
+        ctx.accounts.pool.min_reserve = min_reserve;
+        Ok(())
+    }
+
+    /// Caps the input and output of any single swap, e.g. to blunt whale dumps
+    /// and sandwiching while a new pool is thin. 0 disables either cap. Only
+    /// the pool authority may call this.
+    pub fn set_swap_limits(
+        ctx: Context<UpdatePool>,
+        max_swap_in: u64,
+        max_swap_out: u64,
+    ) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        pool.max_swap_in = max_swap_in;
+        pool.max_swap_out = max_swap_out;
+        Ok(())
+    }
+
+    /// Gives swappers holding at least `threshold` of the pool's LP tokens
+    /// `discount_bps` off the swap fee (5_000 halves it) when they pass their
+    /// LP token account to `swap`, `swap_partial`, or `swap_exact_out`. A
+    /// `threshold` of 0 turns the discount off. Only the pool authority may
+    /// call this.
+    pub fn set_lp_discount(
+        ctx: Context<UpdatePool>,
+        threshold: u64,
+        discount_bps: u16,
+    ) -> Result<()> {
+        require!(
+            discount_bps as u64 <= FEE_DENOMINATOR,
+            SwapError::InvalidFee
+        );
+        let pool = &mut ctx.accounts.pool;
+        pool.lp_discount_threshold = threshold;
+        pool.lp_discount_bps = discount_bps;
+        Ok(())
+    }
+
+    /// Schedules the swap fee to become `new_fee_bps` after
+    /// `FEE_TIMELOCK_SECONDS`. Proposing again replaces the pending change and
+    /// restarts the delay.
+    pub fn propose_fee(ctx: Context<UpdatePool>, new_fee_bps: u16) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        // A dynamic fee is set by its band, not by hand
+        require!(pool.fee_band.is_none(), SwapError::DynamicFee);
+        require!(new_fee_bps <= MAX_FEE_BPS, SwapError::InvalidFee);
+        // The protocol and referral cuts must still fit inside the new fee
+        require!(
+            pool.protocol_fee_bps as u32 + pool.referral_fee_bps as u32 <= new_fee_bps as u32,
+            SwapError::InvalidFee
+        );
+
+        pool.pending_fee_bps = new_fee_bps;
+        pool.fee_effective_ts = Clock::get()?
+            .unix_timestamp
+            .checked_add(FEE_TIMELOCK_SECONDS)
+            .ok_or(SwapError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Routes future `collect_protocol_fees` payouts to token accounts owned by
+    /// `fee_recipient` (e.g. a treasury multisig). Only the pool authority may
+    /// call this.
+    pub fn set_fee_recipient(ctx: Context<UpdatePool>, fee_recipient: Pubkey) -> Result<()> {
+        ctx.accounts.pool.fee_recipient = fee_recipient;
+        Ok(())
+    }
+
+    /// First step of an authority transfer: records `new_authority` as pending.
+    /// Nothing changes until that key calls `accept_authority`.
+    pub fn propose_authority(ctx: Context<UpdatePool>, new_authority: Pubkey) -> Result<()> {
+        ctx.accounts.pool.pending_authority = Some(new_authority);
+        Ok(())
+    }
+
+    /// Second step of an authority transfer, signed by the pending authority.
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        pool.authority = ctx.accounts.pending_authority.key();
+        pool.pending_authority = None;
+        Ok(())
+    }
+
+    /// Attaches Metaplex token metadata to the pool's LP mint so wallets can
+    /// display positions. The pool PDA signs as mint authority and stays the
+    /// metadata's update authority. Only the pool authority may call this.
+    pub fn create_lp_metadata(
+        ctx: Context<CreateLpMetadata>,
+        name: String,
+        symbol: String,
+        uri: String,
+    ) -> Result<()> {
+        let seeds = &[
+            b"pool",
+            ctx.accounts.pool.mint_a.as_ref(),
+            ctx.accounts.pool.mint_b.as_ref(),
+            &ctx.accounts.pool.fee_tier.to_le_bytes(),
+            ctx.accounts.pool.label_seed(),
+            &[ctx.accounts.pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        metadata::create_metadata_accounts_v3(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                CreateMetadataAccountsV3 {
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    mint: ctx.accounts.lp_mint.to_account_info(),
+                    mint_authority: ctx.accounts.pool.to_account_info(),
+                    payer: ctx.accounts.authority.to_account_info(),
+                    update_authority: ctx.accounts.pool.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    rent: ctx.accounts.rent.to_account_info(),
+                },
+                signer,
+            ),
+            DataV2 {
+                name,
+                symbol,
+                uri,
+                seller_fee_basis_points: 0,
+                creators: None,
+                collection: None,
+                uses: None,
+            },
+            true,
+            true,
+            None,
+        )
+    }
+
+    /// Removes the LP mint's mint authority, so no LP tokens can ever be minted
+    /// again and the LP supply can only shrink. Deposits fail at the mint CPI
+    /// from then on; withdrawals, swaps, and fee collection carry on. This
+    /// cannot be undone. Only the pool authority may call this.
+    pub fn freeze_lp_mint(ctx: Context<FreezeLpMint>) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        let seeds = &[
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+            &[pool.bump],
+        ];
+
+        token_interface::set_authority(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                SetAuthority {
+                    current_authority: pool.to_account_info(),
+                    account_or_mint: ctx.accounts.lp_mint.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            spl_token_2022::instruction::AuthorityType::MintTokens,
+            None,
+        )?;
+
+        emit!(LpMintFrozen {
+            pool: pool.key(),
+            lp_mint: ctx.accounts.lp_mint.key(),
+            lp_supply: ctx.accounts.lp_mint.supply,
+        });
+
+        Ok(())
+    }
+
+    /// Checks the LP mint against the pool and repairs it where it can, e.g.
+    /// after a half-finished metadata setup. The supply must be empty or
+    /// include the locked `MINIMUM_LIQUIDITY`, otherwise it fails with
+    /// `LpMintInconsistent`. If the mint authority has ended up with anyone
+    /// but the pool, that account must sign as `current_mint_authority` and
+    /// hands it back to the pool. A mint already under the pool, or frozen
+    /// by `freeze_lp_mint`, is left alone, so calling this again is harmless.
+    /// Only the pool authority may call this.
+    pub fn ensure_lp_mint(ctx: Context<EnsureLpMint>) -> Result<()> {
+        let lp_mint = &ctx.accounts.lp_mint;
+        require!(
+            lp_supply_consistent(lp_mint.supply, ctx.accounts.locked_lp.amount),
+            SwapError::LpMintInconsistent
+        );
+
+        let pool_key = ctx.accounts.pool.key();
+        let current_mint_authority = ctx.accounts.current_mint_authority.as_ref();
+        let restored = lp_mint_needs_restore(
+            &pool_key,
+            lp_mint.mint_authority.into(),
+            current_mint_authority.map(|signer| signer.key()),
+        )?;
+        if let (true, Some(current_mint_authority)) = (restored, current_mint_authority) {
+            token_interface::set_authority(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    SetAuthority {
+                        current_authority: current_mint_authority.to_account_info(),
+                        account_or_mint: lp_mint.to_account_info(),
+                    },
+                ),
+                spl_token_2022::instruction::AuthorityType::MintTokens,
+                Some(pool_key),
+            )?;
+        }
+
+        emit!(LpMintEnsured {
+            pool: pool_key,
+            lp_mint: lp_mint.key(),
+            lp_supply: lp_mint.supply,
+            restored,
+        });
+
+        Ok(())
+    }
+
+    /// Brings a pool created under an older `Pool` layout up to
+    /// `POOL_VERSION`: grows the account to the current size, with the
+    /// authority paying the extra rent, and gives the fields added since their
+    /// defaults. Fails with `PoolUpToDate` on a current pool. Only the pool
+    /// authority may call this.
+    pub fn migrate_pool(ctx: Context<MigratePool>) -> Result<()> {
+        let pool_info = ctx.accounts.pool.to_account_info();
+        let space = 8 + Pool::INIT_SPACE;
+        let allocated = pool_info.data_len();
+        if allocated < space {
+            let rent = Rent::get()?
+                .minimum_balance(space)
+                .saturating_sub(pool_info.lamports());
+            if rent > 0 {
+                system_program::transfer(
+                    CpiContext::new(
+                        ctx.accounts.system_program.to_account_info(),
+                        system_program::Transfer {
+                            from: ctx.accounts.authority.to_account_info(),
+                            to: pool_info.clone(),
+                        },
+                    ),
+                    rent,
+                )?;
+            }
+            pool_info.resize(space)?;
+        }
+        // The added fields start where the old record ends, which is only the
+        // new zeroed bytes if `ticks` was full
+        clear_pool_slack(&mut pool_info.try_borrow_mut_data()?, allocated)?;
+
+        let mut pool = Pool::try_deserialize(&mut &pool_info.try_borrow_data()?[..])?;
+        require_keys_eq!(
+            pool.authority,
+            ctx.accounts.authority.key(),
+            SwapError::Unauthorized
+        );
+        let from_version = pool.version;
+        pool.migrate()?;
+        pool.try_serialize(&mut &mut pool_info.try_borrow_mut_data()?[..])?;
+
+        emit!(PoolMigrated {
+            pool: pool_info.key(),
+            from_version,
+            to_version: pool.version,
+        });
+
+        Ok(())
+    }
+
+    /// Sends the accrued protocol fees to the fee recipient's token accounts and
+    /// resets the counters. Only the pool authority may call this.
+    pub fn collect_protocol_fees(ctx: Context<CollectProtocolFees>) -> Result<()> {
+        let amount_a = ctx.accounts.pool.protocol_fees_a;
+        let amount_b = ctx.accounts.pool.protocol_fees_b;
+
+        let seeds = &[
+            b"pool",
+            ctx.accounts.pool.mint_a.as_ref(),
+            ctx.accounts.pool.mint_b.as_ref(),
+            &ctx.accounts.pool.fee_tier.to_le_bytes(),
+            ctx.accounts.pool.label_seed(),
+            &[ctx.accounts.pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_a,
+            &ctx.accounts.treasury_token_a,
+            &ctx.accounts.mint_a,
+            ctx.accounts.pool.to_account_info(),
+            signer,
+            amount_a,
+        )?;
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_b,
+            &ctx.accounts.treasury_token_b,
+            &ctx.accounts.mint_b,
+            ctx.accounts.pool.to_account_info(),
+            signer,
+            amount_b,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        pool.protocol_fees_a = 0;
+        pool.protocol_fees_b = 0;
+
+        Ok(())
+    }
+
+    /// Sweeps `amount_a` / `amount_b` from the vaults to the authority's token
+    /// accounts during an incident. Only the pool authority may call this, and
+    /// only while the pool is paused.
+    pub fn emergency_withdraw(
+        ctx: Context<AuthorityWithdraw>,
+        amount_a: u64,
+        amount_b: u64,
+    ) -> Result<()> {
+        require!(ctx.accounts.pool.is_paused, SwapError::PoolNotPaused);
+
+        let seeds = &[
+            b"pool",
+            ctx.accounts.pool.mint_a.as_ref(),
+            ctx.accounts.pool.mint_b.as_ref(),
+            &ctx.accounts.pool.fee_tier.to_le_bytes(),
+            ctx.accounts.pool.label_seed(),
+            &[ctx.accounts.pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_a,
+            &ctx.accounts.treasury_token_a,
+            &ctx.accounts.mint_a,
+            ctx.accounts.pool.to_account_info(),
+            signer,
+            amount_a,
+        )?;
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_b,
+            &ctx.accounts.treasury_token_b,
+            &ctx.accounts.mint_b,
+            ctx.accounts.pool.to_account_info(),
+            signer,
+            amount_b,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        pool.update_oracle(Clock::get()?.unix_timestamp);
+        pool.debit_vault(amount_a, true);
+        pool.debit_vault(amount_b, false);
+        pool.record_k();
+
+        emit!(EmergencyWithdraw {
+            pool: pool.key(),
+            authority: ctx.accounts.authority.key(),
+            amount_a,
+            amount_b,
+        });
+
+        Ok(())
+    }
+
+    /// Sends `amount` of a token that was sent to the pool by mistake, held in
+    /// a pool-owned account, to `destination`. The mint must be foreign to the
+    /// pool: not `mint_a`, `mint_b`, or the LP mint, so the reserves and the
+    /// locked liquidity can never be touched. Only the pool authority may call
+    /// this.
+    pub fn recover_foreign_token(ctx: Context<RecoverForeignToken>, amount: u64) -> Result<()> {
+        require!(amount > 0, SwapError::ZeroAmount);
+
+        let seeds = &[
+            b"pool",
+            ctx.accounts.pool.mint_a.as_ref(),
+            ctx.accounts.pool.mint_b.as_ref(),
+            &ctx.accounts.pool.fee_tier.to_le_bytes(),
+            ctx.accounts.pool.label_seed(),
+            &[ctx.accounts.pool.bump],
+        ];
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.source,
+            &ctx.accounts.destination,
+            &ctx.accounts.mint,
+            ctx.accounts.pool.to_account_info(),
+            &[&seeds[..]],
+            amount,
+        )?;
+
+        emit!(ForeignTokenRecovered {
+            pool: ctx.accounts.pool.key(),
+            mint: ctx.accounts.mint.key(),
+            destination: ctx.accounts.destination.key(),
+            amount,
+        });
+
+        Ok(())
+    }
+
+    /// Sends whatever the vaults hold beyond the stored reserves and uncollected
+    /// protocol fees (e.g. tokens transferred straight to a vault) to the given
+    /// recipient accounts. Anyone may call this; the reserves are untouched.
+    pub fn skim(ctx: Context<Skim>) -> Result<()> {
+        require!(
+            !ctx.accounts.pool.flash_loan_active,
+            SwapError::FlashLoanActive
+        );
+        // A positive rebase shows up as surplus in the vaults, and it belongs
+        // to the LPs rather than whoever skims first
+        require!(!ctx.accounts.pool.rebasing, SwapError::RebasingPool);
+        lock_pool(&mut ctx.accounts.pool)?;
+
+        let pool = &ctx.accounts.pool;
+        let amount_a = pool.untracked_balance(ctx.accounts.vault_a.amount, true);
+        let amount_b = pool.untracked_balance(ctx.accounts.vault_b.amount, false);
+
+        let seeds = &[
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_a,
+            &ctx.accounts.recipient_token_a,
+            &ctx.accounts.mint_a,
+            ctx.accounts.pool.to_account_info(),
+            signer,
+            amount_a,
+        )?;
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_b,
+            &ctx.accounts.recipient_token_b,
+            &ctx.accounts.mint_b,
+            ctx.accounts.pool.to_account_info(),
+            signer,
+            amount_b,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        pool.unlock();
+
+        emit!(Skimmed {
+            pool: pool.key(),
+            amount_a,
+            amount_b,
+        });
+
+        Ok(())
+    }
+
+    /// Transfers `amount_a` and `amount_b` into the vaults as a gift to the
+    /// pool's liquidity providers. Unlike a deposit it mints no LP tokens, and
+    /// unlike tokens sent straight to a vault (which `skim` would sweep) it is
+    /// added to the reserves, so existing LPs share it pro rata. A lopsided
+    /// donation moves the price like any change to the reserves. On
+    /// concentrated pools it goes to the positions in range instead.
+    pub fn donate(ctx: Context<Donate>, amount_a: u64, amount_b: u64) -> Result<()> {
+        require!(!ctx.accounts.pool.is_paused, SwapError::PoolPaused);
+        require!(
+            !ctx.accounts.pool.flash_loan_active,
+            SwapError::FlashLoanActive
+        );
+        require!(amount_a > 0 || amount_b > 0, SwapError::ZeroAmount);
+        // With no LPs yet the donation would only go to the first depositor
+        require!(
+            ctx.accounts.lp_mint.supply > 0 || ctx.accounts.pool.liquidity > 0,
+            SwapError::InsufficientLiquidity
+        );
+        lock_pool(&mut ctx.accounts.pool)?;
+        ctx.accounts
+            .pool
+            .update_oracle(Clock::get()?.unix_timestamp);
+
+        // Credit what actually landed in the vaults, as deposits do
+        let received_a = transfer_tokens_received(
+            &ctx.accounts.token_program,
+            &ctx.accounts.donor_token_a,
+            &mut ctx.accounts.vault_a,
+            &ctx.accounts.mint_a,
+            ctx.accounts.donor.to_account_info(),
+            &[],
+            amount_a,
+        )?;
+        let received_b = transfer_tokens_received(
+            &ctx.accounts.token_program,
+            &ctx.accounts.donor_token_b,
+            &mut ctx.accounts.vault_b,
+            &ctx.accounts.mint_b,
+            ctx.accounts.donor.to_account_info(),
+            &[],
+            amount_b,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        pool.donate(received_a, received_b)?;
+        pool.record_k();
+        pool.unlock();
+
+        emit!(Donated {
+            pool: pool.key(),
+            donor: ctx.accounts.donor.key(),
+            amount_a: received_a,
+            amount_b: received_b,
+        });
+
+        Ok(())
+    }
+
+    /// Resets the stored reserves to what the vaults actually hold, less the
+    /// uncollected protocol fees. This moves the price, so only the pool
+    /// authority may call it and every call emits `ReservesSynced`.
+    pub fn sync(ctx: Context<SyncReserves>) -> Result<()> {
+        require!(
+            !ctx.accounts.pool.flash_loan_active,
+            SwapError::FlashLoanActive
+        );
+        let vault_a = ctx.accounts.vault_a.amount;
+        let vault_b = ctx.accounts.vault_b.amount;
+
+        let pool = &mut ctx.accounts.pool;
+        pool.lock()?;
+        pool.update_oracle(Clock::get()?.unix_timestamp);
+        let (old_reserve_a, old_reserve_b) = (pool.reserve_a, pool.reserve_b);
+        pool.sync_reserves(vault_a, vault_b);
+        pool.record_k();
+        pool.unlock();
+
+        emit!(ReservesSynced {
+            pool: pool.key(),
+            authority: ctx.accounts.authority.key(),
+            old_reserve_a,
+            old_reserve_b,
+            reserve_a: pool.reserve_a,
+            reserve_b: pool.reserve_b,
+        });
+
+        Ok(())
+    }
+
+    /// Reconciles a rebasing pool's stored reserves with what its vaults hold
+    /// after the token's balances have changed on their own. A positive
+    /// rebase is credited to the reserves and a negative one debited, so the
+    /// change lands on every LP share pro rata. Anyone may call it; it fails
+    /// with `NotRebasingPool` for pools not flagged `rebasing` at creation.
+    pub fn sync_rebase(ctx: Context<SyncRebase>) -> Result<()> {
+        require!(ctx.accounts.pool.rebasing, SwapError::NotRebasingPool);
+        require!(
+            !ctx.accounts.pool.flash_loan_active,
+            SwapError::FlashLoanActive
+        );
+        let vault_a = ctx.accounts.vault_a.amount;
+        let vault_b = ctx.accounts.vault_b.amount;
+
+        let pool = &mut ctx.accounts.pool;
+        pool.lock()?;
+        pool.update_oracle(Clock::get()?.unix_timestamp);
+        let (old_reserve_a, old_reserve_b) = (pool.reserve_a, pool.reserve_b);
+        pool.sync_reserves(vault_a, vault_b);
+        pool.record_k();
+        pool.unlock();
+
+        emit!(RebaseSynced {
+            pool: pool.key(),
+            old_reserve_a,
+            old_reserve_b,
+            reserve_a: pool.reserve_a,
+            reserve_b: pool.reserve_b,
+        });
+
+        Ok(())
+    }
+
+    /// Closes a drained pool, returning the rent of its vaults, locked LP
+    /// account, and the pool itself to the authority. Once every LP token left
+    /// is the locked `MINIMUM_LIQUIDITY`, burns it and sweeps what remains in
+    /// the vaults (the dust reserves it stood for, and any stray transfers) to
+    /// the authority's treasury accounts. Protocol fees must be collected and,
+    /// on concentrated pools, every position closed first.
+    pub fn close_pool(ctx: Context<ClosePool>) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        require!(
+            pool.is_drained(ctx.accounts.lp_mint.supply, ctx.accounts.locked_lp.amount),
+            SwapError::PoolNotEmpty
+        );
+
+        let seeds = &[
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let locked = ctx.accounts.locked_lp.amount;
+        if locked > 0 {
+            let cpi_accounts = Burn {
+                mint: ctx.accounts.lp_mint.to_account_info(),
+                from: ctx.accounts.locked_lp.to_account_info(),
+                authority: ctx.accounts.pool.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                signer,
+            );
+            token_interface::burn(cpi_ctx, locked)?;
+        }
+
+        let (amount_a, amount_b) = (ctx.accounts.vault_a.amount, ctx.accounts.vault_b.amount);
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_a,
+            &ctx.accounts.treasury_token_a,
+            &ctx.accounts.mint_a,
+            ctx.accounts.pool.to_account_info(),
+            signer,
+            amount_a,
+        )?;
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_b,
+            &ctx.accounts.treasury_token_b,
+            &ctx.accounts.mint_b,
+            ctx.accounts.pool.to_account_info(),
+            signer,
+            amount_b,
+        )?;
+
+        for account in [
+            ctx.accounts.vault_a.to_account_info(),
+            ctx.accounts.vault_b.to_account_info(),
+            ctx.accounts.locked_lp.to_account_info(),
+        ] {
+            let cpi_accounts = CloseAccount {
+                account,
+                destination: ctx.accounts.authority.to_account_info(),
+                authority: ctx.accounts.pool.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                signer,
+            );
+            token_interface::close_account(cpi_ctx)?;
+        }
+
+        // The pool account itself is closed to the authority by the `close`
+        // constraint once this returns
+        Ok(())
+    }
+
+    /// Emits the marginal price of each token in terms of the other, normalized
+    /// for mint decimals and scaled by `PRICE_SCALE`.
+    pub fn get_spot_price(ctx: Context<GetSpotPrice>) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        let (decimals_a, decimals_b) = (pool.decimals_a, pool.decimals_b);
+        let (reserve_a, reserve_b) = pool.price_reserves();
+
+        emit!(SpotPrice {
+            pool: pool.key(),
+            price_a_in_b: calculate_spot_price(reserve_a, decimals_a, reserve_b, decimals_b)?,
+            price_b_in_a: calculate_spot_price(reserve_b, decimals_b, reserve_a, decimals_a)?,
+        });
+
+        Ok(())
+    }
+
+    /// Emits the stored reserves and the LP supply, so clients can read them
+    /// without depending on the pool account layout.
+    pub fn get_reserves(ctx: Context<GetReserves>) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+
+        emit!(Reserves {
+            pool: pool.key(),
+            reserve_a: pool.reserve_a,
+            reserve_b: pool.reserve_b,
+            lp_supply: ctx.accounts.lp_mint.supply,
+        });
+
+        Ok(())
+    }
+
+    /// Emits the pool's mints, reserves, fee, LP supply, paused state, and
+    /// authority in one `PoolInfo` event, so a front end can build its view
+    /// of the pool in one call without depending on the account layout.
+    pub fn get_pool_info(ctx: Context<GetReserves>) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+
+        emit!(PoolInfo {
+            pool: pool.key(),
+            mint_a: pool.mint_a,
+            mint_b: pool.mint_b,
+            lp_mint: pool.lp_mint,
+            reserve_a: pool.reserve_a,
+            reserve_b: pool.reserve_b,
+            fee_bps: pool.fee_bps,
+            lp_supply: ctx.accounts.lp_mint.supply,
+            is_paused: pool.is_paused,
+            authority: pool.authority,
+        });
+
+        Ok(())
+    }
+
+    /// Emits what `lp_amount` LP tokens would withdraw from each reserve right
+    /// now, i.e. exactly what `remove_liquidity` would pay for them.
+    pub fn get_lp_value(ctx: Context<GetReserves>, lp_amount: u64) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        require!(
+            pool.curve_type != CurveType::Concentrated,
+            SwapError::UnsupportedCurve
+        );
+        let lp_supply = ctx.accounts.lp_mint.supply;
+        require!(lp_amount <= lp_supply, SwapError::InsufficientLiquidity);
+        let (amount_a, amount_b) = pool.lp_value(lp_amount, lp_supply)?;
+
+        emit!(LpValue {
+            pool: pool.key(),
+            lp_amount,
+            amount_a,
+            amount_b,
+        });
+
+        Ok(())
+    }
+
+    /// Emits the TWAP accumulators with the time they were last advanced and the
+    /// current time, so integrators can sample the oracle and reject stale
+    /// readings without depending on the pool account layout.
+    pub fn get_oracle_observation(ctx: Context<GetOracleObservation>) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        require!(pool.oracle_enabled, SwapError::OracleDisabled);
+
+        emit!(OracleObservation {
+            pool: pool.key(),
+            price_cumulative_a: pool.price_cumulative_a,
+            price_cumulative_b: pool.price_cumulative_b,
+            last_update_ts: pool.last_update_ts,
+            current_ts: Clock::get()?.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    /// Returns the TWAP over the last `seconds_ago` seconds, interpolated from the
+    /// pool's stored observations. The window must not reach back past the
+    /// oldest observation. Also emitted as a `TwapResult` event.
+    pub fn consult(ctx: Context<Consult>, seconds_ago: u32) -> Result<Twap> {
+        let pool = &ctx.accounts.pool;
+        let twap = pool.twap(Clock::get()?.unix_timestamp, seconds_ago)?;
+
+        emit!(TwapResult {
+            pool: pool.key(),
+            seconds_ago,
+            price_a_in_b: twap.price_a_in_b,
+            price_b_in_a: twap.price_b_in_a,
+        });
+
+        Ok(twap)
+    }
+
+    /// Previews the output of a swap without moving any tokens. The amount is
+    /// returned and also emitted as a `QuoteResult` event for clients that
+    /// read logs, alongside a `QuoteDetailed` event with the swap's price
+    /// impact and effective price for display.
+    pub fn quote_swap(ctx: Context<QuoteSwap>, amount_in: u64, a_to_b: bool) -> Result<u64> {
+        // Quote with the fee a swap would charge right now
+        let mut pool = Pool::clone(&ctx.accounts.pool);
+        let now = Clock::get()?.unix_timestamp;
+        pool.update_oracle(now);
+        pool.refresh_fee(now);
+        let amount_out = pool.swap_output(amount_in, a_to_b)?;
+
+        emit!(QuoteResult {
+            pool: ctx.accounts.pool.key(),
+            amount_in,
+            amount_out,
+            a_to_b,
+        });
+
+        // A swap that pays out nothing has no execution price; it loses the
+        // whole input
+        let (price_impact_bps, effective_price) = if amount_out == 0 {
+            (FEE_DENOMINATOR, 0)
+        } else {
+            let (decimals_in, decimals_out) = if a_to_b {
+                (pool.decimals_a, pool.decimals_b)
+            } else {
+                (pool.decimals_b, pool.decimals_a)
+            };
+            (
+                pool.price_impact_bps(amount_in, amount_out, a_to_b)?,
+                calculate_spot_price(amount_in, decimals_in, amount_out, decimals_out)?,
+            )
+        };
+        emit!(QuoteDetailed {
+            pool: ctx.accounts.pool.key(),
+            amount_out,
+            price_impact_bps,
+            effective_price,
+        });
+
+        Ok(amount_out)
+    }
+
+    /// Emits, in a `MaxInputForImpact` event, the largest input a swap in the
+    /// given direction can take right now without its price impact (as
+    /// checked by `swap`'s `max_price_impact_bps`) exceeding `max_impact_bps`,
+    /// and the output it would get. Impact is whole basis points, so one more
+    /// unit of input goes over the limit. Also returns the input.
+    pub fn max_input_for_impact(
+        ctx: Context<QuoteSwap>,
+        max_impact_bps: u16,
+        a_to_b: bool,
+    ) -> Result<u64> {
+        // Solve with the fee a swap would charge right now
+        let mut pool = Pool::clone(&ctx.accounts.pool);
+        let now = Clock::get()?.unix_timestamp;
+        pool.update_oracle(now);
+        pool.refresh_fee(now);
+        let (reserve_in, _) = pool.directed_reserves(a_to_b);
+        require!(reserve_in > 0, SwapError::InsufficientLiquidity);
+        // Beyond this the input reserve itself would overflow
+        let amount_in =
+            pool.max_input_within_impact(u64::MAX - reserve_in, max_impact_bps, a_to_b)?;
+        let amount_out = pool.swap_output(amount_in, a_to_b)?;
+
+        emit!(MaxInputForImpact {
+            pool: ctx.accounts.pool.key(),
+            max_impact_bps,
+            a_to_b,
+            amount_in,
+            amount_out,
+        });
+
+        Ok(amount_in)
+    }
+
+    /// Quotes a route through the pools passed as remaining accounts, starting
+    /// from `amount_in` of `mint_in`. Each pool must contain the token the previous
+    /// hop produced. Emits every hop's output in a `PathQuote` event.
+    pub fn quote_path<'info>(
+        ctx: Context<'_, '_, 'info, 'info, QuotePath>,
+        amount_in: u64,
+        mint_in: Pubkey,
+    ) -> Result<u64> {
+        require!(!ctx.remaining_accounts.is_empty(), SwapError::InvalidPath);
+
+        let now = Clock::get()?.unix_timestamp;
+        let mut mint = mint_in;
+        let mut amount = amount_in;
+        let mut amounts = Vec::with_capacity(ctx.remaining_accounts.len());
+        for info in ctx.remaining_accounts {
+            let mut pool = Account::<Pool>::try_from(info)?;
+            pool.update_oracle(now);
+            pool.refresh_fee(now);
+            let a_to_b = if mint == pool.mint_a {
+                true
+            } else if mint == pool.mint_b {
+                false
+            } else {
+                return err!(SwapError::InvalidPath);
+            };
+
+            amount = pool.swap_output(amount, a_to_b)?;
+            mint = if a_to_b { pool.mint_b } else { pool.mint_a };
+            amounts.push(amount);
+        }
+
+        emit!(PathQuote {
+            amount_in,
+            amount_out: amount,
+            mint_in,
+            mint_out: mint,
+            amounts,
+        });
+
+        Ok(amount)
+    }
+}
+
+// Helper function to run one batch_swap leg against its slice of the remaining
+// accounts. The pool is written back before returning so a later leg through
+// the same pool sees this one's reserves.
+fn execute_batch_leg<'info>(
+    user: &Signer<'info>,
+    token_program: &Interface<'info, TokenInterface>,
+    accounts: &'info [AccountInfo<'info>],
+    leg: &BatchLeg,
+    now: i64,
+    protocol_fee_on: bool,
+) -> Result<()> {
+    let mut pool = Account::<Pool>::try_from(&accounts[0])?;
+    let mint_in = InterfaceAccount::<Mint>::try_from(&accounts[1])?;
+    let mint_out = InterfaceAccount::<Mint>::try_from(&accounts[2])?;
+    let mut vault_in = InterfaceAccount::<TokenAccount>::try_from(&accounts[3])?;
+    let vault_out = InterfaceAccount::<TokenAccount>::try_from(&accounts[4])?;
+    let (user_token_in, user_token_out) = (&accounts[5], &accounts[6]);
+
+    require!(!pool.is_paused, SwapError::PoolPaused);
+    require!(!pool.flash_loan_active, SwapError::FlashLoanActive);
+    // Legs carry no last_swap account to enforce a cooldown with
+    require!(
+        pool.min_swap_interval == 0,
+        SwapError::CooldownRequiresDirectSwap
+    );
+    require!(leg.amount_in > 0, SwapError::ZeroAmount);
+    pool.check_compute(sol_remaining_compute_units())?;
+    let a_to_b = leg_direction(
+        &pool,
+        mint_in.key(),
+        mint_out.key(),
+        vault_in.key(),
+        vault_out.key(),
+    )?;
+    lock_pool(&mut pool)?;
+    pool.update_oracle(now);
+    pool.refresh_fee(now);
+    pool.check_input_after_fee(leg.amount_in)?;
+
+    let amount_in = transfer_tokens_received(
+        token_program,
+        user_token_in,
+        &mut vault_in,
+        &mint_in,
+        user.to_account_info(),
+        &[],
+        leg.amount_in,
+    )?;
+    let amount_out = pool.swap_output(amount_in, a_to_b)?;
+    require!(amount_out > 0, SwapError::ZeroOutput);
+    require!(
+        amount_out >= leg.minimum_amount_out,
+        SwapError::SlippageTooHigh
+    );
+
+    let seeds = &[
+        b"pool",
+        pool.mint_a.as_ref(),
+        pool.mint_b.as_ref(),
+        &pool.fee_tier.to_le_bytes(),
+        pool.label_seed(),
+        &[pool.bump],
+    ];
+    transfer_tokens(
+        token_program,
+        &vault_out,
+        user_token_out,
+        &mint_out,
+        pool.to_account_info(),
+        &[&seeds[..]],
+        amount_out,
+    )?;
+
+    let fees = pool.fee_split(amount_in, protocol_fee_on, false)?;
+    pool.apply_swap(amount_in, amount_out, a_to_b, fees)?;
+    pool.record_k();
+    pool.unlock();
+    pool.exit(&crate::ID)?;
+
+    emit!(SwapExecuted {
+        pool: pool.key(),
+        user: user.key(),
+        mint_a: pool.mint_a,
+        mint_b: pool.mint_b,
+        amount_in,
+        amount_out,
+        a_to_b,
+        reserve_a: pool.reserve_a,
+        reserve_b: pool.reserve_b,
+    });
+
+    Ok(())
+}
+
+// Helper function to run one batch_add_liquidity deposit against its slice of
+// the remaining accounts. The pool is written back before returning so a later
+// deposit into the same pool sees this one's reserves.
+fn execute_batch_deposit<'info>(
+    user: &Signer<'info>,
+    token_program: &Interface<'info, TokenInterface>,
+    accounts: &'info [AccountInfo<'info>],
+    deposit: &BatchDeposit,
+    now: i64,
+) -> Result<()> {
+    let mut pool = Account::<Pool>::try_from(&accounts[0])?;
+    let mint_a = InterfaceAccount::<Mint>::try_from(&accounts[1])?;
+    let mint_b = InterfaceAccount::<Mint>::try_from(&accounts[2])?;
+    let mut vault_a = InterfaceAccount::<TokenAccount>::try_from(&accounts[3])?;
+    let mut vault_b = InterfaceAccount::<TokenAccount>::try_from(&accounts[4])?;
+    let lp_mint = InterfaceAccount::<Mint>::try_from(&accounts[5])?;
+    let (user_token_a, user_token_b, user_lp_token) = (&accounts[6], &accounts[7], &accounts[8]);
+
+    require!(!pool.is_paused, SwapError::PoolPaused);
+    require!(!pool.flash_loan_active, SwapError::FlashLoanActive);
+    require!(
+        deposit.max_amount_a > 0 && deposit.max_amount_b > 0,
+        SwapError::ZeroAmount
+    );
+    require!(
+        pool.curve_type != CurveType::Concentrated,
+        SwapError::UnsupportedCurve
+    );
+    require!(
+        (mint_a.key(), mint_b.key(), lp_mint.key()) == (pool.mint_a, pool.mint_b, pool.lp_mint),
+        SwapError::InvalidBatch
+    );
+    let (vault_a_key, vault_b_key) = pool_vaults(&pool)?;
+    require_keys_eq!(vault_a.key(), vault_a_key, SwapError::InvalidVault);
+    require_keys_eq!(vault_b.key(), vault_b_key, SwapError::InvalidVault);
+    require!(lp_mint.supply > 0, SwapError::NoLiquidity);
+    lock_pool(&mut pool)?;
+
+    let (amount_a, amount_b) = calculate_deposit_amounts(
+        deposit.max_amount_a,
+        deposit.max_amount_b,
+        pool.reserve_a,
+        pool.reserve_b,
+    )?;
+    pool.update_oracle(now);
+
+    let received_a = transfer_tokens_received(
+        token_program,
+        user_token_a,
+        &mut vault_a,
+        &mint_a,
+        user.to_account_info(),
+        &[],
+        amount_a,
+    )?;
+    let received_b = transfer_tokens_received(
+        token_program,
+        user_token_b,
+        &mut vault_b,
+        &mint_b,
+        user.to_account_info(),
+        &[],
+        amount_b,
+    )?;
+
+    let lp_amount = calculate_lp_to_mint(
+        received_a,
+        received_b,
+        pool.reserve_a,
+        pool.reserve_b,
+        lp_mint.supply,
+    )?;
+    require!(lp_amount >= deposit.min_lp_out, SwapError::SlippageTooHigh);
+    pool.check_liquidity_cap(lp_mint.supply, lp_amount)?;
+
+    let seeds = &[
+        b"pool",
+        pool.mint_a.as_ref(),
+        pool.mint_b.as_ref(),
+        &pool.fee_tier.to_le_bytes(),
+        pool.label_seed(),
+        &[pool.bump],
+    ];
+    token_interface::mint_to(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            MintTo {
+                mint: lp_mint.to_account_info(),
+                to: user_lp_token.to_account_info(),
+                authority: pool.to_account_info(),
+            },
+            &[&seeds[..]],
+        ),
+        lp_amount,
+    )?;
+
+    pool.add_reserves(received_a, received_b)?;
+    pool.record_k();
+    pool.unlock();
+    pool.exit(&crate::ID)?;
+
+    emit!(LiquidityAdded {
+        pool: pool.key(),
+        user: user.key(),
+        amount_a: received_a,
+        amount_b: received_b,
+        lp_minted: lp_amount,
+        reserve_a: pool.reserve_a,
+        reserve_b: pool.reserve_b,
+    });
+
+    Ok(())
+}
+
+// Zeroes a pool account past its serialized record. `ticks` is only as long as
+// it is, so the record can end well before the account does, and the bytes past
+// it keep whatever a longer record left there. Fields appended since the
+// record's layout are read from those bytes, so they must be cleared before
+// migrating. `allocated` is the account's size under its old layout, which
+// tells how many of the fields after `ticks` the record has.
+fn clear_pool_slack(data: &mut [u8], allocated: usize) -> Result<()> {
+    let body = data.get(8..).ok_or(ErrorCode::AccountDidNotDeserialize)?;
+    let mut rest = body;
+    PoolHead::deserialize(&mut rest).map_err(|_| ErrorCode::AccountDidNotDeserialize)?;
+    let head_len = body.len() - rest.len();
+    let tail_len = allocated
+        .checked_sub(8 + PoolHead::INIT_SPACE)
+        .ok_or(ErrorCode::AccountDidNotDeserialize)?;
+    let end = 8 + head_len + tail_len;
+    data.get_mut(end..)
+        .ok_or(ErrorCode::AccountDidNotDeserialize)?
+        .fill(0);
+    Ok(())
+}
+
+// Helper function to take the pool's reentrancy lock and write it to the account
+// straight away, so a nested call into this program during a CPI sees it
+fn lock_pool(pool: &mut Account<Pool>) -> Result<()> {
+    pool.lock()?;
+    pool.exit(&crate::ID)
+}
+
+// Helper function to move tokens with `transfer_checked`, which Token-2022 requires.
+// Pass empty `signer_seeds` when the authority signs the transaction itself.
+fn transfer_tokens<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    from: &impl ToAccountInfo<'info>,
+    to: &impl ToAccountInfo<'info>,
+    mint: &InterfaceAccount<'info, Mint>,
+    authority: AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    let cpi_accounts = TransferChecked {
+        from: from.to_account_info(),
+        mint: mint.to_account_info(),
+        to: to.to_account_info(),
+        authority,
+    };
+    let cpi_ctx =
+        CpiContext::new_with_signer(token_program.to_account_info(), cpi_accounts, signer_seeds);
+    token_interface::transfer_checked(cpi_ctx, amount, mint.decimals)
+}
+
+// Same as transfer_tokens, but reloads `to` and returns how much it actually
+// received, which is less than `amount` for fee-on-transfer tokens
+fn transfer_tokens_received<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    from: &impl ToAccountInfo<'info>,
+    to: &mut InterfaceAccount<'info, TokenAccount>,
+    mint: &InterfaceAccount<'info, Mint>,
+    authority: AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<u64> {
+    let balance_before = to.amount;
+    transfer_tokens(
+        token_program,
+        from,
+        to,
+        mint,
+        authority,
+        signer_seeds,
+        amount,
+    )?;
+
+    to.reload()?;
+    to.amount
+        .checked_sub(balance_before)
+        .ok_or(SwapError::MathOverflow.into())
+}
+
+// Helper function for swap_two_hop's transfers. With `exact` the amount is
+// known to arrive in full, so `to` is not reloaded to measure it and its
+// cached balance goes stale.
+#[allow(clippy::too_many_arguments)]
+fn transfer_tokens_hop<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    from: &impl ToAccountInfo<'info>,
+    to: &mut InterfaceAccount<'info, TokenAccount>,
+    mint: &InterfaceAccount<'info, Mint>,
+    authority: AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+    exact: bool,
+) -> Result<u64> {
+    if exact {
+        transfer_tokens(
+            token_program,
+            from,
+            &*to,
+            mint,
+            authority,
+            signer_seeds,
+            amount,
+        )?;
+        return Ok(amount);
+    }
+    transfer_tokens_received(
+        token_program,
+        from,
+        to,
+        mint,
+        authority,
+        signer_seeds,
+        amount,
+    )
+}
+
+// Helper function to recognise the wrapped SOL mint of either token program
+fn is_native_mint(mint: &Pubkey) -> bool {
+    *mint == spl_token::native_mint::ID || *mint == spl_token_2022::native_mint::ID
+}
+
+// Helper function to check that a swap side passed without a user token account
+// is native SOL, and to pick out the accounts needed to wrap or unwrap it
+fn native_sol_accounts<'a, 'info>(
+    mint: &InterfaceAccount<'info, Mint>,
+    wsol_account: &'a Option<UncheckedAccount<'info>>,
+    system_program: &'a Option<Program<'info, System>>,
+) -> Result<(&'a UncheckedAccount<'info>, &'a Program<'info, System>)> {
+    require!(is_native_mint(&mint.key()), SwapError::MissingTokenAccount);
+    match (wsol_account, system_program) {
+        (Some(wsol_account), Some(system_program)) => Ok((wsol_account, system_program)),
+        _ => err!(SwapError::NativeSolAccountsMissing),
+    }
+}
+
+// Helper function to create the user's temporary wSOL account at its PDA and
+// wrap `lamports` of the user's SOL into it. The user owns the account, so it
+// signs for moving the tokens out and for closing it.
+fn open_wsol_account<'info>(
+    user: &Signer<'info>,
+    wsol_account: &UncheckedAccount<'info>,
+    mint: &InterfaceAccount<'info, Mint>,
+    token_program: &Interface<'info, TokenInterface>,
+    system_program: &Program<'info, System>,
+    lamports: u64,
+) -> Result<()> {
+    let (expected, bump) =
+        Pubkey::find_program_address(&[b"wsol", user.key().as_ref()], &crate::ID);
+    require_keys_eq!(wsol_account.key(), expected, SwapError::InvalidWsolAccount);
+    let seeds = &[b"wsol", user.key.as_ref(), &[bump]];
+    let signer = &[&seeds[..]];
+
+    let space = spl_token::state::Account::LEN;
+    let rent = Rent::get()?.minimum_balance(space);
+    if wsol_account.lamports() == 0 {
+        system_program::create_account(
+            CpiContext::new_with_signer(
+                system_program.to_account_info(),
+                CreateAccount {
+                    from: user.to_account_info(),
+                    to: wsol_account.to_account_info(),
+                },
+                signer,
+            ),
+            rent,
+            space as u64,
+            token_program.key,
+        )?;
+    } else {
+        // Anyone can send lamports to the address, which makes create_account
+        // refuse it, so top it up and claim it step by step instead
+        let top_up = rent.saturating_sub(wsol_account.lamports());
+        if top_up > 0 {
+            system_program::transfer(
+                CpiContext::new(
+                    system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: user.to_account_info(),
+                        to: wsol_account.to_account_info(),
+                    },
+                ),
+                top_up,
+            )?;
+        }
+        system_program::allocate(
+            CpiContext::new_with_signer(
+                system_program.to_account_info(),
+                Allocate {
+                    account_to_allocate: wsol_account.to_account_info(),
+                },
+                signer,
+            ),
+            space as u64,
+        )?;
+        system_program::assign(
+            CpiContext::new_with_signer(
+                system_program.to_account_info(),
+                Assign {
+                    account_to_assign: wsol_account.to_account_info(),
+                },
+                signer,
+            ),
+            token_program.key,
+        )?;
+    }
+
+    token_interface::initialize_account3(CpiContext::new(
+        token_program.to_account_info(),
+        InitializeAccount3 {
+            account: wsol_account.to_account_info(),
+            mint: mint.to_account_info(),
+            authority: user.to_account_info(),
+        },
+    ))?;
+
+    if lamports > 0 {
+        system_program::transfer(
+            CpiContext::new(
+                system_program.to_account_info(),
+                system_program::Transfer {
+                    from: user.to_account_info(),
+                    to: wsol_account.to_account_info(),
+                },
+            ),
+            lamports,
+        )?;
+        token_interface::sync_native(CpiContext::new(
+            token_program.to_account_info(),
+            SyncNative {
+                account: wsol_account.to_account_info(),
+            },
+        ))?;
+    }
+
+    Ok(())
+}
+
+// Helper function to close the temporary wSOL account, returning its rent and
+// any wrapped SOL to the user as lamports
+fn close_wsol_account<'info>(
+    user: &Signer<'info>,
+    wsol_account: &UncheckedAccount<'info>,
+    token_program: &Interface<'info, TokenInterface>,
+) -> Result<()> {
+    token_interface::close_account(CpiContext::new(
+        token_program.to_account_info(),
+        CloseAccount {
+            account: wsol_account.to_account_info(),
+            destination: user.to_account_info(),
+            authority: user.to_account_info(),
+        },
+    ))
+}
+
+/// The constant-product invariant `k = reserve_a * reserve_b`, the value
+/// `last_invariant_k` records. Two `u64` reserves multiply to at most
+/// `(2^64 - 1)^2`, which always fits in a `u128`, so this can't overflow.
+pub fn constant_product_invariant(reserve_a: u64, reserve_b: u64) -> u128 {
+    reserve_a as u128 * reserve_b as u128
+}
+
+// Helper function to calculate swap output using constant product formula.
+// The fee is taken off the input first and stays in the vault for LPs.
+fn calculate_swap_output(
+    amount_in: u64,
+    reserve_in: u64,
+    reserve_out: u64,
+    fee_bps: u16,
+) -> Result<u64> {
+    require!(
+        reserve_in > 0 && reserve_out > 0,
+        SwapError::InsufficientLiquidity
+    );
+
+    let amount_in_with_fee = mul_div(
+        amount_in as u128,
+        (FEE_DENOMINATOR - fee_bps as u64) as u128,
+        FEE_DENOMINATOR as u128,
+        RoundingMode::Down,
+    )?;
+
+    let denominator = (reserve_in as u128)
+        .checked_add(amount_in_with_fee)
+        .ok_or(SwapError::MathOverflow)?;
+
+    let amount_out = mul_div(
+        amount_in_with_fee,
+        reserve_out as u128,
+        denominator,
+        RoundingMode::Down,
+    )?;
+
+    // Never hand out the whole output reserve
+    require!(
+        amount_out < reserve_out as u128,
+        SwapError::InsufficientLiquidity
+    );
+
+    u64::try_from(amount_out).map_err(|_| error!(SwapError::MathOverflow))
+}
+
+// Helper function to calculate the input needed for an exact output, the inverse
+// of calculate_swap_output. Both steps round up so the pool is never short-changed.
+fn calculate_swap_input(
+    amount_out: u64,
+    reserve_in: u64,
+    reserve_out: u64,
+    fee_bps: u16,
+) -> Result<u64> {
+    require!(
+        reserve_in > 0 && reserve_out > 0,
+        SwapError::InsufficientLiquidity
+    );
+    require!(amount_out < reserve_out, SwapError::InsufficientLiquidity);
+
+    // amount_in_with_fee = (reserve_in * amount_out) / (reserve_out - amount_out)
+    let amount_in_with_fee = mul_div(
+        reserve_in as u128,
+        amount_out as u128,
+        (reserve_out - amount_out) as u128,
+        RoundingMode::Up,
+    )?;
+
+    gross_up_for_fee(amount_in_with_fee, fee_bps)
+}
+
+// Helper function to add the fee back onto a net input, rounding up:
+// amount_in = amount_in_with_fee * 10000 / (10000 - fee_bps)
+fn gross_up_for_fee(amount_in_with_fee: u128, fee_bps: u16) -> Result<u64> {
+    let amount_in = mul_div(
+        amount_in_with_fee,
+        FEE_DENOMINATOR as u128,
+        (FEE_DENOMINATOR - fee_bps as u64) as u128,
+        RoundingMode::Up,
+    )?;
+
+    u64::try_from(amount_in).map_err(|_| error!(SwapError::MathOverflow))
+}
+
+// Helper function to calculate swap output on the StableSwap curve. Like the
+// constant-product path the fee comes off the input first; the output is
+// rounded down by one more unit so the invariant can't shrink.
+fn calculate_output_stable(
+    amount_in: u64,
+    reserve_in: u64,
+    reserve_out: u64,
+    fee_bps: u16,
+    amp: u64,
+) -> Result<u64> {
+    require!(
+        reserve_in > 0 && reserve_out > 0,
+        SwapError::InsufficientLiquidity
+    );
+
+    let amount_in_with_fee = mul_div(
+        amount_in as u128,
+        (FEE_DENOMINATOR - fee_bps as u64) as u128,
+        FEE_DENOMINATOR as u128,
+        RoundingMode::Down,
+    )?;
+
+    let d = compute_stable_d(reserve_in as u128, reserve_out as u128, amp)?;
+    let new_reserve_in = (reserve_in as u128)
+        .checked_add(amount_in_with_fee)
+        .ok_or(SwapError::MathOverflow)?;
+    let new_reserve_out = compute_stable_y(new_reserve_in, d, amp)?;
+
+    let amount_out = (reserve_out as u128)
+        .saturating_sub(new_reserve_out)
+        .saturating_sub(1);
+
+    u64::try_from(amount_out).map_err(|_| error!(SwapError::MathOverflow))
+}
+
+// Helper function to calculate the input needed for an exact output on the
+// StableSwap curve, rounding up in the pool's favour
+fn calculate_input_stable(
+    amount_out: u64,
+    reserve_in: u64,
+    reserve_out: u64,
+    fee_bps: u16,
+    amp: u64,
+) -> Result<u64> {
+    require!(
+        reserve_in > 0 && reserve_out > 0,
+        SwapError::InsufficientLiquidity
+    );
+    require!(amount_out < reserve_out, SwapError::InsufficientLiquidity);
+
+    let d = compute_stable_d(reserve_in as u128, reserve_out as u128, amp)?;
+    let new_reserve_out = (reserve_out - amount_out) as u128;
+    let new_reserve_in = compute_stable_y(new_reserve_out, d, amp)?;
+
+    // Covers both Newton rounding and the unit calculate_output_stable holds back
+    let amount_in_with_fee = new_reserve_in
+        .checked_sub(reserve_in as u128)
+        .and_then(|n| n.checked_add(2))
+        .ok_or(SwapError::MathOverflow)?;
+
+    gross_up_for_fee(amount_in_with_fee, fee_bps)
+}
+
+// Helper function to solve the two-token StableSwap invariant for D by Newton's
+// method, with ann = amp * n:
+// ann * (x + y) + D = ann * D + D^3 / (4 * x * y)
+fn compute_stable_d(x: u128, y: u128, amp: u64) -> Result<u128> {
+    let sum = x.checked_add(y).ok_or(SwapError::MathOverflow)?;
+    let ann = amp as u128 * STABLE_N_COINS;
+
+    let mut d = sum;
+    for _ in 0..STABLE_MAX_ITERATIONS {
+        // d_p = D^3 / (4 * x * y)
+        let d_p = d
+            .checked_mul(d)
+            .and_then(|n| n.checked_div(x * STABLE_N_COINS))
+            .and_then(|n| n.checked_mul(d))
+            .and_then(|n| n.checked_div(y * STABLE_N_COINS))
+            .ok_or(SwapError::MathOverflow)?;
+
+        let previous = d;
+        let numerator = ann
+            .checked_mul(sum)
+            .and_then(|n| n.checked_add(d_p * STABLE_N_COINS))
+            .and_then(|n| n.checked_mul(d))
+            .ok_or(SwapError::MathOverflow)?;
+        let denominator = (ann - 1)
+            .checked_mul(d)
+            .and_then(|n| n.checked_add((STABLE_N_COINS + 1) * d_p))
+            .ok_or(SwapError::MathOverflow)?;
+        d = numerator / denominator;
+
+        if d.abs_diff(previous) <= 1 {
+            return Ok(d);
+        }
+    }
+
+    // Newton's method converges in a handful of steps for any sane reserves
+    err!(SwapError::MathOverflow)
+}
+
+// Helper function to find the other reserve y that keeps the invariant at D
+// once one reserve becomes x, again by Newton's method
+fn compute_stable_y(x: u128, d: u128, amp: u64) -> Result<u128> {
+    let ann = amp as u128 * STABLE_N_COINS;
+
+    // y^2 + (b - D) * y = c, with c = D^3 / (4 * x * ann) and b = x + D / ann
+    let c = d
+        .checked_mul(d)
+        .and_then(|n| n.checked_div(x * STABLE_N_COINS))
+        .and_then(|n| n.checked_mul(d))
+        .and_then(|n| n.checked_div(ann * STABLE_N_COINS))
+        .ok_or(SwapError::MathOverflow)?;
+    let b = x.checked_add(d / ann).ok_or(SwapError::MathOverflow)?;
+
+    let mut y = d;
+    for _ in 0..STABLE_MAX_ITERATIONS {
+        let previous = y;
+        let numerator = y
+            .checked_mul(y)
+            .and_then(|n| n.checked_add(c))
+            .ok_or(SwapError::MathOverflow)?;
+        let denominator = (2 * y)
+            .checked_add(b)
+            .and_then(|n| n.checked_sub(d))
+            .filter(|n| *n > 0)
+            .ok_or(SwapError::MathOverflow)?;
+        y = numerator / denominator;
+
+        if y.abs_diff(previous) <= 1 {
+            return Ok(y);
+        }
+    }
+
+    err!(SwapError::MathOverflow)
+}
+
+// Helper function to calculate price impact in basis points: how much worse the
+// execution price (amount_out / amount_in) is than the spot price
+// (reserve_out / reserve_in). The swap fee counts towards the impact.
+fn calculate_price_impact_bps(
+    amount_in: u64,
+    amount_out: u64,
+    reserve_in: u64,
+    reserve_out: u64,
+) -> Result<u64> {
+    // execution / spot = (amount_out * reserve_in) / (amount_in * reserve_out)
+    let executed = (amount_out as u128)
+        .checked_mul(reserve_in as u128)
+        .ok_or(SwapError::MathOverflow)?
+        .checked_mul(FEE_DENOMINATOR as u128)
+        .ok_or(SwapError::MathOverflow)?;
+    let spot = (amount_in as u128)
+        .checked_mul(reserve_out as u128)
+        .ok_or(SwapError::MathOverflow)?;
+    require!(spot > 0, SwapError::InsufficientLiquidity);
+
+    let ratio_bps = executed / spot;
+    Ok((FEE_DENOMINATOR as u128).saturating_sub(ratio_bps) as u64)
+}
+
+// Helper function to calculate the price of one whole base token in whole quote
+// tokens, scaled by PRICE_SCALE:
+// (reserve_quote / 10^decimals_quote) / (reserve_base / 10^decimals_base)
+fn calculate_spot_price(
+    reserve_base: u64,
+    decimals_base: u8,
+    reserve_quote: u64,
+    decimals_quote: u8,
+) -> Result<u128> {
+    require!(
+        reserve_base > 0 && reserve_quote > 0,
+        SwapError::InsufficientLiquidity
+    );
+
+    let numerator = (reserve_quote as u128)
+        .checked_mul(10u128.pow(decimals_base as u32))
+        .and_then(|n| n.checked_mul(PRICE_SCALE))
+        .ok_or(SwapError::MathOverflow)?;
+    let denominator = (reserve_base as u128)
+        .checked_mul(10u128.pow(decimals_quote as u32))
+        .ok_or(SwapError::MathOverflow)?;
+
+    Ok(numerator / denominator)
+}
+
+// Helper function to check a mint's decimals are within the range the price
+// math supports
+fn supported_decimals(decimals: u8) -> bool {
+    (MIN_MINT_DECIMALS..=MAX_MINT_DECIMALS).contains(&decimals)
+}
+
+// Helper function to check that `price` is within `tolerance_bps` of `expected`:
+// |price - expected| * 10000 <= expected * tolerance_bps
+fn price_within_tolerance(price: u128, expected: u128, tolerance_bps: u16) -> Result<bool> {
+    let deviation = price
+        .abs_diff(expected)
+        .checked_mul(FEE_DENOMINATOR as u128)
+        .ok_or(SwapError::MathOverflow)?;
+    let allowed = expected
+        .checked_mul(tolerance_bps as u128)
+        .ok_or(SwapError::MathOverflow)?;
+
+    Ok(deviation <= allowed)
+}
+
+// Helper function to add `reserve_quote / reserve_base * elapsed` (scaled by
+// PRICE_SCALE) to a price accumulator, wrapping on overflow
+fn accumulate_price(cumulative: u128, reserve_base: u64, reserve_quote: u64, elapsed: u64) -> u128 {
+    let price = (reserve_quote as u128) * PRICE_SCALE / (reserve_base as u128);
+    cumulative.wrapping_add(price.wrapping_mul(elapsed as u128))
+}
+
+// Helper function to place a dynamic fee in its band by the spot price's
+// deviation from the TWAP: the band's minimum at no deviation, rising linearly
+// to its maximum at VOLATILITY_CAP_BPS or more
+fn calculate_dynamic_fee(spot: u128, twap: u128, band: FeeBand) -> u16 {
+    if twap == 0 {
+        return band.min_fee_bps;
+    }
+    let deviation_bps = (spot.abs_diff(twap).saturating_mul(FEE_DENOMINATOR as u128) / twap)
+        .min(VOLATILITY_CAP_BPS as u128);
+    let range = (band.max_fee_bps - band.min_fee_bps) as u128;
+
+    band.min_fee_bps + (range * deviation_bps / VOLATILITY_CAP_BPS as u128) as u16
+}
+
+// Helper function to calculate the fee a swap actually charges on `amount_in`:
+// the part the curve doesn't see, i.e. amount_in minus the rounded-down net
+fn calculate_swap_fee(amount_in: u64, fee_bps: u16) -> u64 {
+    let net =
+        amount_in as u128 * (FEE_DENOMINATOR - fee_bps as u64) as u128 / FEE_DENOMINATOR as u128;
+    amount_in - net as u64
+}
+
+// A swap fee shared out: `lp` stays in the vault as reserve, `protocol` is
+// accrued to protocol_fees_a/b for collection, and `referral` is paid to the
+// swap's referrer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FeeSplit {
+    pub lp: u64,
+    pub protocol: u64,
+    pub referral: u64,
+}
+
+impl FeeSplit {
+    pub fn total(&self) -> u64 {
+        self.lp + self.protocol + self.referral
+    }
+}
+
+// The fee a swap is charged and the cuts out of it, all in bps of the input.
+// Normally the pool's own (`Pool::fee_rates`), but a swap can be charged less,
+// e.g. with the LP holder discount or none at all, without it being written to
+// the pool.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FeeRates {
+    pub fee_bps: u16,
+    pub protocol_fee_bps: u16,
+    pub referral_fee_bps: u16,
+}
+
+// Helper function to split the fee a swap charges on `amount_in` at `fee_bps`.
+// The protocol and referral cuts are their bps of the input, rounded down, and
+// must fit inside the fee; the LPs get the rest, including the rounding.
+fn distribute_fee(
+    amount_in: u64,
+    fee_bps: u16,
+    protocol_fee_bps: u16,
+    referral_fee_bps: u16,
+) -> Result<FeeSplit> {
+    require!(
+        protocol_fee_bps as u32 + referral_fee_bps as u32 <= fee_bps as u32,
+        SwapError::InvalidFee
+    );
+    let total = calculate_swap_fee(amount_in, fee_bps);
+    let protocol = calculate_fee(amount_in, protocol_fee_bps)?;
+    let referral = calculate_fee(amount_in, referral_fee_bps)?;
+    Ok(FeeSplit {
+        lp: total - protocol - referral,
+        protocol,
+        referral,
+    })
+}
+
+// Helper function to give a pool's label as its last address seed. The
+// canonical pool's all-zero label becomes an empty seed, which leaves its
+// address as it was before pools had labels.
+fn label_seed(label: &[u8; 8]) -> &[u8] {
+    if *label == [0; 8] {
+        &[]
+    } else {
+        label
+    }
+}
+
+// Helper function to calculate a basis-point share of an amount, rounded down
+fn calculate_fee(amount: u64, fee_bps: u16) -> Result<u64> {
+    let fee = mul_div(
+        amount as u128,
+        fee_bps as u128,
+        FEE_DENOMINATOR as u128,
+        RoundingMode::Down,
+    )?;
+
+    u64::try_from(fee).map_err(|_| error!(SwapError::MathOverflow))
+}
+
+// Whether an instruction after the current one settles a flash loan or flash
+// swap for `pool`: one of this program's, starting with `discriminator`, with
+// the pool as its second account. Programs can't run code at the end of a
+// transaction, so the repayment must already be scheduled.
+fn repayment_scheduled(
+    instructions: &UncheckedAccount,
+    pool: &Pubkey,
+    discriminator: &[u8],
+) -> Result<bool> {
+    let instructions = instructions.to_account_info();
+    let mut index = load_current_index_checked(&instructions)? as usize + 1;
+    while let Ok(ix) = load_instruction_at_checked(index, &instructions) {
+        if ix.program_id == crate::ID
+            && ix.data.starts_with(discriminator)
+            && ix.accounts.get(1).map(|meta| &meta.pubkey) == Some(pool)
+        {
+            return Ok(true);
+        }
+        index += 1;
+    }
+    Ok(false)
+}
+
+// An LP mint's supply is either empty or includes the MINIMUM_LIQUIDITY that
+// the first deposit locked for good (plus anything sent to the locked account)
+fn lp_supply_consistent(supply: u64, locked: u64) -> bool {
+    if supply == 0 {
+        locked == 0
+    } else {
+        locked >= MINIMUM_LIQUIDITY && locked <= supply
+    }
+}
+
+// Whether an LP mint's authority has to be handed back to `pool`. Only the
+// current holder can give it back, so it must be the co-signer; a removed
+// authority (freeze_lp_mint) can't be restored by anyone and is left as is.
+fn lp_mint_needs_restore(
+    pool: &Pubkey,
+    mint_authority: Option<Pubkey>,
+    co_signer: Option<Pubkey>,
+) -> Result<bool> {
+    match mint_authority {
+        None => Ok(false),
+        Some(authority) if authority == *pool => Ok(false),
+        Some(authority) => {
+            require!(
+                co_signer == Some(authority),
+                SwapError::LpMintAuthorityMismatch
+            );
+            Ok(true)
+        }
+    }
+}
+
+// Helper function to calculate a flash loan fee, rounded up so that even the
+// smallest loans pay something
+fn calculate_flash_loan_fee(amount: u64, fee_bps: u16) -> Result<u64> {
+    let fee = mul_div(
+        amount as u128,
+        fee_bps as u128,
+        FEE_DENOMINATOR as u128,
+        RoundingMode::Up,
+    )?;
+
+    u64::try_from(fee).map_err(|_| error!(SwapError::MathOverflow))
+}
+
+// Helper function to calculate LP tokens owed for a deposit.
+// The first deposit mints sqrt(amount_a * amount_b) less MINIMUM_LIQUIDITY, which
+// add_liquidity locks in the pool; later deposits mint the
+// smaller of the two proportional shares so the pool ratio can't be gamed.
+fn calculate_lp_to_mint(
+    amount_a: u64,
+    amount_b: u64,
+    reserve_a: u64,
+    reserve_b: u64,
+    lp_supply: u64,
+) -> Result<u64> {
+    if lp_supply == 0 {
+        let product = (amount_a as u128)
+            .checked_mul(amount_b as u128)
+            .ok_or(SwapError::MathOverflow)?;
+        let liquidity = integer_sqrt(product);
+        require!(
+            liquidity > MINIMUM_LIQUIDITY,
+            SwapError::InsufficientInitialLiquidity
+        );
+        return Ok(liquidity - MINIMUM_LIQUIDITY);
+    }
+
+    let share_a = mul_div(
+        amount_a as u128,
+        lp_supply as u128,
+        reserve_a as u128,
+        RoundingMode::Down,
+    )?;
+    let share_b = mul_div(
+        amount_b as u128,
+        lp_supply as u128,
+        reserve_b as u128,
+        RoundingMode::Down,
+    )?;
+
+    u64::try_from(share_a.min(share_b)).map_err(|_| error!(SwapError::MathOverflow))
+}
+
+// Helper function to fit a deposit to the pool ratio. Empty pools take both
+// amounts as given; otherwise the scarcer side is used in full and the other is
+// scaled down to match, rounding up so the depositor covers the remainder.
+fn calculate_deposit_amounts(
+    max_amount_a: u64,
+    max_amount_b: u64,
+    reserve_a: u64,
+    reserve_b: u64,
+) -> Result<(u64, u64)> {
+    if reserve_a == 0 && reserve_b == 0 {
+        return Ok((max_amount_a, max_amount_b));
+    }
+
+    let optimal_b = mul_div(
+        max_amount_a as u128,
+        reserve_b as u128,
+        reserve_a as u128,
+        RoundingMode::Up,
+    )?;
+    if optimal_b <= max_amount_b as u128 {
+        return Ok((max_amount_a, optimal_b as u64));
+    }
+
+    let optimal_a = mul_div(
+        max_amount_b as u128,
+        reserve_a as u128,
+        reserve_b as u128,
+        RoundingMode::Up,
+    )?;
+    // optimal_b > max_amount_b means max_amount_a * reserve_b / reserve_a is
+    // strictly above max_amount_b, so optimal_a can't exceed max_amount_a
+    Ok((optimal_a as u64, max_amount_b))
+}
+
+// Helper function to calculate how much of a single-sided deposit `a` to swap
+// through a constant-product pool so that the rest of the deposit and the swap
+// output are in the ratio of the reserves after the swap. With `R` the
+// input-side reserve and `f` the fee fraction, solving
+// (a - s) / (R + s) = out(s) / (R_out - out(s)) for the swap amount `s` gives
+//
+//   s = (sqrt(R^2 (2 - f)^2 + 4 (1 - f) a R) - R (2 - f)) / (2 (1 - f))
+//
+// which in basis points, with D = FEE_DENOMINATOR and g = D - fee_bps, is
+//
+//   s = (sqrt(R^2 (D + g)^2 + 4 g D a R) - R (D + g)) / (2 g)
+//
+// `s` scales linearly with `a` and `R` together, so both are first shifted
+// down to 48 bits to keep the squares inside u128. The protocol fee's small
+// effect on the reserves is ignored.
+fn calculate_single_sided_swap(amount_in: u64, reserve_in: u64, fee_bps: u16) -> Result<u64> {
+    let shift = (64 - amount_in.max(reserve_in).leading_zeros()).saturating_sub(48);
+    let a = (amount_in >> shift) as u128;
+    let r = (reserve_in >> shift) as u128;
+    let d = FEE_DENOMINATOR as u128;
+    let g = d - fee_bps as u128;
+
+    let b = r * (d + g);
+    let discriminant = b
+        .checked_mul(b)
+        .and_then(|bb| bb.checked_add(4 * g * d * a * r))
+        .ok_or(SwapError::MathOverflow)?;
+    let swap_in = ((integer_sqrt(discriminant) as u128).saturating_sub(b) / (2 * g)) << shift;
+
+    // Never swap more than was deposited
+    Ok((swap_in as u64).min(amount_in))
+}
+
+// Helper function to calculate a reserve's share for a given LP amount, rounded
+// down
+fn calculate_withdraw_amount(lp_amount: u64, reserve: u64, lp_supply: u64) -> Result<u64> {
+    let amount = mul_div(
+        lp_amount as u128,
+        reserve as u128,
+        lp_supply as u128,
+        RoundingMode::Down,
+    )?;
+
+    u64::try_from(amount).map_err(|_| error!(SwapError::MathOverflow))
+}
+
+// Which way a division rounds. Every division that sets an amount moving
+// between a user and the pool rounds in the pool's favour: down for what the
+// pool pays out or mints, up for what it charges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RoundingMode {
+    Down,
+    Up,
+}
+
+// Helper function to calculate `a * b / c`, rounding as `rounding` says. The
+// product is taken at 256 bits, so only a quotient that doesn't fit overflows.
+fn mul_div(a: u128, b: u128, c: u128, rounding: RoundingMode) -> Result<u128> {
+    require!(c > 0, SwapError::MathOverflow);
+    if let Some(product) = a.checked_mul(b) {
+        return Ok(match rounding {
+            RoundingMode::Down => product / c,
+            RoundingMode::Up => product.div_ceil(c),
+        });
+    }
+
+    let (high, low) = wide_mul(a, b);
+    require!(high < c, SwapError::MathOverflow);
+    // Long division of the 256-bit product, one bit of `low` at a time. The
+    // remainder stays below `c`, so shifting it can carry out at most one bit.
+    let mut remainder = high;
+    let mut quotient = 0u128;
+    for i in (0..128).rev() {
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | ((low >> i) & 1);
+        quotient <<= 1;
+        if carry == 1 || remainder >= c {
+            remainder = remainder.wrapping_sub(c);
+            quotient |= 1;
+        }
+    }
+
+    match rounding {
+        RoundingMode::Up if remainder > 0 => quotient
+            .checked_add(1)
+            .ok_or(SwapError::MathOverflow.into()),
+        _ => Ok(quotient),
+    }
+}
+
+// Full 256-bit product of `a` and `b` as (high, low) halves
+fn wide_mul(a: u128, b: u128) -> (u128, u128) {
+    const MASK: u128 = u64::MAX as u128;
+    let (a_high, a_low) = (a >> 64, a & MASK);
+    let (b_high, b_low) = (b >> 64, b & MASK);
+
+    let low_low = a_low * b_low;
+    let high_low = a_high * b_low;
+    let low_high = a_low * b_high;
+    let cross = (low_low >> 64) + (high_low & MASK) + (low_high & MASK);
+
+    let low = (cross << 64) | (low_low & MASK);
+    let high = a_high * b_high + (high_low >> 64) + (low_high >> 64) + (cross >> 64);
+    (high, low)
+}
+
+// 1/sqrt(1.0001)^(2^i) as 64-bit fractions, for sqrt_price_at_tick
+const TICK_RATIOS: [u128; 18] = [
+    0xfffcb933bd6fad37,
+    0xfff97272373d4132,
+    0xfff2e50f5f656932,
+    0xffe5caca7e10e4e6,
+    0xffcb9843d60f6159,
+    0xff973b41fa98c081,
+    0xff2ea16466c96a38,
+    0xfe5dee046a99a2a8,
+    0xfcbe86c7900a88ae,
+    0xf987a7253ac41317,
+    0xf3392b0822b70005,
+    0xe7159475a2c29b74,
+    0xd097f3bdfd2022b8,
+    0xa9f746462d870fdf,
+    0x70d869a156d2a1b8,
+    0x31be135f97d08fd9,
+    0x09aa508b5b7a84e1,
+    0x005d6af8dedb8119,
+];
+
+// Helper function to calculate sqrt(1.0001^tick) as a Q64.64 number, the
+// square root of the price of token A in token B (raw units) at `tick`
+fn sqrt_price_at_tick(tick: i32) -> Result<u128> {
+    require!(
+        (MIN_TICK..=MAX_TICK).contains(&tick),
+        SwapError::InvalidTickRange
+    );
+
+    // Multiply up 1/sqrt(1.0001)^|tick| from its binary digits, then invert
+    // it for positive ticks
+    let mut ratio = Q64;
+    for (bit, factor) in TICK_RATIOS.iter().enumerate() {
+        if tick.unsigned_abs() & (1 << bit) != 0 {
+            ratio = (ratio * factor) >> 64;
+        }
+    }
+    Ok(if tick > 0 { u128::MAX / ratio } else { ratio })
+}
+
+// Helper function to find the highest tick whose sqrt price is at or below
+// `sqrt_price`, by bisection over the tick range
+fn tick_at_sqrt_price(sqrt_price: u128) -> Result<i32> {
+    let (mut low, mut high) = (MIN_TICK, MAX_TICK);
+    while low < high {
+        let mid = low + (high - low + 1) / 2;
+        if sqrt_price_at_tick(mid)? <= sqrt_price {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    Ok(low)
+}
+
+// Helper function to calculate the token A that `liquidity` holds between two
+// sqrt prices: L * (upper - lower) / (lower * upper)
+fn calculate_amount_a_delta(
+    sqrt_lower: u128,
+    sqrt_upper: u128,
+    liquidity: u128,
+    rounding: RoundingMode,
+) -> Result<u128> {
+    // L / sqrt(lower) first keeps both steps within one unit of rounding
+    let liquidity_over_lower = mul_div(liquidity, Q64, sqrt_lower, rounding)?;
+    mul_div(
+        liquidity_over_lower,
+        sqrt_upper - sqrt_lower,
+        sqrt_upper,
+        rounding,
+    )
+}
+
+// Helper function to calculate the token B that `liquidity` holds between two
+// sqrt prices: L * (upper - lower)
+fn calculate_amount_b_delta(
+    sqrt_lower: u128,
+    sqrt_upper: u128,
+    liquidity: u128,
+    rounding: RoundingMode,
+) -> Result<u128> {
+    mul_div(liquidity, sqrt_upper - sqrt_lower, Q64, rounding)
+}
+
+// Helper function to calculate where `amount_in` (after fees) moves the sqrt
+// price within a single range of `liquidity`. Token A in lowers the price to
+// L * P / (L + amount * P), token B in raises it by amount / L; both round
+// against the swapper.
+fn next_sqrt_price(
+    sqrt_price: u128,
+    liquidity: u128,
+    amount_in: u128,
+    a_to_b: bool,
+) -> Result<u128> {
+    if a_to_b {
+        let denominator = liquidity
+            .checked_add(mul_div(amount_in, sqrt_price, Q64, RoundingMode::Down)?)
+            .ok_or(SwapError::MathOverflow)?;
+        mul_div(liquidity, sqrt_price, denominator, RoundingMode::Up)
+    } else {
+        sqrt_price
+            .checked_add(mul_div(amount_in, Q64, liquidity, RoundingMode::Down)?)
+            .ok_or(SwapError::MathOverflow.into())
+    }
+}
+
+// Integer square root (rounded down) using the Babylonian method
+fn integer_sqrt(value: u128) -> u64 {
+    if value < 2 {
+        return value as u64;
+    }
+
+    // Start from a power of two at or above the root so the iteration only descends
+    let mut x = 1u128 << ((128 - value.leading_zeros()).div_ceil(2));
+    loop {
+        let y = (x + value / x) >> 1;
+        if y >= x {
+            return x as u64;
+        }
+        x = y;
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializeFactory<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + Factory::INIT_SPACE,
+        seeds = [b"factory"],
+        bump
+    )]
+    pub factory: Account<'info, Factory>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeMintWhitelist<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + MintWhitelist::INIT_SPACE,
+        seeds = [b"mint_whitelist"],
+        bump
+    )]
+    pub mint_whitelist: Account<'info, MintWhitelist>,
+
+    // The whitelist is program-wide, so only the program's upgrade authority
+    // may create it
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::SimpleSwap>,
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(authority.key())
+            @ SwapError::Unauthorized
+    )]
+    pub program_data: Account<'info, ProgramData>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMintWhitelist<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"mint_whitelist"],
+        bump = mint_whitelist.bump,
+        has_one = authority @ SwapError::Unauthorized
+    )]
+    pub mint_whitelist: Account<'info, MintWhitelist>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeGlobalConfig<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + GlobalConfig::INIT_SPACE,
+        seeds = [b"global_config"],
+        bump
+    )]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    // Like the mint whitelist, the config is program-wide and reserved for the
+    // program's upgrade authority
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::SimpleSwap>,
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(admin.key())
+            @ SwapError::Unauthorized
+    )]
+    pub program_data: Account<'info, ProgramData>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateGlobalConfig<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"global_config"],
+        bump = global_config.bump,
+        has_one = admin @ SwapError::Unauthorized
+    )]
+    pub global_config: Account<'info, GlobalConfig>,
+}
+
+#[derive(Accounts)]
+#[instruction(fee_tier: u16, label: [u8; 8])]
+pub struct InitializePool<'info> {
+    /// Pays the rent for the pool and its accounts, which lets a relayer
+    /// fund pool creation on the authority's behalf
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub authority: Signer<'info>,
+
+    #[account(mut, seeds = [b"factory"], bump = factory.bump)]
+    pub factory: Account<'info, Factory>,
+
+    /// CHECK: the mint whitelist PDA, which only restricts pool creation once
+    /// it has been created; read by `MintWhitelist::load`
+    #[account(seeds = [b"mint_whitelist"], bump)]
+    pub mint_whitelist: UncheckedAccount<'info>,
+
+    /// CHECK: the global config PDA, whose fee defaults apply once it has been
+    /// created; read by `GlobalConfig::load`
+    #[account(seeds = [b"global_config"], bump)]
+    pub global_config: UncheckedAccount<'info>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + Pool::INIT_SPACE,
+        seeds = [
+            b"pool",
+            mint_a.key().as_ref(),
+            mint_b.key().as_ref(),
+            &fee_tier.to_le_bytes(),
+            label_seed(&label),
+        ],
+        bump
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    pub mint_a: InterfaceAccount<'info, Mint>,
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = payer,
+        token::mint = mint_a,
+        token::authority = pool,
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = payer,
+        token::mint = mint_b,
+        token::authority = pool,
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = payer,
+        mint::decimals = LP_MINT_DECIMALS,
+        mint::authority = pool,
+        seeds = [b"lp_mint", pool.key().as_ref()],
+        bump
+    )]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = payer,
+        token::mint = lp_mint,
+        token::authority = pool,
+        seeds = [b"locked_lp", pool.key().as_ref()],
+        bump
+    )]
+    pub locked_lp: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> InitializePool<'info> {
+    // Validates the pool's settings and writes its initial state, shared by
+    // initialize_pool and initialize_pool_with_liquidity
+    #[allow(clippy::too_many_arguments)]
+    fn create(
+        &mut self,
+        bumps: &InitializePoolBumps,
+        fee_tier: u16,
+        label: [u8; 8],
+        fee_bps: Option<u16>,
+        protocol_fee_bps: Option<u16>,
+        referral_fee_bps: Option<u16>,
+        curve_type: Option<CurveType>,
+        fee_band: Option<FeeBand>,
+        initial_tick: Option<i32>,
+        tick_spacing: Option<u16>,
+        rebasing: Option<bool>,
+        oracle_enabled: Option<bool>,
+    ) -> Result<()> {
+        require_keys_neq!(
+            self.mint_a.key(),
+            self.mint_b.key(),
+            SwapError::IdenticalMints
+        );
+        require!(
+            self.mint_a.key() < self.mint_b.key(),
+            SwapError::MintsNotSorted
+        );
+
+        if let Some(whitelist) = MintWhitelist::load(&self.mint_whitelist)? {
+            if whitelist.enabled {
+                for mint in [self.mint_a.key(), self.mint_b.key()] {
+                    require!(whitelist.allows(&mint), SwapError::MintNotWhitelisted);
+                }
+            }
+        }
+
+        for decimals in [self.mint_a.decimals, self.mint_b.decimals] {
+            require!(supported_decimals(decimals), SwapError::UnsupportedDecimals);
+        }
+
+        require!(FEE_TIERS.contains(&fee_tier), SwapError::InvalidFeeTier);
+        let config = GlobalConfig::load(&self.global_config)?;
+        let default_fee_bps = config.as_ref().map_or(fee_tier, |c| c.default_fee_bps);
+        let fee_bps = fee_bps.unwrap_or(fee_band.map_or(default_fee_bps, |band| band.min_fee_bps));
+        require!(fee_bps <= MAX_FEE_BPS, SwapError::InvalidFee);
+        // The lowest fee the pool can charge, which the cuts below must fit in
+        let floor_fee_bps = match fee_band {
+            Some(band) => {
+                require!(
+                    band.min_fee_bps <= fee_bps && fee_bps <= band.max_fee_bps,
+                    SwapError::InvalidFee
+                );
+                require!(band.max_fee_bps <= MAX_FEE_BPS, SwapError::InvalidFee);
+                band.min_fee_bps
+            }
+            None => fee_bps,
+        };
+        let protocol_fee_bps =
+            protocol_fee_bps.unwrap_or(config.as_ref().map_or(0, |c| c.default_protocol_fee_bps));
+        require!(protocol_fee_bps <= floor_fee_bps, SwapError::InvalidFee);
+        // The protocol and referral cuts both come out of the swap fee
+        let referral_fee_bps = referral_fee_bps.unwrap_or(0);
+        require!(
+            referral_fee_bps <= floor_fee_bps - protocol_fee_bps,
+            SwapError::InvalidFee
+        );
+        let curve_type = curve_type.unwrap_or(CurveType::ConstantProduct);
+        if let CurveType::Stable { amp } = curve_type {
+            require!(amp > 0 && amp <= MAX_AMP, SwapError::InvalidAmp);
+        }
+        // Positions earn the whole LP fee through fee growth, which leaves no
+        // way to pay a referrer out of it
+        if curve_type == CurveType::Concentrated {
+            require!(referral_fee_bps == 0, SwapError::InvalidFee);
+        }
+        require!(
+            initial_tick.is_some() == (curve_type == CurveType::Concentrated),
+            SwapError::InvalidTickRange
+        );
+        let tick_spacing = if curve_type == CurveType::Concentrated {
+            let tick_spacing = tick_spacing.unwrap_or(TICK_SPACING as u16);
+            require!(tick_spacing > 0, SwapError::InvalidTickRange);
+            tick_spacing
+        } else {
+            require!(tick_spacing.is_none(), SwapError::InvalidTickRange);
+            0
+        };
+        // A concentrated pool's reserves follow its liquidity and price, so
+        // they can't simply be reset to the vaults
+        let rebasing = rebasing.unwrap_or(false);
+        require!(
+            !(rebasing && curve_type == CurveType::Concentrated),
+            SwapError::UnsupportedCurve
+        );
+        // A dynamic fee is priced off the TWAP
+        let oracle_enabled = oracle_enabled.unwrap_or(true);
+        require!(
+            oracle_enabled || fee_band.is_none(),
+            SwapError::OracleDisabled
+        );
+
+        let pool = &mut self.pool;
+        pool.authority = self.authority.key();
+        pool.creator = self.authority.key();
+        pool.fee_recipient = self.authority.key();
+        pool.mint_a = self.mint_a.key();
+        pool.mint_b = self.mint_b.key();
+        pool.lp_mint = self.lp_mint.key();
+        pool.decimals_a = self.mint_a.decimals;
+        pool.decimals_b = self.mint_b.decimals;
+        pool.fee_tier = fee_tier;
+        pool.label = label;
+        pool.fee_bps = fee_bps;
+        pool.protocol_fee_bps = protocol_fee_bps;
+        pool.referral_fee_bps = referral_fee_bps;
+        pool.fee_band = fee_band;
+        pool.curve_type = curve_type;
+        pool.rebasing = rebasing;
+        pool.oracle_enabled = oracle_enabled;
+        if let Some(tick) = initial_tick {
+            pool.sqrt_price = sqrt_price_at_tick(tick)?;
+            pool.tick_current = tick;
+        }
+        pool.tick_spacing = tick_spacing;
+        let now = Clock::get()?.unix_timestamp;
+        pool.created_at = now;
+        pool.last_update_ts = now;
+        pool.volatility_ts = now;
+        if oracle_enabled {
+            pool.write_observation(now);
+        }
+        pool.bump = bumps.pool;
+        pool.vault_a_bump = bumps.vault_a;
+        pool.vault_b_bump = bumps.vault_b;
+        pool.version = POOL_VERSION;
+
+        let factory = &mut self.factory;
+        let index = factory.pool_count;
+        factory.pool_count = index.checked_add(1).ok_or(SwapError::MathOverflow)?;
+
+        emit!(PoolCreated {
+            pool: pool.key(),
+            mint_a: pool.mint_a,
+            mint_b: pool.mint_b,
+            fee_tier,
+            index,
+        });
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializePoolWithLiquidity<'info> {
+    pub init: InitializePool<'info>,
+
+    #[account(mut)]
+    pub user_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = init.payer,
+        token::mint = init.lp_mint,
+        token::authority = init.authority,
+    )]
+    pub user_lp_token: InterfaceAccount<'info, TokenAccount>,
+
+    // The same programs as in `init`, which the LP account's creation needs
+    // at this level
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AddLiquidity<'info> {
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = mint_a,
+        has_one = mint_b
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    pub mint_a: InterfaceAccount<'info, Mint>,
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    #[account(mut)]
+    pub user_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump = pool.vault_a_bump,
+        token::mint = pool.mint_a,
+        token::authority = pool,
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump = pool.vault_b_bump,
+        token::mint = pool.mint_b,
+        token::authority = pool,
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"lp_mint", pool.key().as_ref()],
+        bump,
+        address = pool.lp_mint,
+    )]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"locked_lp", pool.key().as_ref()],
+        bump,
+    )]
+    pub locked_lp: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = lp_mint,
+    )]
+    pub user_lp_token: InterfaceAccount<'info, TokenAccount>,
+
+    // Only add_liquidity_single swaps, and so reads it
+    /// CHECK: the global config PDA, whose protocol fee switch applies once
+    /// it has been created; read by `GlobalConfig::protocol_fee_on`
+    #[account(seeds = [b"global_config"], bump)]
+    pub global_config: UncheckedAccount<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveLiquidity<'info> {
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = mint_a,
+        has_one = mint_b
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    pub mint_a: InterfaceAccount<'info, Mint>,
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    #[account(mut)]
+    pub user_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump = pool.vault_a_bump,
+        token::mint = pool.mint_a,
+        token::authority = pool,
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump = pool.vault_b_bump,
+        token::mint = pool.mint_b,
+        token::authority = pool,
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"lp_mint", pool.key().as_ref()],
+        bump,
+        address = pool.lp_mint,
+    )]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = lp_mint,
+        token::authority = user,
+    )]
+    pub user_lp_token: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(tick_lower: i32, tick_upper: i32)]
+pub struct OpenPosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = mint_a,
+        has_one = mint_b
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    pub mint_a: InterfaceAccount<'info, Mint>,
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    #[account(mut)]
+    pub user_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump = pool.vault_a_bump,
+        token::mint = pool.mint_a,
+        token::authority = pool,
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump = pool.vault_b_bump,
+        token::mint = pool.mint_b,
+        token::authority = pool,
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    // One position per owner and range
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + Position::INIT_SPACE,
+        seeds = [
+            b"position",
+            pool.key().as_ref(),
+            owner.key().as_ref(),
+            &tick_lower.to_le_bytes(),
+            &tick_upper.to_le_bytes(),
+        ],
+        bump
+    )]
+    pub position: Account<'info, Position>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = mint_a,
+        has_one = mint_b
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    pub mint_a: InterfaceAccount<'info, Mint>,
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    #[account(mut)]
+    pub user_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump = pool.vault_a_bump,
+        token::mint = pool.mint_a,
+        token::authority = pool,
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump = pool.vault_b_bump,
+        token::mint = pool.mint_b,
+        token::authority = pool,
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        close = owner,
+        seeds = [
+            b"position",
+            pool.key().as_ref(),
+            owner.key().as_ref(),
+            &position.tick_lower.to_le_bytes(),
+            &position.tick_upper.to_le_bytes(),
+        ],
+        bump = position.bump,
+        has_one = pool,
+        has_one = owner
+    )]
+    pub position: Account<'info, Position>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct Swap<'info> {
+    // Mutable so it can fund and reclaim a temporary wSOL account
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = mint_a,
+        has_one = mint_b
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    pub mint_a: InterfaceAccount<'info, Mint>,
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    // Either may be omitted when its mint is native SOL, in which case the
+    // swap wraps or unwraps through `wsol_account` instead
+    #[account(mut)]
+    pub user_token_a: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub user_token_b: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    // Vaults are pinned to this pool by seed, mint, and owner. The owner check
+    // repeats what token::authority enforces so a refactor can't drop it.
+    #[account(
+        mut,
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump = pool.vault_a_bump,
+        token::mint = pool.mint_a,
+        token::authority = pool,
+        constraint = vault_a.owner == pool.key() @ SwapError::InvalidVault,
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump = pool.vault_b_bump,
+        token::mint = pool.mint_b,
+        token::authority = pool,
+        constraint = vault_b.owner == pool.key() @ SwapError::InvalidVault,
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    // Optional integrator account, in the input token, that receives
+    // `pool.referral_fee_bps` of the input
+    #[account(mut)]
+    pub referrer: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// CHECK: the user's temporary wSOL account, PDA `["wsol", user]`. It only
+    /// exists for the duration of a native SOL swap; `open_wsol_account`
+    /// checks the address and creates it.
+    #[account(mut)]
+    pub wsol_account: Option<UncheckedAccount<'info>>,
+
+    // The user's last swap on this pool, required once the pool has a swap
+    // cooldown. Created on the user's first swap there.
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + LastSwap::INIT_SPACE,
+        seeds = [b"last_swap", pool.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub last_swap: Option<Account<'info, LastSwap>>,
+
+    // The user's LP tokens for this pool, passed to claim the pool's LP
+    // holder fee discount
+    #[account(
+        token::mint = pool.lp_mint,
+        token::authority = user,
+    )]
+    pub user_lp_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// CHECK: the global config PDA, whose protocol fee switch applies once
+    /// it has been created; read by `GlobalConfig::protocol_fee_on`
+    #[account(seeds = [b"global_config"], bump)]
+    pub global_config: UncheckedAccount<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Option<Program<'info, System>>,
+}
+
+impl<'info> Swap<'info> {
+    // The rates this swap is charged: the pool's own, with the fee lowered if
+    // the user's LP balance earns the LP holder discount. The pool keeps its
+    // stored fee.
+    fn fee_rates(&self) -> FeeRates {
+        let mut rates = self.pool.fee_rates();
+        if let Some(user_lp_token) = &self.user_lp_token {
+            rates.fee_bps = self.pool.discounted_fee_bps(user_lp_token.amount);
+        }
+        rates
+    }
+
+    // Enforces the pool's swap cooldown, if any, and records this swap as the
+    // user's latest. `bump` is the `last_swap` PDA's.
+    fn check_cooldown(&mut self, now: i64, bump: Option<u8>) -> Result<()> {
+        let interval = self.pool.min_swap_interval;
+        let Some(last_swap) = self.last_swap.as_mut() else {
+            return if interval == 0 {
+                Ok(())
+            } else {
+                err!(SwapError::LastSwapAccountMissing)
+            };
+        };
+        if let Some(bump) = bump {
+            last_swap.bump = bump;
+        }
+        last_swap.record(now, interval)
+    }
+
+    // strict-checks: re-reads the vaults after the transfers and checks the
+    // pool's accounting against them
+    #[cfg(feature = "strict-checks")]
+    fn assert_invariant(&mut self, invariant_before: u128) -> Result<()> {
+        self.vault_a.reload()?;
+        self.vault_b.reload()?;
+        self.pool.assert_invariant(
+            Some(invariant_before),
+            self.vault_a.amount,
+            self.vault_b.amount,
+        )
+    }
+
+    // Everything after the pre-trade checks and lock: takes the input, pays out
+    // at `rates`, updates the reserves and releases the lock. Returns the
+    // output amount.
+    fn execute(
+        &mut self,
+        amount_in: u64,
+        minimum_amount_out: u64,
+        a_to_b: bool,
+        max_price_impact_bps: Option<u16>,
+        rates: FeeRates,
+    ) -> Result<u64> {
+        #[cfg(feature = "strict-checks")]
+        let invariant_before = self.pool.invariant()?;
+
+        self.pool.check_input_after_fee_at(amount_in, rates)?;
+        // Take the input first and price only what the vault actually received
+        let amount_in = self.transfer_in(amount_in, a_to_b)?;
+
+        // Calculate output amount using constant product formula (x * y = k).
+        // Pricing uses the stored reserves so tokens donated straight to a
+        // vault can't move the curve.
+        let amount_out = self.pool.swap_output_at(amount_in, a_to_b, rates)?;
+
+        // Taking the input for nothing would be a donation, whatever the
+        // caller's slippage setting
+        require!(amount_out > 0, SwapError::ZeroOutput);
+        require!(amount_out >= minimum_amount_out, SwapError::SlippageTooHigh);
+
+        if let Some(max_price_impact_bps) = max_price_impact_bps {
+            let price_impact_bps = self.pool.price_impact_bps(amount_in, amount_out, a_to_b)?;
+            require!(
+                price_impact_bps <= max_price_impact_bps as u64,
+                SwapError::PriceImpactTooHigh
+            );
+        }
+
+        self.transfer_out(amount_out, a_to_b)?;
+
+        // A referrer's cut is paid out of the fee, not the user's output
+        let protocol_fee_on = GlobalConfig::protocol_fee_on(&self.global_config)?;
+        let fees =
+            self.pool
+                .fee_split_at(amount_in, rates, protocol_fee_on, self.referrer.is_some())?;
+        self.pay_referrer(fees.referral, a_to_b)?;
+
+        // Update stored reserves
+        self.pool.apply_swap(amount_in, amount_out, a_to_b, fees)?;
+        #[cfg(feature = "strict-checks")]
+        self.assert_invariant(invariant_before)?;
+        self.pool.record_k();
+        self.pool.unlock();
+        let pool = &self.pool;
+
+        emit!(SwapExecuted {
+            pool: pool.key(),
+            user: self.user.key(),
+            mint_a: pool.mint_a,
+            mint_b: pool.mint_b,
+            amount_in,
+            amount_out,
+            a_to_b,
+            reserve_a: pool.reserve_a,
+            reserve_b: pool.reserve_b,
+        });
+
+        Ok(amount_out)
+    }
+
+    // Moves `amount` of the input token from the user into its vault and returns
+    // how much the vault actually received
+    fn transfer_in(&mut self, amount: u64, a_to_b: bool) -> Result<u64> {
+        // simulate: price and book the swap as if the full input arrived, but
+        // leave every token where it is
+        if cfg!(feature = "simulate") {
+            msg!("Simulated swap: no tokens transferred");
+            return Ok(amount);
+        }
+
+        let (user_in, vault_in, mint_in) = if a_to_b {
+            (&self.user_token_a, &mut self.vault_a, &self.mint_a)
+        } else {
+            (&self.user_token_b, &mut self.vault_b, &self.mint_b)
+        };
+
+        // No token account for the input: wrap that much of the user's SOL in
+        // a temporary account and pay from there
+        let Some(user_in) = user_in else {
+            let (wsol_account, system_program) =
+                native_sol_accounts(mint_in, &self.wsol_account, &self.system_program)?;
+            open_wsol_account(
+                &self.user,
+                wsol_account,
+                mint_in,
+                &self.token_program,
+                system_program,
+                amount,
+            )?;
+            let received = transfer_tokens_received(
+                &self.token_program,
+                wsol_account,
+                vault_in,
+                mint_in,
+                self.user.to_account_info(),
+                &[],
+                amount,
+            )?;
+            close_wsol_account(&self.user, wsol_account, &self.token_program)?;
+            return Ok(received);
+        };
+
+        // Fail with a clear error rather than the token program's own
+        require!(user_in.amount >= amount, SwapError::InsufficientUserBalance);
+        transfer_tokens_received(
+            &self.token_program,
+            user_in,
+            vault_in,
+            mint_in,
+            self.user.to_account_info(),
+            &[],
+            amount,
+        )
+    }
+
+    // Moves `amount` of the output token from its vault to the user
+    fn transfer_out(&self, amount: u64, a_to_b: bool) -> Result<()> {
+        if cfg!(feature = "simulate") {
+            return Ok(());
+        }
+
+        let (vault_out, user_out, mint_out) = if a_to_b {
+            (&self.vault_b, &self.user_token_b, &self.mint_b)
+        } else {
+            (&self.vault_a, &self.user_token_a, &self.mint_a)
+        };
+
+        let seeds = &[
+            b"pool",
+            self.pool.mint_a.as_ref(),
+            self.pool.mint_b.as_ref(),
+            &self.pool.fee_tier.to_le_bytes(),
+            self.pool.label_seed(),
+            &[self.pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        // No token account for the output: pay into a temporary wSOL account
+        // and close it so the user receives lamports
+        let Some(user_out) = user_out else {
+            let (wsol_account, system_program) =
+                native_sol_accounts(mint_out, &self.wsol_account, &self.system_program)?;
+            open_wsol_account(
+                &self.user,
+                wsol_account,
+                mint_out,
+                &self.token_program,
+                system_program,
+                0,
+            )?;
+            transfer_tokens(
+                &self.token_program,
+                vault_out,
+                wsol_account,
+                mint_out,
+                self.pool.to_account_info(),
+                signer,
+                amount,
+            )?;
+            return close_wsol_account(&self.user, wsol_account, &self.token_program);
+        };
+
+        transfer_tokens(
+            &self.token_program,
+            vault_out,
+            user_out,
+            mint_out,
+            self.pool.to_account_info(),
+            signer,
+            amount,
+        )
+    }
+
+    // Moves `amount` of the input token from its vault to the referrer
+    fn pay_referrer(&self, amount: u64, a_to_b: bool) -> Result<()> {
+        let Some(referrer) = &self.referrer else {
+            return Ok(());
+        };
+        let (vault_in, mint_in) = if a_to_b {
+            (&self.vault_a, &self.mint_a)
+        } else {
+            (&self.vault_b, &self.mint_b)
+        };
+        require_keys_eq!(referrer.mint, mint_in.key(), SwapError::InvalidReferrer);
+        if amount == 0 || cfg!(feature = "simulate") {
+            return Ok(());
+        }
+
+        let seeds = &[
+            b"pool",
+            self.pool.mint_a.as_ref(),
+            self.pool.mint_b.as_ref(),
+            &self.pool.fee_tier.to_le_bytes(),
+            self.pool.label_seed(),
+            &[self.pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        transfer_tokens(
+            &self.token_program,
+            vault_in,
+            referrer,
+            mint_in,
+            self.pool.to_account_info(),
+            signer,
+            amount,
+        )
+    }
+}
+
+#[derive(Accounts)]
+pub struct SwapTwoHop<'info> {
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool_1.mint_a.as_ref(),
+            pool_1.mint_b.as_ref(),
+            &pool_1.fee_tier.to_le_bytes(),
+            pool_1.label_seed(),
+        ],
+        bump = pool_1.bump
+    )]
+    pub pool_1: Box<Account<'info, Pool>>,
+
+    // Each leg books its swap on its own copy of the pool, so one pool passed
+    // twice would keep only the second leg's booking
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool_2.mint_a.as_ref(),
+            pool_2.mint_b.as_ref(),
+            &pool_2.fee_tier.to_le_bytes(),
+            pool_2.label_seed(),
+        ],
+        bump = pool_2.bump,
+        constraint = pool_2.key() != pool_1.key() @ SwapError::RepeatedPool
+    )]
+    pub pool_2: Box<Account<'info, Pool>>,
+
+    pub mint_in: Box<InterfaceAccount<'info, Mint>>,
+    pub mint_mid: Box<InterfaceAccount<'info, Mint>>,
+    pub mint_out: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(mut, token::mint = mint_in)]
+    pub user_token_in: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        token::mint = mint_mid,
+        token::authority = user,
+    )]
+    pub user_token_mid: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut, token::mint = mint_out)]
+    pub user_token_out: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    // Vault addresses depend on the direction of each leg, so they are checked
+    // in `first_leg_direction` / `second_leg_direction`
+    #[account(mut)]
+    pub pool_1_vault_in: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub pool_1_vault_out: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub pool_2_vault_in: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub pool_2_vault_out: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    /// CHECK: the global config PDA, whose protocol fee switch applies once
+    /// it has been created; read by `GlobalConfig::protocol_fee_on`
+    #[account(seeds = [b"global_config"], bump)]
+    pub global_config: UncheckedAccount<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+impl<'info> SwapTwoHop<'info> {
+    // Direction of the in -> mid leg through pool_1, checking its vaults
+    fn first_leg_direction(&self) -> Result<bool> {
+        leg_direction(
+            &self.pool_1,
+            self.mint_in.key(),
+            self.mint_mid.key(),
+            self.pool_1_vault_in.key(),
+            self.pool_1_vault_out.key(),
+        )
+    }
+
+    // Direction of the mid -> out leg through pool_2, checking its vaults
+    fn second_leg_direction(&self) -> Result<bool> {
+        leg_direction(
+            &self.pool_2,
+            self.mint_mid.key(),
+            self.mint_out.key(),
+            self.pool_2_vault_in.key(),
+            self.pool_2_vault_out.key(),
+        )
+    }
+
+    // Whether every transfer of the route arrives in full. The SPL Token
+    // program has no transfer fees; Token-2022 mints may, so those hops
+    // measure what they received.
+    fn exact_hops_supported(&self) -> bool {
+        self.token_program.key() == spl_token::ID
+    }
+}
+
+// Legs are passed as remaining accounts; see batch_swap for the layout
+#[derive(Accounts)]
+pub struct BatchSwap<'info> {
+    pub user: Signer<'info>,
+
+    /// CHECK: the global config PDA, whose protocol fee switch applies once
+    /// it has been created; read by `GlobalConfig::protocol_fee_on`
+    #[account(seeds = [b"global_config"], bump)]
+    pub global_config: UncheckedAccount<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+// Deposits are passed as remaining accounts; see batch_add_liquidity for the
+// layout
+#[derive(Accounts)]
+pub struct BatchAddLiquidity<'info> {
+    pub user: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+// Helper function to rebuild the addresses of `pool`'s vaults from the bumps
+// stored at creation, without searching for them
+fn pool_vaults(pool: &Account<Pool>) -> Result<(Pubkey, Pubkey)> {
+    let pool_key = pool.key();
+    let vault = |seed: &[u8], bump: u8| {
+        Pubkey::create_program_address(&[seed, pool_key.as_ref(), &[bump]], &crate::ID)
+            .map_err(|_| error!(SwapError::InvalidVault))
+    };
+    Ok((
+        vault(b"vault_a", pool.vault_a_bump)?,
+        vault(b"vault_b", pool.vault_b_bump)?,
+    ))
+}
+
+// Helper function to work out which way a swap crosses `pool` and to check the
+// vaults passed for it are the pool's own
+fn leg_direction(
+    pool: &Account<Pool>,
+    mint_in: Pubkey,
+    mint_out: Pubkey,
+    vault_in: Pubkey,
+    vault_out: Pubkey,
+) -> Result<bool> {
+    let a_to_b = if (pool.mint_a, pool.mint_b) == (mint_in, mint_out) {
+        true
+    } else if (pool.mint_b, pool.mint_a) == (mint_in, mint_out) {
+        false
+    } else {
+        return err!(SwapError::InvalidPath);
+    };
+
+    let (vault_a, vault_b) = pool_vaults(pool)?;
+    let (expected_in, expected_out) = if a_to_b {
+        (vault_a, vault_b)
+    } else {
+        (vault_b, vault_a)
+    };
+    require_keys_eq!(vault_in, expected_in, SwapError::InvalidVault);
+    require_keys_eq!(vault_out, expected_out, SwapError::InvalidVault);
+
+    Ok(a_to_b)
+}
+
+#[derive(Accounts)]
+pub struct FlashLoan<'info> {
+    pub borrower: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = mint_a,
+        has_one = mint_b
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub mint_a: InterfaceAccount<'info, Mint>,
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    #[account(mut)]
+    pub borrower_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub borrower_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump = pool.vault_a_bump,
+        token::mint = pool.mint_a,
+        token::authority = pool,
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump = pool.vault_b_bump,
+        token::mint = pool.mint_b,
+        token::authority = pool,
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: the instructions sysvar, pinned by address
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+impl<'info> FlashLoan<'info> {
+    // Moves `amount` from the borrower into the loaned token's vault and returns
+    // how much the vault actually received
+    fn transfer_in(&mut self, amount: u64, a_token: bool) -> Result<u64> {
+        let (borrower_token, vault, mint) = if a_token {
+            (&self.borrower_token_a, &mut self.vault_a, &self.mint_a)
+        } else {
+            (&self.borrower_token_b, &mut self.vault_b, &self.mint_b)
+        };
+
+        transfer_tokens_received(
+            &self.token_program,
+            borrower_token,
+            vault,
+            mint,
+            self.borrower.to_account_info(),
+            &[],
+            amount,
+        )
+    }
+
+    // Moves `amount` of the loaned token from its vault to the borrower
+    fn transfer_out(&self, amount: u64, a_token: bool) -> Result<()> {
+        let (vault, borrower_token, mint) = if a_token {
+            (&self.vault_a, &self.borrower_token_a, &self.mint_a)
+        } else {
+            (&self.vault_b, &self.borrower_token_b, &self.mint_b)
+        };
+
+        let seeds = &[
+            b"pool",
+            self.pool.mint_a.as_ref(),
+            self.pool.mint_b.as_ref(),
+            &self.pool.fee_tier.to_le_bytes(),
+            self.pool.label_seed(),
+            &[self.pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        transfer_tokens(
+            &self.token_program,
+            vault,
+            borrower_token,
+            mint,
+            self.pool.to_account_info(),
+            signer,
+            amount,
+        )
+    }
+}
+
+#[derive(Accounts)]
+pub struct UpdatePool<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = authority @ SwapError::Unauthorized
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct CreateLpMetadata<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = authority @ SwapError::Unauthorized
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(address = pool.lp_mint)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: created and validated by the token metadata program
+    #[account(
+        mut,
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), lp_mint.key().as_ref()],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub metadata: UncheckedAccount<'info>,
+
+    pub token_metadata_program: Program<'info, Metadata>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct FreezeLpMint<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = authority @ SwapError::Unauthorized
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut, address = pool.lp_mint)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct EnsureLpMint<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = authority @ SwapError::Unauthorized
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut, address = pool.lp_mint)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(seeds = [b"locked_lp", pool.key().as_ref()], bump)]
+    pub locked_lp: InterfaceAccount<'info, TokenAccount>,
+
+    /// Whoever holds the LP mint's authority, if not the pool; signs it back
+    pub current_mint_authority: Option<Signer<'info>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct MigratePool<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    /// CHECK: a pool that may still have an older layout, which `Account<Pool>`
+    /// could not load; `migrate_pool` checks its discriminator and authority
+    #[account(mut, owner = crate::ID)]
+    pub pool: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
+    pub pending_authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        constraint = pool.pending_authority == Some(pending_authority.key())
+            @ SwapError::NotPendingAuthority
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct Skim<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = mint_a,
+        has_one = mint_b
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub mint_a: InterfaceAccount<'info, Mint>,
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump = pool.vault_a_bump,
+        token::mint = pool.mint_a,
+        token::authority = pool,
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump = pool.vault_b_bump,
+        token::mint = pool.mint_b,
+        token::authority = pool,
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = pool.mint_a)]
+    pub recipient_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = pool.mint_b)]
+    pub recipient_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct Donate<'info> {
+    pub donor: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = mint_a,
+        has_one = mint_b
+    )]
+    pub pool: Box<Account<'info, Pool>>,
+
+    pub mint_a: InterfaceAccount<'info, Mint>,
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    #[account(mut)]
+    pub donor_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub donor_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump = pool.vault_a_bump,
+        token::mint = pool.mint_a,
+        token::authority = pool,
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump = pool.vault_b_bump,
+        token::mint = pool.mint_b,
+        token::authority = pool,
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.lp_mint)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SyncReserves<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = authority @ SwapError::Unauthorized
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump = pool.vault_a_bump,
+        token::mint = pool.mint_a,
+        token::authority = pool,
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump = pool.vault_b_bump,
+        token::mint = pool.mint_b,
+        token::authority = pool,
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct SyncRebase<'info> {
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump = pool.vault_a_bump,
+        token::mint = pool.mint_a,
+        token::authority = pool,
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump = pool.vault_b_bump,
+        token::mint = pool.mint_b,
+        token::authority = pool,
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePool<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = authority @ SwapError::Unauthorized,
+        has_one = mint_a,
+        has_one = mint_b,
+        close = authority
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub mint_a: InterfaceAccount<'info, Mint>,
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump = pool.vault_a_bump,
+        token::authority = pool,
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump = pool.vault_b_bump,
+        token::authority = pool,
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    // Receive what is left in the vaults
+    #[account(
+        mut,
+        token::mint = pool.mint_a,
+        token::authority = authority,
+    )]
+    pub treasury_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = pool.mint_b,
+        token::authority = authority,
+    )]
+    pub treasury_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"lp_mint", pool.key().as_ref()],
+        bump,
+        address = pool.lp_mint,
+    )]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"locked_lp", pool.key().as_ref()],
+        bump,
+    )]
+    pub locked_lp: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+// Accounts for the authority paying the accrued protocol fees out to the pool's
+// fee recipient
+#[derive(Accounts)]
+pub struct CollectProtocolFees<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = authority @ SwapError::Unauthorized,
+        has_one = mint_a,
+        has_one = mint_b
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub mint_a: InterfaceAccount<'info, Mint>,
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump = pool.vault_a_bump,
+        token::mint = pool.mint_a,
+        token::authority = pool,
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump = pool.vault_b_bump,
+        token::mint = pool.mint_b,
+        token::authority = pool,
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = pool.mint_a,
+        token::authority = pool.fee_recipient,
+    )]
+    pub treasury_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = pool.mint_b,
+        token::authority = pool.fee_recipient,
+    )]
+    pub treasury_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+// Accounts for the authority moving tokens out of the vaults to its own token
+// accounts during an incident
+#[derive(Accounts)]
+pub struct AuthorityWithdraw<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = authority @ SwapError::Unauthorized,
+        has_one = mint_a,
+        has_one = mint_b
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub mint_a: InterfaceAccount<'info, Mint>,
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_a", pool.key().as_ref()],
+        bump = pool.vault_a_bump,
+        token::mint = pool.mint_a,
+        token::authority = pool,
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_b", pool.key().as_ref()],
+        bump = pool.vault_b_bump,
+        token::mint = pool.mint_b,
+        token::authority = pool,
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = pool.mint_a,
+        token::authority = authority,
+    )]
+    pub treasury_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = pool.mint_b,
+        token::authority = authority,
+    )]
+    pub treasury_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct RecoverForeignToken<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump,
+        has_one = authority @ SwapError::Unauthorized
+    )]
+    pub pool: Account<'info, Pool>,
+
+    // Anything the pool trades or mints is off limits: its vaults hold the
+    // reserves and locked_lp the locked liquidity
+    #[account(
+        constraint = ![pool.mint_a, pool.mint_b, pool.lp_mint].contains(&mint.key())
+            @ SwapError::NotForeignMint
+    )]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = pool,
+    )]
+    pub source: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = mint)]
+    pub destination: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct GetSpotPrice<'info> {
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct GetReserves<'info> {
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(address = pool.lp_mint)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct GetOracleObservation<'info> {
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct Consult<'info> {
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteSwap<'info> {
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            pool.label_seed(),
+        ],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+// Pools are passed as remaining accounts
+#[derive(Accounts)]
+pub struct QuotePath {}
+
+// Pricing curve a pool is created with
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub enum CurveType {
+    // x * y = k
+    ConstantProduct,
+    // Curve's StableSwap invariant; higher `amp` keeps prices flatter near parity
+    Stable { amp: u64 },
+    // Uniswap V3 style: liquidity is supplied by positions over tick ranges,
+    // and only the positions in range at the current price trade and earn fees
+    Concentrated,
+}
+
+// Range a dynamic fee moves in, in basis points
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub struct FeeBand {
+    pub min_fee_bps: u16,
+    pub max_fee_bps: u16,
+}
+
+// One batch_swap leg; the direction follows from the leg's mints
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchLeg {
+    pub amount_in: u64,
+    pub minimum_amount_out: u64,
+}
+
+// One batch_add_liquidity deposit; the same limits add_liquidity takes
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchDeposit {
+    pub max_amount_a: u64,
+    pub max_amount_b: u64,
+    pub min_lp_out: u64,
+}
+
+// Price accumulators as of `timestamp`
+#[derive(
+    AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq, InitSpace,
+)]
+pub struct Observation {
+    pub timestamp: i64,
+    pub price_cumulative_a: u128,
+    pub price_cumulative_b: u128,
+}
+
+// Time-weighted average prices returned by consult, scaled by PRICE_SCALE
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Twap {
+    pub price_a_in_b: u128,
+    pub price_b_in_a: u128,
+}
+
+// An initialized tick of a concentrated pool
+#[derive(
+    AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq, InitSpace,
+)]
+pub struct Tick {
+    pub index: i32,
+    pub liquidity_net: i128, // added to the pool's liquidity when crossed upwards
+    pub liquidity_gross: u128, // liquidity of all positions that end here
+    // Fee growth on the other side of this tick from the current price
+    pub fee_growth_outside_a: u128,
+    pub fee_growth_outside_b: u128,
+}
+
+// Program-wide registry; `pool_count` doubles as a cursor for indexers
+#[account]
+#[derive(InitSpace)]
+pub struct Factory {
+    pub pool_count: u64,
+    pub bump: u8,
+}
+
+// Liquidity a user provides to a concentrated pool over [tick_lower, tick_upper)
+#[account]
+#[derive(InitSpace)]
+pub struct Position {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    pub liquidity: u128,
+    // Fee growth inside the range when the position was opened
+    pub fee_growth_inside_a: u128,
+    pub fee_growth_inside_b: u128,
+    pub bump: u8,
+}
+
+// Mints approved for new pools in a permissioned deployment. Optional: while
+// it doesn't exist, or is disabled, any pair can form a pool.
+#[account]
+#[derive(InitSpace)]
+pub struct MintWhitelist {
+    pub authority: Pubkey,
+    pub enabled: bool,
+    #[max_len(MAX_WHITELISTED_MINTS)]
+    pub mints: Vec<Pubkey>,
+    pub bump: u8,
+}
+
+impl MintWhitelist {
+    // The whitelist behind `account`, or None if it was never created
+    pub fn load(account: &AccountInfo) -> Result<Option<Self>> {
+        if account.owner != &crate::ID || account.data_is_empty() {
+            return Ok(None);
+        }
+        let data = account.try_borrow_data()?;
+        Ok(Some(Self::try_deserialize(&mut &data[..])?))
+    }
+
+    pub fn allows(&self, mint: &Pubkey) -> bool {
+        self.mints.contains(mint)
+    }
+
+    pub fn add(&mut self, mint: Pubkey) -> Result<()> {
+        if self.allows(&mint) {
+            return Ok(());
+        }
+        require!(
+            self.mints.len() < MAX_WHITELISTED_MINTS,
+            SwapError::WhitelistFull
+        );
+        self.mints.push(mint);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, mint: Pubkey) {
+        self.mints.retain(|m| m != &mint);
+    }
+}
+
+// When a user last swapped on a pool, for pools with a swap cooldown
+#[account]
+#[derive(InitSpace)]
+pub struct LastSwap {
+    pub timestamp: i64,
+    pub bump: u8,
+}
+
+impl LastSwap {
+    // Records a swap at `now`, failing if it comes less than `interval`
+    // seconds after the previous one. A new record (timestamp 0) has no
+    // previous swap.
+    pub fn record(&mut self, now: i64, interval: i64) -> Result<()> {
+        require!(
+            self.timestamp == 0 || now >= self.timestamp.saturating_add(interval),
+            SwapError::SwapTooSoon
+        );
+        self.timestamp = now;
+        Ok(())
+    }
+}
+
+// Program-wide fee defaults for new pools. Optional: while it doesn't exist,
+// pools default to their fee tier and no protocol fee.
+#[account]
+#[derive(InitSpace)]
+pub struct GlobalConfig {
+    pub admin: Pubkey,
+    pub default_fee_bps: u16,
+    pub default_protocol_fee_bps: u16,
+    pub protocol_fee_on: bool, // off: every pool's full swap fee goes to its LPs
+    pub bump: u8,
+}
+
+impl GlobalConfig {
+    // The config behind `account`, or None if it was never created
+    pub fn load(account: &AccountInfo) -> Result<Option<Self>> {
+        if account.owner != &crate::ID || account.data_is_empty() {
+            return Ok(None);
+        }
+        let data = account.try_borrow_data()?;
+        Ok(Some(Self::try_deserialize(&mut &data[..])?))
+    }
+
+    // Whether swaps accrue protocol fees: the config's switch, or on while the
+    // config doesn't exist
+    pub fn protocol_fee_on(account: &AccountInfo) -> Result<bool> {
+        Ok(Self::load(account)?.is_none_or(|config| config.protocol_fee_on))
+    }
+
+    // Same limits initialize_pool puts on explicit fees. The default fee must
+    // also be one of the tiers, so a pool that takes it charges a tier's fee.
+    pub fn set_defaults(&mut self, fee_bps: u16, protocol_fee_bps: u16) -> Result<()> {
+        require!(FEE_TIERS.contains(&fee_bps), SwapError::InvalidFeeTier);
+        require!(protocol_fee_bps <= fee_bps, SwapError::InvalidFee);
+        self.default_fee_bps = fee_bps;
+        self.default_protocol_fee_bps = protocol_fee_bps;
+        Ok(())
+    }
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Pool {
+    pub authority: Pubkey,
+    pub pending_authority: Option<Pubkey>,
+    pub creator: Pubkey,       // authority at creation; never changes
+    pub fee_recipient: Pubkey, // owns the accounts protocol fees are collected to
+    pub created_at: i64,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    pub lp_mint: Pubkey,
+    pub decimals_a: u8,
+    pub decimals_b: u8,
+    pub fee_tier: u16, // part of the pool's address; fee_bps may move away from it
+    pub fee_bps: u16,
+    pub protocol_fee_bps: u16,
+    pub referral_fee_bps: u16,
+    pub pending_fee_bps: u16,
+    pub fee_effective_ts: i64, // when pending_fee_bps takes over, 0 if none
+    pub fee_band: Option<FeeBand>, // set for pools whose fee follows volatility
+    pub curve_type: CurveType,
+    pub rebasing: bool, // holds a token whose balances change out of band
+    pub protocol_fees_a: u64,
+    pub protocol_fees_b: u64,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+    pub last_invariant_k: u128, // reserve_a * reserve_b after the last change to either
+    pub is_paused: bool,
+    pub locked: bool, // set for the duration of swap and liquidity operations
+    pub flash_loan_active: bool,
+    pub flash_loan_a_token: bool,
+    pub flash_loan_amount: u64,
+    pub oracle_enabled: bool, // whether the TWAP accumulators below are kept
+    pub price_cumulative_a: u128,
+    pub price_cumulative_b: u128,
+    pub cumulative_volume_a: u128,
+    pub cumulative_volume_b: u128,
+    pub cumulative_fees: u128,
+    pub last_update_ts: i64,
+    // price_cumulative_a and time at the start of the current volatility window
+    pub volatility_cumulative: u128,
+    pub volatility_ts: i64,
+    // Ring buffer of past accumulator values, newest at observation_index
+    pub observations: [Observation; OBSERVATION_COUNT],
+    pub observation_index: u8,
+    pub observation_count: u8,
+    // Concentrated pools only: the current sqrt price (Q64.64) and its tick, the
+    // liquidity in range there, and fee growth per unit of liquidity (Q64.64)
+    pub sqrt_price: u128,
+    pub tick_current: i32,
+    pub liquidity: u128,
+    pub fee_growth_global_a: u128,
+    pub fee_growth_global_b: u128,
+    pub position_fees_a: u64, // earned by positions and not yet paid out
+    pub position_fees_b: u64,
+    #[max_len(MAX_TICKS)]
+    pub ticks: Vec<Tick>, // initialized ticks, sorted by index
+    pub min_swap_interval: i64, // seconds between one user's swaps, 0 if unlimited
+    pub liquidity_cap: u64,     // most LP tokens that may exist, 0 if uncapped
+    pub min_reserve: u64,       // floor a swap may not take either reserve below
+    pub lp_discount_threshold: u64, // LP balance that earns the fee discount, 0 if none
+    pub lp_discount_bps: u16,   // share of fee_bps waived for those holders
+    pub bump: u8,
+    pub vault_a_bump: u8,
+    pub vault_b_bump: u8,
+    pub version: u8,         // layout version, see POOL_VERSION
+    pub flash_swap: bool,    // the active flash loan is a flash swap's output
+    pub label: [u8; 8],      // part of the address of an isolated pool, zero otherwise
+    pub compute_guard: bool, // swaps check the compute left against estimated_swap_compute
+    pub max_swap_in: u64,    // largest input a single swap may take, 0 if uncapped
+    pub max_swap_out: u64,   // largest output a single swap may pay, 0 if uncapped
+    pub tick_spacing: u16,   // positions' ticks are multiples of it, 0 unless concentrated
+}
+
+// `Pool`'s fields up to and including `ticks`, its last variable-length field.
+// New fields are only ever appended after these, so this part of the layout is
+// the same in every version; clear_pool_slack reads it to find where an old
+// record ends. Only its length is used, never the values.
+#[allow(dead_code)]
+#[derive(AnchorDeserialize, InitSpace)]
+struct PoolHead {
+    authority: Pubkey,
+    pending_authority: Option<Pubkey>,
+    creator: Pubkey,
+    fee_recipient: Pubkey,
+    created_at: i64,
+    mint_a: Pubkey,
+    mint_b: Pubkey,
+    lp_mint: Pubkey,
+    decimals_a: u8,
+    decimals_b: u8,
+    fee_tier: u16,
+    fee_bps: u16,
+    protocol_fee_bps: u16,
+    referral_fee_bps: u16,
+    pending_fee_bps: u16,
+    fee_effective_ts: i64,
+    fee_band: Option<FeeBand>,
+    curve_type: CurveType,
+    rebasing: bool,
+    protocol_fees_a: u64,
+    protocol_fees_b: u64,
+    reserve_a: u64,
+    reserve_b: u64,
+    last_invariant_k: u128,
+    is_paused: bool,
+    locked: bool,
+    flash_loan_active: bool,
+    flash_loan_a_token: bool,
+    flash_loan_amount: u64,
+    oracle_enabled: bool,
+    price_cumulative_a: u128,
+    price_cumulative_b: u128,
+    cumulative_volume_a: u128,
+    cumulative_volume_b: u128,
+    cumulative_fees: u128,
+    last_update_ts: i64,
+    volatility_cumulative: u128,
+    volatility_ts: i64,
+    observations: [Observation; OBSERVATION_COUNT],
+    observation_index: u8,
+    observation_count: u8,
+    sqrt_price: u128,
+    tick_current: i32,
+    liquidity: u128,
+    fee_growth_global_a: u128,
+    fee_growth_global_b: u128,
+    position_fees_a: u64,
+    position_fees_b: u64,
+    #[max_len(MAX_TICKS)]
+    ticks: Vec<Tick>,
+}
+
+impl Pool {
+    // The pool's label as its last address seed, see label_seed
+    pub fn label_seed(&self) -> &[u8] {
+        label_seed(&self.label)
+    }
+
+    // Upgrades a pool read from an older layout, whose missing trailing fields
+    // were read as zeroes, to POOL_VERSION
+    pub fn migrate(&mut self) -> Result<()> {
+        require!(self.version < POOL_VERSION, SwapError::PoolUpToDate);
+        // Version 0 predates the version field itself, version 1 the
+        // flash_swap flag, version 2 the label, version 3 the compute guard,
+        // version 4 the swap limits, and version 5 the tick spacing. Zero is
+        // the right default for all but the last: pools from before labels
+        // have the canonical address, the guard starts off, and a zero limit
+        // is no limit. Concentrated pools used to share TICK_SPACING.
+        if self.version < 6 && self.curve_type == CurveType::Concentrated {
+            self.tick_spacing = TICK_SPACING as u16;
+        }
+        self.version = POOL_VERSION;
+        Ok(())
+    }
+
+    // Advances the TWAP accumulators by the current price times the seconds since
+    // the last update. Must run before the reserves change. Accumulators wrap on
+    // overflow by design; consumers only ever difference two samples.
+    pub fn update_oracle(&mut self, now: i64) {
+        if !self.oracle_enabled {
+            return;
+        }
+        let elapsed = now.saturating_sub(self.last_update_ts);
+        let (reserve_a, reserve_b) = self.price_reserves();
+        if elapsed > 0 && reserve_a > 0 && reserve_b > 0 {
+            self.price_cumulative_a = accumulate_price(
+                self.price_cumulative_a,
+                reserve_a,
+                reserve_b,
+                elapsed as u64,
+            );
+            self.price_cumulative_b = accumulate_price(
+                self.price_cumulative_b,
+                reserve_b,
+                reserve_a,
+                elapsed as u64,
+            );
+        } else if reserve_a == 0 || reserve_b == 0 {
+            // No price to average yet, so the volatility window can't start
+            self.volatility_cumulative = self.price_cumulative_a;
+            self.volatility_ts = now;
+        }
+        if elapsed > 0 {
+            self.write_observation(now);
+        }
+        self.last_update_ts = now;
+    }
+
+    // Records the current accumulators as of `now` in the observation ring
+    // buffer, overwriting the oldest entry once it is full
+    pub fn write_observation(&mut self, now: i64) {
+        if self.observation_count > 0 {
+            self.observation_index =
+                ((self.observation_index as usize + 1) % OBSERVATION_COUNT) as u8;
+        }
+        if (self.observation_count as usize) < OBSERVATION_COUNT {
+            self.observation_count += 1;
+        }
+        self.observations[self.observation_index as usize] = Observation {
+            timestamp: now,
+            price_cumulative_a: self.price_cumulative_a,
+            price_cumulative_b: self.price_cumulative_b,
+        };
+    }
+
+    // Accumulator values at `target`. After the newest observation the current
+    // reserves have set the price, so it is extrapolated from them; before it,
+    // the price was constant between neighbouring observations, so it is
+    // interpolated linearly.
+    pub fn cumulatives_at(&self, target: i64) -> Result<(u128, u128)> {
+        let newest = self.observations[self.observation_index as usize];
+        if target >= newest.timestamp {
+            let (reserve_a, reserve_b) = self.price_reserves();
+            if reserve_a == 0 || reserve_b == 0 {
+                return Ok((newest.price_cumulative_a, newest.price_cumulative_b));
+            }
+            let elapsed = (target - newest.timestamp) as u64;
+            return Ok((
+                accumulate_price(newest.price_cumulative_a, reserve_a, reserve_b, elapsed),
+                accumulate_price(newest.price_cumulative_b, reserve_b, reserve_a, elapsed),
+            ));
+        }
+
+        let mut later = newest;
+        for i in 1..self.observation_count as usize {
+            let index =
+                (self.observation_index as usize + OBSERVATION_COUNT - i) % OBSERVATION_COUNT;
+            let earlier = self.observations[index];
+            if earlier.timestamp <= target {
+                let span = (later.timestamp - earlier.timestamp) as u128;
+                let offset = (target - earlier.timestamp) as u128;
+                let interpolate = |from: u128, to: u128| {
+                    from.wrapping_add((to.wrapping_sub(from) / span).wrapping_mul(offset))
+                };
+                return Ok((
+                    interpolate(earlier.price_cumulative_a, later.price_cumulative_a),
+                    interpolate(earlier.price_cumulative_b, later.price_cumulative_b),
+                ));
+            }
+            later = earlier;
+        }
+
+        err!(SwapError::ObservationTooOld)
+    }
+
+    // Average prices over the `seconds_ago` seconds up to `now`
+    pub fn twap(&self, now: i64, seconds_ago: u32) -> Result<Twap> {
+        require!(self.oracle_enabled, SwapError::OracleDisabled);
+        require!(seconds_ago > 0, SwapError::ZeroAmount);
+        let (current_a, current_b) = self.cumulatives_at(now)?;
+        let (past_a, past_b) = self.cumulatives_at(now - seconds_ago as i64)?;
+
+        Ok(Twap {
+            price_a_in_b: current_a.wrapping_sub(past_a) / seconds_ago as u128,
+            price_b_in_a: current_b.wrapping_sub(past_b) / seconds_ago as u128,
+        })
+    }
+
+    // Whether the pool has nothing left but dust: no LP tokens besides those
+    // in locked_lp (`locked_lp` is its balance), no concentrated positions, and
+    // no protocol fees waiting to be collected
+    pub fn is_drained(&self, lp_supply: u64, locked_lp: u64) -> bool {
+        lp_supply == locked_lp
+            && self.ticks.is_empty()
+            && self.protocol_fees_a == 0
+            && self.protocol_fees_b == 0
+    }
+
+    // Reentrancy guard around operations that read reserves, move tokens, and
+    // then write reserves back
+    pub fn lock(&mut self) -> Result<()> {
+        require!(!self.locked, SwapError::Reentrancy);
+        self.locked = true;
+        Ok(())
+    }
+
+    pub fn unlock(&mut self) {
+        self.locked = false;
+    }
+
+    // Credits a deposit to both reserves. Neither is touched unless both fit,
+    // so a deposit that would wrap a reserve fails cleanly with MathOverflow.
+    pub fn add_reserves(&mut self, amount_a: u64, amount_b: u64) -> Result<()> {
+        let reserve_a = self
+            .reserve_a
+            .checked_add(amount_a)
+            .ok_or(SwapError::MathOverflow)?;
+        let reserve_b = self
+            .reserve_b
+            .checked_add(amount_b)
+            .ok_or(SwapError::MathOverflow)?;
+        self.reserve_a = reserve_a;
+        self.reserve_b = reserve_b;
+        Ok(())
+    }
+
+    // Snapshots `reserve_a * reserve_b` for off-chain audits. Runs after every
+    // instruction that moves the reserves, so between two reads it should only
+    // grow from fees or scale with deposits and withdrawals.
+    pub fn record_k(&mut self) {
+        self.last_invariant_k = constant_product_invariant(self.reserve_a, self.reserve_b);
+    }
+
+    // Switches to the proposed fee once its timelock has passed. Runs before any
+    // fee is charged so a due change can't be skipped.
+    pub fn promote_pending_fee(&mut self, now: i64) {
+        if self.fee_effective_ts != 0 && now >= self.fee_effective_ts {
+            self.fee_bps = self.pending_fee_bps;
+            self.pending_fee_bps = 0;
+            self.fee_effective_ts = 0;
+        }
+    }
+
+    // Brings `fee_bps` up to date before it is charged: applies a due fee
+    // proposal, or re-prices a dynamic fee. Must run after `update_oracle`.
+    pub fn refresh_fee(&mut self, now: i64) {
+        self.promote_pending_fee(now);
+        self.update_dynamic_fee(now);
+    }
+
+    // Sets a dynamic fee from how far the spot price has moved away from the
+    // TWAP over the current volatility window, then starts a new window once
+    // this one is VOLATILITY_WINDOW_SECONDS old
+    pub fn update_dynamic_fee(&mut self, now: i64) {
+        let Some(band) = self.fee_band else {
+            return;
+        };
+        let (reserve_a, reserve_b) = self.price_reserves();
+        if reserve_a == 0 || reserve_b == 0 {
+            return;
+        }
+
+        let elapsed = now.saturating_sub(self.volatility_ts);
+        if elapsed > 0 {
+            let twap = self
+                .price_cumulative_a
+                .wrapping_sub(self.volatility_cumulative)
+                / elapsed as u128;
+            let spot = reserve_b as u128 * PRICE_SCALE / reserve_a as u128;
+            self.fee_bps = calculate_dynamic_fee(spot, twap, band);
+        }
+        if elapsed >= VOLATILITY_WINDOW_SECONDS {
+            self.volatility_cumulative = self.price_cumulative_a;
+            self.volatility_ts = now;
+        }
+    }
+
+    // The fee for a swapper holding `lp_balance` of this pool's LP tokens:
+    // `fee_bps` less `lp_discount_bps` of it once the balance reaches
+    // `lp_discount_threshold`. The protocol and referral cuts come out of the
+    // fee, so it never drops below them.
+    pub fn discounted_fee_bps(&self, lp_balance: u64) -> u16 {
+        if self.lp_discount_threshold == 0 || lp_balance < self.lp_discount_threshold {
+            return self.fee_bps;
+        }
+        let discount = (self.fee_bps as u64 * self.lp_discount_bps as u64 / FEE_DENOMINATOR) as u16;
+        (self.fee_bps - discount).max(self.protocol_fee_bps + self.referral_fee_bps)
+    }
+
+    // The fee and cuts the pool charges a swap that gets no discount
+    pub fn fee_rates(&self) -> FeeRates {
+        FeeRates {
+            fee_bps: self.fee_bps,
+            protocol_fee_bps: self.protocol_fee_bps,
+            referral_fee_bps: self.referral_fee_bps,
+        }
+    }
+
+    // Output for swapping `amount_in` against the stored reserves, fee included
+    pub fn swap_output(&self, amount_in: u64, a_to_b: bool) -> Result<u64> {
+        self.swap_output_at(amount_in, a_to_b, self.fee_rates())
+    }
+
+    // Same as swap_output, charging `rates` instead of the pool's own
+    pub fn swap_output_at(&self, amount_in: u64, a_to_b: bool, rates: FeeRates) -> Result<u64> {
+        let (reserve_in, reserve_out) = self.directed_reserves(a_to_b);
+        match self.curve_type {
+            CurveType::ConstantProduct => {
+                calculate_swap_output(amount_in, reserve_in, reserve_out, rates.fee_bps)
+            }
+            CurveType::Stable { amp } => {
+                calculate_output_stable(amount_in, reserve_in, reserve_out, rates.fee_bps, amp)
+            }
+            CurveType::Concentrated => {
+                // Dry run on a copy; apply_swap runs the same swap for real
+                let mut pool = Box::new(self.clone());
+                let fees = self.fee_split_at(amount_in, rates, true, false)?;
+                Ok(pool.swap_concentrated(amount_in, a_to_b, fees)?.0)
+            }
+        }
+    }
+
+    // Input required to receive exactly `amount_out`, fee included
+    pub fn swap_input(&self, amount_out: u64, a_to_b: bool) -> Result<u64> {
+        self.swap_input_at(amount_out, a_to_b, self.fee_rates())
+    }
+
+    // Same as swap_input, charging `rates` instead of the pool's own
+    pub fn swap_input_at(&self, amount_out: u64, a_to_b: bool, rates: FeeRates) -> Result<u64> {
+        let (reserve_in, reserve_out) = self.directed_reserves(a_to_b);
+        match self.curve_type {
+            CurveType::ConstantProduct => {
+                calculate_swap_input(amount_out, reserve_in, reserve_out, rates.fee_bps)
+            }
+            CurveType::Stable { amp } => {
+                calculate_input_stable(amount_out, reserve_in, reserve_out, rates.fee_bps, amp)
+            }
+            CurveType::Concentrated => err!(SwapError::UnsupportedCurve),
+        }
+    }
+
+    // (reserve_in, reserve_out) for a swap in the given direction
+    fn directed_reserves(&self, a_to_b: bool) -> (u64, u64) {
+        if a_to_b {
+            (self.reserve_a, self.reserve_b)
+        } else {
+            (self.reserve_b, self.reserve_a)
+        }
+    }
+
+    // The pool's fee on `amount_in` split by its configured cuts. The protocol
+    // cut only applies while the global switch is on, and the referral cut
+    // only to swaps with a referrer; otherwise those parts go to the LPs.
+    pub fn fee_split(
+        &self,
+        amount_in: u64,
+        protocol_fee_on: bool,
+        referred: bool,
+    ) -> Result<FeeSplit> {
+        self.fee_split_at(amount_in, self.fee_rates(), protocol_fee_on, referred)
+    }
+
+    // Same as fee_split, charging `rates` instead of the pool's own
+    pub fn fee_split_at(
+        &self,
+        amount_in: u64,
+        rates: FeeRates,
+        protocol_fee_on: bool,
+        referred: bool,
+    ) -> Result<FeeSplit> {
+        let protocol_fee_bps = if protocol_fee_on {
+            rates.protocol_fee_bps
+        } else {
+            0
+        };
+        let referral_fee_bps = if referred { rates.referral_fee_bps } else { 0 };
+        distribute_fee(amount_in, rates.fee_bps, protocol_fee_bps, referral_fee_bps)
+    }
+
+    // A position's range must be non-empty, within MIN_TICK..=MAX_TICK, and
+    // start and end on the pool's tick spacing
+    pub fn check_tick_range(&self, tick_lower: i32, tick_upper: i32) -> Result<()> {
+        let spacing = i32::from(self.tick_spacing);
+        require!(
+            spacing > 0
+                && tick_lower < tick_upper
+                && tick_lower >= MIN_TICK
+                && tick_upper <= MAX_TICK
+                && tick_lower % spacing == 0
+                && tick_upper % spacing == 0,
+            SwapError::InvalidTickRange
+        );
+        Ok(())
+    }
+
+    // Fails a single swap whose input or output is over the pool's caps
+    pub fn check_swap_limits(&self, amount_in: u64, amount_out: u64) -> Result<()> {
+        require!(
+            self.max_swap_in == 0 || amount_in <= self.max_swap_in,
+            SwapError::SwapLimitExceeded
+        );
+        require!(
+            self.max_swap_out == 0 || amount_out <= self.max_swap_out,
+            SwapError::SwapLimitExceeded
+        );
+        Ok(())
+    }
+
+    // Upper estimate of a swap's compute on this pool, along its heaviest path
+    // for the pool's curve and features (see SWAP_BASE_COMPUTE)
+    pub fn estimated_swap_compute(&self) -> u64 {
+        let curve = match self.curve_type {
+            CurveType::ConstantProduct => 0,
+            CurveType::Stable { .. } => STABLE_CURVE_COMPUTE,
+            // A swap may cross every initialized tick
+            CurveType::Concentrated => self.ticks.len() as u64 * TICK_CROSSING_COMPUTE,
+        };
+        let oracle = if self.oracle_enabled {
+            ORACLE_COMPUTE
+        } else {
+            0
+        };
+        let dynamic_fee = if self.fee_band.is_some() {
+            DYNAMIC_FEE_COMPUTE
+        } else {
+            0
+        };
+        SWAP_BASE_COMPUTE + curve + oracle + dynamic_fee
+    }
+
+    // With the compute guard on, fails early when `remaining` compute units
+    // won't cover the swap's estimate, rather than running out part way
+    pub fn check_compute(&self, remaining: u64) -> Result<()> {
+        if self.compute_guard {
+            require!(
+                remaining >= self.estimated_swap_compute(),
+                SwapError::ComputeBudgetTooLow
+            );
+        }
+        Ok(())
+    }
+
+    // Fails before any tokens move if the swap fee would round `amount_in`
+    // down to nothing, leaving a swap that only donates its input to the pool
+    pub fn check_input_after_fee(&self, amount_in: u64) -> Result<()> {
+        self.check_input_after_fee_at(amount_in, self.fee_rates())
+    }
+
+    // Same as check_input_after_fee, charging `rates` instead of the pool's own
+    pub fn check_input_after_fee_at(&self, amount_in: u64, rates: FeeRates) -> Result<()> {
+        require!(
+            calculate_swap_fee(amount_in, rates.fee_bps) < amount_in,
+            SwapError::AmountTooSmall
+        );
+        Ok(())
+    }
+
+    // How far a swap's execution price falls below the pre-trade spot price
+    pub fn price_impact_bps(&self, amount_in: u64, amount_out: u64, a_to_b: bool) -> Result<u64> {
+        let (reserve_a, reserve_b) = self.price_reserves();
+        if a_to_b {
+            calculate_price_impact_bps(amount_in, amount_out, reserve_a, reserve_b)
+        } else {
+            calculate_price_impact_bps(amount_in, amount_out, reserve_b, reserve_a)
+        }
+    }
+
+    // Largest part of `amount_in`, found by bisection, whose swap stays within
+    // `max_impact_bps` and still pays out something. 0 if no part does.
+    pub fn max_input_within_impact(
+        &self,
+        amount_in: u64,
+        max_impact_bps: u16,
+        a_to_b: bool,
+    ) -> Result<u64> {
+        self.max_input_within_impact_at(amount_in, max_impact_bps, a_to_b, self.fee_rates())
+    }
+
+    // Same as max_input_within_impact, charging `rates` instead of the pool's
+    // own
+    pub fn max_input_within_impact_at(
+        &self,
+        amount_in: u64,
+        max_impact_bps: u16,
+        a_to_b: bool,
+        rates: FeeRates,
+    ) -> Result<u64> {
+        let fits = |amount: u64| -> Result<bool> {
+            let amount_out = self.swap_output_at(amount, a_to_b, rates)?;
+            Ok(amount_out > 0
+                && self.price_impact_bps(amount, amount_out, a_to_b)? <= max_impact_bps as u64)
+        };
+        if fits(amount_in)? {
+            return Ok(amount_in);
+        }
+        // `low` always fits (or is 0), `high` never does
+        let (mut low, mut high) = (0u64, amount_in);
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if fits(mid)? {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        Ok(low)
+    }
+
+    // Books tokens leaving a vault outside of trading: the LPs' reserve is
+    // debited first, then any uncollected protocol fees, then fees owed to
+    // concentrated positions. Anything beyond those was never tracked (e.g. a
+    // donation).
+    pub fn debit_vault(&mut self, amount: u64, a_side: bool) {
+        let (reserve, protocol_fees, position_fees) = if a_side {
+            (
+                &mut self.reserve_a,
+                &mut self.protocol_fees_a,
+                &mut self.position_fees_a,
+            )
+        } else {
+            (
+                &mut self.reserve_b,
+                &mut self.protocol_fees_b,
+                &mut self.position_fees_b,
+            )
+        };
+        let from_reserve = amount.min(*reserve);
+        *reserve -= from_reserve;
+        let from_protocol_fees = (amount - from_reserve).min(*protocol_fees);
+        *protocol_fees -= from_protocol_fees;
+        *position_fees = position_fees.saturating_sub(amount - from_reserve - from_protocol_fees);
+    }
+
+    // What a vault holds beyond the LPs' reserve and the uncollected protocol
+    // and position fees, i.e. tokens that reached it outside of the pool's own
+    // instructions
+    pub fn untracked_balance(&self, vault_amount: u64, a_side: bool) -> u64 {
+        let (reserve, protocol_fees, position_fees) = if a_side {
+            (self.reserve_a, self.protocol_fees_a, self.position_fees_a)
+        } else {
+            (self.reserve_b, self.protocol_fees_b, self.position_fees_b)
+        };
+        vault_amount.saturating_sub(
+            reserve
+                .saturating_add(protocol_fees)
+                .saturating_add(position_fees),
+        )
+    }
+
+    // Sets each reserve to its vault balance less the uncollected protocol and
+    // position fees. Fees a vault can no longer cover are written down to what
+    // it holds.
+    pub fn sync_reserves(&mut self, vault_a: u64, vault_b: u64) {
+        self.protocol_fees_a = self.protocol_fees_a.min(vault_a);
+        self.protocol_fees_b = self.protocol_fees_b.min(vault_b);
+        self.position_fees_a = self.position_fees_a.min(vault_a - self.protocol_fees_a);
+        self.position_fees_b = self.position_fees_b.min(vault_b - self.protocol_fees_b);
+        self.reserve_a = vault_a - self.protocol_fees_a - self.position_fees_a;
+        self.reserve_b = vault_b - self.protocol_fees_b - self.position_fees_b;
+    }
+
+    // The share of each reserve `lp_amount` of `lp_supply` LP tokens redeems
+    // for, rounded down. With no LP supply there is nothing to redeem, so that
+    // fails outright instead of reaching the division.
+    pub fn lp_value(&self, lp_amount: u64, lp_supply: u64) -> Result<(u64, u64)> {
+        require!(lp_supply > 0, SwapError::NoLiquidity);
+        Ok((
+            calculate_withdraw_amount(lp_amount, self.reserve_a, lp_supply)?,
+            calculate_withdraw_amount(lp_amount, self.reserve_b, lp_supply)?,
+        ))
+    }
+
+    // Fails if minting `lp_amount` on top of `lp_supply` would take the LP
+    // supply past `liquidity_cap`. The first deposit also mints
+    // MINIMUM_LIQUIDITY, which counts towards the cap.
+    pub fn check_liquidity_cap(&self, lp_supply: u64, lp_amount: u64) -> Result<()> {
+        if self.liquidity_cap == 0 {
+            return Ok(());
+        }
+        let locked = if lp_supply == 0 { MINIMUM_LIQUIDITY } else { 0 };
+        let new_supply = lp_supply
+            .checked_add(lp_amount)
+            .and_then(|supply| supply.checked_add(locked))
+            .ok_or(SwapError::MathOverflow)?;
+        require!(
+            new_supply <= self.liquidity_cap,
+            SwapError::LiquidityCapExceeded
+        );
+        Ok(())
+    }
+
+    // Gives a donation to the liquidity providers. It joins the reserves, so
+    // every LP token is worth more, except on concentrated pools, whose
+    // reserves are position principal: there it is shared among the positions
+    // in range like a swap fee.
+    pub fn donate(&mut self, amount_a: u64, amount_b: u64) -> Result<()> {
+        if self.curve_type == CurveType::Concentrated {
+            require!(self.liquidity > 0, SwapError::InsufficientLiquidity);
+            if amount_a > 0 {
+                self.credit_position_fees(amount_a, true)?;
+            }
+            if amount_b > 0 {
+                self.credit_position_fees(amount_b, false)?;
+            }
+            return Ok(());
+        }
+        self.add_reserves(amount_a, amount_b)
+    }
+
+    // Books a flash swap's repayment: the borrowed flash_loan_amount leaves its
+    // reserve and `received_a` / `received_b` join theirs. As in Uniswap V2, the
+    // new reserves less the fee on what came in must keep the product they
+    // started with; the fee stays in the reserves for LPs.
+    pub fn settle_flash_swap(&mut self, received_a: u64, received_b: u64) -> Result<()> {
+        let (out_a, out_b) = if self.flash_loan_a_token {
+            (self.flash_loan_amount, 0)
+        } else {
+            (0, self.flash_loan_amount)
+        };
+        let reserve_a = self
+            .reserve_a
+            .checked_add(received_a)
+            .and_then(|reserve| reserve.checked_sub(out_a))
+            .ok_or(SwapError::MathOverflow)?;
+        let reserve_b = self
+            .reserve_b
+            .checked_add(received_b)
+            .and_then(|reserve| reserve.checked_sub(out_b))
+            .ok_or(SwapError::MathOverflow)?;
+
+        // Both sides scaled by FEE_DENOMINATOR, so the products need 256 bits
+        let denominator = FEE_DENOMINATOR as u128;
+        let fee_bps = self.fee_bps as u128;
+        let adjusted_a = reserve_a as u128 * denominator - received_a as u128 * fee_bps;
+        let adjusted_b = reserve_b as u128 * denominator - received_b as u128 * fee_bps;
+        let k_before = constant_product_invariant(self.reserve_a, self.reserve_b);
+        require!(
+            wide_mul(adjusted_a, adjusted_b) >= wide_mul(k_before, denominator * denominator),
+            SwapError::FlashLoanNotRepaid
+        );
+        require!(
+            reserve_a.min(reserve_b) >= self.min_reserve,
+            SwapError::ReserveBelowFloor
+        );
+
+        self.reserve_a = reserve_a;
+        self.reserve_b = reserve_b;
+        Ok(())
+    }
+
+    // Records a completed swap against the stored reserves, with `fees` from
+    // fee_split. The protocol's cut of the fee stays in the vault but is
+    // tracked separately so LPs don't own it; the referral cut has already
+    // left the vault.
+    pub fn apply_swap(
+        &mut self,
+        amount_in: u64,
+        amount_out: u64,
+        a_to_b: bool,
+        fees: FeeSplit,
+    ) -> Result<()> {
+        self.check_swap_limits(amount_in, amount_out)?;
+        // Concentrated pools also move their price and pay the LP fee out to
+        // positions in range, so that part doesn't join the reserve either
+        let position_fee = if self.curve_type == CurveType::Concentrated {
+            let (swap_out, position_fee) = self.swap_concentrated(amount_in, a_to_b, fees)?;
+            require!(swap_out == amount_out, SwapError::InvariantViolated);
+            position_fee
+        } else {
+            0
+        };
+        let (reserve_in, reserve_out, protocol_fees) = if a_to_b {
+            (
+                &mut self.reserve_a,
+                &mut self.reserve_b,
+                &mut self.protocol_fees_a,
+            )
+        } else {
+            (
+                &mut self.reserve_b,
+                &mut self.reserve_a,
+                &mut self.protocol_fees_b,
+            )
+        };
+        *protocol_fees = protocol_fees
+            .checked_add(fees.protocol)
+            .ok_or(SwapError::MathOverflow)?;
+        *reserve_in = reserve_in
+            .checked_add(amount_in - fees.protocol - fees.referral - position_fee)
+            .ok_or(SwapError::MathOverflow)?;
+        *reserve_out = reserve_out
+            .checked_sub(amount_out)
+            .ok_or(SwapError::MathOverflow)?;
+        // Only the output side shrinks
+        require!(
+            *reserve_out >= self.min_reserve,
+            SwapError::ReserveBelowFloor
+        );
+
+        // Lifetime stats only; saturate rather than fail a swap over them
+        let volume = if a_to_b {
+            &mut self.cumulative_volume_a
+        } else {
+            &mut self.cumulative_volume_b
+        };
+        *volume = volume.saturating_add(amount_in as u128);
+        self.cumulative_fees = self.cumulative_fees.saturating_add(fees.total() as u128);
+        Ok(())
+    }
+}
+
+// Concentrated liquidity: tick bookkeeping, position amounts and fees, and the
+// tick-crossing swap
+impl Pool {
+    // Reserves whose ratio is the spot price. A concentrated pool's reserves say
+    // nothing about its price, so it reports the virtual reserves of
+    // PRICE_REFERENCE_LIQUIDITY at `sqrt_price` instead.
+    pub fn price_reserves(&self) -> (u64, u64) {
+        match self.curve_type {
+            CurveType::Concentrated => (
+                (PRICE_REFERENCE_LIQUIDITY * Q64 / self.sqrt_price) as u64,
+                (PRICE_REFERENCE_LIQUIDITY * self.sqrt_price / Q64) as u64,
+            ),
+            _ => (self.reserve_a, self.reserve_b),
+        }
+    }
+
+    // The initialized tick at `index`, or a blank one
+    fn tick(&self, index: i32) -> Tick {
+        self.ticks
+            .binary_search_by_key(&index, |tick| tick.index)
+            .map(|position| self.ticks[position])
+            .unwrap_or_default()
+    }
+
+    // Fee growth per unit of liquidity inside [tick_lower, tick_upper): the
+    // global growth less what each end has recorded on its far side
+    pub fn fee_growth_inside(&self, tick_lower: i32, tick_upper: i32) -> (u128, u128) {
+        let (lower, upper) = (self.tick(tick_lower), self.tick(tick_upper));
+        let inside = |global: u128, lower_outside: u128, upper_outside: u128| {
+            let below = if self.tick_current >= tick_lower {
+                lower_outside
+            } else {
+                global.wrapping_sub(lower_outside)
+            };
+            let above = if self.tick_current < tick_upper {
+                upper_outside
+            } else {
+                global.wrapping_sub(upper_outside)
+            };
+            global.wrapping_sub(below).wrapping_sub(above)
+        };
+
+        (
+            inside(
+                self.fee_growth_global_a,
+                lower.fee_growth_outside_a,
+                upper.fee_growth_outside_a,
+            ),
+            inside(
+                self.fee_growth_global_b,
+                lower.fee_growth_outside_b,
+                upper.fee_growth_outside_b,
+            ),
+        )
+    }
+
+    // Adds `liquidity_delta` (removes, if negative) over [tick_lower,
+    // tick_upper): updates the ticks at both ends and, if the range holds the
+    // current price, the liquidity in range
+    pub fn update_position(
+        &mut self,
+        tick_lower: i32,
+        tick_upper: i32,
+        liquidity_delta: i128,
+    ) -> Result<()> {
+        let opposite = liquidity_delta
+            .checked_neg()
+            .ok_or(SwapError::MathOverflow)?;
+        self.update_tick(tick_lower, liquidity_delta, liquidity_delta)?;
+        self.update_tick(tick_upper, liquidity_delta, opposite)?;
+
+        if tick_lower <= self.tick_current && self.tick_current < tick_upper {
+            self.liquidity = self
+                .liquidity
+                .checked_add_signed(liquidity_delta)
+                .ok_or(SwapError::MathOverflow)?;
+        }
+        Ok(())
+    }
+
+    // Applies a position change to the tick at `index`, initializing it if no
+    // position ended there yet and dropping it once none does
+    fn update_tick(&mut self, index: i32, gross_delta: i128, net_delta: i128) -> Result<()> {
+        let position = match self.ticks.binary_search_by_key(&index, |tick| tick.index) {
+            Ok(position) => position,
+            Err(position) => {
+                require!(self.ticks.len() < MAX_TICKS, SwapError::TooManyTicks);
+                // By convention all growth so far happened below a tick at or
+                // below the current one, and above any other
+                let (outside_a, outside_b) = if index <= self.tick_current {
+                    (self.fee_growth_global_a, self.fee_growth_global_b)
+                } else {
+                    (0, 0)
+                };
+                self.ticks.insert(
+                    position,
+                    Tick {
+                        index,
+                        fee_growth_outside_a: outside_a,
+                        fee_growth_outside_b: outside_b,
+                        ..Tick::default()
+                    },
+                );
+                position
+            }
+        };
+
+        let tick = &mut self.ticks[position];
+        tick.liquidity_gross = tick
+            .liquidity_gross
+            .checked_add_signed(gross_delta)
+            .ok_or(SwapError::MathOverflow)?;
+        tick.liquidity_net = tick
+            .liquidity_net
+            .checked_add(net_delta)
+            .ok_or(SwapError::MathOverflow)?;
+        if tick.liquidity_gross == 0 {
+            self.ticks.remove(position);
+        }
+        Ok(())
+    }
+
+    // Tokens backing `liquidity` over [tick_lower, tick_upper) at the current
+    // price: only token A below the range, only token B above it, both inside
+    fn position_amounts(
+        &self,
+        tick_lower: i32,
+        tick_upper: i32,
+        liquidity: u128,
+        rounding: RoundingMode,
+    ) -> Result<(u64, u64)> {
+        let sqrt_lower = sqrt_price_at_tick(tick_lower)?;
+        let sqrt_upper = sqrt_price_at_tick(tick_upper)?;
+        let sqrt_price = self.sqrt_price.clamp(sqrt_lower, sqrt_upper);
+
+        let amount_a = calculate_amount_a_delta(sqrt_price, sqrt_upper, liquidity, rounding)?;
+        let amount_b = calculate_amount_b_delta(sqrt_lower, sqrt_price, liquidity, rounding)?;
+        Ok((
+            u64::try_from(amount_a).map_err(|_| error!(SwapError::MathOverflow))?,
+            u64::try_from(amount_b).map_err(|_| error!(SwapError::MathOverflow))?,
+        ))
+    }
+
+    // Fees `liquidity` over [tick_lower, tick_upper) has earned since the fee
+    // growth inside the range was `inside_a` / `inside_b`, rounded down
+    pub fn position_fees(
+        &self,
+        tick_lower: i32,
+        tick_upper: i32,
+        liquidity: u128,
+        inside_a: u128,
+        inside_b: u128,
+    ) -> Result<(u64, u64)> {
+        let (now_a, now_b) = self.fee_growth_inside(tick_lower, tick_upper);
+        let earned = |now: u128, then: u128| -> Result<u64> {
+            let fees = mul_div(liquidity, now.wrapping_sub(then), Q64, RoundingMode::Down)?;
+            u64::try_from(fees).map_err(|_| error!(SwapError::MathOverflow))
+        };
+        Ok((earned(now_a, inside_a)?, earned(now_b, inside_b)?))
+    }
+
+    // Shares `fee` of one token among the liquidity in range as fee growth and
+    // books it as owed to positions. There must be liquidity in range.
+    pub fn credit_position_fees(&mut self, fee: u64, a_side: bool) -> Result<()> {
+        let growth = mul_div(fee as u128, Q64, self.liquidity, RoundingMode::Down)?;
+        let (global, owed) = if a_side {
+            (&mut self.fee_growth_global_a, &mut self.position_fees_a)
+        } else {
+            (&mut self.fee_growth_global_b, &mut self.position_fees_b)
+        };
+        *global = global.wrapping_add(growth);
+        *owed = owed.checked_add(fee).ok_or(SwapError::MathOverflow)?;
+        Ok(())
+    }
+
+    // Moves the price across the initialized tick at `index`: the fee growth
+    // recorded outside it flips sides, and its positions enter or leave range
+    fn cross_tick(&mut self, index: i32, a_to_b: bool) -> Result<()> {
+        let (global_a, global_b) = (self.fee_growth_global_a, self.fee_growth_global_b);
+        let position = self
+            .ticks
+            .binary_search_by_key(&index, |tick| tick.index)
+            .map_err(|_| error!(SwapError::InvalidTickRange))?;
+        let tick = &mut self.ticks[position];
+        tick.fee_growth_outside_a = global_a.wrapping_sub(tick.fee_growth_outside_a);
+        tick.fee_growth_outside_b = global_b.wrapping_sub(tick.fee_growth_outside_b);
+
+        let liquidity_net = if a_to_b {
+            tick.liquidity_net
+                .checked_neg()
+                .ok_or(SwapError::MathOverflow)?
+        } else {
+            tick.liquidity_net
+        };
+        self.liquidity = self
+            .liquidity
+            .checked_add_signed(liquidity_net)
+            .ok_or(SwapError::MathOverflow)?;
+        self.tick_current = if a_to_b { index - 1 } else { index };
+        Ok(())
+    }
+
+    // Swaps `amount_in` through the tick ranges, crossing initialized ticks as
+    // the price moves. Within a range the pool trades as constant product on
+    // the liquidity in range, which earns the LP fee on the input it takes.
+    // Returns the output and the part of `fees.lp` credited to positions.
+    fn swap_concentrated(
+        &mut self,
+        amount_in: u64,
+        a_to_b: bool,
+        fees: FeeSplit,
+    ) -> Result<(u64, u64)> {
+        let lp_fee = fees.lp;
+        let net_in = (amount_in - fees.total()) as u128;
+
+        let mut remaining = net_in;
+        let mut amount_out = 0u128;
+        let mut fee_credited = 0u64;
+        while remaining > 0 {
+            // The next initialized tick the price meets, or the end of the
+            // tick range
+            let next_tick = if a_to_b {
+                self.ticks
+                    .iter()
+                    .rev()
+                    .find(|tick| tick.index <= self.tick_current)
+            } else {
+                self.ticks
+                    .iter()
+                    .find(|tick| tick.index > self.tick_current)
+            }
+            .map(|tick| tick.index);
+            let bound = if a_to_b { MIN_TICK } else { MAX_TICK };
+            let target = sqrt_price_at_tick(next_tick.unwrap_or(bound))?;
+
+            if self.liquidity == 0 {
+                // Nothing to trade against until the next tick
+                self.sqrt_price = target;
+            } else {
+                let (step_in, sqrt_next) = self.swap_step(remaining, target, a_to_b)?;
+                let step_out = if a_to_b {
+                    calculate_amount_b_delta(
+                        sqrt_next,
+                        self.sqrt_price,
+                        self.liquidity,
+                        RoundingMode::Down,
+                    )?
+                } else {
+                    calculate_amount_a_delta(
+                        self.sqrt_price,
+                        sqrt_next,
+                        self.liquidity,
+                        RoundingMode::Down,
+                    )?
+                };
+
+                // Each range earns its share of the fee by input taken; the last
+                // one also gets the rounding remainder
+                remaining -= step_in;
+                let step_fee = if remaining == 0 {
+                    lp_fee - fee_credited
+                } else {
+                    mul_div(lp_fee as u128, step_in, net_in, RoundingMode::Down)? as u64
+                };
+                self.credit_position_fees(step_fee, a_to_b)?;
+                fee_credited += step_fee;
+                amount_out += step_out;
+                self.sqrt_price = sqrt_next;
+            }
+
+            if self.sqrt_price == target {
+                let Some(index) = next_tick else {
+                    break;
+                };
+                self.cross_tick(index, a_to_b)?;
+            } else {
+                self.tick_current = tick_at_sqrt_price(self.sqrt_price)?;
+            }
+        }
+        require!(remaining == 0, SwapError::InsufficientLiquidity);
+
+        let amount_out = u64::try_from(amount_out).map_err(|_| error!(SwapError::MathOverflow))?;
+        Ok((amount_out, fee_credited))
+    }
+
+    // One step of a concentrated swap within the current range: how much of
+    // `remaining` it takes and the sqrt price it leaves, stopping at `target`
+    fn swap_step(&self, remaining: u128, target: u128, a_to_b: bool) -> Result<(u128, u128)> {
+        let to_target = if a_to_b {
+            calculate_amount_a_delta(target, self.sqrt_price, self.liquidity, RoundingMode::Up)?
+        } else {
+            calculate_amount_b_delta(self.sqrt_price, target, self.liquidity, RoundingMode::Up)?
+        };
+        if remaining >= to_target {
+            return Ok((to_target, target));
+        }
+
+        let sqrt_next = next_sqrt_price(self.sqrt_price, self.liquidity, remaining, a_to_b)?;
+        let sqrt_next = if a_to_b {
+            sqrt_next.max(target)
+        } else {
+            sqrt_next.min(target)
+        };
+        Ok((remaining, sqrt_next))
+    }
+}
+
+// Extra accounting checks compiled in with the `strict-checks` feature. They
+// cost compute on every swap and deposit, so production builds leave them out.
+#[cfg(feature = "strict-checks")]
+impl Pool {
+    // The curve's invariant: x * y for constant product, D for stable pools
+    pub fn invariant(&self) -> Result<u128> {
+        match self.curve_type {
+            CurveType::ConstantProduct => {
+                Ok(constant_product_invariant(self.reserve_a, self.reserve_b))
+            }
+            CurveType::Stable { amp } => {
+                compute_stable_d(self.reserve_a as u128, self.reserve_b as u128, amp)
+            }
+            // No single invariant spans the tick ranges; only the vault
+            // coverage below is checked
+            CurveType::Concentrated => Ok(0),
+        }
+    }
+
+    // Fails unless the vaults cover the reserves plus the uncollected protocol
+    // and position fees and, when `invariant_before` is given (swaps), the invariant has not
+    // dropped below it. Fees only ever raise the invariant; stable pools get
+    // one unit of slack for rounding in the Newton iteration.
+    pub fn assert_invariant(
+        &self,
+        invariant_before: Option<u128>,
+        vault_a: u64,
+        vault_b: u64,
+    ) -> Result<()> {
+        if let Some(before) = invariant_before {
+            let slack = match self.curve_type {
+                CurveType::ConstantProduct | CurveType::Concentrated => 0,
+                CurveType::Stable { .. } => 1,
+            };
+            require!(
+                self.invariant()? + slack >= before,
+                SwapError::InvariantViolated
+            );
+        }
+        require!(
+            (self.reserve_a as u128 + self.protocol_fees_a as u128 + self.position_fees_a as u128)
+                <= vault_a as u128
+                && (self.reserve_b as u128
+                    + self.protocol_fees_b as u128
+                    + self.position_fees_b as u128)
+                    <= vault_b as u128,
+            SwapError::InvariantViolated
+        );
+        Ok(())
+    }
+}
+
+#[event]
+pub struct PoolCreated {
+    pub pool: Pubkey,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    pub fee_tier: u16,
+    pub index: u64, // position in creation order, starting at 0
+}
+
+#[event]
+pub struct SwapExecuted {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub a_to_b: bool,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+}
+
+#[event]
+pub struct FeeFreeSwap {
+    pub pool: Pubkey,
+    pub authority: Pubkey,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub a_to_b: bool,
+}
+
+#[event]
+pub struct LiquidityAdded {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub lp_minted: u64,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+}
+
+#[event]
+pub struct LiquidityRemoved {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub lp_burned: u64,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+}
+
+#[event]
+pub struct PartialFill {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub amount_filled: u64,   // part of the requested input that was swapped
+    pub amount_refunded: u64, // part left with the user
+    pub amount_out: u64,
+    pub a_to_b: bool,
+}
+
+#[event]
+pub struct FlashSwapped {
+    pub pool: Pubkey,
+    pub borrower: Pubkey,
+    pub amount_out: u64,
+    pub a_to_b: bool,
+    pub amount_a: u64, // repaid in token A
+    pub amount_b: u64, // repaid in token B
+}
+
+#[event]
+pub struct PositionOpened {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub position: Pubkey,
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    pub liquidity: u128,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+#[event]
+pub struct PositionClosed {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub position: Pubkey,
+    pub liquidity: u128,
+    pub amount_a: u64, // principal paid out, fees excluded
+    pub amount_b: u64,
+    pub fees_a: u64,
+    pub fees_b: u64,
+}
+
+#[event]
+pub struct OracleObservation {
+    pub pool: Pubkey,
+    pub price_cumulative_a: u128,
+    pub price_cumulative_b: u128,
+    pub last_update_ts: i64, // when the accumulators were last advanced
+    pub current_ts: i64,     // cluster time when this was emitted
+}
+
+#[event]
+pub struct TwapResult {
+    pub pool: Pubkey,
+    pub seconds_ago: u32,
+    pub price_a_in_b: u128, // both scaled by PRICE_SCALE
+    pub price_b_in_a: u128,
+}
+
+#[event]
+pub struct QuoteResult {
+    pub pool: Pubkey,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub a_to_b: bool,
+}
+
+#[event]
+pub struct QuoteDetailed {
+    pub pool: Pubkey,
+    pub amount_out: u64,
+    pub price_impact_bps: u64, // execution price's shortfall from spot, fee included
+    pub effective_price: u128, // output per whole input token, scaled by PRICE_SCALE
+}
+
+#[event]
+pub struct MaxInputForImpact {
+    pub pool: Pubkey,
+    pub max_impact_bps: u16,
+    pub a_to_b: bool,
+    pub amount_in: u64,
+    pub amount_out: u64,
+}
+
+#[event]
+pub struct PathQuote {
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub mint_in: Pubkey,
+    pub mint_out: Pubkey,
+    pub amounts: Vec<u64>, // output of each hop, in path order
+}
+
+#[event]
+pub struct EmergencyWithdraw {
+    pub pool: Pubkey,
+    pub authority: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+#[event]
+pub struct LpMintFrozen {
+    pub pool: Pubkey,
+    pub lp_mint: Pubkey,
+    pub lp_supply: u64,
+}
+
+#[event]
+pub struct LpMintEnsured {
+    pub pool: Pubkey,
+    pub lp_mint: Pubkey,
+    pub lp_supply: u64,
+    pub restored: bool,
+}
+
+#[event]
+pub struct PoolMigrated {
+    pub pool: Pubkey,
+    pub from_version: u8,
+    pub to_version: u8,
+}
+
+#[event]
+pub struct ForeignTokenRecovered {
+    pub pool: Pubkey,
+    pub mint: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct Skimmed {
+    pub pool: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+#[event]
+pub struct Donated {
+    pub pool: Pubkey,
+    pub donor: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+#[event]
+pub struct ReservesSynced {
+    pub pool: Pubkey,
     pub authority: Pubkey,
+    pub old_reserve_a: u64,
+    pub old_reserve_b: u64,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+}
+
+#[event]
+pub struct RebaseSynced {
+    pub pool: Pubkey,
+    pub old_reserve_a: u64,
+    pub old_reserve_b: u64,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+}
+
+#[event]
+pub struct SpotPrice {
+    pub pool: Pubkey,
+    pub price_a_in_b: u128,
+    pub price_b_in_a: u128,
+}
+
+#[event]
+pub struct Reserves {
+    pub pool: Pubkey,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+    pub lp_supply: u64,
+}
+
+#[event]
+pub struct PoolInfo {
+    pub pool: Pubkey,
     pub mint_a: Pubkey,
     pub mint_b: Pubkey,
-    pub bump: u8,
+    pub lp_mint: Pubkey,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+    pub fee_bps: u16,
+    pub lp_supply: u64,
+    pub is_paused: bool,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct LpValue {
+    pub pool: Pubkey,
+    pub lp_amount: u64,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+#[error_code]
+pub enum SwapError {
+    #[msg("Math operation overflow")]
+    MathOverflow,
+    #[msg("Slippage tolerance exceeded")]
+    SlippageTooHigh,
+    #[msg("Fee exceeds the allowed maximum")]
+    InvalidFee,
+    #[msg("Fee tier is not one of FEE_TIERS")]
+    InvalidFeeTier,
+    #[msg("Transaction deadline exceeded")]
+    DeadlineExceeded,
+    #[msg("Pool mints must be different")]
+    IdenticalMints,
+    #[msg("mint_a must sort before mint_b")]
+    MintsNotSorted,
+    #[msg("Insufficient liquidity for this trade")]
+    InsufficientLiquidity,
+    #[msg("Swap comes sooner after the user's last swap than the pool allows")]
+    SwapTooSoon,
+    #[msg("Pool has a swap cooldown, so the last_swap account is required")]
+    LastSwapAccountMissing,
+    #[msg("Swap interval must not be negative")]
+    InvalidSwapInterval,
+    #[msg("Pools with a swap cooldown only take direct swaps")]
+    CooldownRequiresDirectSwap,
+    #[msg("Pool has no LP supply")]
+    NoLiquidity,
+    #[msg("Deposit would take the LP supply over the pool's liquidity cap")]
+    LiquidityCapExceeded,
+    #[msg("User does not hold enough of the input token")]
+    InsufficientUserBalance,
+    #[msg("First deposit is too small to lock the minimum liquidity")]
+    InsufficientInitialLiquidity,
+    #[msg("Initial deposit price is outside the expected range")]
+    PriceOutOfRange,
+    #[msg("Price impact exceeds the allowed maximum")]
+    PriceImpactTooHigh,
+    #[msg("Flash loan was not repaid with its fee in the same transaction")]
+    FlashLoanNotRepaid,
+    #[msg("A flash loan is outstanding on this pool")]
+    FlashLoanActive,
+    #[msg("No flash loan is outstanding on this pool")]
+    NoActiveFlashLoan,
+    #[msg("Amount must be greater than zero")]
+    ZeroAmount,
+    #[msg("Swap would pay out zero tokens")]
+    ZeroOutput,
+    #[msg("Amplification coefficient must be between 1 and MAX_AMP")]
+    InvalidAmp,
+    #[msg("Swap path is empty or a pool does not hold the previous hop's token")]
+    InvalidPath,
+    #[msg("Vault does not belong to the pool")]
+    InvalidVault,
+    #[msg("Referrer account does not hold the input token")]
+    InvalidReferrer,
+    #[msg("A user token account is required unless the mint is native SOL")]
+    MissingTokenAccount,
+    #[msg("Native SOL swaps need the wsol_account and system_program accounts")]
+    NativeSolAccountsMissing,
+    #[msg("wsol_account is not the user's temporary wSOL address")]
+    InvalidWsolAccount,
+    #[msg("Pool fee is dynamic and follows its fee band")]
+    DynamicFee,
+    #[msg("TWAP window reaches back past the oldest stored observation")]
+    ObservationTooOld,
+    #[msg("Batch legs and remaining accounts don't line up")]
+    InvalidBatch,
+    #[msg("Mint decimals are outside the supported range")]
+    UnsupportedDecimals,
+    #[msg("Operation is not supported on this pool's curve")]
+    UnsupportedCurve,
+    #[msg("Pool accounting invariant violated")]
+    InvariantViolated,
+    #[msg("Mint is not on the whitelist")]
+    MintNotWhitelisted,
+    #[msg("Mint whitelist is full")]
+    WhitelistFull,
+    #[msg("Tick is out of range, off the tick spacing, or not a valid range end")]
+    InvalidTickRange,
+    #[msg("Pool has no room for more initialized ticks")]
+    TooManyTicks,
+    #[msg("Pool still holds liquidity")]
+    PoolNotEmpty,
+    #[msg("Pool is already in the middle of an operation")]
+    Reentrancy,
+    #[msg("Pool is paused")]
+    PoolPaused,
+    #[msg("Pool must be paused first")]
+    PoolNotPaused,
+    #[msg("Signer is not the pool authority")]
+    Unauthorized,
+    #[msg("Signer is not the pending pool authority")]
+    NotPendingAuthority,
+    #[msg("Mint belongs to the pool; only foreign tokens can be recovered")]
+    NotForeignMint,
+    #[msg("Swap would take a reserve below the pool's minimum")]
+    ReserveBelowFloor,
+    #[msg("Pool was not created for a rebasing token")]
+    NotRebasingPool,
+    #[msg("Not available on pools for rebasing tokens")]
+    RebasingPool,
+    #[msg("Input is too small to leave anything after the swap fee")]
+    AmountTooSmall,
+    #[msg("Pool does not keep a TWAP oracle")]
+    OracleDisabled,
+    #[msg("Pool already has the current layout")]
+    PoolUpToDate,
+    #[msg("Not enough compute left for this pool's swap; raise the compute unit limit or split the transaction")]
+    ComputeBudgetTooLow,
+    #[msg("Swap is over the pool's per-swap input or output limit")]
+    SwapLimitExceeded,
+    #[msg("LP supply does not include the locked minimum liquidity")]
+    LpMintInconsistent,
+    #[msg("LP mint authority is neither the pool nor the signing current mint authority")]
+    LpMintAuthorityMismatch,
+    #[msg("Exact hops need the SPL Token program, whose transfers never charge a fee")]
+    ExactHopsUnsupported,
+    #[msg("A two-hop route must go through two different pools")]
+    RepeatedPool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_pool(reserve_a: u64, reserve_b: u64) -> Pool {
+        Pool {
+            authority: Pubkey::default(),
+            pending_authority: None,
+            creator: Pubkey::default(),
+            fee_recipient: Pubkey::default(),
+            created_at: 0,
+            mint_a: Pubkey::default(),
+            mint_b: Pubkey::default(),
+            lp_mint: Pubkey::default(),
+            decimals_a: 6,
+            decimals_b: 6,
+            fee_tier: DEFAULT_FEE_BPS,
+            fee_bps: DEFAULT_FEE_BPS,
+            protocol_fee_bps: 0,
+            referral_fee_bps: 0,
+            pending_fee_bps: 0,
+            fee_effective_ts: 0,
+            fee_band: None,
+            curve_type: CurveType::ConstantProduct,
+            rebasing: false,
+            oracle_enabled: true,
+            protocol_fees_a: 0,
+            protocol_fees_b: 0,
+            reserve_a,
+            reserve_b,
+            last_invariant_k: constant_product_invariant(reserve_a, reserve_b),
+            is_paused: false,
+            locked: false,
+            flash_loan_active: false,
+            flash_loan_a_token: false,
+            flash_loan_amount: 0,
+            price_cumulative_a: 0,
+            price_cumulative_b: 0,
+            cumulative_volume_a: 0,
+            cumulative_volume_b: 0,
+            cumulative_fees: 0,
+            last_update_ts: 0,
+            volatility_cumulative: 0,
+            volatility_ts: 0,
+            observations: [Observation::default(); OBSERVATION_COUNT],
+            observation_index: 0,
+            observation_count: 0,
+            sqrt_price: 0,
+            tick_current: 0,
+            liquidity: 0,
+            fee_growth_global_a: 0,
+            fee_growth_global_b: 0,
+            position_fees_a: 0,
+            position_fees_b: 0,
+            ticks: Vec::new(),
+            min_swap_interval: 0,
+            liquidity_cap: 0,
+            min_reserve: 0,
+            lp_discount_threshold: 0,
+            lp_discount_bps: 0,
+            bump: 0,
+            vault_a_bump: 0,
+            vault_b_bump: 0,
+            version: POOL_VERSION,
+            flash_swap: false,
+            label: [0; 8],
+            compute_guard: false,
+            max_swap_in: 0,
+            max_swap_out: 0,
+            tick_spacing: 0,
+        }
+    }
+
+    // An empty concentrated pool priced at 1:1
+    fn concentrated_pool() -> Pool {
+        let mut pool = test_pool(0, 0);
+        pool.curve_type = CurveType::Concentrated;
+        pool.sqrt_price = sqrt_price_at_tick(0).unwrap();
+        pool.tick_spacing = TICK_SPACING as u16;
+        pool
+    }
+
+    // Mirrors open_position's bookkeeping; returns the fee growth inside the
+    // range that the position starts from
+    fn open_test_position(
+        pool: &mut Pool,
+        tick_lower: i32,
+        tick_upper: i32,
+        liquidity: u128,
+    ) -> (u128, u128) {
+        let (amount_a, amount_b) = pool
+            .position_amounts(tick_lower, tick_upper, liquidity, RoundingMode::Up)
+            .unwrap();
+        pool.update_position(tick_lower, tick_upper, liquidity as i128)
+            .unwrap();
+        pool.reserve_a += amount_a;
+        pool.reserve_b += amount_b;
+        pool.fee_growth_inside(tick_lower, tick_upper)
+    }
+
+    #[test]
+    fn pending_fee_applies_only_after_its_timelock() {
+        let mut pool = test_pool(1_000_000, 1_000_000);
+        pool.pending_fee_bps = 100;
+        pool.fee_effective_ts = 1_000 + FEE_TIMELOCK_SECONDS;
+        let old_fee_out = pool.swap_output(10_000, true).unwrap();
+
+        pool.promote_pending_fee(1_000 + FEE_TIMELOCK_SECONDS - 1);
+        assert_eq!(pool.fee_bps, DEFAULT_FEE_BPS);
+        assert_eq!(pool.swap_output(10_000, true).unwrap(), old_fee_out);
+
+        pool.promote_pending_fee(1_000 + FEE_TIMELOCK_SECONDS);
+        assert_eq!(pool.fee_bps, 100);
+        assert_eq!(pool.fee_effective_ts, 0);
+        assert!(pool.swap_output(10_000, true).unwrap() < old_fee_out);
+
+        // Nothing pending: later calls leave the fee alone
+        pool.promote_pending_fee(i64::MAX);
+        assert_eq!(pool.fee_bps, 100);
+    }
+
+    #[test]
+    fn dynamic_fee_scales_with_deviation_from_twap() {
+        let band = FeeBand {
+            min_fee_bps: 10,
+            max_fee_bps: 110,
+        };
+        assert_eq!(calculate_dynamic_fee(PRICE_SCALE, PRICE_SCALE, band), 10);
+        assert_eq!(calculate_dynamic_fee(PRICE_SCALE, 0, band), 10);
+
+        // Halfway to VOLATILITY_CAP_BPS in either direction is halfway up the band
+        let half_cap = PRICE_SCALE * VOLATILITY_CAP_BPS as u128 / 2 / FEE_DENOMINATOR as u128;
+        assert_eq!(
+            calculate_dynamic_fee(PRICE_SCALE + half_cap, PRICE_SCALE, band),
+            60
+        );
+        assert_eq!(
+            calculate_dynamic_fee(PRICE_SCALE - half_cap, PRICE_SCALE, band),
+            60
+        );
+
+        // Anything past the cap pays the maximum
+        assert_eq!(
+            calculate_dynamic_fee(3 * PRICE_SCALE, PRICE_SCALE, band),
+            110
+        );
+    }
+
+    #[test]
+    fn large_fast_move_raises_the_dynamic_fee() {
+        let mut pool = test_pool(1_000_000, 1_000_000);
+        pool.fee_band = Some(FeeBand {
+            min_fee_bps: 5,
+            max_fee_bps: 100,
+        });
+        pool.fee_bps = 5;
+
+        // A calm minute at 1:1 keeps the fee at the bottom of the band
+        pool.update_oracle(60);
+        pool.refresh_fee(60);
+        assert_eq!(pool.fee_bps, 5);
+
+        // A big swap moves the price, and shortly after it the spot price is far
+        // from the window's average
+        let amount_out = pool.swap_output(200_000, true).unwrap();
+        pool.apply_swap(
+            200_000,
+            amount_out,
+            true,
+            pool.fee_split(200_000, true, false).unwrap(),
+        )
+        .unwrap();
+        pool.update_oracle(70);
+        pool.refresh_fee(70);
+        assert!(pool.fee_bps > 5);
+        let volatile_fee = pool.fee_bps;
+
+        // As the new price settles into the average the fee comes back down
+        pool.update_oracle(VOLATILITY_WINDOW_SECONDS - 1);
+        pool.refresh_fee(VOLATILITY_WINDOW_SECONDS - 1);
+        assert!(pool.fee_bps < volatile_fee);
+
+        // A pool without a band keeps its fee
+        let mut fixed = test_pool(1_000_000, 1_000_000);
+        fixed.reserve_a = 2_000_000;
+        fixed.update_oracle(60);
+        fixed.refresh_fee(60);
+        assert_eq!(fixed.fee_bps, DEFAULT_FEE_BPS);
+    }
+
+    #[test]
+    fn partial_fill_is_the_largest_input_within_the_impact_limit() {
+        let pool = test_pool(1_000_000, 1_000_000);
+
+        // A small swap fits entirely
+        assert_eq!(
+            pool.max_input_within_impact(1_000, 100, true).unwrap(),
+            1_000
+        );
+
+        // An oversized one is cut to the largest amount within 1%, and one
+        // more unit would break the limit
+        let filled = pool.max_input_within_impact(500_000, 100, true).unwrap();
+        assert!(filled > 0 && filled < 500_000);
+        let fits = |amount: u64| {
+            let amount_out = pool.swap_output(amount, true).unwrap();
+            pool.price_impact_bps(amount, amount_out, true).unwrap() <= 100
+        };
+        assert!(fits(filled));
+        assert!(!fits(filled + 1));
+
+        // Nothing fits under the fee alone
+        assert_eq!(pool.max_input_within_impact(500_000, 0, true).unwrap(), 0);
+    }
+
+    #[test]
+    fn max_input_for_impact_solves_over_the_whole_input_range() {
+        let stable = Pool {
+            curve_type: CurveType::Stable { amp: 100 },
+            ..test_pool(1_000_000, 1_000_000)
+        };
+        for pool in [test_pool(1_000_000, 4_000_000), stable] {
+            for max_impact_bps in [100, 5_000, 9_999] {
+                // As max_input_for_impact bounds the search
+                let amount = pool
+                    .max_input_within_impact(u64::MAX - pool.reserve_a, max_impact_bps, true)
+                    .unwrap();
+                let fits = |amount: u64| {
+                    let amount_out = pool.swap_output(amount, true).unwrap();
+                    pool.price_impact_bps(amount, amount_out, true).unwrap()
+                        <= max_impact_bps as u64
+                };
+                assert!(amount > 0);
+                assert!(fits(amount));
+                assert!(!fits(amount + 1));
+            }
+        }
+    }
+
+    #[test]
+    fn mul_div_takes_the_full_256_bit_product() {
+        assert_eq!(
+            mul_div(u128::MAX, u128::MAX, u128::MAX, RoundingMode::Down).unwrap(),
+            u128::MAX
+        );
+        assert_eq!(
+            mul_div(1 << 100, 1 << 100, 1 << 90, RoundingMode::Down).unwrap(),
+            1 << 110
+        );
+        // (2^128 - 1) * 3 / 2 = 2^128 + 2^127 - 2 + 1/2 doesn't fit
+        assert!(mul_div(u128::MAX, 3, 2, RoundingMode::Down).is_err());
+        assert_eq!(
+            mul_div(u128::MAX, 3, 4, RoundingMode::Down).unwrap(),
+            u128::MAX / 4 * 3 + 2
+        );
+        assert_eq!(
+            mul_div(u128::MAX, 3, 4, RoundingMode::Up).unwrap(),
+            u128::MAX / 4 * 3 + 3
+        );
+    }
+
+    #[test]
+    fn sqrt_price_follows_the_tick_formula() {
+        assert_eq!(sqrt_price_at_tick(0).unwrap(), Q64);
+        for tick in [MIN_TICK, -60_000, -61, -1, 1, 60, 887, 60_000, MAX_TICK] {
+            let expected = (tick as f64 / 2.0 * 0.0001f64.ln_1p()).exp() * Q64 as f64;
+            let actual = sqrt_price_at_tick(tick).unwrap() as f64;
+            assert!((actual / expected - 1.0).abs() < 1e-12, "tick {tick}");
+
+            // Each tick is the last one at or below its own sqrt price
+            let sqrt_price = sqrt_price_at_tick(tick).unwrap();
+            assert_eq!(tick_at_sqrt_price(sqrt_price).unwrap(), tick);
+            if tick > MIN_TICK {
+                assert_eq!(tick_at_sqrt_price(sqrt_price - 1).unwrap(), tick - 1);
+            }
+        }
+        assert!(sqrt_price_at_tick(MAX_TICK + 1).is_err());
+        assert!(sqrt_price_at_tick(MIN_TICK - 1).is_err());
+    }
+
+    #[test]
+    fn concentrated_position_round_trip_never_pays_out_more() {
+        let mut pool = concentrated_pool();
+        let liquidity = 1_000_000_007;
+        open_test_position(&mut pool, -600, 600, liquidity);
+        let (deposit_a, deposit_b) = (pool.reserve_a, pool.reserve_b);
+        assert!(deposit_a > 0 && deposit_b > 0);
+
+        let (paid_a, paid_b) = pool
+            .position_amounts(-600, 600, liquidity, RoundingMode::Down)
+            .unwrap();
+        assert!(paid_a <= deposit_a && paid_b <= deposit_b);
+
+        // Out of range the position holds a single token
+        let above = pool
+            .position_amounts(1_200, 1_800, liquidity, RoundingMode::Up)
+            .unwrap();
+        assert!(above.0 > 0 && above.1 == 0);
+        let below = pool
+            .position_amounts(-1_800, -1_200, liquidity, RoundingMode::Up)
+            .unwrap();
+        assert!(below.0 == 0 && below.1 > 0);
+    }
+
+    #[test]
+    fn concentrated_fees_go_only_to_positions_in_range() {
+        let mut pool = concentrated_pool();
+        let liquidity = 1_000_000_000_000;
+        let in_range = open_test_position(&mut pool, -600, 600, liquidity);
+        let below = open_test_position(&mut pool, -1_800, -600, liquidity);
+        let above = open_test_position(&mut pool, 1_200, 1_800, liquidity);
+        assert_eq!(pool.liquidity, liquidity);
+        assert_eq!(pool.ticks.len(), 5);
+
+        let fees = |pool: &Pool, lower: i32, upper: i32, inside: (u128, u128)| {
+            pool.position_fees(lower, upper, liquidity, inside.0, inside.1)
+                .unwrap()
+        };
+
+        // A small A -> B swap stays in the first range
+        let amount_out = pool.swap_output(1_000_000, true).unwrap();
+        pool.apply_swap(
+            1_000_000,
+            amount_out,
+            true,
+            pool.fee_split(1_000_000, true, false).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            pool.tick_current,
+            tick_at_sqrt_price(pool.sqrt_price).unwrap()
+        );
+        assert!(pool.tick_current >= -600);
+        let earned = fees(&pool, -600, 600, in_range).0;
+        assert!(earned > 0);
+        assert!(earned <= calculate_swap_fee(1_000_000, DEFAULT_FEE_BPS));
+        assert_eq!(fees(&pool, -1_800, -600, below), (0, 0));
+        assert_eq!(fees(&pool, 1_200, 1_800, above), (0, 0));
+
+        // A large one crosses tick -600, handing over to the lower range
+        let amount_out = pool.swap_output(40_000_000_000, true).unwrap();
+        pool.apply_swap(
+            40_000_000_000,
+            amount_out,
+            true,
+            pool.fee_split(40_000_000_000, true, false).unwrap(),
+        )
+        .unwrap();
+        assert!(pool.tick_current < -600);
+        assert_eq!(pool.liquidity, liquidity);
+        assert!(fees(&pool, -1_800, -600, below).0 > 0);
+        assert_eq!(fees(&pool, 1_200, 1_800, above), (0, 0));
+
+        // Everything owed is covered by what the pool booked for positions
+        let owed: u64 = [(-600, 600, in_range), (-1_800, -600, below)]
+            .into_iter()
+            .map(|(lower, upper, inside)| fees(&pool, lower, upper, inside).0)
+            .sum();
+        assert!(owed <= pool.position_fees_a);
+        assert!(pool.position_fees_a - owed <= 2);
+
+        // Past the last initialized tick there is nothing left to trade against
+        assert_eq!(
+            pool.swap_output(u64::MAX / 2, true).unwrap_err(),
+            SwapError::InsufficientLiquidity.into()
+        );
+    }
+
+    #[test]
+    fn lp_value_is_the_rounded_down_share_of_each_reserve() {
+        let pool = test_pool(1_000_001, 3_000_000);
+        assert_eq!(pool.lp_value(1_000, 1_000).unwrap(), (1_000_001, 3_000_000));
+        // 1/3 of 1_000_001 is 333_333.67
+        assert_eq!(pool.lp_value(1, 3).unwrap(), (333_333, 1_000_000));
+        assert_eq!(pool.lp_value(0, 1_000).unwrap(), (0, 0));
+        assert_eq!(
+            pool.lp_value(1, 0).unwrap_err(),
+            SwapError::NoLiquidity.into()
+        );
+    }
+
+    #[test]
+    fn recorded_k_scales_with_liquidity_and_grows_with_fees() {
+        let mut pool = test_pool(1_000_000, 4_000_000);
+        assert_eq!(pool.last_invariant_k, 4_000_000_000_000);
+
+        // A deposit of half the reserves scales k by 1.5^2
+        pool.reserve_a += 500_000;
+        pool.reserve_b += 2_000_000;
+        pool.record_k();
+        assert_eq!(pool.last_invariant_k, 9_000_000_000_000);
+
+        // Burning a third of the LP supply takes k back down
+        let (amount_a, amount_b) = pool.lp_value(1_000_000, 3_000_000).unwrap();
+        pool.reserve_a -= amount_a;
+        pool.reserve_b -= amount_b;
+        pool.record_k();
+        assert_eq!(pool.last_invariant_k, 4_000_000_000_000);
+
+        // The swap fee stays in the pool, so k grows
+        for a_to_b in [true, false] {
+            let k_before = pool.last_invariant_k;
+            let amount_out = pool.swap_output(50_000, a_to_b).unwrap();
+            pool.apply_swap(
+                50_000,
+                amount_out,
+                a_to_b,
+                pool.fee_split(50_000, true, false).unwrap(),
+            )
+            .unwrap();
+            pool.record_k();
+            assert!(pool.last_invariant_k > k_before);
+        }
+    }
+
+    #[test]
+    fn deposits_fail_cleanly_instead_of_wrapping_a_full_reserve() {
+        let mut pool = test_pool(u64::MAX - 10, u64::MAX - 20);
+        pool.add_reserves(10, 20).unwrap();
+        assert_eq!((pool.reserve_a, pool.reserve_b), (u64::MAX, u64::MAX));
+
+        // Either side overflowing fails the whole credit
+        let mut pool = test_pool(u64::MAX - 10, 1_000);
+        for (amount_a, amount_b) in [(11, 0), (0, u64::MAX), (u64::MAX, u64::MAX)] {
+            assert_eq!(
+                pool.add_reserves(amount_a, amount_b).unwrap_err(),
+                SwapError::MathOverflow.into()
+            );
+            assert_eq!((pool.reserve_a, pool.reserve_b), (u64::MAX - 10, 1_000));
+        }
+        pool.add_reserves(10, 1_000).unwrap();
+        assert_eq!((pool.reserve_a, pool.reserve_b), (u64::MAX, 2_000));
+
+        // Donations are credited the same way
+        let mut pool = test_pool(1_000, u64::MAX - 10);
+        assert_eq!(
+            pool.donate(5, 11).unwrap_err(),
+            SwapError::MathOverflow.into()
+        );
+        assert_eq!((pool.reserve_a, pool.reserve_b), (1_000, u64::MAX - 10));
+    }
+
+    #[test]
+    fn constant_product_invariant_holds_the_largest_reserves() {
+        assert_eq!(constant_product_invariant(0, u64::MAX), 0);
+        assert_eq!(
+            constant_product_invariant(1_000_000, 4_000_000),
+            4_000_000_000_000
+        );
+        // (2^64 - 1)^2 = 2^128 - 2^65 + 1, just under u128::MAX
+        assert_eq!(
+            constant_product_invariant(u64::MAX, u64::MAX),
+            u128::MAX - (1 << 65) + 2
+        );
+        assert_eq!(
+            constant_product_invariant(u64::MAX, 2),
+            u64::MAX as u128 * 2
+        );
+
+        // record_k agrees, even for a pool holding the most of both tokens
+        let mut pool = test_pool(u64::MAX, u64::MAX);
+        pool.record_k();
+        assert_eq!(
+            pool.last_invariant_k,
+            constant_product_invariant(u64::MAX, u64::MAX)
+        );
+    }
+
+    #[test]
+    fn rebase_sync_moves_lp_value_with_the_vaults() {
+        let mut pool = test_pool(1_000_000, 1_000_000);
+        assert_eq!(
+            pool.lp_value(100_000, 1_000_000).unwrap(),
+            (100_000, 100_000)
+        );
+
+        // A 10% positive rebase of token A, then a 20% negative one
+        pool.sync_reserves(1_100_000, 1_000_000);
+        assert_eq!(
+            pool.lp_value(100_000, 1_000_000).unwrap(),
+            (110_000, 100_000)
+        );
+        pool.sync_reserves(880_000, 1_000_000);
+        assert_eq!(
+            pool.lp_value(100_000, 1_000_000).unwrap(),
+            (88_000, 100_000)
+        );
+    }
+
+    #[test]
+    fn disabled_oracle_never_advances() {
+        let mut pool = test_pool(1_000_000, 2_000_000);
+        pool.oracle_enabled = false;
+        pool.update_oracle(100);
+        assert_eq!(pool.price_cumulative_a, 0);
+        assert_eq!(pool.price_cumulative_b, 0);
+        assert_eq!(pool.observation_count, 0);
+        assert_eq!(
+            pool.twap(100, 50).unwrap_err(),
+            SwapError::OracleDisabled.into()
+        );
+
+        pool.oracle_enabled = true;
+        pool.update_oracle(200);
+        assert!(pool.price_cumulative_a > 0);
+    }
+
+    #[test]
+    fn swap_fee_must_leave_some_input() {
+        let mut pool = test_pool(1_000_000, 1_000_000);
+        pool.fee_bps = MAX_FEE_BPS;
+        // At a 10% fee 1 unit nets 0.9, which rounds down to nothing
+        assert_eq!(
+            pool.check_input_after_fee(1).unwrap_err(),
+            SwapError::AmountTooSmall.into()
+        );
+        pool.check_input_after_fee(2).unwrap();
+    }
+
+    #[test]
+    fn swaps_stop_at_the_reserve_floor() {
+        let mut pool = test_pool(1_000_000, 1_000_000);
+        pool.min_reserve = 900_000;
+        let mut drained = pool.clone();
+        drained
+            .apply_swap(
+                200_000,
+                100_000,
+                true,
+                drained.fee_split(200_000, true, false).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(drained.reserve_b, 900_000);
+        assert_eq!(
+            pool.apply_swap(
+                200_000,
+                100_001,
+                true,
+                pool.fee_split(200_000, true, false).unwrap()
+            )
+            .unwrap_err(),
+            SwapError::ReserveBelowFloor.into()
+        );
+    }
+
+    #[test]
+    fn swap_limits_cap_each_swaps_input_and_output() {
+        let mut pool = test_pool(1_000_000, 1_000_000);
+        pool.check_swap_limits(u64::MAX, u64::MAX).unwrap();
+
+        pool.max_swap_in = 50_000;
+        pool.max_swap_out = 40_000;
+        pool.check_swap_limits(50_000, 40_000).unwrap();
+        for (amount_in, amount_out) in [(50_001, 40_000), (50_000, 40_001)] {
+            assert_eq!(
+                pool.check_swap_limits(amount_in, amount_out).unwrap_err(),
+                SwapError::SwapLimitExceeded.into()
+            );
+        }
+
+        // Each cap is independent, and applied to every swap
+        pool.max_swap_in = 0;
+        pool.check_swap_limits(u64::MAX, 40_000).unwrap();
+        let amount_out = pool.swap_output(100_000, true).unwrap();
+        assert!(amount_out > 40_000);
+        let fees = pool.fee_split(100_000, true, false).unwrap();
+        assert_eq!(
+            pool.apply_swap(100_000, amount_out, true, fees)
+                .unwrap_err(),
+            SwapError::SwapLimitExceeded.into()
+        );
+    }
+
+    #[test]
+    fn lp_supply_must_include_the_locked_minimum() {
+        assert!(lp_supply_consistent(0, 0));
+        assert!(lp_supply_consistent(MINIMUM_LIQUIDITY, MINIMUM_LIQUIDITY));
+        assert!(lp_supply_consistent(1_000_000, MINIMUM_LIQUIDITY + 5));
+
+        assert!(!lp_supply_consistent(0, MINIMUM_LIQUIDITY));
+        assert!(!lp_supply_consistent(1_000_000, 0));
+        assert!(!lp_supply_consistent(1_000_000, MINIMUM_LIQUIDITY - 1));
+    }
+
+    #[test]
+    fn lp_mint_authority_is_restored_only_from_its_holder() {
+        let pool = Pubkey::new_unique();
+        let stray = Pubkey::new_unique();
+
+        // Already the pool's, or frozen: nothing to do, with or without a signer
+        for signer in [None, Some(stray)] {
+            assert!(!lp_mint_needs_restore(&pool, Some(pool), signer).unwrap());
+            assert!(!lp_mint_needs_restore(&pool, None, signer).unwrap());
+        }
+
+        // Held elsewhere: only the holder's signature can hand it back
+        for signer in [None, Some(Pubkey::new_unique())] {
+            assert_eq!(
+                lp_mint_needs_restore(&pool, Some(stray), signer).unwrap_err(),
+                SwapError::LpMintAuthorityMismatch.into()
+            );
+        }
+        assert!(lp_mint_needs_restore(&pool, Some(stray), Some(stray)).unwrap());
+    }
+
+    #[test]
+    fn liquidity_cap_bounds_the_lp_supply_after_a_deposit() {
+        let mut pool = test_pool(0, 0);
+        pool.check_liquidity_cap(u64::MAX - 1, 1).unwrap();
+
+        pool.liquidity_cap = 10_000;
+        // The first deposit's locked MINIMUM_LIQUIDITY counts too
+        pool.check_liquidity_cap(0, 9_000).unwrap();
+        assert_eq!(
+            pool.check_liquidity_cap(0, 9_001).unwrap_err(),
+            SwapError::LiquidityCapExceeded.into()
+        );
+        pool.check_liquidity_cap(4_000, 6_000).unwrap();
+        assert_eq!(
+            pool.check_liquidity_cap(4_000, 6_001).unwrap_err(),
+            SwapError::LiquidityCapExceeded.into()
+        );
+    }
+
+    #[test]
+    fn lp_discount_applies_from_the_threshold_and_keeps_the_protocol_cut() {
+        let mut pool = test_pool(1_000_000, 1_000_000);
+        pool.fee_bps = 30;
+        pool.lp_discount_bps = 5_000;
+        // No threshold, no discount
+        assert_eq!(pool.discounted_fee_bps(u64::MAX), 30);
+
+        pool.lp_discount_threshold = 1_000;
+        assert_eq!(pool.discounted_fee_bps(999), 30);
+        assert_eq!(pool.discounted_fee_bps(1_000), 15);
+        let full = pool.swap_output(100_000, true).unwrap();
+        let rates = FeeRates {
+            fee_bps: pool.discounted_fee_bps(1_000),
+            ..pool.fee_rates()
+        };
+        assert!(pool.swap_output_at(100_000, true, rates).unwrap() > full);
+        // The discount is charged, never stored
+        assert_eq!(pool.fee_bps, 30);
+        assert_eq!(pool.swap_output(100_000, true).unwrap(), full);
+
+        // A waived fee leaves nothing to split
+        let waived = pool
+            .swap_output_at(100_000, true, FeeRates::default())
+            .unwrap();
+        assert!(waived > full);
+        assert_eq!(
+            pool.fee_split_at(100_000, FeeRates::default(), true, true)
+                .unwrap(),
+            FeeSplit::default()
+        );
+
+        pool.fee_bps = 30;
+        pool.protocol_fee_bps = 10;
+        pool.referral_fee_bps = 10;
+        assert_eq!(pool.discounted_fee_bps(1_000), 20);
+        pool.lp_discount_bps = 10_000;
+        assert_eq!(pool.discounted_fee_bps(1_000), 20);
+    }
+
+    #[test]
+    fn pool_is_drained_once_only_the_locked_liquidity_is_left() {
+        let mut pool = test_pool(1_000, 1_000);
+        assert!(pool.is_drained(0, 0));
+        assert!(pool.is_drained(MINIMUM_LIQUIDITY, MINIMUM_LIQUIDITY));
+        // Some LP still holds a share of the reserves
+        assert!(!pool.is_drained(MINIMUM_LIQUIDITY + 1, MINIMUM_LIQUIDITY));
+
+        pool.protocol_fees_b = 1;
+        assert!(!pool.is_drained(MINIMUM_LIQUIDITY, MINIMUM_LIQUIDITY));
+        pool.protocol_fees_b = 0;
+
+        pool.update_position(-TICK_SPACING, TICK_SPACING, 1_000)
+            .unwrap();
+        assert!(!pool.is_drained(0, 0));
+    }
+
+    #[test]
+    fn donation_is_shared_pro_rata_by_existing_lps() {
+        let mut pool = test_pool(1_000_000, 2_000_000);
+        let lp_supply = 1_000_000;
+        pool.donate(100_000, 200_000).unwrap();
+        assert_eq!((pool.reserve_a, pool.reserve_b), (1_100_000, 2_200_000));
+
+        // A quarter of the LP tokens withdraws a quarter of the donation too
+        let share = |reserve| calculate_withdraw_amount(250_000, reserve, lp_supply).unwrap();
+        assert_eq!(share(pool.reserve_a), 275_000);
+        assert_eq!(share(pool.reserve_b), 550_000);
+
+        // On a concentrated pool it is owed to the positions in range instead
+        let mut pool = concentrated_pool();
+        assert_eq!(
+            pool.donate(1_000, 0).unwrap_err(),
+            SwapError::InsufficientLiquidity.into()
+        );
+        let liquidity = 1_000_000_000_000;
+        let in_range = open_test_position(&mut pool, -600, 600, liquidity);
+        let above = open_test_position(&mut pool, 1_200, 1_800, liquidity);
+        let reserves = (pool.reserve_a, pool.reserve_b);
+        pool.donate(1_000_000, 0).unwrap();
+        assert_eq!((pool.reserve_a, pool.reserve_b), reserves);
+        assert_eq!(pool.position_fees_a, 1_000_000);
+        let fees = |lower, upper, inside: (u128, u128)| {
+            pool.position_fees(lower, upper, liquidity, inside.0, inside.1)
+                .unwrap()
+        };
+        assert!(1_000_000 - fees(-600, 600, in_range).0 <= 1);
+        assert_eq!(fees(1_200, 1_800, above), (0, 0));
+    }
+
+    #[test]
+    fn consult_interpolates_between_observations() {
+        let mut pool = test_pool(1_000_000, 1_000_000);
+        pool.write_observation(0);
+
+        // 1:1 for the first 100 seconds, then 2 B per A
+        pool.update_oracle(100);
+        pool.reserve_b = 2_000_000;
+        pool.update_oracle(200);
+
+        // [50, 250] is 50 seconds at 1 and 150 at 2, the last 50 extrapolated
+        // from the current reserves
+        let twap = pool.twap(250, 200).unwrap();
+        assert_eq!(twap.price_a_in_b, PRICE_SCALE * 7 / 4);
+        assert_eq!(pool.twap(250, 150).unwrap().price_a_in_b, PRICE_SCALE * 2);
+        assert_eq!(pool.twap(100, 100).unwrap().price_a_in_b, PRICE_SCALE);
+        assert_eq!(
+            twap.price_b_in_a,
+            (PRICE_SCALE * 50 + PRICE_SCALE / 2 * 150) / 200
+        );
+    }
+
+    #[test]
+    fn observation_ring_buffer_keeps_the_latest_entries() {
+        let mut pool = test_pool(1_000_000, 1_000_000);
+        pool.write_observation(0);
+        for now in 1..=20 {
+            pool.update_oracle(now * 10);
+        }
+        assert_eq!(pool.observation_count as usize, OBSERVATION_COUNT);
+
+        // The oldest surviving observation is at 50, 15 updates before the newest
+        assert_eq!(pool.twap(200, 150).unwrap().price_a_in_b, PRICE_SCALE);
+        assert_eq!(
+            pool.twap(200, 151).unwrap_err(),
+            SwapError::ObservationTooOld.into()
+        );
+        assert_eq!(pool.twap(200, 0).unwrap_err(), SwapError::ZeroAmount.into());
+    }
+
+    #[cfg(feature = "strict-checks")]
+    #[test]
+    fn invariant_check_catches_corrupted_reserves() {
+        let mut pool = test_pool(1_000_000, 1_000_000);
+        pool.protocol_fee_bps = 5;
+        let before = pool.invariant().unwrap();
+
+        let amount_out = pool.swap_output(10_000, true).unwrap();
+        pool.apply_swap(
+            10_000,
+            amount_out,
+            true,
+            pool.fee_split(10_000, true, false).unwrap(),
+        )
+        .unwrap();
+        let (vault_a, vault_b) = (1_010_000, 1_000_000 - amount_out);
+        assert!(pool
+            .assert_invariant(Some(before), vault_a, vault_b)
+            .is_ok());
+
+        // Paying out one token too many shrinks x * y
+        let mut overpaid = pool.clone();
+        overpaid.reserve_b -= 1_000;
+        assert_eq!(
+            overpaid
+                .assert_invariant(Some(before), vault_a, vault_b)
+                .unwrap_err(),
+            SwapError::InvariantViolated.into()
+        );
+
+        // Reserves plus protocol fees claiming more than the vault holds
+        let mut inflated = pool.clone();
+        inflated.reserve_a += 1;
+        assert_eq!(
+            inflated
+                .assert_invariant(None, vault_a, vault_b)
+                .unwrap_err(),
+            SwapError::InvariantViolated.into()
+        );
+    }
+
+    #[test]
+    fn reentrancy_guard_rejects_a_nested_entry() {
+        let mut pool = test_pool(1_000_000, 1_000_000);
+        pool.lock().unwrap();
+
+        // A nested swap or liquidity call would hit the lock the outer one holds
+        assert_eq!(pool.lock().unwrap_err(), SwapError::Reentrancy.into());
+
+        pool.unlock();
+        assert!(pool.lock().is_ok());
+    }
+
+    #[test]
+    fn protocol_fee_switch_off_leaves_the_cut_with_lps() {
+        let mut on = test_pool(1_000_000, 1_000_000);
+        on.protocol_fee_bps = 10;
+        let mut off = on.clone();
+        let amount_out = on.swap_output(100_000, true).unwrap();
+
+        on.apply_swap(
+            100_000,
+            amount_out,
+            true,
+            on.fee_split(100_000, true, false).unwrap(),
+        )
+        .unwrap();
+        off.apply_swap(
+            100_000,
+            amount_out,
+            true,
+            off.fee_split(100_000, false, false).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(on.protocol_fees_a, 100);
+        assert_eq!(on.reserve_a, 1_099_900);
+        assert_eq!(off.protocol_fees_a, 0);
+        assert_eq!(off.reserve_a, 1_100_000);
+        // The trader gets the same either way
+        assert_eq!(on.reserve_b, off.reserve_b);
+    }
+
+    #[test]
+    fn untracked_balance_excludes_reserves_and_protocol_fees() {
+        let mut pool = test_pool(1_000_000, 2_000_000);
+        pool.protocol_fees_a = 300;
+
+        assert_eq!(pool.untracked_balance(1_000_300, true), 0);
+        assert_eq!(pool.untracked_balance(1_005_300, true), 5_000);
+        assert_eq!(pool.untracked_balance(2_000_001, false), 1);
+
+        // A vault short of its books (e.g. mid flash loan) has nothing to skim
+        assert_eq!(pool.untracked_balance(500_000, true), 0);
+    }
+
+    #[test]
+    fn sync_counts_donations_but_not_protocol_fees() {
+        let mut pool = test_pool(1_000_000, 2_000_000);
+        pool.protocol_fees_a = 300;
+
+        pool.sync_reserves(1_005_300, 2_000_000);
+        assert_eq!(pool.reserve_a, 1_005_000);
+        assert_eq!(pool.reserve_b, 2_000_000);
+        assert_eq!(pool.protocol_fees_a, 300);
+
+        // A vault that can't cover its fees leaves nothing for the LPs
+        pool.sync_reserves(200, 2_000_000);
+        assert_eq!(pool.reserve_a, 0);
+        assert_eq!(pool.protocol_fees_a, 200);
+    }
+
+    #[test]
+    fn flash_loan_fee_rounds_up() {
+        assert_eq!(calculate_flash_loan_fee(1_000_000, 30).unwrap(), 3_000);
+        assert_eq!(calculate_flash_loan_fee(1, 30).unwrap(), 1);
+        assert_eq!(calculate_flash_loan_fee(1_000, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn sequential_deposits_mint_proportional_lp() {
+        // First deposit into an empty pool mints sqrt(a * b), less the locked minimum
+        let first = calculate_lp_to_mint(1_000_000, 4_000_000, 0, 0, 0).unwrap();
+        assert_eq!(first, 2_000_000 - MINIMUM_LIQUIDITY);
+        let supply = first + MINIMUM_LIQUIDITY;
+
+        // A second deposit at the same ratio gets the same share of the supply
+        let second =
+            calculate_lp_to_mint(500_000, 2_000_000, 1_000_000, 4_000_000, supply).unwrap();
+        assert_eq!(second, 1_000_000);
+
+        // An unbalanced deposit is credited by its scarcer side
+        let skewed =
+            calculate_lp_to_mint(500_000, 8_000_000, 1_500_000, 6_000_000, supply + second)
+                .unwrap();
+        assert_eq!(skewed, 1_000_000);
+    }
+
+    #[test]
+    fn deposits_are_fitted_to_the_pool_ratio() {
+        // An empty pool takes whatever ratio the first depositor sets
+        assert_eq!(
+            calculate_deposit_amounts(100, 900, 0, 0).unwrap(),
+            (100, 900)
+        );
+
+        // Too much B: all of A is used, B is cut back to the 1:4 ratio
+        assert_eq!(
+            calculate_deposit_amounts(1_000, 10_000, 1_000_000, 4_000_000).unwrap(),
+            (1_000, 4_000)
+        );
+
+        // Too much A: all of B is used, A is cut back
+        assert_eq!(
+            calculate_deposit_amounts(10_000, 4_000, 1_000_000, 4_000_000).unwrap(),
+            (1_000, 4_000)
+        );
+
+        // Rounding never pulls more than the caller allowed
+        let (a, b) = calculate_deposit_amounts(333, 1_000, 3_000, 7_000).unwrap();
+        assert!(a <= 333 && b <= 1_000);
+    }
+
+    #[test]
+    fn rounding_modes_favour_the_pool() {
+        assert_eq!(mul_div(7, 3, 2, RoundingMode::Down).unwrap(), 10);
+        assert_eq!(mul_div(7, 3, 2, RoundingMode::Up).unwrap(), 11);
+        assert_eq!(mul_div(8, 3, 2, RoundingMode::Up).unwrap(), 12);
+        assert!(mul_div(1, 1, 0, RoundingMode::Down).is_err());
+        assert!(mul_div(u128::MAX, 2, 1, RoundingMode::Down).is_err());
+
+        // Payouts and mints round down, charges round up
+        assert_eq!(calculate_withdraw_amount(1, 3, 2).unwrap(), 1);
+        assert_eq!(calculate_lp_to_mint(1, 1, 3, 3, 2).unwrap(), 0);
+        assert_eq!(calculate_flash_loan_fee(1, 1).unwrap(), 1);
+        assert_eq!(calculate_deposit_amounts(1, 10, 3, 2).unwrap(), (1, 1));
+    }
+
+    // xorshift64, enough randomness for the property test below without a
+    // dependency
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn rounding_never_shrinks_the_invariant_per_lp_share() {
+        for (curve_type, fee_bps) in [
+            (CurveType::ConstantProduct, 0),
+            (CurveType::ConstantProduct, DEFAULT_FEE_BPS),
+            (CurveType::Stable { amp: 100 }, 0),
+            (CurveType::Stable { amp: 100 }, DEFAULT_FEE_BPS),
+        ] {
+            let mut pool = test_pool(1_000_000, 1_000_000);
+            pool.curve_type = curve_type;
+            pool.fee_bps = fee_bps;
+            pool.protocol_fee_bps = fee_bps / 6;
+            let mut supply = 1_000_000u64;
+            let mut rng = 0x9E37_79B9_7F4A_7C15u64;
+
+            // x * y per LP share squared, or D per LP share, compared by
+            // cross-multiplying. D gets one unit of slack for Newton rounding.
+            let invariant = |pool: &Pool| match pool.curve_type {
+                CurveType::ConstantProduct => {
+                    constant_product_invariant(pool.reserve_a, pool.reserve_b)
+                }
+                CurveType::Stable { amp } => {
+                    compute_stable_d(pool.reserve_a as u128, pool.reserve_b as u128, amp).unwrap()
+                }
+                CurveType::Concentrated => unreachable!(),
+            };
+            let per_share_holds = |before: u128, s_before: u64, after: u128, s_after: u64| {
+                let (s_before, s_after) = (s_before as u128, s_after as u128);
+                match curve_type {
+                    CurveType::ConstantProduct => {
+                        after * s_before * s_before >= before * s_after * s_after
+                    }
+                    CurveType::Stable { .. } => (after + 1) * s_before >= before * s_after,
+                    CurveType::Concentrated => unreachable!(),
+                }
+            };
+
+            for _ in 0..5_000 {
+                let before = invariant(&pool);
+                let supply_before = supply;
+                let a_to_b = next_random(&mut rng).is_multiple_of(2);
+                let (reserve_in, reserve_out) = if a_to_b {
+                    (pool.reserve_a, pool.reserve_b)
+                } else {
+                    (pool.reserve_b, pool.reserve_a)
+                };
+
+                match next_random(&mut rng) % 4 {
+                    0 => {
+                        let amount_in = next_random(&mut rng) % (reserve_in / 100) + 1;
+                        let amount_out = pool.swap_output(amount_in, a_to_b).unwrap();
+                        pool.apply_swap(
+                            amount_in,
+                            amount_out,
+                            a_to_b,
+                            pool.fee_split(amount_in, true, false).unwrap(),
+                        )
+                        .unwrap();
+                    }
+                    1 => {
+                        let amount_out = next_random(&mut rng) % (reserve_out / 100) + 1;
+                        let amount_in = pool.swap_input(amount_out, a_to_b).unwrap();
+                        pool.apply_swap(
+                            amount_in,
+                            amount_out,
+                            a_to_b,
+                            pool.fee_split(amount_in, true, false).unwrap(),
+                        )
+                        .unwrap();
+                    }
+                    2 => {
+                        let max_a = next_random(&mut rng) % (pool.reserve_a / 50) + 1;
+                        let max_b = next_random(&mut rng) % (pool.reserve_b / 50) + 1;
+                        let (amount_a, amount_b) =
+                            calculate_deposit_amounts(max_a, max_b, pool.reserve_a, pool.reserve_b)
+                                .unwrap();
+                        let lp = calculate_lp_to_mint(
+                            amount_a,
+                            amount_b,
+                            pool.reserve_a,
+                            pool.reserve_b,
+                            supply,
+                        )
+                        .unwrap();
+                        pool.reserve_a += amount_a;
+                        pool.reserve_b += amount_b;
+                        supply += lp;
+                    }
+                    _ => {
+                        let lp = next_random(&mut rng) % (supply / 50) + 1;
+                        pool.reserve_a -=
+                            calculate_withdraw_amount(lp, pool.reserve_a, supply).unwrap();
+                        pool.reserve_b -=
+                            calculate_withdraw_amount(lp, pool.reserve_b, supply).unwrap();
+                        supply -= lp;
+                    }
+                }
+
+                assert!(
+                    per_share_holds(before, supply_before, invariant(&pool), supply),
+                    "{curve_type:?} at {fee_bps} bps lost value per LP share"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn add_then_remove_returns_deposit_up_to_rounding() {
+        let (mut reserve_a, mut reserve_b) = (1_000_000u64, 4_000_000u64);
+        let mut supply =
+            calculate_lp_to_mint(reserve_a, reserve_b, 0, 0, 0).unwrap() + MINIMUM_LIQUIDITY;
+
+        let (deposit_a, deposit_b) = (333_333u64, 1_333_332u64);
+        let minted =
+            calculate_lp_to_mint(deposit_a, deposit_b, reserve_a, reserve_b, supply).unwrap();
+        reserve_a += deposit_a;
+        reserve_b += deposit_b;
+        supply += minted;
+
+        let out_a = calculate_withdraw_amount(minted, reserve_a, supply).unwrap();
+        let out_b = calculate_withdraw_amount(minted, reserve_b, supply).unwrap();
+
+        // The pool never pays out more than was put in, and only loses dust to rounding
+        assert!(out_a <= deposit_a && deposit_a - out_a <= 1);
+        assert!(out_b <= deposit_b && deposit_b - out_b <= 1);
+    }
+
+    #[test]
+    fn first_deposit_must_exceed_minimum_liquidity() {
+        assert!(calculate_lp_to_mint(1_000, 1_000, 0, 0, 0).is_err());
+        assert!(calculate_lp_to_mint(0, 1_000_000, 0, 0, 0).is_err());
+        assert_eq!(calculate_lp_to_mint(1_001, 1_001, 0, 0, 0).unwrap(), 1);
+    }
+
+    #[test]
+    fn inflating_the_share_price_is_unprofitable() {
+        // The attacker seeds the pool with the smallest accepted deposit
+        let (attacker_a, attacker_b) = (1_001u64, 1_001u64);
+        let attacker_lp = calculate_lp_to_mint(attacker_a, attacker_b, 0, 0, 0).unwrap();
+        let mut supply = attacker_lp + MINIMUM_LIQUIDITY;
+
+        // ...then pumps the reserves so later deposits round down against the victim
+        let donation = 1_000_000u64;
+        let (mut reserve_a, mut reserve_b) = (attacker_a + donation, attacker_b + donation);
+
+        let victim_lp =
+            calculate_lp_to_mint(1_000_000, 1_000_000, reserve_a, reserve_b, supply).unwrap();
+        assert!(victim_lp > 0);
+        reserve_a += 1_000_000;
+        reserve_b += 1_000_000;
+        supply += victim_lp;
+
+        // Most of the donation accrues to the locked shares, so the attacker
+        // withdraws far less than they put in
+        let out_a = calculate_withdraw_amount(attacker_lp, reserve_a, supply).unwrap();
+        let out_b = calculate_withdraw_amount(attacker_lp, reserve_b, supply).unwrap();
+        assert!(out_a < attacker_a + donation);
+        assert!(out_b < attacker_b + donation);
+    }
+
+    #[test]
+    fn swap_fee_reduces_output() {
+        let no_fee = calculate_swap_output(10_000, 1_000_000, 1_000_000, 0).unwrap();
+        let with_fee = calculate_swap_output(10_000, 1_000_000, 1_000_000, 30).unwrap();
+        assert_eq!(no_fee, 9_900);
+        assert_eq!(with_fee, 9_871);
+        assert!(with_fee < no_fee);
+    }
+
+    #[test]
+    fn swap_fee_matches_what_the_curve_withholds() {
+        // 0.3% of 10_000 is exact; 0.3% of 1_001 rounds against the trader
+        assert_eq!(calculate_swap_fee(10_000, 30), 30);
+        assert_eq!(calculate_swap_fee(1_001, 30), 4);
+        assert_eq!(calculate_swap_fee(1_000, 0), 0);
+        assert_eq!(calculate_swap_fee(u64::MAX, 10_000), u64::MAX);
+    }
+
+    #[test]
+    fn swap_fee_grows_reserves() {
+        let (reserve_in, reserve_out) = (1_000_000u64, 1_000_000u64);
+        let amount_in = 50_000u64;
+        let amount_out = calculate_swap_output(amount_in, reserve_in, reserve_out, 30).unwrap();
+
+        let k_before = reserve_in as u128 * reserve_out as u128;
+        let k_after = (reserve_in + amount_in) as u128 * (reserve_out - amount_out) as u128;
+        assert!(k_after > k_before);
+    }
+
+    #[test]
+    fn swap_output_never_drains_the_reserve() {
+        // An empty input side would otherwise pay out the entire output reserve
+        assert!(calculate_swap_output(1_000, 0, 1_000_000, 30).is_err());
+        assert!(calculate_swap_output(1_000, 1_000_000, 0, 30).is_err());
+
+        let out = calculate_swap_output(u64::MAX / 2, 10, 1_000, 0).unwrap();
+        assert!(out < 1_000);
+    }
+
+    #[test]
+    fn swap_math_handles_near_max_reserves() {
+        // amount_in * reserve_out is ~2^127 here, far past what u64 can hold
+        let reserve_in = u64::MAX / 2;
+        let reserve_out = u64::MAX - 1;
+        let amount_in = u64::MAX / 2;
+
+        // Doubling the input reserve buys just under half the output reserve
+        let out = calculate_swap_output(amount_in, reserve_in, reserve_out, 30).unwrap();
+        assert!(out < reserve_out / 2);
+        assert!(out > reserve_out / 100 * 49);
+
+        let amount_needed = calculate_swap_input(out, reserve_in, reserve_out, 30).unwrap();
+        assert!(amount_needed <= amount_in);
+        assert!(calculate_swap_output(amount_needed, reserve_in, reserve_out, 30).unwrap() >= out);
+
+        // A result that can't fit back into u64 is an error rather than a truncation
+        assert!(calculate_swap_input(reserve_out - 1, u64::MAX, reserve_out, 30).is_err());
+    }
+
+    #[test]
+    fn swap_input_buys_at_least_the_requested_output() {
+        for (amount_out, reserve_in, reserve_out, fee_bps) in [
+            (9_871u64, 1_000_000u64, 1_000_000u64, 30u16),
+            (1, 5_000_000, 3_000, 30),
+            (250_000, 4_000_000, 1_000_000, 100),
+            (999_999, 1_000_000, 1_000_000, 0),
+        ] {
+            let amount_in =
+                calculate_swap_input(amount_out, reserve_in, reserve_out, fee_bps).unwrap();
+            let out = calculate_swap_output(amount_in, reserve_in, reserve_out, fee_bps).unwrap();
+            assert!(out >= amount_out);
+        }
+    }
+
+    #[test]
+    fn swap_input_rejects_draining_the_reserve() {
+        assert!(calculate_swap_input(1_000_000, 1_000_000, 1_000_000, 30).is_err());
+        assert!(calculate_swap_input(1_000_001, 1_000_000, 1_000_000, 30).is_err());
+    }
+
+    #[test]
+    fn stable_curve_prices_tighter_near_parity() {
+        let (reserve_in, reserve_out) = (1_000_000_000u64, 1_000_000_000u64);
+        for amount_in in [1_000u64, 1_000_000, 50_000_000] {
+            let constant_product =
+                calculate_swap_output(amount_in, reserve_in, reserve_out, 0).unwrap();
+            let stable =
+                calculate_output_stable(amount_in, reserve_in, reserve_out, 0, 100).unwrap();
+            assert!(stable >= constant_product);
+            assert!(stable <= amount_in);
+        }
+
+        // A 5% trade loses ~5% on x * y = k but stays within 0.1% on the stable curve
+        let stable = calculate_output_stable(50_000_000, reserve_in, reserve_out, 0, 100).unwrap();
+        assert!(stable > 49_950_000);
+        let constant_product =
+            calculate_swap_output(50_000_000, reserve_in, reserve_out, 0).unwrap();
+        assert!(constant_product < 48_000_000);
+    }
+
+    #[test]
+    fn stable_swap_never_shrinks_the_invariant() {
+        let (reserve_in, reserve_out) = (3_000_000u64, 1_000_000u64);
+        let amp = 50;
+        let d_before = compute_stable_d(reserve_in as u128, reserve_out as u128, amp).unwrap();
+
+        let amount_in = 500_000;
+        let out = calculate_output_stable(amount_in, reserve_in, reserve_out, 0, amp).unwrap();
+        assert!(out < reserve_out);
+        let d_after = compute_stable_d(
+            (reserve_in + amount_in) as u128,
+            (reserve_out - out) as u128,
+            amp,
+        )
+        .unwrap();
+        assert!(d_after >= d_before);
+    }
+
+    #[test]
+    fn stable_input_buys_at_least_the_requested_output() {
+        for (amount_out, reserve_in, reserve_out, fee_bps, amp) in [
+            (10_000u64, 1_000_000u64, 1_000_000u64, 4u16, 100u64),
+            (1, 5_000_000, 3_000_000, 30, 10),
+            (900_000, 4_000_000, 1_000_000, 0, 1),
+            (999_000, 1_000_000, 1_000_000, 30, 2_000),
+        ] {
+            let amount_in =
+                calculate_input_stable(amount_out, reserve_in, reserve_out, fee_bps, amp).unwrap();
+            let out =
+                calculate_output_stable(amount_in, reserve_in, reserve_out, fee_bps, amp).unwrap();
+            assert!(out >= amount_out);
+        }
+    }
+
+    #[test]
+    fn price_impact_grows_with_trade_size() {
+        let (reserve_in, reserve_out) = (1_000_000u64, 1_000_000u64);
+        let impact = |amount_in| {
+            let out = calculate_swap_output(amount_in, reserve_in, reserve_out, 0).unwrap();
+            calculate_price_impact_bps(amount_in, out, reserve_in, reserve_out).unwrap()
+        };
+
+        // A 1% trade moves the price by ~1%, a trade the size of the pool by ~50%
+        assert_eq!(impact(10_000), 100);
+        assert_eq!(impact(1_000_000), 5_000);
+        assert!(impact(10_000) < impact(100_000));
+
+        // The fee is part of the cost of execution
+        let out = calculate_swap_output(10_000, reserve_in, reserve_out, 30).unwrap();
+        assert!(calculate_price_impact_bps(10_000, out, reserve_in, reserve_out).unwrap() > 100);
+    }
+
+    #[test]
+    fn spot_price_normalizes_decimals() {
+        // 1_000 A (6 decimals) against 2_000 B (9 decimals): 1 A = 2 B
+        let reserve_a = 1_000 * 10u64.pow(6);
+        let reserve_b = 2_000 * 10u64.pow(9);
+
+        let a_in_b = calculate_spot_price(reserve_a, 6, reserve_b, 9).unwrap();
+        let b_in_a = calculate_spot_price(reserve_b, 9, reserve_a, 6).unwrap();
+        assert_eq!(a_in_b, 2 * PRICE_SCALE);
+        assert_eq!(b_in_a, PRICE_SCALE / 2);
+    }
+
+    #[test]
+    fn single_sided_swap_leaves_a_balanced_deposit() {
+        let mut pool = test_pool(1_000_000, 4_000_000);
+        let deposit = 100_000;
+
+        let swap_in = calculate_single_sided_swap(deposit, pool.reserve_a, pool.fee_bps).unwrap();
+        let swap_out = pool.swap_output(swap_in, true).unwrap();
+        pool.apply_swap(
+            swap_in,
+            swap_out,
+            true,
+            pool.fee_split(swap_in, true, false).unwrap(),
+        )
+        .unwrap();
+
+        // Slightly under half goes through the swap, and what is left matches
+        // the new reserve ratio to within rounding
+        assert!(swap_in < deposit / 2 && swap_in > deposit * 45 / 100);
+        let lhs = (deposit - swap_in) as u128 * pool.reserve_b as u128;
+        let rhs = swap_out as u128 * pool.reserve_a as u128;
+        assert!(lhs.abs_diff(rhs) * 10_000 < rhs);
+
+        // Without a fee the split is R (sqrt(1 + a / R) - 1)
+        assert_eq!(
+            calculate_single_sided_swap(3_000_000, 1_000_000, 0).unwrap(),
+            1_000_000
+        );
+
+        // Large values are scaled down instead of overflowing
+        let swap_in = calculate_single_sided_swap(u64::MAX / 2, u64::MAX / 2, 0).unwrap();
+        let expected = ((u64::MAX / 2) as f64 * (2f64.sqrt() - 1.0)) as u64;
+        assert!(swap_in.abs_diff(expected) < expected / 1_000_000);
+    }
+
+    #[test]
+    fn mint_whitelist_adds_once_and_caps_its_size() {
+        let mut whitelist = MintWhitelist {
+            authority: Pubkey::default(),
+            enabled: true,
+            mints: Vec::new(),
+            bump: 0,
+        };
+        let mint = Pubkey::new_unique();
+        whitelist.add(mint).unwrap();
+        whitelist.add(mint).unwrap();
+        assert_eq!(whitelist.mints, vec![mint]);
+        assert!(whitelist.allows(&mint));
+
+        whitelist.remove(mint);
+        assert!(!whitelist.allows(&mint));
+
+        for _ in 0..MAX_WHITELISTED_MINTS {
+            whitelist.add(Pubkey::new_unique()).unwrap();
+        }
+        assert_eq!(
+            whitelist.add(Pubkey::new_unique()).unwrap_err(),
+            SwapError::WhitelistFull.into()
+        );
+    }
+
+    #[test]
+    fn last_swap_enforces_the_interval_between_swaps() {
+        let mut last_swap = LastSwap {
+            timestamp: 0,
+            bump: 0,
+        };
+        last_swap.record(1_000, 60).unwrap();
+        assert_eq!(
+            last_swap.record(1_059, 60).unwrap_err(),
+            SwapError::SwapTooSoon.into()
+        );
+        // The failed attempt doesn't restart the cooldown
+        assert_eq!(last_swap.timestamp, 1_000);
+        last_swap.record(1_060, 60).unwrap();
+        // Without a cooldown any swap is recorded
+        last_swap.record(1_060, 0).unwrap();
+        assert_eq!(last_swap.timestamp, 1_060);
+    }
+
+    #[test]
+    fn global_config_defaults_obey_the_pool_fee_limits() {
+        let mut config = GlobalConfig {
+            admin: Pubkey::default(),
+            default_fee_bps: 0,
+            default_protocol_fee_bps: 0,
+            protocol_fee_on: true,
+            bump: 0,
+        };
+        config.set_defaults(30, 5).unwrap();
+        assert_eq!(
+            (config.default_fee_bps, config.default_protocol_fee_bps),
+            (30, 5)
+        );
+
+        for (fee_bps, protocol_fee_bps, error) in [
+            (MAX_FEE_BPS + 1, 0, SwapError::InvalidFeeTier),
+            (25, 5, SwapError::InvalidFeeTier),
+            (30, 31, SwapError::InvalidFee),
+        ] {
+            assert_eq!(
+                config.set_defaults(fee_bps, protocol_fee_bps).unwrap_err(),
+                error.into()
+            );
+        }
+        // A rejected update leaves the previous defaults
+        assert_eq!(
+            (config.default_fee_bps, config.default_protocol_fee_bps),
+            (30, 5)
+        );
+    }
+
+    #[test]
+    fn decimals_range_is_inclusive() {
+        assert!(!supported_decimals(MIN_MINT_DECIMALS - 1));
+        assert!(supported_decimals(MIN_MINT_DECIMALS));
+        assert!(supported_decimals(MAX_MINT_DECIMALS));
+        assert!(!supported_decimals(MAX_MINT_DECIMALS + 1));
+    }
+
+    #[test]
+    fn price_tolerance_is_symmetric_and_inclusive() {
+        let expected = 2 * PRICE_SCALE;
+
+        // 1% either side of 2.0 is [1.98, 2.02]
+        assert!(price_within_tolerance(expected, expected, 0).unwrap());
+        assert!(price_within_tolerance(expected * 101 / 100, expected, 100).unwrap());
+        assert!(price_within_tolerance(expected * 99 / 100, expected, 100).unwrap());
+        assert!(!price_within_tolerance(expected * 101 / 100 + 1, expected, 100).unwrap());
+        assert!(!price_within_tolerance(expected * 99 / 100 - 1, expected, 100).unwrap());
+
+        // A deposit with the ratio typed backwards is far outside any sane band
+        let reversed = calculate_spot_price(2_000, 6, 1_000, 6).unwrap();
+        let intended = calculate_spot_price(1_000, 6, 2_000, 6).unwrap();
+        assert!(!price_within_tolerance(reversed, intended, 500).unwrap());
+    }
+
+    #[test]
+    fn price_accumulator_advances_with_time() {
+        // Price of A is 2 B for 10 seconds, then 4 B for 5 seconds
+        let mut cumulative = accumulate_price(0, 1_000, 2_000, 10);
+        assert_eq!(cumulative, 20 * PRICE_SCALE);
+        cumulative = accumulate_price(cumulative, 500, 2_000, 5);
+        assert_eq!(cumulative, 40 * PRICE_SCALE);
+
+        // TWAP over the whole window is the cumulative delta over elapsed time
+        assert_eq!(cumulative / 15, 40 * PRICE_SCALE / 15);
+
+        // Wrapping keeps differences meaningful across overflow
+        let near_max = u128::MAX - PRICE_SCALE;
+        let wrapped = accumulate_price(near_max, 1, 1, 3);
+        assert_eq!(wrapped.wrapping_sub(near_max), 3 * PRICE_SCALE);
+    }
+
+    #[test]
+    fn integer_sqrt_rounds_down() {
+        assert_eq!(integer_sqrt(0), 0);
+        assert_eq!(integer_sqrt(1), 1);
+        assert_eq!(integer_sqrt(15), 3);
+        assert_eq!(integer_sqrt(16), 4);
+        assert_eq!(integer_sqrt(u128::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn integer_sqrt_is_exact_around_perfect_squares() {
+        let roots = (1..=10_000u64).chain([
+            u32::MAX as u64,
+            1 << 32,
+            1_000_000_000_007,
+            u64::MAX / 3,
+            u64::MAX - 1,
+        ]);
+        for root in roots {
+            let square = root as u128 * root as u128;
+            assert_eq!(integer_sqrt(square), root);
+            assert_eq!(integer_sqrt(square - 1), root - 1);
+            assert_eq!(integer_sqrt(square + 1), root);
+        }
+        // Every value from u64::MAX^2 up has the largest root
+        let max_square = u64::MAX as u128 * u64::MAX as u128;
+        assert_eq!(integer_sqrt(max_square - 1), u64::MAX - 1);
+        for value in [max_square, max_square + 1, u128::MAX - 1, u128::MAX] {
+            assert_eq!(integer_sqrt(value), u64::MAX);
+        }
+    }
+
+    #[test]
+    fn distribute_fee_splits_the_fee_by_the_configured_cuts() {
+        let split = |lp, protocol, referral| FeeSplit {
+            lp,
+            protocol,
+            referral,
+        };
+        // All to the LPs
+        assert_eq!(
+            distribute_fee(1_000_000, 30, 0, 0).unwrap(),
+            split(3_000, 0, 0)
+        );
+        // A protocol cut, a referral cut, and both
+        assert_eq!(
+            distribute_fee(1_000_000, 30, 5, 0).unwrap(),
+            split(2_500, 500, 0)
+        );
+        assert_eq!(
+            distribute_fee(1_000_000, 30, 0, 10).unwrap(),
+            split(2_000, 0, 1_000)
+        );
+        assert_eq!(
+            distribute_fee(1_000_000, 30, 5, 10).unwrap(),
+            split(1_500, 500, 1_000)
+        );
+        // Cuts taking the whole fee leave the LPs nothing
+        assert_eq!(
+            distribute_fee(1_000_000, 30, 20, 10).unwrap(),
+            split(0, 2_000, 1_000)
+        );
+        // The cuts round down and the fee up, so the LPs keep the dust
+        let dusty = distribute_fee(999, 30, 5, 10).unwrap();
+        assert_eq!(dusty, split(3, 0, 0));
+        assert_eq!(dusty.total(), calculate_swap_fee(999, 30));
+
+        // Cuts adding up to more than the fee
+        for (protocol, referral) in [(31, 0), (0, 31), (20, 11), (u16::MAX, u16::MAX)] {
+            assert_eq!(
+                distribute_fee(1_000_000, 30, protocol, referral).unwrap_err(),
+                SwapError::InvalidFee.into()
+            );
+        }
+    }
+
+    #[test]
+    fn fee_split_drops_the_cuts_that_do_not_apply() {
+        let mut pool = test_pool(1_000_000, 1_000_000);
+        pool.fee_bps = 30;
+        pool.protocol_fee_bps = 5;
+        pool.referral_fee_bps = 10;
+        let fees = |on, referred| pool.fee_split(1_000_000, on, referred).unwrap();
+        assert_eq!(
+            (fees(true, true).protocol, fees(true, true).referral),
+            (500, 1_000)
+        );
+        assert_eq!(fees(false, true).protocol, 0);
+        assert_eq!(fees(true, false).referral, 0);
+        assert_eq!(fees(false, false).lp, 3_000);
+    }
+
+    #[test]
+    fn flash_swap_repaid_with_the_quoted_input_settles() {
+        let amount_out = calculate_swap_output(10_000, 1_000_000, 1_000_000, 30).unwrap();
+        let flash_swap = |amount_out: u64| {
+            let mut pool = test_pool(1_000_000, 1_000_000);
+            pool.flash_loan_active = true;
+            pool.flash_swap = true;
+            pool.flash_loan_a_token = false;
+            pool.flash_loan_amount = amount_out;
+            pool
+        };
+
+        // Paying the input a swap would have charged keeps k, fee included
+        let mut pool = flash_swap(amount_out);
+        pool.settle_flash_swap(10_000, 0).unwrap();
+        assert_eq!(pool.reserve_a, 1_010_000);
+        assert_eq!(pool.reserve_b, 1_000_000 - amount_out);
+        assert!(pool.reserve_a as u128 * pool.reserve_b as u128 >= 1_000_000u128 * 1_000_000);
+
+        // Short of what the curve and fee ask for
+        let mut pool = flash_swap(10_000);
+        assert_eq!(
+            pool.settle_flash_swap(10_131, 0).unwrap_err(),
+            SwapError::FlashLoanNotRepaid.into()
+        );
+        pool.settle_flash_swap(10_132, 0).unwrap();
+
+        // Handing the output back pays the fee on the returned amount too
+        let mut pool = flash_swap(10_000);
+        assert!(pool.settle_flash_swap(0, 10_000).is_err());
+        pool.settle_flash_swap(0, 10_031).unwrap();
+        assert_eq!(pool.reserve_b, 1_000_031);
+    }
+
+    #[test]
+    fn only_labeled_pools_add_a_seed_to_their_address() {
+        let (mint_a, mint_b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let fee_tier = DEFAULT_FEE_BPS.to_le_bytes();
+        let address = |label: &[u8; 8]| {
+            Pubkey::find_program_address(
+                &[
+                    b"pool",
+                    mint_a.as_ref(),
+                    mint_b.as_ref(),
+                    &fee_tier,
+                    label_seed(label),
+                ],
+                &crate::ID,
+            )
+            .0
+        };
+        let unlabeled = Pubkey::find_program_address(
+            &[b"pool", mint_a.as_ref(), mint_b.as_ref(), &fee_tier],
+            &crate::ID,
+        )
+        .0;
+
+        // The canonical pool keeps the address it had before labels
+        assert_eq!(address(&[0; 8]), unlabeled);
+        let (first, second) = (address(b"strat-01"), address(b"strat-02"));
+        assert_ne!(first, unlabeled);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn migrate_upgrades_a_pool_saved_before_the_version_field() {
+        // Every optional field set and a full tick list, so the serialized
+        // pool fills its whole account
+        let mut pool = test_pool(1_000, 2_000);
+        pool.pending_authority = Some(Pubkey::new_unique());
+        pool.fee_band = Some(FeeBand {
+            min_fee_bps: 5,
+            max_fee_bps: 100,
+        });
+        pool.curve_type = CurveType::Stable { amp: 100 };
+        pool.ticks = vec![Tick::default(); MAX_TICKS];
+        pool.version = 0;
+        let mut data = Vec::new();
+        pool.try_serialize(&mut data).unwrap();
+        assert_eq!(data.len(), 8 + Pool::INIT_SPACE);
+
+        // The version 0 layout ended just before `version`, so it lacks the
+        // last 29 bytes (`version`, `flash_swap`, `label`, `compute_guard`,
+        // the swap limits, and `tick_spacing`)
+        data.truncate(data.len() - 29);
+        assert!(Pool::try_deserialize(&mut &data[..]).is_err());
+
+        // What migrate_pool's resize leaves behind
+        let allocated = data.len();
+        data.extend([0; 29]);
+        clear_pool_slack(&mut data, allocated).unwrap();
+        let mut migrated = Pool::try_deserialize(&mut &data[..]).unwrap();
+        assert_eq!(migrated.version, 0);
+        migrated.migrate().unwrap();
+        assert_eq!(migrated.version, POOL_VERSION);
+        assert_eq!(migrated.reserve_a, 1_000);
+        assert_eq!(migrated.reserve_b, 2_000);
+        assert_eq!(migrated.ticks.len(), MAX_TICKS);
+
+        assert_eq!(
+            migrated.migrate().unwrap_err(),
+            SwapError::PoolUpToDate.into()
+        );
+
+        // Concentrated pools from before per-pool spacing keep the old one
+        let mut concentrated = concentrated_pool();
+        concentrated.tick_spacing = 0;
+        concentrated.version = 5;
+        concentrated.migrate().unwrap();
+        assert_eq!(i32::from(concentrated.tick_spacing), TICK_SPACING);
+        assert_eq!(migrated.tick_spacing, 0);
+    }
+
+    #[test]
+    fn migrate_ignores_what_removed_ticks_left_in_the_account() {
+        // A version 0 pool as saved by the old program: its record lacks the
+        // 29 bytes of fields added since, and so does its account
+        let v0_record = |pool: &Pool| {
+            let mut data = Vec::new();
+            pool.try_serialize(&mut data).unwrap();
+            data.truncate(data.len() - 29);
+            data
+        };
+        let mut pool = test_pool(1_000, 2_000);
+        pool.version = 0;
+        // Ticks whose fee growth bytes happen to read as valid fields
+        let stale = Tick {
+            index: -1,
+            liquidity_net: -1,
+            liquidity_gross: u128::MAX,
+            fee_growth_outside_a: u128::from_le_bytes([1; 16]),
+            fee_growth_outside_b: u128::from_le_bytes([1; 16]),
+        };
+        pool.ticks = vec![stale; MAX_TICKS];
+        let allocated = 8 + Pool::INIT_SPACE - 29;
+        let mut data = v0_record(&pool);
+        data.resize(allocated, 0);
+
+        // Removing ticks rewrites a shorter record over the same account,
+        // leaving the old ticks' bytes after it
+        pool.ticks.truncate(2);
+        let record = v0_record(&pool);
+        data[..record.len()].copy_from_slice(&record);
+
+        data.extend([0; 29]);
+        // Read as is, the added fields come from the third old tick
+        let unsanitized = Pool::try_deserialize(&mut &data[..]).unwrap();
+        assert_eq!(unsanitized.label, [1; 8]);
+        assert!(unsanitized.compute_guard);
+
+        clear_pool_slack(&mut data, allocated).unwrap();
+        let mut migrated = Pool::try_deserialize(&mut &data[..]).unwrap();
+        migrated.migrate().unwrap();
+        assert_eq!(migrated.ticks.len(), 2);
+        assert_eq!(migrated.reserve_b, 2_000);
+        assert_eq!(migrated.label, [0; 8]);
+        assert!(!migrated.flash_swap);
+        assert!(!migrated.compute_guard);
+        assert_eq!((migrated.max_swap_in, migrated.max_swap_out), (0, 0));
+        assert_eq!(migrated.tick_spacing, 0);
+    }
+
+    #[test]
+    fn positions_must_span_a_range_on_the_tick_spacing() {
+        let mut pool = concentrated_pool();
+        pool.tick_spacing = 10;
+        pool.check_tick_range(-20, 30).unwrap();
+        pool.check_tick_range(MIN_TICK, MAX_TICK).unwrap();
+
+        let invalid = [
+            (-15, 30),          // misaligned lower tick
+            (-20, 35),          // misaligned upper tick
+            (30, -20),          // inverted
+            (30, 30),           // empty
+            (MIN_TICK - 10, 0), // below the tick range
+            (0, MAX_TICK + 10), // above it
+        ];
+        for (tick_lower, tick_upper) in invalid {
+            assert_eq!(
+                pool.check_tick_range(tick_lower, tick_upper).unwrap_err(),
+                SwapError::InvalidTickRange.into()
+            );
+        }
+
+        // Spacing is per pool: 10 is off a spacing of 60
+        pool.tick_spacing = 60;
+        assert_eq!(
+            pool.check_tick_range(-20, 30).unwrap_err(),
+            SwapError::InvalidTickRange.into()
+        );
+        pool.check_tick_range(-60, 120).unwrap();
+    }
+
+    #[test]
+    fn compute_guard_rejects_swaps_past_the_heaviest_path_estimate() {
+        let mut pool = test_pool(1_000_000, 1_000_000);
+        pool.oracle_enabled = false;
+        let plain = pool.estimated_swap_compute();
+        assert_eq!(plain, SWAP_BASE_COMPUTE);
+
+        // Every heavy feature on: stable curve, oracle, and a dynamic fee
+        pool.curve_type = CurveType::Stable { amp: 100 };
+        pool.oracle_enabled = true;
+        pool.fee_band = Some(FeeBand {
+            min_fee_bps: 5,
+            max_fee_bps: 100,
+        });
+        let heavy = pool.estimated_swap_compute();
+        assert_eq!(
+            heavy,
+            plain + STABLE_CURVE_COMPUTE + ORACLE_COMPUTE + DYNAMIC_FEE_COMPUTE
+        );
+
+        // Off by default, so any budget passes
+        pool.check_compute(0).unwrap();
+
+        pool.compute_guard = true;
+        assert_eq!(
+            pool.check_compute(heavy - 1).unwrap_err(),
+            SwapError::ComputeBudgetTooLow.into()
+        );
+        pool.check_compute(heavy).unwrap();
+
+        // A concentrated pool's estimate grows with the ticks it could cross
+        let mut concentrated = concentrated_pool();
+        let empty = concentrated.estimated_swap_compute();
+        concentrated.ticks = vec![Tick::default(); 4];
+        assert_eq!(
+            concentrated.estimated_swap_compute(),
+            empty + 4 * TICK_CROSSING_COMPUTE
+        );
+    }
 }
 
-#[error_code]
-pub enum SwapError {
-    #[msg("Math operation overflow")]
-    MathOverflow,
-    #[msg("Slippage tolerance exceeded")]
-    SlippageTooHigh,
-}
\ No newline at end of file
+// Property tests for the constant-product swap math and the integer square
+// root behind first-deposit LP amounts, over random inputs across their
+// whole range
+#[cfg(test)]
+mod proptests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // Checks one swap against the curve's guarantees: it never pays out the
+    // whole output reserve, and the pool's k never shrinks
+    fn check_swap_output(amount_in: u64, reserve_in: u64, reserve_out: u64, fee_bps: u16) -> u64 {
+        let amount_out =
+            calculate_swap_output(amount_in, reserve_in, reserve_out, fee_bps).unwrap();
+        assert!(amount_out < reserve_out);
+
+        let k_before = wide_mul(reserve_in as u128, reserve_out as u128);
+        let k_after = wide_mul(
+            reserve_in as u128 + amount_in as u128,
+            (reserve_out - amount_out) as u128,
+        );
+        assert!(k_after >= k_before);
+        amount_out
+    }
+
+    proptest! {
+        #[test]
+        fn swap_output_keeps_the_invariant(
+            amount_in in any::<u64>(),
+            reserve_in in 1..=u64::MAX,
+            reserve_out in 1..=u64::MAX,
+            fee_bps in 0..=MAX_FEE_BPS,
+        ) {
+            check_swap_output(amount_in, reserve_in, reserve_out, fee_bps);
+        }
+
+        #[test]
+        fn swap_output_keeps_the_invariant_at_realistic_sizes(
+            amount_in in 0..1_000_000_000_000u64,
+            reserve_in in 1..1_000_000_000_000u64,
+            reserve_out in 1..1_000_000_000_000u64,
+            fee_bps in 0..=MAX_FEE_BPS,
+        ) {
+            check_swap_output(amount_in, reserve_in, reserve_out, fee_bps);
+        }
+
+        #[test]
+        fn swap_output_is_monotonic_in_the_input(
+            amount_in in any::<u64>(),
+            extra in any::<u64>(),
+            reserve_in in 1..=u64::MAX,
+            reserve_out in 1..=u64::MAX,
+            fee_bps in 0..=MAX_FEE_BPS,
+        ) {
+            let smaller = check_swap_output(amount_in, reserve_in, reserve_out, fee_bps);
+            let larger = check_swap_output(
+                amount_in.saturating_add(extra),
+                reserve_in,
+                reserve_out,
+                fee_bps,
+            );
+            prop_assert!(smaller <= larger);
+        }
+
+        #[test]
+        fn swap_output_falls_as_the_fee_rises(
+            amount_in in any::<u64>(),
+            reserve_in in 1..=u64::MAX,
+            reserve_out in 1..=u64::MAX,
+            fee_bps in 0..MAX_FEE_BPS,
+        ) {
+            let cheaper = check_swap_output(amount_in, reserve_in, reserve_out, fee_bps);
+            let dearer = check_swap_output(amount_in, reserve_in, reserve_out, fee_bps + 1);
+            prop_assert!(dearer <= cheaper);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn integer_sqrt_brackets_the_root(value in any::<u128>()) {
+            let root = integer_sqrt(value) as u128;
+            prop_assert!(root * root <= value);
+            // (root + 1)^2 can pass u128::MAX, where it is above value anyway
+            prop_assert!((root + 1).checked_mul(root + 1).is_none_or(|next| next > value));
+        }
+    }
+
+    #[test]
+    fn swap_output_handles_products_past_u64() {
+        // Inputs whose products overflowed back when the swap math
+        // multiplied and added in u64
+        for (amount_in, reserve_in, reserve_out) in [
+            (u64::MAX, u64::MAX, u64::MAX),
+            (u64::MAX, 1, u64::MAX),
+            (1, u64::MAX, u64::MAX),
+            (u64::MAX / 2, u64::MAX / 2, u64::MAX - 1),
+            (u64::MAX, u64::MAX - 1, 1),
+        ] {
+            for fee_bps in [0, 30, MAX_FEE_BPS] {
+                check_swap_output(amount_in, reserve_in, reserve_out, fee_bps);
+            }
+        }
+    }
+}
@@ -1,16 +1,3823 @@
 import * as anchor from "@coral-xyz/anchor";
-import { Program } from "@coral-xyz/anchor";
+import { Program, BN } from "@coral-xyz/anchor";
+import {
+  BPF_LOADER_UPGRADEABLE_PROGRAM_ID,
+  ComputeBudgetProgram,
+  Keypair,
+  PublicKey,
+  SystemProgram,
+  SYSVAR_INSTRUCTIONS_PUBKEY,
+  Transaction,
+} from "@solana/web3.js";
+import {
+  burnChecked,
+  createAccount,
+  createInitializeMintInstruction,
+  createInitializePermanentDelegateInstruction,
+  createMint,
+  createWrappedNativeAccount,
+  ExtensionType,
+  getAccount,
+  getMint,
+  getMintLen,
+  mintTo,
+  NATIVE_MINT,
+  transfer,
+  TOKEN_2022_PROGRAM_ID,
+  TOKEN_PROGRAM_ID,
+} from "@solana/spl-token";
+import { assert } from "chai";
 import { SimpleSwap } from "../target/types/simple_swap";
 
 describe("swap-2", () => {
   // Configure the client to use the local cluster.
-  anchor.setProvider(anchor.AnchorProvider.env());
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
 
-  const program = anchor.workspace.swap2 as Program<any>;
+  const program = anchor.workspace.simpleSwap as Program<SimpleSwap>;
+  const payer = (provider.wallet as anchor.Wallet).payer;
+  const connection = provider.connection;
+
+  const FEE_DENOMINATOR = new BN(10_000);
+  const DEFAULT_FEE_TIER = 30;
+  // The canonical pool's label
+  const NO_LABEL = Array(8).fill(0);
+
+  interface PoolFixture {
+    mintA: PublicKey;
+    mintB: PublicKey;
+    pool: PublicKey;
+    vaultA: PublicKey;
+    vaultB: PublicKey;
+    lpMint: PublicKey;
+    lockedLp: PublicKey;
+    userTokenA: PublicKey;
+    userTokenB: PublicKey;
+    userLpToken: PublicKey;
+    tokenProgram: PublicKey;
+  }
+
+  function findPda(seeds: Buffer[]): PublicKey {
+    return PublicKey.findProgramAddressSync(seeds, program.programId)[0];
+  }
+
+  // Mirrors calculate_swap_output on-chain
+  function expectedSwapOutput(
+    amountIn: BN,
+    reserveIn: BN,
+    reserveOut: BN,
+    feeBps: number
+  ): BN {
+    const amountInWithFee = amountIn
+      .mul(FEE_DENOMINATOR.subn(feeBps))
+      .div(FEE_DENOMINATOR);
+    return amountInWithFee.mul(reserveOut).div(reserveIn.add(amountInWithFee));
+  }
+
+  async function parseEvents(signature: string) {
+    const tx = await connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    const parser = new anchor.EventParser(program.programId, program.coder);
+    return Array.from(parser.parseLogs(tx.meta.logMessages));
+  }
+
+  async function expectError(promise: Promise<unknown>, code: string) {
+    try {
+      await promise;
+    } catch (err) {
+      assert.equal(err.error?.errorCode?.code, code);
+      return;
+    }
+    assert.fail(`expected ${code}`);
+  }
+
+  function deadlineIn(seconds: number): number {
+    return Math.floor(Date.now() / 1000) + seconds;
+  }
+
+  async function balance(
+    tokenAccount: PublicKey,
+    tokenProgram = TOKEN_PROGRAM_ID
+  ): Promise<BN> {
+    const account = await getAccount(
+      connection,
+      tokenAccount,
+      undefined,
+      tokenProgram
+    );
+    return new BN(account.amount.toString());
+  }
+
+  function poolAddresses(
+    mintA: PublicKey,
+    mintB: PublicKey,
+    feeTier = DEFAULT_FEE_TIER,
+    label = NO_LABEL
+  ) {
+    const tier = Buffer.alloc(2);
+    tier.writeUInt16LE(feeTier);
+    // Only a nonzero label is part of the address
+    const labelSeed = label.some((b) => b !== 0) ? [Buffer.from(label)] : [];
+    const pool = findPda([
+      Buffer.from("pool"),
+      mintA.toBuffer(),
+      mintB.toBuffer(),
+      tier,
+      ...labelSeed,
+    ]);
+    return {
+      pool,
+      vaultA: findPda([Buffer.from("vault_a"), pool.toBuffer()]),
+      vaultB: findPda([Buffer.from("vault_b"), pool.toBuffer()]),
+      lpMint: findPda([Buffer.from("lp_mint"), pool.toBuffer()]),
+      lockedLp: findPda([Buffer.from("locked_lp"), pool.toBuffer()]),
+    };
+  }
+
+  function newMint(
+    decimals = 6,
+    tokenProgram = TOKEN_PROGRAM_ID
+  ): Promise<PublicKey> {
+    return createMint(
+      connection,
+      payer,
+      payer.publicKey,
+      null,
+      decimals,
+      undefined,
+      undefined,
+      tokenProgram
+    );
+  }
+
+  // Pools require mint_a < mint_b
+  function sortMints(x: PublicKey, y: PublicKey): [PublicKey, PublicKey] {
+    return Buffer.compare(x.toBuffer(), y.toBuffer()) < 0 ? [x, y] : [y, x];
+  }
+
+  // A fresh pair of mints in canonical order
+  async function newMintPair(
+    decimals: [number, number] = [6, 6],
+    tokenProgram = TOKEN_PROGRAM_ID
+  ): Promise<[PublicKey, PublicKey]> {
+    const x = await newMint(decimals[0], tokenProgram);
+    const y = await newMint(decimals[1], tokenProgram);
+    return sortMints(x, y);
+  }
+
+  interface PoolOptions {
+    decimals?: [number, number];
+    feeTier?: number;
+    // Eight bytes naming an isolated pool for the pair
+    label?: number[];
+    feeBps?: number;
+    protocolFeeBps?: number;
+    referralFeeBps?: number;
+    curveType?:
+      | { constantProduct: {} }
+      | { stable: { amp: BN } }
+      | { concentrated: {} };
+    feeBand?: { minFeeBps: number; maxFeeBps: number };
+    initialTick?: number;
+    tickSpacing?: number;
+    rebasing?: boolean;
+    oracleEnabled?: boolean;
+    fundAmount?: number;
+    tokenProgram?: PublicKey;
+    // Reuse existing mints instead of creating a fresh pair
+    mints?: [PublicKey, PublicKey];
+  }
+
+  async function initializePool(
+    mintA: PublicKey,
+    mintB: PublicKey,
+    opts: PoolOptions = {}
+  ): Promise<string> {
+    return program.methods
+      .initializePool(
+        opts.feeTier ?? DEFAULT_FEE_TIER,
+        opts.label ?? NO_LABEL,
+        opts.feeBps ?? null,
+        opts.protocolFeeBps ?? null,
+        opts.referralFeeBps ?? null,
+        opts.curveType ?? null,
+        opts.feeBand ?? null,
+        opts.initialTick ?? null,
+        opts.tickSpacing ?? null,
+        opts.rebasing ?? null,
+        opts.oracleEnabled ?? null
+      )
+      .accountsPartial({
+        payer: payer.publicKey,
+        authority: payer.publicKey,
+        factory,
+        mintA,
+        mintB,
+        ...poolAddresses(mintA, mintB, opts.feeTier, opts.label),
+        tokenProgram: opts.tokenProgram ?? TOKEN_PROGRAM_ID,
+      })
+      .rpc({ commitment: "confirmed" });
+  }
+
+  // Creates a pool (for two fresh mints unless `opts.mints` is given) and
+  // funded user accounts
+  async function createPool(opts: PoolOptions = {}): Promise<PoolFixture> {
+    const fundAmount = opts.fundAmount ?? 1_000_000_000;
+    const tokenProgram = opts.tokenProgram ?? TOKEN_PROGRAM_ID;
+    const [mintA, mintB] = opts.mints
+      ? sortMints(...opts.mints)
+      : await newMintPair(opts.decimals, tokenProgram);
+
+    const { pool, vaultA, vaultB, lpMint, lockedLp } = poolAddresses(
+      mintA,
+      mintB,
+      opts.feeTier,
+      opts.label
+    );
+    await initializePool(mintA, mintB, opts);
+
+    const userTokenA = await createAccount(
+      connection,
+      payer,
+      mintA,
+      payer.publicKey,
+      Keypair.generate(),
+      undefined,
+      tokenProgram
+    );
+    const userTokenB = await createAccount(
+      connection,
+      payer,
+      mintB,
+      payer.publicKey,
+      Keypair.generate(),
+      undefined,
+      tokenProgram
+    );
+    const userLpToken = await createAccount(
+      connection,
+      payer,
+      lpMint,
+      payer.publicKey,
+      Keypair.generate(),
+      undefined,
+      tokenProgram
+    );
+    for (const [mint, account] of [
+      [mintA, userTokenA],
+      [mintB, userTokenB],
+    ]) {
+      await mintTo(
+        connection,
+        payer,
+        mint,
+        account,
+        payer,
+        fundAmount,
+        [],
+        undefined,
+        tokenProgram
+      );
+    }
+
+    return {
+      mintA,
+      mintB,
+      pool,
+      vaultA,
+      vaultB,
+      lpMint,
+      lockedLp,
+      userTokenA,
+      userTokenB,
+      userLpToken,
+      tokenProgram,
+    };
+  }
+
+  async function addLiquidity(
+    f: PoolFixture,
+    maxAmountA: number,
+    maxAmountB: number,
+    minLpOut = 0,
+    expectedPrice: BN | null = null,
+    priceToleranceBps = 0
+  ): Promise<string> {
+    return program.methods
+      .addLiquidity(
+        new BN(maxAmountA),
+        new BN(maxAmountB),
+        new BN(minLpOut),
+        expectedPrice,
+        priceToleranceBps
+      )
+      .accountsPartial({
+        user: payer.publicKey,
+        pool: f.pool,
+        mintA: f.mintA,
+        mintB: f.mintB,
+        userTokenA: f.userTokenA,
+        userTokenB: f.userTokenB,
+        vaultA: f.vaultA,
+        vaultB: f.vaultB,
+        lpMint: f.lpMint,
+        lockedLp: f.lockedLp,
+        userLpToken: f.userLpToken,
+        tokenProgram: f.tokenProgram,
+      })
+      .rpc({ commitment: "confirmed" });
+  }
+
+  async function swap(
+    f: PoolFixture,
+    amountIn: number,
+    minimumAmountOut: number,
+    aToB: boolean,
+    deadline = deadlineIn(60),
+    maxPriceImpactBps: number | null = null,
+    referrer: PublicKey | null = null
+  ): Promise<string> {
+    return program.methods
+      .swap(
+        new BN(amountIn),
+        new BN(minimumAmountOut),
+        aToB,
+        new BN(deadline),
+        maxPriceImpactBps
+      )
+      .accountsPartial({
+        user: payer.publicKey,
+        pool: f.pool,
+        mintA: f.mintA,
+        mintB: f.mintB,
+        userTokenA: f.userTokenA,
+        userTokenB: f.userTokenB,
+        vaultA: f.vaultA,
+        vaultB: f.vaultB,
+        referrer,
+        tokenProgram: f.tokenProgram,
+      })
+      .rpc({ commitment: "confirmed" });
+  }
+
+  async function removeLiquidity(
+    f: PoolFixture,
+    lpAmount: number,
+    minA = 0,
+    minB = 0,
+    deadline = deadlineIn(60)
+  ): Promise<string> {
+    return program.methods
+      .removeLiquidity(
+        new BN(lpAmount),
+        new BN(minA),
+        new BN(minB),
+        new BN(deadline)
+      )
+      .accountsPartial({
+        user: payer.publicKey,
+        pool: f.pool,
+        mintA: f.mintA,
+        mintB: f.mintB,
+        userTokenA: f.userTokenA,
+        userTokenB: f.userTokenB,
+        vaultA: f.vaultA,
+        vaultB: f.vaultB,
+        lpMint: f.lpMint,
+        userLpToken: f.userLpToken,
+        tokenProgram: f.tokenProgram,
+      })
+      .rpc({ commitment: "confirmed" });
+  }
+
+  function positionAddress(
+    f: PoolFixture,
+    tickLower: number,
+    tickUpper: number
+  ): PublicKey {
+    const tick = (index: number) => {
+      const bytes = Buffer.alloc(4);
+      bytes.writeInt32LE(index);
+      return bytes;
+    };
+    return findPda([
+      Buffer.from("position"),
+      f.pool.toBuffer(),
+      payer.publicKey.toBuffer(),
+      tick(tickLower),
+      tick(tickUpper),
+    ]);
+  }
+
+  async function openPosition(
+    f: PoolFixture,
+    tickLower: number,
+    tickUpper: number,
+    liquidity: number
+  ): Promise<string> {
+    return program.methods
+      .openPosition(
+        tickLower,
+        tickUpper,
+        new BN(liquidity),
+        new BN(1_000_000_000),
+        new BN(1_000_000_000)
+      )
+      .accountsPartial({
+        owner: payer.publicKey,
+        pool: f.pool,
+        mintA: f.mintA,
+        mintB: f.mintB,
+        userTokenA: f.userTokenA,
+        userTokenB: f.userTokenB,
+        vaultA: f.vaultA,
+        vaultB: f.vaultB,
+        position: positionAddress(f, tickLower, tickUpper),
+        tokenProgram: f.tokenProgram,
+      })
+      .rpc({ commitment: "confirmed" });
+  }
+
+  async function closePosition(
+    f: PoolFixture,
+    tickLower: number,
+    tickUpper: number
+  ): Promise<string> {
+    return program.methods
+      .closePosition(new BN(0), new BN(0))
+      .accountsPartial({
+        owner: payer.publicKey,
+        pool: f.pool,
+        mintA: f.mintA,
+        mintB: f.mintB,
+        userTokenA: f.userTokenA,
+        userTokenB: f.userTokenB,
+        vaultA: f.vaultA,
+        vaultB: f.vaultB,
+        position: positionAddress(f, tickLower, tickUpper),
+        tokenProgram: f.tokenProgram,
+      })
+      .rpc({ commitment: "confirmed" });
+  }
+
+  async function swapExactOut(
+    f: PoolFixture,
+    amountOut: number,
+    maximumAmountIn: number,
+    aToB: boolean
+  ): Promise<string> {
+    return program.methods
+      .swapExactOut(new BN(amountOut), new BN(maximumAmountIn), aToB)
+      .accountsPartial({
+        user: payer.publicKey,
+        pool: f.pool,
+        mintA: f.mintA,
+        mintB: f.mintB,
+        userTokenA: f.userTokenA,
+        userTokenB: f.userTokenB,
+        vaultA: f.vaultA,
+        vaultB: f.vaultB,
+        tokenProgram: f.tokenProgram,
+      })
+      .rpc({ commitment: "confirmed" });
+  }
+
+  function tokenAccountFor(f: PoolFixture, mint: PublicKey): PublicKey {
+    return mint.equals(f.mintA) ? f.userTokenA : f.userTokenB;
+  }
+
+  function vaultFor(f: PoolFixture, mint: PublicKey): PublicKey {
+    return mint.equals(f.mintA) ? f.vaultA : f.vaultB;
+  }
+
+  // Two funded pools X/Y and Y/Z that route X -> Y -> Z
+  async function createRoute(tokenProgram = TOKEN_PROGRAM_ID) {
+    const [mintX, mintY, mintZ] = [
+      await newMint(6, tokenProgram),
+      await newMint(6, tokenProgram),
+      await newMint(6, tokenProgram),
+    ];
+    const first = await createPool({ mints: [mintX, mintY], tokenProgram });
+    const second = await createPool({ mints: [mintY, mintZ], tokenProgram });
+    await addLiquidity(first, 1_000_000, 2_000_000);
+    await addLiquidity(second, 3_000_000, 1_000_000);
+    return { mintX, mintY, mintZ, first, second };
+  }
+
+  // Mirrors a route hop by hop, returning each hop's output
+  async function expectedRoute(
+    pools: PoolFixture[],
+    mintIn: PublicKey,
+    amountIn: BN
+  ): Promise<BN[]> {
+    const hops: BN[] = [];
+    let amount = amountIn;
+    let mint = mintIn;
+    for (const f of pools) {
+      const pool = await program.account.pool.fetch(f.pool);
+      const aToB = pool.mintA.equals(mint);
+      const [reserveIn, reserveOut] = aToB
+        ? [pool.reserveA, pool.reserveB]
+        : [pool.reserveB, pool.reserveA];
+      amount = expectedSwapOutput(amount, reserveIn, reserveOut, pool.feeBps);
+      mint = aToB ? pool.mintB : pool.mintA;
+      hops.push(amount);
+    }
+    return hops;
+  }
+
+  // Fresh token accounts for both of a pool's mints, owned by `owner`
+  async function newTreasury(
+    f: PoolFixture,
+    owner: PublicKey = payer.publicKey
+  ): Promise<[PublicKey, PublicKey]> {
+    const accounts: PublicKey[] = [];
+    for (const mint of [f.mintA, f.mintB]) {
+      accounts.push(
+        await createAccount(
+          connection,
+          payer,
+          mint,
+          owner,
+          Keypair.generate(),
+          undefined,
+          f.tokenProgram
+        )
+      );
+    }
+    return [accounts[0], accounts[1]];
+  }
+
+  function authorityWithdrawAccounts(
+    f: PoolFixture,
+    treasuryA: PublicKey,
+    treasuryB: PublicKey,
+    authority: PublicKey = payer.publicKey
+  ) {
+    return {
+      authority,
+      pool: f.pool,
+      mintA: f.mintA,
+      mintB: f.mintB,
+      vaultA: f.vaultA,
+      vaultB: f.vaultB,
+      treasuryTokenA: treasuryA,
+      treasuryTokenB: treasuryB,
+      tokenProgram: f.tokenProgram,
+    };
+  }
+
+  function flashLoanAccounts(f: PoolFixture) {
+    return {
+      borrower: payer.publicKey,
+      pool: f.pool,
+      mintA: f.mintA,
+      mintB: f.mintB,
+      borrowerTokenA: f.userTokenA,
+      borrowerTokenB: f.userTokenB,
+      vaultA: f.vaultA,
+      vaultB: f.vaultB,
+      instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+      tokenProgram: f.tokenProgram,
+    };
+  }
+
+  async function setPaused(
+    f: PoolFixture,
+    paused: boolean,
+    authority: Keypair = payer
+  ) {
+    await program.methods
+      .setPaused(paused)
+      .accountsPartial({ authority: authority.publicKey, pool: f.pool })
+      .signers([authority])
+      .rpc();
+  }
+
+  // Sweeps what is left in the vaults to the payer's own token accounts
+  function closePool(f: PoolFixture): Promise<string> {
+    return program.methods
+      .closePool()
+      .accountsPartial({
+        authority: payer.publicKey,
+        pool: f.pool,
+        mintA: f.mintA,
+        mintB: f.mintB,
+        vaultA: f.vaultA,
+        vaultB: f.vaultB,
+        treasuryTokenA: f.userTokenA,
+        treasuryTokenB: f.userTokenB,
+        lpMint: f.lpMint,
+        lockedLp: f.lockedLp,
+        tokenProgram: f.tokenProgram,
+      })
+      .rpc({ commitment: "confirmed" });
+  }
+
+  const factory = findPda([Buffer.from("factory")]);
+
+  before(async () => {
+    // The factory is a program-wide singleton, so it may already exist
+    if ((await connection.getAccountInfo(factory)) === null) {
+      await program.methods
+        .initializeFactory()
+        .accountsPartial({ payer: payer.publicKey, factory })
+        .rpc();
+    }
+  });
 
   it("Is initialized!", async () => {
-    // Add your test here.
-    const tx = await program.methods.initialize().rpc();
-    console.log("Your transaction signature", tx);
+    const f = await createPool();
+
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.ok(pool.mintA.equals(f.mintA));
+    assert.ok(pool.mintB.equals(f.mintB));
+    assert.ok(pool.lpMint.equals(f.lpMint));
+    assert.equal(pool.feeBps, 30);
+  });
+
+  it("prices swaps from stored reserves, ignoring donations", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    // Donate straight to vault A, bypassing add_liquidity
+    await transfer(connection, payer, f.userTokenA, f.vaultA, payer, 500_000);
+
+    const before = await program.account.pool.fetch(f.pool);
+    assert.equal(before.reserveA.toNumber(), 1_000_000);
+    assert.equal(before.reserveB.toNumber(), 1_000_000);
+
+    const amountIn = 10_000;
+    const expected = expectedSwapOutput(
+      new BN(amountIn),
+      before.reserveA,
+      before.reserveB,
+      before.feeBps
+    );
+
+    const userBBefore = await balance(f.userTokenB);
+    await swap(f, amountIn, 0, true);
+    const received = (await balance(f.userTokenB)).sub(userBBefore);
+
+    assert.equal(received.toString(), expected.toString());
+
+    const after = await program.account.pool.fetch(f.pool);
+    assert.equal(after.reserveA.toNumber(), 1_000_000 + amountIn);
+    assert.equal(
+      after.reserveB.toString(),
+      before.reserveB.sub(expected).toString()
+    );
+  });
+
+  it("emits SwapExecuted with the executed amounts", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 2_000_000);
+
+    const userABefore = await balance(f.userTokenA);
+    const signature = await swap(f, 25_000, 0, false);
+    const received = (await balance(f.userTokenA)).sub(userABefore);
+
+    const events = await parseEvents(signature);
+    const event = events.find((e) => e.name === "swapExecuted");
+    assert.ok(event, "SwapExecuted not emitted");
+
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.ok(event.data.pool.equals(f.pool));
+    assert.ok(event.data.user.equals(payer.publicKey));
+    assert.ok(event.data.mintA.equals(f.mintA));
+    assert.ok(event.data.mintB.equals(f.mintB));
+    assert.equal(event.data.amountIn.toNumber(), 25_000);
+    assert.equal(event.data.amountOut.toString(), received.toString());
+    assert.isFalse(event.data.aToB);
+    assert.equal(event.data.reserveA.toString(), pool.reserveA.toString());
+    assert.equal(event.data.reserveB.toString(), pool.reserveB.toString());
+  });
+
+  it("tracks lifetime swap volume and fees", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    const swaps: [number, boolean][] = [
+      [10_000, true],
+      [1_001, false],
+      [25_000, true],
+      [7_777, false],
+    ];
+    let [volumeA, volumeB, fees] = [new BN(0), new BN(0), new BN(0)];
+    for (const [amountIn, aToB] of swaps) {
+      await swap(f, amountIn, 0, aToB);
+      const amount = new BN(amountIn);
+      if (aToB) {
+        volumeA = volumeA.add(amount);
+      } else {
+        volumeB = volumeB.add(amount);
+      }
+      // The fee is whatever the rounded-down net input leaves behind
+      const net = amount.muln(10_000 - 30).div(FEE_DENOMINATOR);
+      fees = fees.add(amount.sub(net));
+    }
+
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.cumulativeVolumeA.toString(), volumeA.toString());
+    assert.equal(pool.cumulativeVolumeB.toString(), volumeB.toString());
+    assert.equal(pool.cumulativeFees.toString(), fees.toString());
+  });
+
+  it("rejects a vault that belongs to another pool", async () => {
+    const f = await createPool();
+    const other = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    await addLiquidity(other, 1_000_000, 1_000_000);
+
+    await expectError(
+      program.methods
+        .swap(new BN(10_000), new BN(0), true, new BN(deadlineIn(60)), null)
+        .accountsPartial({
+          user: payer.publicKey,
+          pool: f.pool,
+          mintA: f.mintA,
+          mintB: f.mintB,
+          userTokenA: f.userTokenA,
+          userTokenB: f.userTokenB,
+          vaultA: f.vaultA,
+          vaultB: other.vaultB,
+          tokenProgram: f.tokenProgram,
+        })
+        .rpc(),
+      "ConstraintSeeds"
+    );
+    assert.equal((await balance(other.vaultB)).toNumber(), 1_000_000);
+  });
+
+  it("rejects swaps past their deadline", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    await expectError(
+      swap(f, 10_000, 0, true, deadlineIn(-60)),
+      "DeadlineExceeded"
+    );
+  });
+
+  it("applies deadline, slippage, and impact checks in order", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 10_000, 10_000);
+
+    // 5_000 in pays out ~3_326, a ~33% worse price than spot. Starting from
+    // a swap that breaks all three protections, each fix reveals the next.
+    await expectError(
+      swap(f, 5_000, 4_000, true, deadlineIn(-60), 1_000),
+      "DeadlineExceeded"
+    );
+    await expectError(
+      swap(f, 5_000, 4_000, true, deadlineIn(60), 1_000),
+      "SlippageTooHigh"
+    );
+    await expectError(
+      swap(f, 5_000, 3_000, true, deadlineIn(60), 1_000),
+      "PriceImpactTooHigh"
+    );
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.reserveA.toNumber(), 10_000);
+
+    await swap(f, 5_000, 3_000, true, deadlineIn(60), 4_000);
+  });
+
+  it("lets only the authority swap without the fee", async () => {
+    const f = await createPool({ feeBps: 30 });
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    const swapNoFee = (user: Keypair) =>
+      program.methods
+        .swapNoFee(new BN(10_000), new BN(0), true)
+        .accountsPartial({
+          user: user.publicKey,
+          pool: f.pool,
+          mintA: f.mintA,
+          mintB: f.mintB,
+          userTokenA: f.userTokenA,
+          userTokenB: f.userTokenB,
+          vaultA: f.vaultA,
+          vaultB: f.vaultB,
+          referrer: null,
+          tokenProgram: f.tokenProgram,
+        })
+        .signers([user])
+        .rpc({ commitment: "confirmed" });
+
+    await expectError(swapNoFee(Keypair.generate()), "Unauthorized");
+
+    // 10_000 * 1_000_000 / 1_010_000 with nothing taken off the input; the
+    // 0.3% fee would leave 9_871
+    const before = await balance(f.userTokenB);
+    const signature = await swapNoFee(payer);
+    const received = (await balance(f.userTokenB)).sub(before);
+    assert.equal(received.toNumber(), 9_900);
+
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "feeFreeSwap"
+    );
+    assert.ok(event, "FeeFreeSwap not emitted");
+    assert.equal(event.data.amountOut.toNumber(), 9_900);
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.feeBps, 30);
+    assert.equal(pool.reserveA.toNumber(), 1_010_000);
+  });
+
+  it("rejects a swap larger than the user's balance", async () => {
+    const f = await createPool({ fundAmount: 1_000_000 });
+    await addLiquidity(f, 400_000, 400_000);
+
+    // The user has 600_000 of each token left
+    await expectError(swap(f, 600_001, 0, true), "InsufficientUserBalance");
+    await expectError(swap(f, 700_000, 0, false), "InsufficientUserBalance");
+    await swap(f, 600_000, 0, true);
+    assert.equal((await balance(f.userTokenA)).toNumber(), 0);
+  });
+
+  it("rejects a second swap within the pool's cooldown", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    await program.methods
+      .setMinSwapInterval(new BN(3_600))
+      .accountsPartial({ authority: payer.publicKey, pool: f.pool })
+      .rpc();
+
+    const lastSwap = findPda([
+      Buffer.from("last_swap"),
+      f.pool.toBuffer(),
+      payer.publicKey.toBuffer(),
+    ]);
+    const swapWith = (record: PublicKey | null) =>
+      program.methods
+        .swap(new BN(1_000), new BN(0), true, new BN(deadlineIn(60)), null)
+        .accountsPartial({
+          user: payer.publicKey,
+          pool: f.pool,
+          mintA: f.mintA,
+          mintB: f.mintB,
+          userTokenA: f.userTokenA,
+          userTokenB: f.userTokenB,
+          vaultA: f.vaultA,
+          vaultB: f.vaultB,
+          lastSwap: record,
+          tokenProgram: f.tokenProgram,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+
+    // The first swap creates the user's record
+    await swapWith(lastSwap);
+    const record = await program.account.lastSwap.fetch(lastSwap);
+    assert.isAbove(record.timestamp.toNumber(), 0);
+
+    await expectError(swapWith(lastSwap), "SwapTooSoon");
+    await expectError(swapWith(null), "LastSwapAccountMissing");
+
+    // Turning the cooldown off lets the user swap again straight away
+    await program.methods
+      .setMinSwapInterval(new BN(0))
+      .accountsPartial({ authority: payer.publicKey, pool: f.pool })
+      .rpc();
+    await swapWith(lastSwap);
+  });
+
+  it("rejects withdrawals from a pool with no LP supply", async () => {
+    const f = await createPool();
+    const lpMint = await getMint(connection, f.lpMint);
+    assert.equal(Number(lpMint.supply), 0);
+
+    await expectError(removeLiquidity(f, 1), "NoLiquidity");
+  });
+
+  it("rejects withdrawals past their deadline", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    await expectError(
+      removeLiquidity(f, 10_000, 0, 0, deadlineIn(-60)),
+      "DeadlineExceeded"
+    );
+    const lpBefore = await balance(f.userLpToken);
+    await removeLiquidity(f, 10_000);
+    const lpAfter = await balance(f.userLpToken);
+    assert.equal(lpBefore.sub(lpAfter).toNumber(), 10_000);
+  });
+
+  it("caps price impact even when the minimum output is met", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 10_000, 10_000);
+
+    // Half the pool's depth: ~3_326 out, a ~33% worse price than spot
+    await expectError(
+      swap(f, 5_000, 3_000, true, deadlineIn(60), 1_000),
+      "PriceImpactTooHigh"
+    );
+    await swap(f, 5_000, 3_000, true, deadlineIn(60), 4_000);
+  });
+
+  it("partially fills an oversized swap and leaves the rest", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 10_000, 10_000);
+    const userABefore = await balance(f.userTokenA);
+
+    // 5_000 in would be ~33% off spot; only the part within 10% is filled
+    const signature = await program.methods
+      .swapPartial(new BN(5_000), new BN(1), 1_000, true)
+      .accountsPartial({
+        user: payer.publicKey,
+        pool: f.pool,
+        mintA: f.mintA,
+        mintB: f.mintB,
+        userTokenA: f.userTokenA,
+        userTokenB: f.userTokenB,
+        vaultA: f.vaultA,
+        vaultB: f.vaultB,
+        referrer: null,
+        tokenProgram: f.tokenProgram,
+      })
+      .rpc({ commitment: "confirmed" });
+
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "partialFill"
+    );
+    assert.ok(event, "PartialFill not emitted");
+    const filled = event.data.amountFilled.toNumber();
+    assert.isAbove(filled, 0);
+    assert.isBelow(filled, 5_000);
+    assert.equal(event.data.amountRefunded.toNumber(), 5_000 - filled);
+    assert.isAbove(event.data.amountOut.toNumber(), 0);
+
+    // Only the filled part left the user's account
+    const userAAfter = await balance(f.userTokenA);
+    assert.equal(userABefore.sub(userAAfter).toNumber(), filled);
+  });
+
+  it("stores the vault bumps and swaps against them", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    const pool = await program.account.pool.fetch(f.pool);
+    for (const [seed, vault, bump] of [
+      ["vault_a", f.vaultA, pool.vaultABump],
+      ["vault_b", f.vaultB, pool.vaultBBump],
+    ] as [string, PublicKey, number][]) {
+      const [address, expected] = PublicKey.findProgramAddressSync(
+        [Buffer.from(seed), f.pool.toBuffer()],
+        program.programId
+      );
+      assert.ok(address.equals(vault));
+      assert.equal(bump, expected);
+    }
+
+    const before = await balance(f.userTokenB);
+    await swap(f, 10_000, 1, true);
+    assert.equal(
+      (await balance(f.userTokenB)).sub(before).toString(),
+      expectedSwapOutput(
+        new BN(10_000),
+        pool.reserveA,
+        pool.reserveB,
+        pool.feeBps
+      ).toString()
+    );
+  });
+
+  it("records the pool's creator and creation time", async () => {
+    const f = await createPool();
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.ok(pool.creator.equals(payer.publicKey));
+
+    const slot = await connection.getSlot("confirmed");
+    const now = await connection.getBlockTime(slot);
+    assert.isAbove(pool.createdAt.toNumber(), 0);
+    assert.isAtMost(pool.createdAt.toNumber(), now);
+    // Nothing has traded yet, so the oracle was last touched at creation
+    assert.equal(pool.createdAt.toNumber(), pool.lastUpdateTs.toNumber());
+  });
+
+  it("counts pools in the factory and announces each one", async () => {
+    const countBefore = (await program.account.factory.fetch(factory))
+      .poolCount;
+
+    const created = [];
+    for (let i = 0; i < 2; i++) {
+      const [mintA, mintB] = await newMintPair();
+      const signature = await initializePool(mintA, mintB);
+      const event = (await parseEvents(signature)).find(
+        (e) => e.name === "poolCreated"
+      );
+      assert.ok(event, "PoolCreated not emitted");
+      assert.ok(event.data.pool.equals(poolAddresses(mintA, mintB).pool));
+      assert.ok(event.data.mintA.equals(mintA));
+      assert.ok(event.data.mintB.equals(mintB));
+      created.push(event.data.index.toNumber());
+    }
+
+    const countAfter = (await program.account.factory.fetch(factory))
+      .poolCount;
+    assert.equal(countAfter.sub(countBefore).toNumber(), 2);
+    assert.deepEqual(created, [
+      countBefore.toNumber(),
+      countBefore.toNumber() + 1,
+    ]);
+  });
+
+  it("lets a separate payer fund a pool's creation", async () => {
+    // An unfunded key, so the rent can only have come from the payer
+    const authority = Keypair.generate();
+    const [mintA, mintB] = await newMintPair();
+    const { pool } = poolAddresses(mintA, mintB);
+
+    await program.methods
+      .initializePool(
+        DEFAULT_FEE_TIER,
+        NO_LABEL,
+        null,
+        null,
+        null,
+        null,
+        null,
+        null,
+        null,
+        null,
+        null
+      )
+      .accountsPartial({
+        payer: payer.publicKey,
+        authority: authority.publicKey,
+        factory,
+        mintA,
+        mintB,
+        ...poolAddresses(mintA, mintB),
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([authority])
+      .rpc({ commitment: "confirmed" });
+
+    const state = await program.account.pool.fetch(pool);
+    assert.ok(state.authority.equals(authority.publicKey));
+    assert.ok(state.creator.equals(authority.publicKey));
+    assert.ok(state.feeRecipient.equals(authority.publicKey));
+    assert.equal(await connection.getBalance(authority.publicKey), 0);
+  });
+
+  it("prices near parity tighter on a stable pool", async () => {
+    const constantProduct = await createPool();
+    const stable = await createPool({
+      curveType: { stable: { amp: new BN(100) } },
+    });
+
+    const received = [];
+    for (const f of [constantProduct, stable]) {
+      await addLiquidity(f, 10_000_000, 10_000_000);
+      const before = await balance(f.userTokenB);
+      await swap(f, 500_000, 0, true);
+      received.push((await balance(f.userTokenB)).sub(before));
+    }
+
+    // Both pay the 0.3% fee, but only the constant-product pool slips ~5%
+    assert.isTrue(received[1].gt(received[0]));
+    assert.isAbove(received[1].toNumber(), 495_000);
+    assert.isBelow(received[0].toNumber(), 480_000);
+  });
+
+  it("pays swap fees only to concentrated positions in range", async () => {
+    const f = await createPool({
+      curveType: { concentrated: {} },
+      initialTick: 0,
+    });
+    // The price starts at tick 0: one position straddles it, the other sits
+    // entirely above it and holds only token A
+    await openPosition(f, -600, 600, 100_000_000);
+    await openPosition(f, 1_200, 1_800, 100_000_000);
+
+    // A -> B lowers the price, staying inside the first range
+    await swap(f, 100_000, 0, true);
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.isAtLeast(pool.tickCurrent, -600);
+    assert.isBelow(pool.tickCurrent, 0);
+
+    const closed = async (tickLower: number, tickUpper: number) => {
+      const signature = await closePosition(f, tickLower, tickUpper);
+      const event = (await parseEvents(signature)).find(
+        (e) => e.name === "positionClosed"
+      );
+      assert.ok(event, "PositionClosed not emitted");
+      return event.data;
+    };
+    const inRange = await closed(-600, 600);
+    assert.isAbove(inRange.feesA.toNumber(), 0);
+    assert.equal(inRange.feesB.toNumber(), 0);
+    const outOfRange = await closed(1_200, 1_800);
+    assert.equal(outOfRange.feesA.toNumber(), 0);
+    assert.equal(outOfRange.feesB.toNumber(), 0);
+
+    // Both positions are gone, along with their ticks
+    const after = await program.account.pool.fetch(f.pool);
+    assert.equal(after.ticks.length, 0);
+    assert.equal(after.liquidity.toNumber(), 0);
+  });
+
+  it("rejects positions off the tick spacing", async () => {
+    const f = await createPool({
+      curveType: { concentrated: {} },
+      initialTick: 0,
+    });
+    assert.equal((await program.account.pool.fetch(f.pool)).tickSpacing, 60);
+    await expectError(
+      openPosition(f, -600, 610, 1_000_000),
+      "InvalidTickRange"
+    );
+    await expectError(openPosition(f, 600, 600, 1_000_000), "InvalidTickRange");
+    await expectError(
+      openPosition(f, 600, -600, 1_000_000),
+      "InvalidTickRange"
+    );
+  });
+
+  it("aligns positions to a pool's own tick spacing", async () => {
+    const f = await createPool({
+      curveType: { concentrated: {} },
+      initialTick: 0,
+      tickSpacing: 10,
+    });
+    assert.equal((await program.account.pool.fetch(f.pool)).tickSpacing, 10);
+
+    // Misaligned for this pool, then inverted
+    await expectError(openPosition(f, -15, 30, 1_000_000), "InvalidTickRange");
+    await expectError(openPosition(f, 30, -20, 1_000_000), "InvalidTickRange");
+    // Off the default spacing of 60, but on this pool's
+    await openPosition(f, -20, 30, 1_000_000);
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.liquidity.toNumber(), 1_000_000);
+
+    // Spacing only applies to concentrated pools, and can't be zero
+    const [mintA, mintB] = await newMintPair();
+    await expectError(
+      initializePool(mintA, mintB, { tickSpacing: 10 }),
+      "InvalidTickRange"
+    );
+    await expectError(
+      initializePool(mintA, mintB, {
+        curveType: { concentrated: {} },
+        initialTick: 0,
+        tickSpacing: 0,
+      }),
+      "InvalidTickRange"
+    );
+  });
+
+  it("rejects a stable pool without amplification", async () => {
+    const [mintA, mintB] = await newMintPair();
+
+    await expectError(
+      initializePool(mintA, mintB, {
+        curveType: { stable: { amp: new BN(0) } },
+      }),
+      "InvalidAmp"
+    );
+  });
+
+  it("quotes a two-hop path across pools", async () => {
+    const { mintX, mintZ, first, second } = await createRoute();
+    const hops = await expectedRoute(
+      [first, second],
+      mintX,
+      new BN(50_000)
+    );
+    const amount = hops[hops.length - 1];
+
+    const signature = await program.methods
+      .quotePath(new BN(50_000), mintX)
+      .remainingAccounts(
+        [first, second].map((f) => ({
+          pubkey: f.pool,
+          isSigner: false,
+          isWritable: false,
+        }))
+      )
+      .rpc({ commitment: "confirmed" });
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "pathQuote"
+    );
+    assert.ok(event, "PathQuote not emitted");
+    assert.ok(event.data.mintOut.equals(mintZ));
+    assert.deepEqual(
+      event.data.amounts.map((a: BN) => a.toString()),
+      hops.map((a) => a.toString())
+    );
+    assert.equal(event.data.amountOut.toString(), amount.toString());
+
+    // The second pool alone doesn't hold X
+    await expectError(
+      program.methods
+        .quotePath(new BN(50_000), mintX)
+        .remainingAccounts([
+          { pubkey: second.pool, isSigner: false, isWritable: false },
+        ])
+        .rpc(),
+      "InvalidPath"
+    );
+  });
+
+  it("swaps across two pools with slippage on the final leg", async () => {
+    const { mintX, mintY, mintZ, first, second } = await createRoute();
+    const hops = await expectedRoute(
+      [first, second],
+      mintX,
+      new BN(50_000)
+    );
+    const expected = hops[1];
+
+    const swapTwoHop = (minimumAmountOut: BN) =>
+      program.methods
+        .swapTwoHop(new BN(50_000), minimumAmountOut, false)
+        .accountsPartial({
+          user: payer.publicKey,
+          pool1: first.pool,
+          pool2: second.pool,
+          mintIn: mintX,
+          mintMid: mintY,
+          mintOut: mintZ,
+          userTokenIn: tokenAccountFor(first, mintX),
+          userTokenMid: tokenAccountFor(first, mintY),
+          userTokenOut: tokenAccountFor(second, mintZ),
+          pool1VaultIn: vaultFor(first, mintX),
+          pool1VaultOut: vaultFor(first, mintY),
+          pool2VaultIn: vaultFor(second, mintY),
+          pool2VaultOut: vaultFor(second, mintZ),
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    await expectError(swapTwoHop(expected.addn(1)), "SlippageTooHigh");
+
+    const zBefore = await balance(tokenAccountFor(second, mintZ));
+    const yBefore = await balance(tokenAccountFor(first, mintY));
+    await swapTwoHop(expected);
+    const zReceived = (await balance(tokenAccountFor(second, mintZ))).sub(
+      zBefore
+    );
+    assert.equal(zReceived.toString(), expected.toString());
+    // The intermediate tokens only pass through
+    assert.equal(
+      (await balance(tokenAccountFor(first, mintY))).toString(),
+      yBefore.toString()
+    );
+  });
+
+  it("rejects a two-hop round trip through one pool", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    await expectError(
+      program.methods
+        .swapTwoHop(new BN(50_000), new BN(0), false)
+        .accountsPartial({
+          user: payer.publicKey,
+          pool1: f.pool,
+          pool2: f.pool,
+          mintIn: f.mintA,
+          mintMid: f.mintB,
+          mintOut: f.mintA,
+          userTokenIn: f.userTokenA,
+          userTokenMid: f.userTokenB,
+          userTokenOut: f.userTokenA,
+          pool1VaultIn: f.vaultA,
+          pool1VaultOut: f.vaultB,
+          pool2VaultIn: f.vaultB,
+          pool2VaultOut: f.vaultA,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc(),
+      "RepeatedPool"
+    );
+  });
+
+  it("saves compute on exact hops without changing the output", async () => {
+    // Two identical routes under the same token program, one swapped with the
+    // exact_hops hint and one measuring every hop
+    const run = async (exactHops: boolean, tokenProgram = TOKEN_PROGRAM_ID) => {
+      const { mintX, mintY, mintZ, first, second } = await createRoute(
+        tokenProgram
+      );
+      const out = tokenAccountFor(second, mintZ);
+      const before = await balance(out, tokenProgram);
+      const signature = await program.methods
+        .swapTwoHop(new BN(50_000), new BN(0), exactHops)
+        .accountsPartial({
+          user: payer.publicKey,
+          pool1: first.pool,
+          pool2: second.pool,
+          mintIn: mintX,
+          mintMid: mintY,
+          mintOut: mintZ,
+          userTokenIn: tokenAccountFor(first, mintX),
+          userTokenMid: tokenAccountFor(first, mintY),
+          userTokenOut: out,
+          pool1VaultIn: vaultFor(first, mintX),
+          pool1VaultOut: vaultFor(first, mintY),
+          pool2VaultIn: vaultFor(second, mintY),
+          pool2VaultOut: vaultFor(second, mintZ),
+          tokenProgram,
+        })
+        .rpc({ commitment: "confirmed" });
+      const tx = await connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+
+      // Reserves still match the vaults without the reloads
+      for (const f of [first, second]) {
+        const pool = await program.account.pool.fetch(f.pool);
+        const vaultA = await balance(f.vaultA, tokenProgram);
+        assert.equal(pool.reserveA.toString(), vaultA.toString());
+        const vaultB = await balance(f.vaultB, tokenProgram);
+        assert.equal(pool.reserveB.toString(), vaultB.toString());
+      }
+      return {
+        received: (await balance(out, tokenProgram)).sub(before),
+        computeUnits: tx.meta.computeUnitsConsumed,
+      };
+    };
+
+    const exact = await run(true);
+    const measured = await run(false);
+    assert.equal(exact.received.toString(), measured.received.toString());
+    // Three reloads skipped, each well over 100 CU
+    assert.isAtLeast(measured.computeUnits - exact.computeUnits, 300);
+
+    // Token-2022 transfers may charge a fee, so they can't skip measuring
+    await expectError(run(true, TOKEN_2022_PROGRAM_ID), "ExactHopsUnsupported");
+    await run(false, TOKEN_2022_PROGRAM_ID);
+  });
+
+  it("batches swaps across unrelated pools atomically", async () => {
+    const first = await createPool();
+    const second = await createPool();
+    await addLiquidity(first, 1_000_000, 1_000_000);
+    await addLiquidity(second, 1_000_000, 2_000_000);
+
+    // A -> B through the first pool, B -> A through the second
+    const legAccounts = (f: PoolFixture, aToB: boolean) => {
+      const [mintIn, mintOut] = aToB ? [f.mintA, f.mintB] : [f.mintB, f.mintA];
+      const [vaultIn, vaultOut] = aToB
+        ? [f.vaultA, f.vaultB]
+        : [f.vaultB, f.vaultA];
+      const [userIn, userOut] = aToB
+        ? [f.userTokenA, f.userTokenB]
+        : [f.userTokenB, f.userTokenA];
+      return [
+        { pubkey: f.pool, isSigner: false, isWritable: true },
+        { pubkey: mintIn, isSigner: false, isWritable: false },
+        { pubkey: mintOut, isSigner: false, isWritable: false },
+        { pubkey: vaultIn, isSigner: false, isWritable: true },
+        { pubkey: vaultOut, isSigner: false, isWritable: true },
+        { pubkey: userIn, isSigner: false, isWritable: true },
+        { pubkey: userOut, isSigner: false, isWritable: true },
+      ];
+    };
+    const firstPool = await program.account.pool.fetch(first.pool);
+    const secondPool = await program.account.pool.fetch(second.pool);
+    const firstOut = expectedSwapOutput(
+      new BN(40_000),
+      firstPool.reserveA,
+      firstPool.reserveB,
+      firstPool.feeBps
+    );
+    const secondOut = expectedSwapOutput(
+      new BN(60_000),
+      secondPool.reserveB,
+      secondPool.reserveA,
+      secondPool.feeBps
+    );
+    const batchSwap = (secondMinimum: BN) =>
+      program.methods
+        .batchSwap(
+          [
+            { amountIn: new BN(40_000), minimumAmountOut: firstOut },
+            { amountIn: new BN(60_000), minimumAmountOut: secondMinimum },
+          ],
+          new BN(deadlineIn(60))
+        )
+        .accountsPartial({
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .remainingAccounts([
+          ...legAccounts(first, true),
+          ...legAccounts(second, false),
+        ])
+        .rpc();
+
+    // The second leg's slippage check reverts the first leg too
+    await expectError(batchSwap(secondOut.addn(1)), "SlippageTooHigh");
+    let pool = await program.account.pool.fetch(first.pool);
+    assert.equal(pool.reserveA.toString(), firstPool.reserveA.toString());
+    assert.equal(pool.reserveB.toString(), firstPool.reserveB.toString());
+
+    const firstBBefore = await balance(first.userTokenB);
+    const secondABefore = await balance(second.userTokenA);
+    await batchSwap(secondOut);
+    assert.equal(
+      (await balance(first.userTokenB)).sub(firstBBefore).toString(),
+      firstOut.toString()
+    );
+    assert.equal(
+      (await balance(second.userTokenA)).sub(secondABefore).toString(),
+      secondOut.toString()
+    );
+    pool = await program.account.pool.fetch(second.pool);
+    assert.equal(
+      pool.reserveB.toString(),
+      secondPool.reserveB.addn(60_000).toString()
+    );
+  });
+
+  it("batches deposits into several pools atomically", async () => {
+    const first = await createPool();
+    const second = await createPool();
+    await addLiquidity(first, 1_000_000, 1_000_000);
+    await addLiquidity(second, 1_000_000, 2_000_000);
+
+    const depositAccounts = (f: PoolFixture) => [
+      { pubkey: f.pool, isSigner: false, isWritable: true },
+      { pubkey: f.mintA, isSigner: false, isWritable: false },
+      { pubkey: f.mintB, isSigner: false, isWritable: false },
+      { pubkey: f.vaultA, isSigner: false, isWritable: true },
+      { pubkey: f.vaultB, isSigner: false, isWritable: true },
+      { pubkey: f.lpMint, isSigner: false, isWritable: true },
+      { pubkey: f.userTokenA, isSigner: false, isWritable: true },
+      { pubkey: f.userTokenB, isSigner: false, isWritable: true },
+      { pubkey: f.userLpToken, isSigner: false, isWritable: true },
+    ];
+    // A tenth of each pool's reserves mints a tenth of its LP supply
+    const lpSupply = async (f: PoolFixture) =>
+      (await getMint(connection, f.lpMint)).supply;
+    const firstSupply = await lpSupply(first);
+    const secondSupply = await lpSupply(second);
+    const firstLp = new BN((firstSupply / BigInt(10)).toString());
+    const secondLp = new BN((secondSupply / BigInt(10)).toString());
+    const batchAddLiquidity = (secondMinLp: BN) =>
+      program.methods
+        .batchAddLiquidity(
+          [
+            {
+              maxAmountA: new BN(100_000),
+              maxAmountB: new BN(100_000),
+              minLpOut: firstLp,
+            },
+            {
+              maxAmountA: new BN(100_000),
+              maxAmountB: new BN(200_000),
+              minLpOut: secondMinLp,
+            },
+          ],
+          new BN(deadlineIn(60))
+        )
+        .accountsPartial({
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .remainingAccounts([
+          ...depositAccounts(first),
+          ...depositAccounts(second),
+        ])
+        .rpc();
+
+    // The second deposit's slippage check reverts the first one too
+    await expectError(batchAddLiquidity(secondLp.addn(1)), "SlippageTooHigh");
+    assert.equal(await lpSupply(first), firstSupply);
+
+    const firstBefore = await balance(first.userLpToken);
+    const secondBefore = await balance(second.userLpToken);
+    await batchAddLiquidity(secondLp);
+    assert.equal(
+      (await balance(first.userLpToken)).sub(firstBefore).toString(),
+      firstLp.toString()
+    );
+    assert.equal(
+      (await balance(second.userLpToken)).sub(secondBefore).toString(),
+      secondLp.toString()
+    );
+    assert.equal(
+      (await lpSupply(first)).toString(),
+      new BN(firstSupply.toString()).add(firstLp).toString()
+    );
+    assert.equal(
+      (await lpSupply(second)).toString(),
+      new BN(secondSupply.toString()).add(secondLp).toString()
+    );
+    const pool = await program.account.pool.fetch(second.pool);
+    assert.equal(pool.reserveA.toNumber(), 1_100_000);
+    assert.equal(pool.reserveB.toNumber(), 2_200_000);
+  });
+
+  it("rejects a pool with identical mints", async () => {
+    const mint = await newMint();
+
+    await expectError(initializePool(mint, mint), "IdenticalMints");
+  });
+
+  it("accepts mints in canonical order", async () => {
+    const [mintA, mintB] = await newMintPair();
+    await initializePool(mintA, mintB);
+
+    const pool = await program.account.pool.fetch(
+      poolAddresses(mintA, mintB).pool
+    );
+    assert.ok(pool.mintA.equals(mintA));
+  });
+
+  it("rejects mints in reverse order", async () => {
+    const [mintA, mintB] = await newMintPair();
+
+    await expectError(initializePool(mintB, mintA), "MintsNotSorted");
+  });
+
+  it("creates a pool per fee tier for the same pair", async () => {
+    const [mintA, mintB] = await newMintPair();
+    await initializePool(mintA, mintB, { feeTier: 5 });
+    await initializePool(mintA, mintB, { feeTier: 100 });
+
+    const low = poolAddresses(mintA, mintB, 5).pool;
+    const high = poolAddresses(mintA, mintB, 100).pool;
+    assert.notOk(low.equals(high));
+    const [lowPool, highPool] = [
+      await program.account.pool.fetch(low),
+      await program.account.pool.fetch(high),
+    ];
+    // Each pool charges its own tier unless told otherwise
+    assert.equal(lowPool.feeTier, 5);
+    assert.equal(lowPool.feeBps, 5);
+    assert.equal(highPool.feeTier, 100);
+    assert.equal(highPool.feeBps, 100);
+
+    // Only the listed tiers are allowed
+    await expectError(
+      initializePool(mintA, mintB, { feeTier: 50 }),
+      "InvalidFeeTier"
+    );
+  });
+
+  it("keeps labeled pools for the same pair independent", async () => {
+    const mints = await newMintPair();
+    const label = (name: string) => Array.from(Buffer.from(name));
+    const canonical = await createPool({ mints });
+    const first = await createPool({ mints, label: label("strat-01") });
+    const second = await createPool({ mints, label: label("strat-02") });
+
+    const addresses = [canonical, first, second].map((f) => f.pool);
+    assert.equal(new Set(addresses.map((a) => a.toBase58())).size, 3);
+    // The unlabeled pool keeps the canonical address
+    assert.ok(canonical.pool.equals(poolAddresses(mints[0], mints[1]).pool));
+    assert.deepEqual(
+      (await program.account.pool.fetch(first.pool)).label,
+      label("strat-01")
+    );
+
+    await addLiquidity(first, 1_000_000, 1_000_000);
+    await addLiquidity(second, 2_000_000, 8_000_000);
+    await swap(first, 10_000, 0, true);
+
+    const [a, b] = [
+      await program.account.pool.fetch(first.pool),
+      await program.account.pool.fetch(second.pool),
+    ];
+    assert.equal(a.reserveA.toNumber(), 1_010_000);
+    assert.equal(b.reserveA.toNumber(), 2_000_000);
+    assert.equal(b.reserveB.toNumber(), 8_000_000);
+  });
+
+  it("accepts mint decimals only within the supported range", async () => {
+    for (const decimals of [1, 12]) {
+      const [mintA, mintB] = await newMintPair([decimals, 6]);
+      await initializePool(mintA, mintB);
+
+      const pool = await program.account.pool.fetch(
+        poolAddresses(mintA, mintB).pool
+      );
+      // Sorting the mints may have put either one first, but each side's
+      // cached decimals must be its own mint's
+      assert.equal(pool.decimalsA, (await getMint(connection, mintA)).decimals);
+      assert.equal(pool.decimalsB, (await getMint(connection, mintB)).decimals);
+      assert.sameMembers([pool.decimalsA, pool.decimalsB], [decimals, 6]);
+    }
+
+    for (const decimals of [0, 13]) {
+      const [mintA, mintB] = await newMintPair([6, decimals]);
+      await expectError(initializePool(mintA, mintB), "UnsupportedDecimals");
+    }
+  });
+
+  it("restricts new pools to whitelisted mints once enabled", async () => {
+    const [mintA, mintB] = await newMintPair();
+    const [outsider, other] = await newMintPair();
+    const mintWhitelist = findPda([Buffer.from("mint_whitelist")]);
+    const [programData] = PublicKey.findProgramAddressSync(
+      [program.programId.toBuffer()],
+      BPF_LOADER_UPGRADEABLE_PROGRAM_ID
+    );
+
+    // The test validator deploys the program with the wallet as its upgrade
+    // authority (`upgradeable = true` in Anchor.toml)
+    await program.methods
+      .initializeMintWhitelist()
+      .accountsPartial({
+        authority: payer.publicKey,
+        mintWhitelist,
+        program: program.programId,
+        programData,
+      })
+      .rpc();
+
+    try {
+      for (const mint of [mintA, mintB, other]) {
+        await program.methods
+          .addAllowedMint(mint)
+          .accountsPartial({ authority: payer.publicKey, mintWhitelist })
+          .rpc();
+      }
+
+      await initializePool(mintA, mintB);
+      const [lowA, highA] = sortMints(outsider, mintA);
+      await expectError(initializePool(lowA, highA), "MintNotWhitelisted");
+
+      // Removing a mint blocks new pools with it
+      await program.methods
+        .removeAllowedMint(other)
+        .accountsPartial({ authority: payer.publicKey, mintWhitelist })
+        .rpc();
+      const [lowB, highB] = sortMints(other, mintB);
+      await expectError(initializePool(lowB, highB), "MintNotWhitelisted");
+    } finally {
+      // Leave pool creation open for the other tests
+      await program.methods
+        .setMintWhitelistEnabled(false)
+        .accountsPartial({ authority: payer.publicKey, mintWhitelist })
+        .rpc();
+    }
+
+    await initializePool(outsider, other);
+  });
+
+  it("quotes exactly what a subsequent swap pays out", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 3_000_000, 1_000_000);
+
+    const signature = await program.methods
+      .quoteSwap(new BN(40_000), true)
+      .accountsPartial({ pool: f.pool })
+      .rpc({ commitment: "confirmed" });
+    const quote = (await parseEvents(signature)).find(
+      (e) => e.name === "quoteResult"
+    );
+    assert.ok(quote, "QuoteResult not emitted");
+
+    const userBBefore = await balance(f.userTokenB);
+    await swap(f, 40_000, 0, true);
+    const received = (await balance(f.userTokenB)).sub(userBBefore);
+
+    assert.equal(quote.data.amountOut.toString(), received.toString());
+  });
+
+  it("reports a larger price impact for a larger quote", async () => {
+    const f = await createPool({ feeBps: 30 });
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    const quoteDetailed = async (amountIn: number) => {
+      const signature = await program.methods
+        .quoteSwap(new BN(amountIn), true)
+        .accountsPartial({ pool: f.pool })
+        .rpc({ commitment: "confirmed" });
+      const event = (await parseEvents(signature)).find(
+        (e) => e.name === "quoteDetailed"
+      );
+      assert.ok(event, "QuoteDetailed not emitted");
+      return event.data;
+    };
+    const small = await quoteDetailed(1_000);
+    const large = await quoteDetailed(200_000);
+
+    // Both pay the 0.3% fee; the large trade also moves the price ~17%
+    assert.isAtLeast(small.priceImpactBps.toNumber(), 30);
+    assert.isBelow(small.priceImpactBps.toNumber(), 50);
+    assert.isAbove(large.priceImpactBps.toNumber(), 1_500);
+    // Same decimals on both sides, so the price is just out / in
+    const scale = new BN(10).pow(new BN(12));
+    for (const [amountIn, quote] of [
+      [1_000, small],
+      [200_000, large],
+    ] as const) {
+      assert.equal(
+        quote.effectivePrice.toString(),
+        quote.amountOut.mul(scale).divn(amountIn).toString()
+      );
+    }
+    assert.isTrue(large.effectivePrice.lt(small.effectivePrice));
+  });
+
+  it("finds the largest swap within a price impact limit", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    const signature = await program.methods
+      .maxInputForImpact(100, true)
+      .accountsPartial({ pool: f.pool })
+      .rpc({ commitment: "confirmed" });
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "maxInputForImpact"
+    );
+    assert.ok(event, "MaxInputForImpact not emitted");
+    const maxInput = event.data.amountIn.toNumber();
+    assert.isAbove(maxInput, 0);
+
+    // One unit more goes over 1%, while the answer itself lands within it
+    await expectError(
+      swap(f, maxInput + 1, 0, true, deadlineIn(60), 100),
+      "PriceImpactTooHigh"
+    );
+    const before = await balance(f.userTokenB);
+    await swap(f, maxInput, 0, true, deadlineIn(60), 100);
+    const received = (await balance(f.userTokenB)).sub(before);
+    assert.equal(received.toString(), event.data.amountOut.toString());
+  });
+
+  // Needs the program built with the `simulate` feature; see "Simulation
+  // builds" in the README
+  const simulateIt = process.env.SIMULATE === "1" ? it : it.skip;
+
+  simulateIt("simulates a swap without moving any tokens", async () => {
+    const f = await createPool({ fundAmount: 1_000_000 });
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    // The user has nothing left to swap with
+    assert.equal((await balance(f.userTokenA)).toNumber(), 0);
+
+    const quoted = await program.methods
+      .quoteSwap(new BN(100_000), true)
+      .accountsPartial({ pool: f.pool })
+      .view();
+    const signature = await swap(f, 100_000, 0, true);
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "swapExecuted"
+    );
+    assert.ok(event, "SwapExecuted not emitted");
+
+    // Priced exactly like the real swap the quote stands for
+    assert.equal(event.data.amountOut.toString(), quoted.toString());
+    assert.equal(
+      event.data.amountOut.toString(),
+      expectedSwapOutput(
+        new BN(100_000),
+        new BN(1_000_000),
+        new BN(1_000_000),
+        30
+      ).toString()
+    );
+
+    // Recorded against the pool, but no tokens changed hands
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.reserveA.toNumber(), 1_100_000);
+    assert.equal((await balance(f.userTokenA)).toNumber(), 0);
+    assert.equal((await balance(f.userTokenB)).toNumber(), 0);
+    assert.equal((await balance(f.vaultA)).toNumber(), 1_000_000);
+    assert.equal((await balance(f.vaultB)).toNumber(), 1_000_000);
+  });
+
+  it("swaps for an exact output amount", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    const userABefore = await balance(f.userTokenA);
+    const userBBefore = await balance(f.userTokenB);
+    await swapExactOut(f, 9_871, 10_000, true);
+
+    const spent = userABefore.sub(await balance(f.userTokenA));
+    const received = (await balance(f.userTokenB)).sub(userBBefore);
+    assert.equal(received.toNumber(), 9_871);
+    assert.isAtMost(spent.toNumber(), 10_000);
+  });
+
+  it("rejects exact-output swaps above the maximum input", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    await expectError(swapExactOut(f, 9_871, 9_000, true), "SlippageTooHigh");
+  });
+
+  it("rejects exact-output swaps for the whole reserve", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    await expectError(
+      swapExactOut(f, 1_000_000, 1_000_000_000, true),
+      "InsufficientLiquidity"
+    );
+  });
+
+  it("locks the minimum liquidity on the first deposit", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 4_000_000);
+
+    // sqrt(1_000_000 * 4_000_000) = 2_000_000, of which 1_000 is locked
+    assert.equal((await balance(f.userLpToken)).toNumber(), 1_999_000);
+    assert.equal((await balance(f.lockedLp)).toNumber(), 1_000);
+    const lpMint = await getMint(connection, f.lpMint);
+    assert.equal(Number(lpMint.supply), 2_000_000);
+  });
+
+  it("creates a pool with its first deposit in one call", async () => {
+    const [mintA, mintB] = await newMintPair();
+    const addresses = poolAddresses(mintA, mintB);
+    const funded = async (mint: PublicKey) => {
+      const account = await createAccount(
+        connection,
+        payer,
+        mint,
+        payer.publicKey,
+        Keypair.generate()
+      );
+      await mintTo(connection, payer, mint, account, payer, 10_000_000);
+      return account;
+    };
+    const userTokenA = await funded(mintA);
+    const userTokenB = await funded(mintB);
+    const userLpToken = Keypair.generate();
+
+    await program.methods
+      .initializePoolWithLiquidity(
+        DEFAULT_FEE_TIER,
+        NO_LABEL,
+        new BN(1_000_000),
+        new BN(4_000_000)
+      )
+      .accountsPartial({
+        init: {
+          payer: payer.publicKey,
+          authority: payer.publicKey,
+          factory,
+          mintA,
+          mintB,
+          ...addresses,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+        userTokenA,
+        userTokenB,
+        userLpToken: userLpToken.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([userLpToken])
+      .rpc({ commitment: "confirmed" });
+
+    const pool = await program.account.pool.fetch(addresses.pool);
+    assert.equal(pool.reserveA.toNumber(), 1_000_000);
+    assert.equal(pool.reserveB.toNumber(), 4_000_000);
+    const lp = await balance(userLpToken.publicKey);
+    assert.equal(lp.toNumber(), 1_999_000);
+    assert.equal((await balance(addresses.lockedLp)).toNumber(), 1_000);
+  });
+
+  it("emits liquidity events with the amounts and new reserves", async () => {
+    const f = await createPool();
+    const eventIn = async (signature: string, name: string) => {
+      const event = (await parseEvents(signature)).find(
+        (e) => e.name === name
+      );
+      assert.ok(event, `${name} not emitted`);
+      return event.data;
+    };
+
+    // sqrt(1_000_000 * 4_000_000) = 2_000_000, less the locked 1_000
+    const added = await eventIn(
+      await addLiquidity(f, 1_000_000, 4_000_000),
+      "liquidityAdded"
+    );
+    assert.ok(added.pool.equals(f.pool));
+    assert.ok(added.user.equals(payer.publicKey));
+    assert.equal(added.amountA.toNumber(), 1_000_000);
+    assert.equal(added.amountB.toNumber(), 4_000_000);
+    assert.equal(added.lpMinted.toNumber(), 1_999_000);
+    assert.equal(added.reserveA.toNumber(), 1_000_000);
+    assert.equal(added.reserveB.toNumber(), 4_000_000);
+
+    const [a0, b0] = [await balance(f.userTokenA), await balance(f.userTokenB)];
+    const removed = await eventIn(
+      await removeLiquidity(f, 500_000),
+      "liquidityRemoved"
+    );
+    const a1 = await balance(f.userTokenA);
+    const b1 = await balance(f.userTokenB);
+    assert.equal(removed.amountA.toString(), a1.sub(a0).toString());
+    assert.equal(removed.amountB.toString(), b1.sub(b0).toString());
+    assert.equal(removed.amountA.toNumber(), 250_000);
+    assert.equal(removed.amountB.toNumber(), 1_000_000);
+    assert.equal(removed.lpBurned.toNumber(), 500_000);
+    assert.equal(removed.reserveA.toNumber(), 750_000);
+    assert.equal(removed.reserveB.toNumber(), 3_000_000);
+  });
+
+  it("tracks k across deposits, withdrawals, and swaps", async () => {
+    const f = await createPool();
+    const k = async () =>
+      (await program.account.pool.fetch(f.pool)).lastInvariantK;
+    assert.equal((await k()).toString(), "0");
+
+    await addLiquidity(f, 1_000_000, 4_000_000);
+    assert.equal((await k()).toString(), "4000000000000");
+    // Half as much again scales k by 1.5^2
+    await addLiquidity(f, 500_000, 2_000_000);
+    assert.equal((await k()).toString(), "9000000000000");
+    // Withdrawing a third of the LP supply takes it back down
+    await removeLiquidity(f, 1_000_000);
+    assert.equal((await k()).toString(), "4000000000000");
+
+    // The fee stays in the reserves, so every swap raises k
+    for (const aToB of [true, false]) {
+      const before = await k();
+      await swap(f, 50_000, 0, aToB);
+      assert.isTrue((await k()).gt(before));
+    }
+  });
+
+  async function setLiquidityCap(f: PoolFixture, liquidityCap: number) {
+    await program.methods
+      .setLiquidityCap(new BN(liquidityCap))
+      .accountsPartial({ authority: payer.publicKey, pool: f.pool })
+      .rpc();
+  }
+
+  it("accepts deposits up to the pool's liquidity cap", async () => {
+    const f = await createPool();
+    // sqrt(1_000_000 * 4_000_000) = 2_000_000 LP, locked part included
+    await setLiquidityCap(f, 2_000_000);
+    await addLiquidity(f, 1_000_000, 4_000_000);
+
+    const lpMint = await getMint(connection, f.lpMint);
+    assert.equal(lpMint.supply.toString(), "2000000");
+
+    // Withdrawing makes room again
+    await removeLiquidity(f, 500_000);
+    await addLiquidity(f, 250_000, 1_000_000);
+  });
+
+  it("rejects a deposit over the pool's liquidity cap", async () => {
+    const f = await createPool();
+    await setLiquidityCap(f, 2_000_000);
+    await addLiquidity(f, 500_000, 2_000_000);
+
+    await expectError(
+      addLiquidity(f, 500_004, 2_000_016),
+      "LiquidityCapExceeded"
+    );
+
+    // 0 lifts the cap
+    await setLiquidityCap(f, 0);
+    await addLiquidity(f, 500_004, 2_000_016);
+  });
+
+  it("caps each swap's input and output at the pool's limits", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 10_000_000, 10_000_000);
+    const setSwapLimits = (maxIn: number, maxOut: number, authority = payer) =>
+      program.methods
+        .setSwapLimits(new BN(maxIn), new BN(maxOut))
+        .accountsPartial({ authority: authority.publicKey, pool: f.pool })
+        .signers([authority])
+        .rpc();
+
+    const stranger = Keypair.generate();
+    await expectError(setSwapLimits(1, 1, stranger), "Unauthorized");
+
+    await setSwapLimits(100_000, 50_000);
+    let pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.maxSwapIn.toNumber(), 100_000);
+    assert.equal(pool.maxSwapOut.toNumber(), 50_000);
+
+    // Within both limits
+    await swap(f, 50_000, 0, true);
+    // Over the input limit, and within it but over the output limit
+    await expectError(swap(f, 100_001, 0, true), "SwapLimitExceeded");
+    await expectError(swap(f, 60_000, 0, false), "SwapLimitExceeded");
+
+    // 0 lifts each cap
+    await setSwapLimits(0, 0);
+    await swap(f, 200_000, 0, true);
+    pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.maxSwapIn.toNumber(), 0);
+  });
+
+  it("stops swaps from draining a reserve below the floor", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    await program.methods
+      .setMinReserve(new BN(800_000))
+      .accountsPartial({ authority: payer.publicKey, pool: f.pool })
+      .rpc();
+
+    // Each swap takes B towards the floor until the next would cross it
+    await swap(f, 100_000, 0, true);
+    await swap(f, 100_000, 0, true);
+    let pool = await program.account.pool.fetch(f.pool);
+    assert.isAtLeast(pool.reserveB.toNumber(), 800_000);
+    await expectError(swap(f, 100_000, 0, true), "ReserveBelowFloor");
+
+    // Swaps that refill B still go through
+    await swap(f, 100_000, 0, false);
+    pool = await program.account.pool.fetch(f.pool);
+    assert.isAbove(pool.reserveB.toNumber(), 900_000);
+  });
+
+  it("pulls only the ratio-matched amounts from a deposit", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 4_000_000);
+
+    // Offer far more B than the 1:4 ratio needs
+    const [aBefore, bBefore] = [
+      await balance(f.userTokenA),
+      await balance(f.userTokenB),
+    ];
+    await addLiquidity(f, 500_000, 10_000_000);
+    const spentA = aBefore.sub(await balance(f.userTokenA));
+    const spentB = bBefore.sub(await balance(f.userTokenB));
+
+    assert.equal(spentA.toNumber(), 500_000);
+    assert.equal(spentB.toNumber(), 2_000_000);
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.reserveA.toNumber(), 1_500_000);
+    assert.equal(pool.reserveB.toNumber(), 6_000_000);
+  });
+
+  it("adds single-sided liquidity worth the input less fees", async () => {
+    const addSingle = (f: PoolFixture) =>
+      program.methods
+        .addLiquiditySingle(new BN(100_000), true, new BN(1))
+        .accountsPartial({
+          user: payer.publicKey,
+          pool: f.pool,
+          mintA: f.mintA,
+          mintB: f.mintB,
+          userTokenA: f.userTokenA,
+          userTokenB: f.userTokenB,
+          vaultA: f.vaultA,
+          vaultB: f.vaultB,
+          lpMint: f.lpMint,
+          lockedLp: f.lockedLp,
+          userLpToken: f.userLpToken,
+          tokenProgram: f.tokenProgram,
+        })
+        .rpc();
+
+    const f = await createPool();
+    await addLiquidity(f, 100_000_000, 400_000_000);
+    const before = await program.account.pool.fetch(f.pool);
+
+    const lpBefore = await balance(f.userLpToken);
+    const bBefore = await balance(f.userTokenB);
+    await addSingle(f);
+    const lpMinted = (await balance(f.userLpToken)).sub(lpBefore);
+
+    // Only token A was taken
+    assert.equal((await balance(f.userTokenB)).toString(), bBefore.toString());
+
+    // Valued at the price before the deposit, the position is worth the
+    // input less the fee on the swapped half and a sliver of price impact
+    const after = await program.account.pool.fetch(f.pool);
+    const supply = new BN(
+      (await getMint(connection, f.lpMint)).supply.toString()
+    );
+    const valueInA = lpMinted
+      .mul(after.reserveA)
+      .div(supply)
+      .add(
+        lpMinted
+          .mul(after.reserveB)
+          .mul(before.reserveA)
+          .div(supply.mul(before.reserveB))
+      );
+    assert.isAtMost(valueInA.toNumber(), 100_000);
+    assert.isAtLeast(valueInA.toNumber(), 99_700);
+
+    // Stable pools have no closed-form split
+    const stable = await createPool({
+      curveType: { stable: { amp: new BN(100) } },
+    });
+    await addLiquidity(stable, 1_000_000, 1_000_000);
+    await expectError(addSingle(stable), "UnsupportedCurve");
+  });
+
+  it("rejects a deposit that would mint less than min_lp_out", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    // Quoted against the current pool: 100_000 of each mints 100_000 LP
+    const quoted = 100_000;
+
+    // A swap lands first and moves the reserves, so the same deposit now buys
+    // a smaller share of the pool
+    await swap(f, 200_000, 0, true);
+    await expectError(
+      addLiquidity(f, 100_000, 100_000, quoted),
+      "SlippageTooHigh"
+    );
+    await addLiquidity(f, 100_000, 100_000, 0);
+  });
+
+  it("rejects a first deposit below the minimum liquidity", async () => {
+    const f = await createPool();
+
+    await expectError(
+      addLiquidity(f, 1_000, 1_000),
+      "InsufficientInitialLiquidity"
+    );
+  });
+
+  it("checks the opening price against the expected price", async () => {
+    const f = await createPool();
+    // The LP means 1 A = 2 B (both mints have 6 decimals)
+    const twoB = new BN(2).mul(new BN(10).pow(new BN(12)));
+
+    // Typing the amounts the wrong way round implies 1 A = 0.5 B
+    await expectError(
+      addLiquidity(f, 2_000_000, 1_000_000, 0, twoB, 100),
+      "PriceOutOfRange"
+    );
+    // Off by 1.5% is outside a 1% band
+    await expectError(
+      addLiquidity(f, 1_000_000, 2_030_000, 0, twoB, 100),
+      "PriceOutOfRange"
+    );
+
+    await addLiquidity(f, 1_000_000, 2_010_000, 0, twoB, 100);
+    const pool = await program.account.pool.fetch(f.pool);
+    expect(pool.reserveB.toNumber()).to.equal(2_010_000);
+  });
+
+  it("rejects zero-amount swaps and deposits", async () => {
+    const f = await createPool();
+
+    await expectError(addLiquidity(f, 0, 0), "ZeroAmount");
+    // A one-sided deposit would leave the other reserve empty
+    await expectError(addLiquidity(f, 0, 1_000_000), "ZeroAmount");
+
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    await expectError(swap(f, 0, 0, true), "ZeroAmount");
+    await expectError(swapExactOut(f, 0, 1_000, true), "ZeroAmount");
+  });
+
+  it("rejects a swap that rounds down to zero output", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 100_000_000, 100_000);
+
+    // 500 A is worth ~0.5 B at this price, which rounds down to nothing
+    await expectError(swap(f, 500, 0, true), "ZeroOutput");
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.reserveA.toNumber(), 100_000_000);
+  });
+
+  it("rejects an input the swap fee rounds away entirely", async () => {
+    const f = await createPool({ feeTier: 100, feeBps: 1_000 });
+    // A is scarce, so a single unit of it still buys some B
+    await addLiquidity(f, 10_000, 1_000_000);
+    const userABefore = await balance(f.userTokenA);
+
+    // 10% of 1 unit leaves 0.9, which rounds down to nothing
+    await expectError(swap(f, 1, 0, true), "AmountTooSmall");
+    assert.equal(
+      (await balance(f.userTokenA)).toString(),
+      userABefore.toString()
+    );
+
+    // Two units keep one after the fee
+    await swap(f, 2, 0, true);
+  });
+
+  it("rejects swaps and deposits while paused", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    await setPaused(f, true);
+    await expectError(swap(f, 10_000, 0, true), "PoolPaused");
+    await expectError(addLiquidity(f, 1_000, 1_000), "PoolPaused");
+
+    await setPaused(f, false);
+    await swap(f, 10_000, 0, true);
+  });
+
+  it("only lets the authority pause the pool", async () => {
+    const f = await createPool();
+
+    await expectError(setPaused(f, true, Keypair.generate()), "Unauthorized");
+    assert.isFalse((await program.account.pool.fetch(f.pool)).isPaused);
+  });
+
+  it("delays a proposed fee change by the timelock", async () => {
+    const f = await createPool({ feeBps: 30 });
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    const proposeFee = (newFeeBps: number, authority: Keypair = payer) =>
+      program.methods
+        .proposeFee(newFeeBps)
+        .accountsPartial({ authority: authority.publicKey, pool: f.pool })
+        .signers([authority])
+        .rpc();
+
+    await expectError(proposeFee(1_001), "InvalidFee");
+    await expectError(proposeFee(100, Keypair.generate()), "Unauthorized");
+
+    await proposeFee(100);
+    const before = await program.account.pool.fetch(f.pool);
+    assert.equal(before.pendingFeeBps, 100);
+    assert.approximately(
+      before.feeEffectiveTs.toNumber(),
+      Math.floor(Date.now() / 1000) + 86_400,
+      60
+    );
+
+    // Until the timelock passes swaps still pay the old fee. The switch-over
+    // itself is covered by the unit tests, as the validator can't skip a day.
+    const expected = expectedSwapOutput(
+      new BN(10_000),
+      before.reserveA,
+      before.reserveB,
+      30
+    );
+    const userBBefore = await balance(f.userTokenB);
+    await swap(f, 10_000, 0, true);
+    assert.equal(
+      (await balance(f.userTokenB)).sub(userBBefore).toString(),
+      expected.toString()
+    );
+    assert.equal((await program.account.pool.fetch(f.pool)).feeBps, 30);
+  });
+
+  it("raises a dynamic fee after a large price move", async () => {
+    const f = await createPool({ feeBand: { minFeeBps: 5, maxFeeBps: 100 } });
+    await addLiquidity(f, 10_000_000, 10_000_000);
+    let pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.feeBps, 5);
+
+    // Let the TWAP settle at 1:1, then move the price by a fifth in one swap
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    await swap(f, 2_500_000, 0, true);
+    pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.feeBps, 5);
+
+    // The next trade sees the spot price far from the window's average
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    await swap(f, 1_000, 0, false);
+    pool = await program.account.pool.fetch(f.pool);
+    assert.isAbove(pool.feeBps, 5);
+    assert.isAtMost(pool.feeBps, 100);
+
+    // The band, not the authority, sets the fee
+    await expectError(
+      program.methods
+        .proposeFee(50)
+        .accountsPartial({ authority: payer.publicKey, pool: f.pool })
+        .rpc(),
+      "DynamicFee"
+    );
+  });
+
+  it("transfers authority in two steps", async () => {
+    const f = await createPool();
+    const newAuthority = Keypair.generate();
+
+    await program.methods
+      .proposeAuthority(newAuthority.publicKey)
+      .accountsPartial({ authority: payer.publicKey, pool: f.pool })
+      .rpc();
+
+    let pool = await program.account.pool.fetch(f.pool);
+    assert.ok(pool.authority.equals(payer.publicKey));
+    assert.ok(pool.pendingAuthority.equals(newAuthority.publicKey));
+
+    await program.methods
+      .acceptAuthority()
+      .accountsPartial({
+        pendingAuthority: newAuthority.publicKey,
+        pool: f.pool,
+      })
+      .signers([newAuthority])
+      .rpc();
+
+    pool = await program.account.pool.fetch(f.pool);
+    assert.ok(pool.authority.equals(newAuthority.publicKey));
+    assert.isNull(pool.pendingAuthority);
+  });
+
+  it("rejects accept_authority from a key that was not proposed", async () => {
+    const f = await createPool();
+    const intruder = Keypair.generate();
+
+    await program.methods
+      .proposeAuthority(Keypair.generate().publicKey)
+      .accountsPartial({ authority: payer.publicKey, pool: f.pool })
+      .rpc();
+
+    await expectError(
+      program.methods
+        .acceptAuthority()
+        .accountsPartial({ pendingAuthority: intruder.publicKey, pool: f.pool })
+        .signers([intruder])
+        .rpc(),
+      "NotPendingAuthority"
+    );
+  });
+
+  it("attaches Metaplex metadata to the LP mint", async () => {
+    const f = await createPool();
+    const metadataProgram = new PublicKey(
+      "metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s"
+    );
+    const [metadata] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("metadata"),
+        metadataProgram.toBuffer(),
+        f.lpMint.toBuffer(),
+      ],
+      metadataProgram
+    );
+
+    await program.methods
+      .createLpMetadata("Swap LP", "SLP", "https://example.com/lp.json")
+      .accountsPartial({
+        authority: payer.publicKey,
+        pool: f.pool,
+        lpMint: f.lpMint,
+        metadata,
+        tokenMetadataProgram: metadataProgram,
+      })
+      .rpc();
+
+    // key (1), update authority (32), mint (32), then length-prefixed
+    // strings padded with NULs
+    const data = (await connection.getAccountInfo(metadata)).data;
+    let offset = 65;
+    const readString = () => {
+      const length = data.readUInt32LE(offset);
+      const value = data
+        .subarray(offset + 4, offset + 4 + length)
+        .toString("utf8")
+        .replace(/\0/g, "");
+      offset += 4 + length;
+      return value;
+    };
+    assert.ok(new PublicKey(data.subarray(1, 33)).equals(f.pool));
+    assert.ok(new PublicKey(data.subarray(33, 65)).equals(f.lpMint));
+    assert.equal(readString(), "Swap LP");
+    assert.equal(readString(), "SLP");
+    assert.equal(readString(), "https://example.com/lp.json");
+  });
+
+  it("freezes the LP mint so no more LP tokens can be minted", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    const freezeLpMint = (authority: Keypair) =>
+      program.methods
+        .freezeLpMint()
+        .accountsPartial({
+          authority: authority.publicKey,
+          pool: f.pool,
+          lpMint: f.lpMint,
+          tokenProgram: f.tokenProgram,
+        })
+        .signers([authority])
+        .rpc({ commitment: "confirmed" });
+
+    await expectError(freezeLpMint(Keypair.generate()), "Unauthorized");
+    await freezeLpMint(payer);
+    const lpMint = await getMint(connection, f.lpMint);
+    assert.isNull(lpMint.mintAuthority);
+
+    // The token program rejects the deposit's mint with FixedSupply (0x5)
+    const err = await addLiquidity(f, 100_000, 100_000).then(
+      () => null,
+      (e) => e
+    );
+    assert.isNotNull(err, "expected the deposit to fail");
+    assert.include(err.logs.join("\n"), "custom program error: 0x5");
+
+    // Withdrawals only burn, so they still work
+    await removeLiquidity(f, 100_000);
+    const after = await getMint(connection, f.lpMint);
+    assert.equal(after.supply.toString(), "900000");
+  });
+
+  it("checks the LP mint and leaves a healthy one alone", async () => {
+    const f = await createPool();
+    const ensureLpMint = async (
+      authority: Keypair,
+      currentMintAuthority: Keypair | null = null
+    ) => {
+      const signature = await program.methods
+        .ensureLpMint()
+        .accountsPartial({
+          authority: authority.publicKey,
+          pool: f.pool,
+          lpMint: f.lpMint,
+          lockedLp: f.lockedLp,
+          currentMintAuthority: currentMintAuthority?.publicKey ?? null,
+          tokenProgram: f.tokenProgram,
+        })
+        .signers(
+          currentMintAuthority ? [authority, currentMintAuthority] : [authority]
+        )
+        .rpc({ commitment: "confirmed" });
+      const event = (await parseEvents(signature)).find(
+        (e) => e.name === "lpMintEnsured"
+      );
+      assert.ok(event, "LpMintEnsured not emitted");
+      return event.data;
+    };
+
+    await expectError(ensureLpMint(Keypair.generate()), "Unauthorized");
+
+    // Empty, then funded: the pool already holds the authority
+    assert.isFalse((await ensureLpMint(payer)).restored);
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    const event = await ensureLpMint(payer);
+    assert.isFalse(event.restored);
+    assert.equal(event.lpSupply.toNumber(), 1_000_000);
+
+    // A stray co-signer can't take or hand over what the pool holds
+    assert.isFalse((await ensureLpMint(payer, Keypair.generate())).restored);
+    let lpMint = await getMint(connection, f.lpMint);
+    assert.ok(lpMint.mintAuthority.equals(f.pool));
+
+    // A frozen mint stays frozen
+    await program.methods
+      .freezeLpMint()
+      .accountsPartial({
+        authority: payer.publicKey,
+        pool: f.pool,
+        lpMint: f.lpMint,
+        tokenProgram: f.tokenProgram,
+      })
+      .rpc({ commitment: "confirmed" });
+    assert.isFalse((await ensureLpMint(payer)).restored);
+    lpMint = await getMint(connection, f.lpMint);
+    assert.isNull(lpMint.mintAuthority);
+  });
+
+  it("creates pools at the current layout version", async () => {
+    const f = await createPool();
+    const state = await program.account.pool.fetch(f.pool);
+    assert.equal(state.version, 6);
+
+    const migratePool = (authority: Keypair) =>
+      program.methods
+        .migratePool()
+        .accountsPartial({ authority: authority.publicKey, pool: f.pool })
+        .signers([authority])
+        .rpc({ commitment: "confirmed" });
+
+    await expectError(migratePool(Keypair.generate()), "Unauthorized");
+    await expectError(migratePool(payer), "PoolUpToDate");
+  });
+
+  it("fails guarded swaps early without enough compute", async () => {
+    // Every heavy feature on: stable curve, oracle, and a dynamic fee
+    const f = await createPool({
+      curveType: { stable: { amp: new BN(100) } },
+      feeBand: { minFeeBps: 5, maxFeeBps: 100 },
+    });
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    await program.methods
+      .setComputeGuard(true)
+      .accountsPartial({ authority: payer.publicKey, pool: f.pool })
+      .rpc();
+
+    // Well under the heavy path's estimate
+    const limit = ComputeBudgetProgram.setComputeUnitLimit({ units: 60_000 });
+    await expectError(
+      program.methods
+        .swap(new BN(10_000), new BN(0), true, new BN(deadlineIn(60)), null)
+        .accountsPartial({
+          user: payer.publicKey,
+          pool: f.pool,
+          mintA: f.mintA,
+          mintB: f.mintB,
+          userTokenA: f.userTokenA,
+          userTokenB: f.userTokenB,
+          vaultA: f.vaultA,
+          vaultB: f.vaultB,
+          referrer: null,
+          tokenProgram: f.tokenProgram,
+        })
+        .preInstructions([limit])
+        .rpc(),
+      "ComputeBudgetTooLow"
+    );
+    assert.equal((await balance(f.vaultA)).toNumber(), 1_000_000);
+
+    // The default limit covers it
+    await swap(f, 10_000, 0, true);
+    assert.equal((await balance(f.vaultA)).toNumber(), 1_010_000);
+  });
+
+  it("guards compute on a two-hop route's second pool", async () => {
+    const { mintX, mintY, mintZ, first, second } = await createRoute();
+    await program.methods
+      .setComputeGuard(true)
+      .accountsPartial({ authority: payer.publicKey, pool: second.pool })
+      .rpc();
+
+    // Covers the first pool's swap but not both
+    const limit = ComputeBudgetProgram.setComputeUnitLimit({ units: 60_000 });
+    const xBefore = await balance(vaultFor(first, mintX));
+    await expectError(
+      program.methods
+        .swapTwoHop(new BN(50_000), new BN(0), false)
+        .accountsPartial({
+          user: payer.publicKey,
+          pool1: first.pool,
+          pool2: second.pool,
+          mintIn: mintX,
+          mintMid: mintY,
+          mintOut: mintZ,
+          userTokenIn: tokenAccountFor(first, mintX),
+          userTokenMid: tokenAccountFor(first, mintY),
+          userTokenOut: tokenAccountFor(second, mintZ),
+          pool1VaultIn: vaultFor(first, mintX),
+          pool1VaultOut: vaultFor(first, mintY),
+          pool2VaultIn: vaultFor(second, mintY),
+          pool2VaultOut: vaultFor(second, mintZ),
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .preInstructions([limit])
+        .rpc(),
+      "ComputeBudgetTooLow"
+    );
+    assert.equal(
+      (await balance(vaultFor(first, mintX))).toString(),
+      xBefore.toString()
+    );
+  });
+
+  it("accrues protocol fees and pays them to the treasury", async () => {
+    const f = await createPool({ feeBps: 30, protocolFeeBps: 5 });
+    await addLiquidity(f, 10_000_000, 10_000_000);
+
+    const swaps: [number, boolean][] = [
+      [100_000, true],
+      [250_000, false],
+      [40_000, true],
+    ];
+    let expectedA = 0;
+    let expectedB = 0;
+    for (const [amountIn, aToB] of swaps) {
+      await swap(f, amountIn, 0, aToB);
+      const protocolFee = Math.floor((amountIn * 5) / 10_000);
+      if (aToB) {
+        expectedA += protocolFee;
+      } else {
+        expectedB += protocolFee;
+      }
+    }
+
+    let pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.protocolFeesA.toNumber(), expectedA);
+    assert.equal(pool.protocolFeesB.toNumber(), expectedB);
+
+    const [treasuryA, treasuryB] = await newTreasury(f);
+    await program.methods
+      .collectProtocolFees()
+      .accountsPartial(authorityWithdrawAccounts(f, treasuryA, treasuryB))
+      .rpc();
+
+    assert.equal((await balance(treasuryA)).toNumber(), expectedA);
+    assert.equal((await balance(treasuryB)).toNumber(), expectedB);
+
+    pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.protocolFeesA.toNumber(), 0);
+    assert.equal(pool.protocolFeesB.toNumber(), 0);
+    // What remains in the vaults is exactly the LPs' reserves
+    assert.equal(
+      (await balance(f.vaultA)).toString(),
+      pool.reserveA.toString()
+    );
+    assert.equal(
+      (await balance(f.vaultB)).toString(),
+      pool.reserveB.toString()
+    );
+  });
+
+  it("pays collected protocol fees to a separate fee recipient", async () => {
+    const f = await createPool({ feeBps: 30, protocolFeeBps: 5 });
+    await addLiquidity(f, 10_000_000, 10_000_000);
+    await swap(f, 100_000, 0, true);
+    assert.ok(
+      (await program.account.pool.fetch(f.pool)).feeRecipient.equals(
+        payer.publicKey
+      )
+    );
+
+    const recipient = Keypair.generate();
+    await expectError(
+      program.methods
+        .setFeeRecipient(recipient.publicKey)
+        .accountsPartial({ authority: recipient.publicKey, pool: f.pool })
+        .signers([recipient])
+        .rpc(),
+      "Unauthorized"
+    );
+    await program.methods
+      .setFeeRecipient(recipient.publicKey)
+      .accountsPartial({ authority: payer.publicKey, pool: f.pool })
+      .rpc();
+
+    // The authority's own accounts no longer qualify
+    const [ownA, ownB] = await newTreasury(f);
+    await expectError(
+      program.methods
+        .collectProtocolFees()
+        .accountsPartial(authorityWithdrawAccounts(f, ownA, ownB))
+        .rpc(),
+      "ConstraintTokenOwner"
+    );
+
+    const [treasuryA, treasuryB] = await newTreasury(f, recipient.publicKey);
+    await program.methods
+      .collectProtocolFees()
+      .accountsPartial(authorityWithdrawAccounts(f, treasuryA, treasuryB))
+      .rpc();
+
+    assert.equal(
+      (await balance(treasuryA)).toNumber(),
+      Math.floor((100_000 * 5) / 10_000)
+    );
+    assert.equal((await balance(ownA)).toNumber(), 0);
+  });
+
+  it("discounts the swap fee for LP holders above the threshold", async () => {
+    const f = await createPool({ feeBps: 30 });
+    await addLiquidity(f, 10_000_000, 10_000_000);
+    // Half the fee off for anyone holding at least 1_000_000 LP tokens
+    await program.methods
+      .setLpDiscount(new BN(1_000_000), 5_000)
+      .accountsPartial({ authority: payer.publicKey, pool: f.pool })
+      .rpc();
+    // Same owner, but no LP tokens in it
+    const emptyLpToken = await createAccount(
+      connection,
+      payer,
+      f.lpMint,
+      payer.publicKey,
+      Keypair.generate(),
+      undefined,
+      f.tokenProgram
+    );
+
+    const swapWith = async (userLpToken: PublicKey | null) => {
+      const pool = await program.account.pool.fetch(f.pool);
+      const before = await balance(f.userTokenB);
+      await program.methods
+        .swap(new BN(100_000), new BN(0), true, new BN(deadlineIn(60)), null)
+        .accountsPartial({
+          user: payer.publicKey,
+          pool: f.pool,
+          mintA: f.mintA,
+          mintB: f.mintB,
+          userTokenA: f.userTokenA,
+          userTokenB: f.userTokenB,
+          vaultA: f.vaultA,
+          vaultB: f.vaultB,
+          referrer: null,
+          userLpToken,
+          tokenProgram: f.tokenProgram,
+        })
+        .rpc();
+      return {
+        out: (await balance(f.userTokenB)).sub(before),
+        reserveIn: pool.reserveA,
+        reserveOut: pool.reserveB,
+      };
+    };
+
+    const holder = await swapWith(f.userLpToken);
+    assert.equal(
+      holder.out.toString(),
+      expectedSwapOutput(
+        new BN(100_000),
+        holder.reserveIn,
+        holder.reserveOut,
+        15
+      ).toString()
+    );
+    for (const userLpToken of [emptyLpToken, null]) {
+      const nonHolder = await swapWith(userLpToken);
+      assert.equal(
+        nonHolder.out.toString(),
+        expectedSwapOutput(
+          new BN(100_000),
+          nonHolder.reserveIn,
+          nonHolder.reserveOut,
+          30
+        ).toString()
+      );
+    }
+    // The discount never sticks to the pool
+    assert.equal((await program.account.pool.fetch(f.pool)).feeBps, 30);
+  });
+
+  it("splits the referral fee out of the swap fee", async () => {
+    const f = await createPool({
+      feeBps: 30,
+      protocolFeeBps: 5,
+      referralFeeBps: 10,
+    });
+    await addLiquidity(f, 10_000_000, 10_000_000);
+    const [referrerA, referrerB] = await newTreasury(
+      f,
+      Keypair.generate().publicKey
+    );
+    const amountIn = 100_000;
+    const referralFee = (amountIn * 10) / 10_000;
+    const protocolFee = (amountIn * 5) / 10_000;
+
+    // Without a referrer the LPs keep everything but the protocol's cut
+    let before = await program.account.pool.fetch(f.pool);
+    await swap(f, amountIn, 0, true);
+    let after = await program.account.pool.fetch(f.pool);
+    assert.equal(
+      after.reserveA.sub(before.reserveA).toNumber(),
+      amountIn - protocolFee
+    );
+
+    // With one, the referrer is paid from the fee and the user's output is
+    // unchanged
+    const quoted = await program.methods
+      .quoteSwap(new BN(amountIn), true)
+      .accountsPartial({ pool: f.pool })
+      .view();
+    const userBBefore = await balance(f.userTokenB);
+    before = after;
+    await swap(f, amountIn, 0, true, deadlineIn(60), null, referrerA);
+    after = await program.account.pool.fetch(f.pool);
+
+    assert.equal((await balance(referrerA)).toNumber(), referralFee);
+    assert.equal(
+      after.reserveA.sub(before.reserveA).toNumber(),
+      amountIn - protocolFee - referralFee
+    );
+    assert.equal(
+      (await balance(f.userTokenB)).sub(userBBefore).toString(),
+      quoted.toString()
+    );
+    // The vault holds exactly the reserve plus the protocol's fees
+    assert.equal(
+      (await balance(f.vaultA)).toString(),
+      after.reserveA.add(after.protocolFeesA).toString()
+    );
+
+    // The referrer must hold the input token
+    await expectError(
+      swap(f, amountIn, 0, true, deadlineIn(60), null, referrerB),
+      "InvalidReferrer"
+    );
+  });
+
+  it("rejects a referral fee larger than the swap fee", async () => {
+    const [mintA, mintB] = await newMintPair();
+
+    await expectError(
+      initializePool(mintA, mintB, {
+        feeBps: 30,
+        protocolFeeBps: 10,
+        referralFeeBps: 25,
+      }),
+      "InvalidFee"
+    );
+  });
+
+  it("reports a decimals-normalized spot price", async () => {
+    const f = await createPool({
+      decimals: [6, 9],
+      fundAmount: 10_000 * 10 ** 9,
+    });
+    const decimalsA = (await getMint(connection, f.mintA)).decimals;
+    const decimalsB = (await getMint(connection, f.mintB)).decimals;
+
+    // Deposit 1_000 whole A against 2_000 whole B, so 1 A = 2 B
+    await addLiquidity(f, 1_000 * 10 ** decimalsA, 2_000 * 10 ** decimalsB);
+
+    const signature = await program.methods
+      .getSpotPrice()
+      .accountsPartial({ pool: f.pool })
+      .rpc({ commitment: "confirmed" });
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "spotPrice"
+    );
+    assert.ok(event, "SpotPrice not emitted");
+
+    const scale = new BN(10).pow(new BN(12));
+    assert.equal(event.data.priceAInB.toString(), scale.muln(2).toString());
+    assert.equal(event.data.priceBInA.toString(), scale.divn(2).toString());
+  });
+
+  it("reports the reserves and LP supply", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 2_000_000);
+    await swap(f, 50_000, 0, true);
+
+    const signature = await program.methods
+      .getReserves()
+      .accountsPartial({ pool: f.pool, lpMint: f.lpMint })
+      .rpc({ commitment: "confirmed" });
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "reserves"
+    );
+    assert.ok(event, "Reserves not emitted");
+
+    const pool = await program.account.pool.fetch(f.pool);
+    const lpMint = await getMint(connection, f.lpMint);
+    assert.ok(event.data.pool.equals(f.pool));
+    assert.equal(event.data.reserveA.toString(), pool.reserveA.toString());
+    assert.equal(event.data.reserveB.toString(), pool.reserveB.toString());
+    assert.equal(event.data.lpSupply.toString(), lpMint.supply.toString());
+    assert.equal(event.data.reserveA.toNumber(), 1_050_000);
+  });
+
+  it("bundles the pool's state into one PoolInfo event", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 2_000_000);
+    await swap(f, 50_000, 0, true);
+    await setPaused(f, true);
+
+    const signature = await program.methods
+      .getPoolInfo()
+      .accountsPartial({ pool: f.pool, lpMint: f.lpMint })
+      .rpc({ commitment: "confirmed" });
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "poolInfo"
+    );
+    assert.ok(event, "PoolInfo not emitted");
+
+    const pool = await program.account.pool.fetch(f.pool);
+    const lpMint = await getMint(connection, f.lpMint);
+    assert.ok(event.data.pool.equals(f.pool));
+    assert.ok(event.data.mintA.equals(pool.mintA));
+    assert.ok(event.data.mintB.equals(pool.mintB));
+    assert.ok(event.data.lpMint.equals(pool.lpMint));
+    assert.equal(event.data.reserveA.toString(), pool.reserveA.toString());
+    assert.equal(event.data.reserveB.toString(), pool.reserveB.toString());
+    assert.equal(event.data.feeBps, pool.feeBps);
+    assert.equal(event.data.lpSupply.toString(), lpMint.supply.toString());
+    assert.isTrue(event.data.isPaused);
+    assert.ok(event.data.authority.equals(pool.authority));
+  });
+
+  it("values LP tokens at what remove_liquidity pays for them", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 2_000_000);
+    await swap(f, 50_000, 0, true);
+    const lpAmount = 123_457;
+
+    const signature = await program.methods
+      .getLpValue(new BN(lpAmount))
+      .accountsPartial({ pool: f.pool, lpMint: f.lpMint })
+      .rpc({ commitment: "confirmed" });
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "lpValue"
+    );
+    assert.ok(event, "LpValue not emitted");
+    assert.equal(event.data.lpAmount.toNumber(), lpAmount);
+
+    const [a0, b0] = [await balance(f.userTokenA), await balance(f.userTokenB)];
+    await removeLiquidity(f, lpAmount);
+    const a1 = await balance(f.userTokenA);
+    const b1 = await balance(f.userTokenB);
+    assert.equal(event.data.amountA.toString(), a1.sub(a0).toString());
+    assert.equal(event.data.amountB.toString(), b1.sub(b0).toString());
+
+    // More than the whole supply has no value to report
+    const lpMint = await getMint(connection, f.lpMint);
+    await expectError(
+      program.methods
+        .getLpValue(new BN(lpMint.supply.toString()).addn(1))
+        .accountsPartial({ pool: f.pool, lpMint: f.lpMint })
+        .rpc(),
+      "InsufficientLiquidity"
+    );
+  });
+
+  it("accumulates the TWAP oracle from pre-swap reserves", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 2_000_000);
+    const scale = new BN(10).pow(new BN(12));
+
+    const before = await program.account.pool.fetch(f.pool);
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    await swap(f, 50_000, 0, true);
+    const after = await program.account.pool.fetch(f.pool);
+
+    // The interval is priced at the reserves that held before the swap
+    const elapsed = after.lastUpdateTs.sub(before.lastUpdateTs);
+    assert.isAbove(elapsed.toNumber(), 0);
+    const priceA = before.reserveB.mul(scale).div(before.reserveA);
+    const priceB = before.reserveA.mul(scale).div(before.reserveB);
+    assert.equal(
+      after.priceCumulativeA.sub(before.priceCumulativeA).toString(),
+      priceA.mul(elapsed).toString()
+    );
+    assert.equal(
+      after.priceCumulativeB.sub(before.priceCumulativeB).toString(),
+      priceB.mul(elapsed).toString()
+    );
+  });
+
+  it("advances the TWAP only on pools with the oracle enabled", async () => {
+    const enabled = await createPool();
+    const disabled = await createPool({ oracleEnabled: false });
+    for (const f of [enabled, disabled]) {
+      await addLiquidity(f, 1_000_000, 2_000_000);
+    }
+
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    for (const f of [enabled, disabled]) {
+      await swap(f, 50_000, 0, true);
+    }
+
+    const on = await program.account.pool.fetch(enabled.pool);
+    assert.isTrue(on.oracleEnabled);
+    assert.isTrue(on.priceCumulativeA.gtn(0));
+    const off = await program.account.pool.fetch(disabled.pool);
+    assert.isFalse(off.oracleEnabled);
+    assert.isTrue(off.priceCumulativeA.isZero());
+    assert.isTrue(off.priceCumulativeB.isZero());
+    await expectError(
+      program.methods
+        .consult(1)
+        .accountsPartial({ pool: disabled.pool })
+        .rpc(),
+      "OracleDisabled"
+    );
+  });
+
+  it("reports an oracle observation with consistent timestamps", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 2_000_000);
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    await swap(f, 50_000, 0, true);
+
+    const signature = await program.methods
+      .getOracleObservation()
+      .accountsPartial({ pool: f.pool })
+      .rpc({ commitment: "confirmed" });
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "oracleObservation"
+    );
+    assert.ok(event, "OracleObservation not emitted");
+
+    // The observation matches the account as the swap left it
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.ok(event.data.pool.equals(f.pool));
+    assert.equal(
+      event.data.priceCumulativeA.toString(),
+      pool.priceCumulativeA.toString()
+    );
+    assert.equal(
+      event.data.priceCumulativeB.toString(),
+      pool.priceCumulativeB.toString()
+    );
+    assert.equal(
+      event.data.lastUpdateTs.toString(),
+      pool.lastUpdateTs.toString()
+    );
+    assert.isTrue(event.data.priceCumulativeA.gtn(0));
+    assert.isAtLeast(
+      event.data.currentTs.toNumber(),
+      event.data.lastUpdateTs.toNumber()
+    );
+  });
+
+  it("consults the TWAP from stored observations", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 2_000_000);
+    await swap(f, 50_000, 0, true);
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+
+    // The last second is entirely after the swap, so its average is the
+    // current spot price
+    const pool = await program.account.pool.fetch(f.pool);
+    const twap = await program.methods
+      .consult(1)
+      .accountsPartial({ pool: f.pool })
+      .view();
+    const scale = new BN(1_000_000_000_000);
+    assert.equal(
+      twap.priceAInB.toString(),
+      pool.reserveB.mul(scale).div(pool.reserveA).toString()
+    );
+    assert.equal(
+      twap.priceBInA.toString(),
+      pool.reserveA.mul(scale).div(pool.reserveB).toString()
+    );
+    assert.isAtLeast(pool.observationCount, 2);
+
+    // Nothing was observed before the pool was created
+    await expectError(
+      program.methods
+        .consult(3_600)
+        .accountsPartial({ pool: f.pool })
+        .rpc(),
+      "ObservationTooOld"
+    );
+  });
+
+  it("credits reserves with the amounts the vaults received", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    await swap(f, 30_000, 0, true);
+    await swap(f, 12_345, 0, false);
+
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.equal(
+      (await balance(f.vaultA)).toString(),
+      pool.reserveA.toString()
+    );
+    assert.equal(
+      (await balance(f.vaultB)).toString(),
+      pool.reserveB.toString()
+    );
+  });
+
+  it("swaps between Token-2022 mints", async () => {
+    const f = await createPool({ tokenProgram: TOKEN_2022_PROGRAM_ID });
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    const lpMint = await getMint(
+      connection,
+      f.lpMint,
+      undefined,
+      TOKEN_2022_PROGRAM_ID
+    );
+    assert.isAbove(Number(lpMint.supply), 0);
+
+    const before = await program.account.pool.fetch(f.pool);
+    const amountIn = 10_000;
+    const expected = expectedSwapOutput(
+      new BN(amountIn),
+      before.reserveA,
+      before.reserveB,
+      before.feeBps
+    );
+
+    const userBBefore = await balance(f.userTokenB, f.tokenProgram);
+    await swap(f, amountIn, 0, true);
+    const received = (await balance(f.userTokenB, f.tokenProgram)).sub(
+      userBBefore
+    );
+    assert.equal(received.toString(), expected.toString());
+
+    const after = await program.account.pool.fetch(f.pool);
+    assert.equal(
+      (await balance(f.vaultA, f.tokenProgram)).toString(),
+      after.reserveA.toString()
+    );
+    assert.equal(
+      (await balance(f.vaultB, f.tokenProgram)).toString(),
+      after.reserveB.toString()
+    );
+  });
+
+  it("wraps native SOL into a swap and unwraps the output", async () => {
+    const other = await newMint();
+    const [mintA, mintB] = sortMints(NATIVE_MINT, other);
+    const nativeIsA = mintA.equals(NATIVE_MINT);
+    await initializePool(mintA, mintB);
+
+    // Liquidity still comes from ordinary token accounts
+    const userWsol = await createWrappedNativeAccount(
+      connection,
+      payer,
+      payer.publicKey,
+      1_000_000_000,
+      Keypair.generate()
+    );
+    const userOther = await createAccount(
+      connection,
+      payer,
+      other,
+      payer.publicKey,
+      Keypair.generate()
+    );
+    await mintTo(connection, payer, other, userOther, payer, 1_000_000_000);
+    const addresses = poolAddresses(mintA, mintB);
+    const f: PoolFixture = {
+      mintA,
+      mintB,
+      ...addresses,
+      userTokenA: nativeIsA ? userWsol : userOther,
+      userTokenB: nativeIsA ? userOther : userWsol,
+      userLpToken: await createAccount(
+        connection,
+        payer,
+        addresses.lpMint,
+        payer.publicKey,
+        Keypair.generate()
+      ),
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+    await addLiquidity(f, 500_000_000, 500_000_000);
+
+    const wsolAccount = findPda([
+      Buffer.from("wsol"),
+      payer.publicKey.toBuffer(),
+    ]);
+    // The native side is passed without a token account
+    const swapNative = (
+      amountIn: number,
+      nativeIn: boolean,
+      userOtherAccount: PublicKey | null = userOther
+    ) =>
+      program.methods
+        .swap(
+          new BN(amountIn),
+          new BN(0),
+          nativeIn === nativeIsA,
+          new BN(deadlineIn(60)),
+          null
+        )
+        .accountsPartial({
+          user: payer.publicKey,
+          pool: f.pool,
+          mintA,
+          mintB,
+          userTokenA: nativeIsA ? null : userOtherAccount,
+          userTokenB: nativeIsA ? userOtherAccount : null,
+          vaultA: f.vaultA,
+          vaultB: f.vaultB,
+          referrer: null,
+          wsolAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+    const txFee = async (signature: string) =>
+      (
+        await connection.getTransaction(signature, {
+          commitment: "confirmed",
+          maxSupportedTransactionVersion: 0,
+        })
+      ).meta.fee;
+    const reserves = async (nativeIn: boolean): Promise<[BN, BN]> => {
+      const pool = await program.account.pool.fetch(f.pool);
+      return nativeIn === nativeIsA
+        ? [pool.reserveA, pool.reserveB]
+        : [pool.reserveB, pool.reserveA];
+    };
+
+    // SOL in: exactly amountIn lamports are spent besides the transaction
+    // fee, since the temporary account's rent comes back when it closes
+    const amountIn = 20_000_000;
+    let [reserveIn, reserveOut] = await reserves(true);
+    let expected = expectedSwapOutput(
+      new BN(amountIn),
+      reserveIn,
+      reserveOut,
+      30
+    );
+    let lamportsBefore = await connection.getBalance(payer.publicKey);
+    const otherBefore = await balance(userOther);
+    let signature = await swapNative(amountIn, true);
+    assert.equal(
+      lamportsBefore -
+        (await connection.getBalance(payer.publicKey)) -
+        (await txFee(signature)),
+      amountIn
+    );
+    assert.equal(
+      (await balance(userOther)).sub(otherBefore).toString(),
+      expected.toString()
+    );
+    assert.isNull(await connection.getAccountInfo(wsolAccount));
+
+    // SOL out: the output is unwrapped straight into the user's lamports
+    [reserveIn, reserveOut] = await reserves(false);
+    expected = expectedSwapOutput(new BN(amountIn), reserveIn, reserveOut, 30);
+    lamportsBefore = await connection.getBalance(payer.publicKey);
+    signature = await swapNative(amountIn, false);
+    assert.equal(
+      (await connection.getBalance(payer.publicKey)) -
+        lamportsBefore +
+        (await txFee(signature)),
+      expected.toNumber()
+    );
+    assert.isNull(await connection.getAccountInfo(wsolAccount));
+
+    // Only the native side may go without a token account
+    await expectError(swapNative(amountIn, false, null), "MissingTokenAccount");
+  });
+
+  it("repays a flash loan with its fee in the same transaction", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    const tx = new Transaction().add(
+      await program.methods
+        .flashLoan(new BN(500_000), true)
+        .accountsPartial(flashLoanAccounts(f))
+        .instruction(),
+      await program.methods
+        .repayFlashLoan()
+        .accountsPartial(flashLoanAccounts(f))
+        .instruction()
+    );
+    const userABefore = await balance(f.userTokenA);
+    await provider.sendAndConfirm(tx);
+
+    // 0.3% of 500_000, paid to the LPs
+    const fee = 1_500;
+    assert.equal(
+      userABefore.sub(await balance(f.userTokenA)).toNumber(),
+      fee
+    );
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.reserveA.toNumber(), 1_000_000 + fee);
+    assert.isFalse(pool.flashLoanActive);
+  });
+
+  it("settles a flash swap repaid with enough input", async () => {
+    const f = await createPool({ feeBps: 30 });
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    const flashSwap = async (repayA: number) =>
+      provider.sendAndConfirm(
+        new Transaction().add(
+          await program.methods
+            .flashSwap(new BN(10_000), true)
+            .accountsPartial(flashLoanAccounts(f))
+            .instruction(),
+          await program.methods
+            .repayFlashSwap(new BN(repayA), new BN(0))
+            .accountsPartial(flashLoanAccounts(f))
+            .instruction()
+        )
+      );
+
+    // 10_000 B out of 1_000_000 needs 10_132 A once 0.3% is taken off it
+    const err = await flashSwap(10_131).then(
+      () => null,
+      (e) => e
+    );
+    assert.isNotNull(err, "expected the short repayment to fail");
+    assert.include(err.logs.join("\n"), "FlashLoanNotRepaid");
+
+    const userABefore = await balance(f.userTokenA);
+    const userBBefore = await balance(f.userTokenB);
+    await flashSwap(10_132);
+    assert.equal(
+      userABefore.sub(await balance(f.userTokenA)).toNumber(),
+      10_132
+    );
+    assert.equal(
+      (await balance(f.userTokenB)).sub(userBBefore).toNumber(),
+      10_000
+    );
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.reserveA.toNumber(), 1_010_132);
+    assert.equal(pool.reserveB.toNumber(), 990_000);
+    assert.isFalse(pool.flashLoanActive);
+    assert.isFalse(pool.flashSwap);
+  });
+
+  it("fails a flash loan that is never repaid", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    await expectError(
+      program.methods
+        .flashLoan(new BN(500_000), true)
+        .accountsPartial(flashLoanAccounts(f))
+        .rpc(),
+      "FlashLoanNotRepaid"
+    );
+    // Nothing left the vault
+    assert.equal((await balance(f.vaultA)).toNumber(), 1_000_000);
+  });
+
+  it("recovers a foreign token sent to the pool", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    // A third token, mistakenly sent to an account the pool owns
+    const stray = await newMint();
+    const strayAccount = await createAccount(
+      connection,
+      payer,
+      stray,
+      f.pool,
+      Keypair.generate()
+    );
+    await mintTo(connection, payer, stray, strayAccount, payer, 5_000);
+    const destination = await createAccount(
+      connection,
+      payer,
+      stray,
+      payer.publicKey,
+      Keypair.generate()
+    );
+    const recover = (
+      mint: PublicKey,
+      source: PublicKey,
+      to: PublicKey,
+      authority = payer
+    ) =>
+      program.methods
+        .recoverForeignToken(new BN(5_000))
+        .accountsPartial({
+          authority: authority.publicKey,
+          pool: f.pool,
+          mint,
+          source,
+          destination: to,
+          tokenProgram: f.tokenProgram,
+        })
+        .signers([authority])
+        .rpc();
+
+    await expectError(
+      recover(stray, strayAccount, destination, Keypair.generate()),
+      "Unauthorized"
+    );
+    // The pool's own tokens are never recoverable, vaults included
+    await expectError(
+      recover(f.mintA, f.vaultA, f.userTokenA),
+      "NotForeignMint"
+    );
+
+    const before = await program.account.pool.fetch(f.pool);
+    await recover(stray, strayAccount, destination);
+    assert.equal((await balance(destination)).toNumber(), 5_000);
+    assert.equal((await balance(strayAccount)).toNumber(), 0);
+
+    const after = await program.account.pool.fetch(f.pool);
+    assert.equal(after.reserveA.toString(), before.reserveA.toString());
+    assert.equal(after.reserveB.toString(), before.reserveB.toString());
+    assert.equal(
+      (await balance(f.vaultA)).toString(),
+      after.reserveA.toString()
+    );
+  });
+
+  it("only allows emergency withdrawals by the authority while paused", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    const [treasuryA, treasuryB] = await newTreasury(f);
+    const emergencyWithdraw = (authority: Keypair, accounts) =>
+      program.methods
+        .emergencyWithdraw(new BN(400_000), new BN(300_000))
+        .accountsPartial(accounts)
+        .signers([authority])
+        .rpc({ commitment: "confirmed" });
+
+    await expectError(
+      emergencyWithdraw(
+        payer,
+        authorityWithdrawAccounts(f, treasuryA, treasuryB)
+      ),
+      "PoolNotPaused"
+    );
+
+    await setPaused(f, true);
+    const intruder = Keypair.generate();
+    const [intruderA, intruderB] = await newTreasury(f, intruder.publicKey);
+    await expectError(
+      emergencyWithdraw(
+        intruder,
+        authorityWithdrawAccounts(f, intruderA, intruderB, intruder.publicKey)
+      ),
+      "Unauthorized"
+    );
+
+    const signature = await emergencyWithdraw(
+      payer,
+      authorityWithdrawAccounts(f, treasuryA, treasuryB)
+    );
+    assert.equal((await balance(treasuryA)).toNumber(), 400_000);
+    assert.equal((await balance(treasuryB)).toNumber(), 300_000);
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.reserveA.toNumber(), 600_000);
+    assert.equal(pool.reserveB.toNumber(), 700_000);
+
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "emergencyWithdraw"
+    );
+    assert.ok(event, "EmergencyWithdraw not emitted");
+    assert.ok(event.data.authority.equals(payer.publicKey));
+    assert.equal(event.data.amountA.toNumber(), 400_000);
+    assert.equal(event.data.amountB.toNumber(), 300_000);
+  });
+
+  it("skims donated tokens without touching the reserves", async () => {
+    const f = await createPool({ protocolFeeBps: 5 });
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    await swap(f, 100_000, 0, true);
+
+    // Donate straight to both vaults
+    await transfer(connection, payer, f.userTokenA, f.vaultA, payer, 7_000);
+    await transfer(connection, payer, f.userTokenB, f.vaultB, payer, 3_000);
+
+    const before = await program.account.pool.fetch(f.pool);
+    const [recipientA, recipientB] = await newTreasury(
+      f,
+      Keypair.generate().publicKey
+    );
+    const signature = await program.methods
+      .skim()
+      .accountsPartial({
+        pool: f.pool,
+        mintA: f.mintA,
+        mintB: f.mintB,
+        vaultA: f.vaultA,
+        vaultB: f.vaultB,
+        recipientTokenA: recipientA,
+        recipientTokenB: recipientB,
+        tokenProgram: f.tokenProgram,
+      })
+      .rpc({ commitment: "confirmed" });
+
+    assert.equal((await balance(recipientA)).toNumber(), 7_000);
+    assert.equal((await balance(recipientB)).toNumber(), 3_000);
+
+    // Reserves and protocol fees stay put, and are all the vaults hold now
+    const after = await program.account.pool.fetch(f.pool);
+    assert.equal(after.reserveA.toString(), before.reserveA.toString());
+    assert.equal(after.reserveB.toString(), before.reserveB.toString());
+    assert.equal(
+      after.protocolFeesA.toString(),
+      before.protocolFeesA.toString()
+    );
+    assert.equal(
+      (await balance(f.vaultA)).toString(),
+      after.reserveA.add(after.protocolFeesA).toString()
+    );
+    assert.equal(
+      (await balance(f.vaultB)).toString(),
+      after.reserveB.toString()
+    );
+
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "skimmed"
+    );
+    assert.equal(event.data.amountA.toNumber(), 7_000);
+    assert.equal(event.data.amountB.toNumber(), 3_000);
+  });
+
+  it("shares a donation pro rata among existing LPs", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+
+    const signature = await program.methods
+      .donate(new BN(100_000), new BN(200_000))
+      .accountsPartial({
+        donor: payer.publicKey,
+        pool: f.pool,
+        mintA: f.mintA,
+        mintB: f.mintB,
+        donorTokenA: f.userTokenA,
+        donorTokenB: f.userTokenB,
+        vaultA: f.vaultA,
+        vaultB: f.vaultB,
+        lpMint: f.lpMint,
+        tokenProgram: f.tokenProgram,
+      })
+      .rpc({ commitment: "confirmed" });
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "donated"
+    );
+    assert.equal(event.data.amountA.toNumber(), 100_000);
+    assert.equal(event.data.amountB.toNumber(), 200_000);
+
+    // No LP tokens were minted, so the donation joins the existing shares
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.reserveA.toNumber(), 1_100_000);
+    assert.equal(pool.reserveB.toNumber(), 1_200_000);
+    const lpMint = await getMint(connection, f.lpMint);
+    assert.equal(Number(lpMint.supply), 1_000_000);
+
+    // Half of the user's 999_000 LP tokens withdraws half their share of it
+    const [a0, b0] = [await balance(f.userTokenA), await balance(f.userTokenB)];
+    await removeLiquidity(f, 499_500);
+    const a1 = await balance(f.userTokenA);
+    const b1 = await balance(f.userTokenB);
+    assert.equal(a1.sub(a0).toNumber(), 549_450);
+    assert.equal(b1.sub(b0).toNumber(), 599_400);
+  });
+
+  it("syncs the stored reserves to the vault balances", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    await transfer(connection, payer, f.userTokenA, f.vaultA, payer, 250_000);
+
+    const syncAccounts = (authority: PublicKey) => ({
+      authority,
+      pool: f.pool,
+      vaultA: f.vaultA,
+      vaultB: f.vaultB,
+    });
+
+    // The price moves, so only the authority may sync
+    const stranger = Keypair.generate();
+    await expectError(
+      program.methods
+        .sync()
+        .accountsPartial(syncAccounts(stranger.publicKey))
+        .signers([stranger])
+        .rpc(),
+      "Unauthorized"
+    );
+
+    const signature = await program.methods
+      .sync()
+      .accountsPartial(syncAccounts(payer.publicKey))
+      .rpc({ commitment: "confirmed" });
+
+    const pool = await program.account.pool.fetch(f.pool);
+    assert.equal(pool.reserveA.toNumber(), 1_250_000);
+    assert.equal(pool.reserveB.toNumber(), 1_000_000);
+
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "reservesSynced"
+    );
+    assert.equal(event.data.oldReserveA.toNumber(), 1_000_000);
+    assert.equal(event.data.reserveA.toNumber(), 1_250_000);
+  });
+
+  it("reconciles a rebasing pool after rebases both ways", async () => {
+    // The payer is the mint's permanent delegate, so it can burn from the
+    // vault the way a token rebasing down shrinks every balance
+    const rebasingMint = Keypair.generate();
+    const space = getMintLen([ExtensionType.PermanentDelegate]);
+    await provider.sendAndConfirm(
+      new Transaction().add(
+        SystemProgram.createAccount({
+          fromPubkey: payer.publicKey,
+          newAccountPubkey: rebasingMint.publicKey,
+          space,
+          lamports: await connection.getMinimumBalanceForRentExemption(space),
+          programId: TOKEN_2022_PROGRAM_ID,
+        }),
+        createInitializePermanentDelegateInstruction(
+          rebasingMint.publicKey,
+          payer.publicKey,
+          TOKEN_2022_PROGRAM_ID
+        ),
+        createInitializeMintInstruction(
+          rebasingMint.publicKey,
+          6,
+          payer.publicKey,
+          null,
+          TOKEN_2022_PROGRAM_ID
+        )
+      ),
+      [rebasingMint]
+    );
+    const f = await createPool({
+      mints: [rebasingMint.publicKey, await newMint(6, TOKEN_2022_PROGRAM_ID)],
+      tokenProgram: TOKEN_2022_PROGRAM_ID,
+      rebasing: true,
+    });
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    const vault = vaultFor(f, rebasingMint.publicKey);
+    const rebasingIsA = rebasingMint.publicKey.equals(f.mintA);
+
+    const syncRebase = () =>
+      program.methods
+        .syncRebase()
+        .accountsPartial({ pool: f.pool, vaultA: f.vaultA, vaultB: f.vaultB })
+        .rpc({ commitment: "confirmed" });
+    // What 100_000 of the 1_000_000 LP supply redeems for on each side
+    const lpValue = async () => {
+      const pool = await program.account.pool.fetch(f.pool);
+      const [rebased, other] = rebasingIsA
+        ? [pool.reserveA, pool.reserveB]
+        : [pool.reserveB, pool.reserveA];
+      return [rebased.toNumber() / 10, other.toNumber() / 10];
+    };
+    assert.deepEqual(await lpValue(), [100_000, 100_000]);
+
+    // +10%, which skim must not hand to whoever calls it first
+    await mintTo(
+      connection,
+      payer,
+      rebasingMint.publicKey,
+      vault,
+      payer,
+      100_000,
+      [],
+      undefined,
+      TOKEN_2022_PROGRAM_ID
+    );
+    await expectError(
+      program.methods
+        .skim()
+        .accountsPartial({
+          pool: f.pool,
+          mintA: f.mintA,
+          mintB: f.mintB,
+          vaultA: f.vaultA,
+          vaultB: f.vaultB,
+          recipientTokenA: f.userTokenA,
+          recipientTokenB: f.userTokenB,
+          tokenProgram: f.tokenProgram,
+        })
+        .rpc(),
+      "RebasingPool"
+    );
+    await syncRebase();
+    assert.deepEqual(await lpValue(), [110_000, 100_000]);
+
+    // -20%
+    await burnChecked(
+      connection,
+      payer,
+      vault,
+      rebasingMint.publicKey,
+      payer,
+      220_000,
+      6,
+      [],
+      undefined,
+      TOKEN_2022_PROGRAM_ID
+    );
+    const signature = await syncRebase();
+    assert.deepEqual(await lpValue(), [88_000, 100_000]);
+    const event = (await parseEvents(signature)).find(
+      (e) => e.name === "rebaseSynced"
+    );
+    const [oldReserve, reserve] = rebasingIsA
+      ? [event.data.oldReserveA, event.data.reserveA]
+      : [event.data.oldReserveB, event.data.reserveB];
+    assert.equal(oldReserve.toNumber(), 1_100_000);
+    assert.equal(reserve.toNumber(), 880_000);
+  });
+
+  it("only syncs rebases on pools flagged as rebasing", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    await expectError(
+      program.methods
+        .syncRebase()
+        .accountsPartial({ pool: f.pool, vaultA: f.vaultA, vaultB: f.vaultB })
+        .rpc(),
+      "NotRebasingPool"
+    );
+  });
+
+  it("closes a drained pool and returns its rent", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    await swap(f, 10_000, 0, true);
+
+    // Only the locked minimum liquidity is left once every LP withdraws
+    await removeLiquidity(f, (await balance(f.userLpToken)).toNumber());
+    assert.equal((await getMint(connection, f.lpMint)).supply, BigInt(1_000));
+    // A stray transfer is swept along with the dust reserves
+    await transfer(connection, payer, f.userTokenA, f.vaultA, payer, 1_000);
+    const dustA = await balance(f.vaultA);
+    const dustB = await balance(f.vaultB);
+    assert.isTrue(dustA.gtn(1_000) && dustB.gtn(0));
+
+    const userABefore = await balance(f.userTokenA);
+    const userBBefore = await balance(f.userTokenB);
+    const lamportsBefore = await connection.getBalance(payer.publicKey);
+    await closePool(f);
+    for (const account of [f.pool, f.vaultA, f.vaultB, f.lockedLp]) {
+      assert.isNull(await connection.getAccountInfo(account));
+    }
+    assert.equal((await getMint(connection, f.lpMint)).supply, BigInt(0));
+    assert.equal(
+      (await balance(f.userTokenA)).sub(userABefore).toString(),
+      dustA.toString()
+    );
+    assert.equal(
+      (await balance(f.userTokenB)).sub(userBBefore).toString(),
+      dustB.toString()
+    );
+    assert.isAbove(
+      await connection.getBalance(payer.publicKey),
+      lamportsBefore
+    );
+  });
+
+  it("refuses to close a pool that LPs still hold", async () => {
+    const f = await createPool();
+    await addLiquidity(f, 1_000_000, 1_000_000);
+    await expectError(closePool(f), "PoolNotEmpty");
+
+    // One LP token outside the locked liquidity is enough to keep it open
+    const lp = await balance(f.userLpToken);
+    await removeLiquidity(f, lp.subn(1).toNumber());
+    await expectError(closePool(f), "PoolNotEmpty");
+  });
+
+  // Keep these last: once the config exists it seeds every later pool's fees
+  it("seeds new pools' fees from the global config", async () => {
+    const globalConfig = findPda([Buffer.from("global_config")]);
+    const [programData] = PublicKey.findProgramAddressSync(
+      [program.programId.toBuffer()],
+      BPF_LOADER_UPGRADEABLE_PROGRAM_ID
+    );
+    const updateGlobalConfig = (
+      feeBps: number,
+      protocolFeeBps: number,
+      admin = payer
+    ) =>
+      program.methods
+        .updateGlobalConfig(feeBps, protocolFeeBps)
+        .accountsPartial({ admin: admin.publicKey, globalConfig })
+        .signers([admin])
+        .rpc();
+    const fees = async (f: PoolFixture) => {
+      const pool = await program.account.pool.fetch(f.pool);
+      return [pool.feeBps, pool.protocolFeeBps];
+    };
+
+    await program.methods
+      .initializeGlobalConfig(100, 5)
+      .accountsPartial({
+        admin: payer.publicKey,
+        globalConfig,
+        program: program.programId,
+        programData,
+      })
+      .rpc();
+
+    try {
+      // The default fee applies whatever the pool's tier
+      const seeded = await createPool();
+      assert.deepEqual(await fees(seeded), [100, 5]);
+      const lowTier = await createPool({ feeTier: 5 });
+      assert.deepEqual(await fees(lowTier), [100, 5]);
+      // Fees given at creation still win
+      const explicit = await createPool({ feeBps: 50, protocolFeeBps: 0 });
+      assert.deepEqual(await fees(explicit), [50, 0]);
+
+      await expectError(
+        updateGlobalConfig(40, 10, Keypair.generate()),
+        "Unauthorized"
+      );
+      // The default fee must be one of the tiers
+      await expectError(updateGlobalConfig(40, 10), "InvalidFeeTier");
+      await expectError(updateGlobalConfig(5, 6), "InvalidFee");
+
+      // New defaults apply to new pools only
+      await updateGlobalConfig(5, 2);
+      assert.deepEqual(await fees(await createPool()), [5, 2]);
+      assert.deepEqual(await fees(seeded), [100, 5]);
+    } finally {
+      // Back to what a 30 bps tier pool gets without a config
+      await updateGlobalConfig(30, 0);
+    }
+  });
+
+  // Needs the global config created by the test above
+  it("accrues protocol fees only while the global switch is on", async () => {
+    const globalConfig = findPda([Buffer.from("global_config")]);
+    const setProtocolFeeOn = (on: boolean, admin = payer) =>
+      program.methods
+        .setProtocolFeeOn(on)
+        .accountsPartial({ admin: admin.publicKey, globalConfig })
+        .signers([admin])
+        .rpc();
+    const f = await createPool({ feeBps: 30, protocolFeeBps: 10 });
+    await addLiquidity(f, 10_000_000, 10_000_000);
+
+    await expectError(
+      setProtocolFeeOn(false, Keypair.generate()),
+      "Unauthorized"
+    );
+
+    try {
+      let before = await program.account.pool.fetch(f.pool);
+      await swap(f, 100_000, 0, true);
+      let after = await program.account.pool.fetch(f.pool);
+      assert.equal(
+        after.protocolFeesA.sub(before.protocolFeesA).toNumber(),
+        100
+      );
+      assert.equal(after.reserveA.sub(before.reserveA).toNumber(), 99_900);
+
+      await setProtocolFeeOn(false);
+      before = after;
+      await swap(f, 100_000, 0, true);
+      after = await program.account.pool.fetch(f.pool);
+      // The whole input, fee included, stays with the LPs
+      assert.equal(
+        after.protocolFeesA.toString(),
+        before.protocolFeesA.toString()
+      );
+      assert.equal(after.reserveA.sub(before.reserveA).toNumber(), 100_000);
+      // The pool's own setting is untouched for when the switch comes back
+      assert.equal(after.protocolFeeBps, 10);
+    } finally {
+      await setProtocolFeeOn(true);
+    }
   });
 });